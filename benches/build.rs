@@ -0,0 +1,58 @@
+// Benchmarks for the two halves of a build: flattening the collected tree, and writing a whole container into memory.
+// Run with `cargo bench`, criterion keeps the previous results in target/criterion to compare against
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use toc_maker::{asset_collector::FolderOptions, io_toc::UeVersion, string::Hasher16, toc_factory, AssetCollector, ChunkTypeRegistry, TocFactoryBuilder};
+
+// the same self-cleaning temp folders the unit tests use
+#[path = "../src/test_fixture.rs"]
+mod test_fixture;
+use test_fixture::TempDir;
+
+// (directories, files in each directory)
+const TREE_SIZES: [(usize, usize); 3] = [(10, 10), (100, 10), (100, 100)];
+
+// Lay out directories * files small bulk files under P3R/Content, nested a few levels deep so path building has some work to do
+fn make_source(directories: usize, files: usize, file_size: usize) -> TempDir {
+    let contents: Vec<(String, Vec<u8>)> = (0..directories)
+        .flat_map(|directory| (0..files).map(move |file| (format!("Group{}/Dir{}/File{}.ubulk", directory % 10, directory, file), vec![file as u8; file_size])))
+        .collect();
+    let entries: Vec<(&str, &[u8])> = contents.iter().map(|(path, data)| (path.as_str(), data.as_slice())).collect();
+    TempDir::with_content(&format!("bench-{}x{}-{}", directories, files, file_size), &entries)
+}
+
+fn flatten(c: &mut Criterion) {
+    let mut group = c.benchmark_group("flatten");
+    let chunk_types = ChunkTypeRegistry::new();
+    for (directories, files) in TREE_SIZES {
+        let source = make_source(directories, files, 0x10);
+        let tree = AssetCollector::from_folder(source.to_str().unwrap(), &chunk_types, &FolderOptions::default()).unwrap().get_toc_tree();
+        group.bench_with_input(BenchmarkId::from_parameter(format!("{}x{}", directories, files)), &tree, |b, tree| {
+            b.iter(|| toc_factory::flatten(tree.clone(), UeVersion::UE4_27, &Hasher16, None).unwrap());
+        });
+    }
+    group.finish();
+}
+
+// Collection is part of this too, write_files always starts from the source folder
+fn write_files(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write_files");
+    group.sample_size(20);
+    for (directories, files) in TREE_SIZES {
+        let source = make_source(directories, files, 0x1000);
+        group.bench_function(BenchmarkId::from_parameter(format!("{}x{}", directories, files)), |b| {
+            b.iter_batched(
+                || TocFactoryBuilder::new(source.to_str().unwrap().to_string()).build().unwrap(),
+                |factory| {
+                    let (mut utoc, mut ucas) = (vec![], vec![]);
+                    factory.write_files(&mut utoc, &mut ucas).unwrap();
+                    (utoc, ucas)
+                },
+                BatchSize::SmallInput
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, flatten, write_files);
+criterion_main!(benches);
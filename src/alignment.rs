@@ -1,4 +1,4 @@
-use std::{fs::File, io::Write, iter};
+use std::{io::Write, iter};
 use num::{PrimInt, Unsigned};
 
 
@@ -33,4 +33,6 @@ pub trait AlignableStream: Write {
     }
 }
 
-impl AlignableStream for File {}
\ No newline at end of file
+// Alignment only ever writes padding, so any writer will do. The ucas is written front to back without seeking, which is what
+// lets it go to stdout or a pipe
+impl<W: Write + ?Sized> AlignableStream for W {}
\ No newline at end of file
@@ -1,17 +1,81 @@
 use std::{
-    fs::{self, File}, 
-    io::BufReader, 
+    collections::HashMap,
+    fmt,
+    fs::{self, DirEntry, File}, 
+    io::{self, Read, Seek, Write},
+    ops::RangeInclusive,
     path::{Path, PathBuf},
     sync::{Arc, RwLock, Weak}
 };
 
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::error::TocError;
+use crate::io_toc::{IoChunkType, IoChunkType4};
 use crate::io_package;
-use crate::platform::Metadata;
+use crate::platform::{Metadata, OsPath};
+pub use crate::platform::SpecialFileKind;
+use crate::toc_ignore::{TocIgnore, TOC_IGNORE_FILE_NAME};
+use crate::toc_meta::FileSettings;
 
 pub type TocDirectorySyncRef = Arc<RwLock<TocDirectory>>;
 pub type TocFileSyncRef = Arc<RwLock<TocFile>>;
 
-pub const SUITABLE_FILE_EXTENSIONS: &'static [&'static str] = ["uasset", "ubulk", "uptnl", "umap"].as_slice();
+// Names become part of the virtual path, which has to be UTF-8
+const NON_UTF8_NAME: &str = "Name isn't valid UTF-8";
+
+// Which file extensions get packaged, and the chunk type each one is packaged as. Starts with the types UE 4.27 cooks
+// (uasset, umap, ubulk, uptnl) and can be extended with extra mappings for experimental asset types
+#[derive(Debug, Clone)]
+pub struct ChunkTypeRegistry {
+    chunk_types: HashMap<String, IoChunkType4>, // lowercase extension without the dot
+}
+
+impl ChunkTypeRegistry {
+    pub fn new() -> Self {
+        let mut chunk_types = HashMap::new();
+        chunk_types.insert("uasset".to_string(), IoChunkType4::ExportBundleData);
+        chunk_types.insert("umap".to_string(), IoChunkType4::ExportBundleData);
+        chunk_types.insert("ubulk".to_string(), IoChunkType4::BulkData);
+        // Optional bulk data doesn't need its own ucas or offset space in 4.27. The game finds it by chunk id like any other
+        // chunk (package id + OptionalBulkData), and the payload offsets the cooker wrote into the asset are from the start of
+        // the .uptnl, which is also the start of its chunk. UE5's separate optional segment containers (.o.utoc) aren't made
+        chunk_types.insert("uptnl".to_string(), IoChunkType4::OptionalBulkData);
+        Self { chunk_types }
+    }
+
+    // Add to (or override) the default mappings
+    pub fn extend(&mut self, chunk_types: HashMap<String, IoChunkType4>) {
+        self.chunk_types.extend(chunk_types.into_iter().map(|(extension, chunk_type)| (extension.trim_start_matches('.').to_ascii_lowercase(), chunk_type)));
+    }
+
+    pub fn get_chunk_type(&self, extension: &str) -> Option<IoChunkType4> {
+        // tools on Windows sometimes export with uppercase extensions (Foo.UASSET), which the game doesn't care about
+        self.chunk_types.get(&extension.to_ascii_lowercase()).copied()
+    }
+
+    // Chunk type of a file from its name, or why it can't be packaged. The collector's check is the only place this is worked out,
+    // the chunk type it finds is kept on the TocFile for TocFlattener::get_file_hash
+    pub fn get_file_chunk_type(&self, name: &str) -> Result<IoChunkType4, SkipReason> {
+        let (_, extension) = Self::split_file_name(name).ok_or(SkipReason::NoFileExtension)?;
+        self.get_chunk_type(extension).ok_or(SkipReason::UnsupportedFileType)
+    }
+
+    // Stem and extension of a file name, split at the last dot like Path::extension (so .uasset has no extension)
+    pub fn split_file_name(name: &str) -> Option<(&str, &str)> {
+        name.rsplit_once('.').filter(|(stem, _)| !stem.is_empty())
+    }
+
+    pub fn contains_chunk_type(&self, chunk_type: IoChunkType4) -> bool {
+        self.chunk_types.values().any(|t| *t == chunk_type)
+    }
+
+    // Every chunk type that some extension is packaged as
+    pub fn chunk_types(&self) -> impl Iterator<Item = IoChunkType4> + '_ {
+        self.chunk_types.values().copied()
+    }
+}
 
 pub struct AssetCollector
 {
@@ -21,22 +85,191 @@ pub struct AssetCollector
 
 impl AssetCollector
 {
-    pub fn from_folder(path: &str) -> Result<Self, &'static str> {
+    pub fn from_folder(path: &str, chunk_types: &ChunkTypeRegistry, options: &FolderOptions) -> Result<Self, TocError> {
+        if Path::new(&path).is_file() {
+            // easy to end up with from tab completion, and read_dir's error for it doesn't say much
+            return Err(TocError::NotADirectory(path.to_string()));
+        }
         if Path::exists(Path::new(&path)) {
             let root_dir = TocDirectory::new_rc(None);
             let mut profiler = AssetCollectorProfiler::new(path.to_string());
+            let walk = FolderWalkSettings { chunk_types, options };
             
             let path: PathBuf = PathBuf::from(path);
-            AssetCollector::add_folder(&path, &root_dir, &mut profiler);
-            Ok(Self {
-                root_dir,
-                profiler,
-            })
+            let ancestors = if options.follow_symlinks { vec![fs::canonicalize(&path)?] } else { vec![] };
+            #[cfg(feature = "parallel")]
+            AssetCollector::add_found_entries(AssetCollector::find_entries(&path, &walk, &TocIgnore::default(), &ancestors), &root_dir, &mut profiler);
+            #[cfg(not(feature = "parallel"))]
+            AssetCollector::add_folder(&path, &root_dir, &walk, &TocIgnore::default(), &ancestors, &mut profiler);
+            let mut collector = Self::from_tree(root_dir, profiler);
+            if options.check_bulk_siblings {
+                collector.check_bulk_siblings();
+            }
+            Ok(collector)
         } else {
-            Err("Input path does not exist")
+            Err(TocError::InputNotFound(path.to_string()))
         }
     }
 
+    // Collect base, then lay overlay over it: a file in overlay replaces the one at the same virtual path in base (matched without
+    // case, like the game does), and everything else in overlay is added alongside base's files. Replaced files keep their place in
+    // base's order so the rest of the TOC doesn't move around, and are counted in the profiler's replaced files
+    pub fn from_folders(base: &str, overlay: &str, chunk_types: &ChunkTypeRegistry, options: &FolderOptions) -> Result<Self, TocError> {
+        // siblings are checked once overlay is in place, since it can add the missing half of a pair
+        let walk_options = FolderOptions { check_bulk_siblings: false, ..options.clone() };
+        let mut collector = AssetCollector::from_folder(base, chunk_types, &walk_options)?;
+        let overlay = AssetCollector::from_folder(overlay, chunk_types, &walk_options)?;
+        collector.profiler.os_path = format!("{} (overlaid with {})", collector.profiler.os_path, overlay.profiler.os_path);
+        collector.profiler.skipped_files.extend(overlay.profiler.skipped_files);
+        collector.profiler.skipped_file_size += overlay.profiler.skipped_file_size;
+        collector.profiler.legacy_cooked_count += overlay.profiler.legacy_cooked_count;
+        collector.profiler.failed_file_system_objects.extend(overlay.profiler.failed_file_system_objects);
+        AssetCollector::overlay_directory(&overlay.root_dir, &collector.root_dir, &mut collector.profiler);
+        if options.check_bulk_siblings {
+            collector.check_bulk_siblings();
+        }
+        Ok(collector)
+    }
+
+    // Find assets that don't have bulk data with the same stem in their folder, and bulk data without an asset, and report
+    // them in the profiler. Bulk data without its asset can never be loaded, and an asset that was cooked with bulk data fails
+    // to load without it. Plenty of assets have no bulk data at all, so this is only done when asked for
+    fn check_bulk_siblings(&mut self) {
+        AssetCollector::find_missing_siblings(&self.root_dir, &mut self.profiler);
+    }
+
+    fn find_missing_siblings(dir: &TocDirectorySyncRef, profiler: &mut AssetCollectorProfiler) {
+        let is_bulk_data = |chunk_type: IoChunkType4| IoChunkType::UE4(chunk_type).is_bulk_data();
+        // the game looks packages up without case, so Foo.uasset and foo.ubulk are a pair
+        let mut files = vec![];
+        let mut stems: HashMap<String, (bool, bool)> = HashMap::new(); // has an asset, has bulk data
+        let mut next_file = dir.read().unwrap().first_file.clone();
+        while let Some(file) = next_file {
+            let file = file.read().unwrap();
+            if let Some((stem, _)) = ChunkTypeRegistry::split_file_name(&file.name) {
+                let pair = stems.entry(stem.to_ascii_lowercase()).or_default();
+                pair.0 |= file.chunk_type == IoChunkType4::ExportBundleData;
+                pair.1 |= is_bulk_data(file.chunk_type);
+                files.push((stem.to_string(), file.chunk_type, file.os_file_path.clone()));
+            }
+            next_file = file.next.clone();
+        }
+        for (stem, chunk_type, os_path) in files {
+            let (has_asset, has_bulk_data) = stems[&stem.to_ascii_lowercase()];
+            if chunk_type == IoChunkType4::ExportBundleData && !has_bulk_data {
+                profiler.add_missing_sibling(&os_path, format!("{stem}.ubulk"));
+            } else if is_bulk_data(chunk_type) && !has_asset {
+                profiler.add_missing_sibling(&os_path, format!("{stem}.uasset"));
+            }
+        }
+        let mut next_child = dir.read().unwrap().first_child.clone();
+        while let Some(child) = next_child {
+            AssetCollector::find_missing_siblings(&child, profiler);
+            next_child = child.read().unwrap().next_sibling.clone();
+        }
+    }
+
+    // Move overlay's files and subdirectories into base, replacing base's files that have the same name. Leaves overlay empty
+    fn overlay_directory(overlay: &TocDirectorySyncRef, base: &TocDirectorySyncRef, profiler: &mut AssetCollectorProfiler) {
+        let mut next_file = overlay.write().unwrap().first_file.take();
+        while let Some(file) = next_file {
+            next_file = file.write().unwrap().next.take();
+            let replaced = TocDirectory::find_file(base, &file.read().unwrap().name);
+            match replaced {
+                Some(replaced) => {
+                    let file = file.read().unwrap();
+                    let mut replaced = replaced.write().unwrap();
+                    log::debug!("{} replaced by {}", replaced.os_file_path, file.os_file_path);
+                    profiler.replace_file(replaced.file_size, file.file_size);
+                    replaced.name = file.name.clone();
+                    replaced.file_size = file.file_size;
+                    replaced.os_file_path = file.os_file_path.clone();
+                    replaced.chunk_type = file.chunk_type;
+                },
+                None => {
+                    profiler.add_added_file(file.read().unwrap().file_size);
+                    base.write().unwrap().add_file(file);
+                }
+            }
+        }
+        let mut next_child = overlay.write().unwrap().first_child.take();
+        while let Some(child) = next_child {
+            next_child = child.write().unwrap().next_sibling.take();
+            let name = child.read().unwrap().name.clone().unwrap_or_default();
+            let base_child = TocDirectory::find_child(base, &name).unwrap_or_else(|| AssetCollector::get_or_add_directory(base, &name, profiler));
+            AssetCollector::overlay_directory(&child, &base_child, profiler);
+        }
+    }
+
+    // Remove directories that don't contain any files, either directly or in any of their subdirectories
+    pub fn prune_empty_directories(&mut self) {
+        let removed = TocDirectory::prune_empty_children(&self.root_dir);
+        self.profiler.remove_directories(removed);
+    }
+
+    pub fn get_skipped_files(&self) -> Vec<(&str, SkipReason)> {
+        self.profiler.get_skipped_files().collect()
+    }
+
+    pub fn get_profiler(&self) -> &AssetCollectorProfiler {
+        &self.profiler
+    }
+
+    // Virtual path and size of every file that will be packaged, in the same order they'll be flattened into the TOC
+    pub fn iter_files(&self) -> impl Iterator<Item = (PathBuf, u64)> {
+        let mut files = vec![];
+        AssetCollector::collect_files(&self.root_dir, &mut files);
+        files.into_iter()
+    }
+
+    fn collect_files(dir: &TocDirectorySyncRef, files: &mut Vec<(PathBuf, u64)>) {
+        let dir_path: PathBuf = TocDirectory::get_path_components(dir).iter().collect();
+        let mut next_file = dir.read().unwrap().first_file.clone();
+        while let Some(curr_file) = next_file {
+            let curr_file = curr_file.read().unwrap();
+            files.push((dir_path.join(&curr_file.name), curr_file.file_size));
+            next_file = curr_file.next.clone();
+        }
+        let mut next_child = dir.read().unwrap().first_child.clone();
+        while let Some(child) = next_child {
+            AssetCollector::collect_files(&child, files);
+            next_child = child.read().unwrap().next_sibling.clone();
+        }
+    }
+
+    // Print the tree the same way the tree command does, with the size of each file. A folder's files come before its subfolders,
+    // which is the order they're flattened into the TOC in
+    pub fn write_tree<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "{}", self.profiler.os_path)?;
+        AssetCollector::write_tree_dir(&self.root_dir, "", writer)
+    }
+
+    fn write_tree_dir<W: Write>(dir: &TocDirectorySyncRef, prefix: &str, writer: &mut W) -> io::Result<()> {
+        let mut files = vec![];
+        let mut next_file = dir.read().unwrap().first_file.clone();
+        while let Some(curr_file) = next_file {
+            let curr_file = curr_file.read().unwrap();
+            files.push(format!("{} ({} bytes)", curr_file.name, curr_file.file_size));
+            next_file = curr_file.next.clone();
+        }
+        let mut children = vec![];
+        let mut next_child = dir.read().unwrap().first_child.clone();
+        while let Some(child) = next_child {
+            next_child = child.read().unwrap().next_sibling.clone();
+            children.push(child);
+        }
+        let entry_count = files.len() + children.len();
+        for (i, file) in files.iter().enumerate() {
+            writeln!(writer, "{prefix}{}{file}", if i + 1 == entry_count { "└── " } else { "├── " })?;
+        }
+        for (i, child) in children.iter().enumerate() {
+            let last = files.len() + i + 1 == entry_count;
+            writeln!(writer, "{prefix}{}{}/", if last { "└── " } else { "├── " }, child.read().unwrap().name.as_deref().unwrap_or_default())?;
+            AssetCollector::write_tree_dir(child, &format!("{prefix}{}", if last { "    " } else { "│   " }), writer)?;
+        }
+        Ok(())
+    }
+
     pub fn get_toc_tree(self) -> TocDirectorySyncRef {
         self.root_dir
     }
@@ -45,50 +278,448 @@ impl AssetCollector
         self.profiler.print();
     }
 
-    fn add_folder(os_folder_path: &PathBuf, toc_folder_path: &TocDirectorySyncRef, mut profiler: &mut AssetCollectorProfiler) {
-        for file_entry in fs::read_dir(os_folder_path).unwrap() {
+    pub fn get_stats(&self) -> CollectionStats {
+        CollectionStats {
+            directory_count: self.profiler.get_directory_count(),
+            file_count: self.profiler.get_added_files_count(),
+            file_size: self.profiler.get_added_files_size(),
+            skipped_count: self.profiler.skipped_files.len() as u64,
+        }
+    }
+
+    #[cfg_attr(feature = "parallel", allow(dead_code))]
+    fn add_folder(os_folder_path: &PathBuf, toc_folder_path: &TocDirectorySyncRef, walk: &FolderWalkSettings, ignore: &TocIgnore, ancestors: &[PathBuf], mut profiler: &mut AssetCollectorProfiler) {
+        let ignore = ignore.with_folder(os_folder_path).unwrap_or_else(|e| {
+            profiler.add_failed_fs_object(os_folder_path.join(TOC_IGNORE_FILE_NAME).to_str().unwrap(), e.to_string());
+            ignore.clone()
+        });
+        // the folder can be gone or unreadable by the time it's walked (permissions changed since its parent was listed), which
+        // only loses what's in it
+        let file_entries = match AssetCollector::list_folder(os_folder_path, walk) {
+            Ok(file_entries) => file_entries,
+            Err(e) => {
+                profiler.add_failed_fs_object(os_folder_path.to_str().unwrap(), e.to_string());
+                return;
+            }
+        };
+        for file_entry in file_entries {
             match &file_entry {
                 Ok(fs_obj) => {
-                    let name = fs_obj.file_name().into_string().unwrap(); 
-                    let file_type = fs_obj.file_type().unwrap();
-                    if file_type.is_dir() {
-                        let mut inner_path = PathBuf::from(os_folder_path);
-                        inner_path.push(&name);
-                        let mut new_dir = TocDirectory::new_rc(Some(name));
-                        toc_folder_path.add_directory(new_dir.clone());
-                        AssetCollector::add_folder(&inner_path,&mut new_dir, &mut profiler);
-                        profiler.add_directory();
-                    } else if file_type.is_file() {
-                        let file_size = Metadata::get_object_size(fs_obj);
-                        match PathBuf::from(&name).extension().map(|e| e.to_str().unwrap()) {
-                            Some(file_extension) => {
-                                if SUITABLE_FILE_EXTENSIONS.contains(&file_extension) {
-                                    if file_extension == "uasset" || file_extension == "umap" { // export bundles - requires checking file header to ensure that it doesn't have the cooked asset signature
-                                        let current_file = File::open(fs_obj.path()).unwrap();
-                                        let mut file_reader = BufReader::with_capacity(4, current_file);
-                                        if !io_package::is_valid_asset_type::<BufReader<File>, byteorder::NativeEndian>(&mut file_reader) {
-                                            profiler.add_skipped_file(os_folder_path.to_str().unwrap(), format!("Was not in TOC-specific uasset format"), file_size);
-                                            println!("{name} skipped");
-                                            continue;
-                                        }
-                                    }
-                                    let new_file = TocFile::new_rc(&name, file_size, fs_obj.path().to_str().unwrap());
-                                    toc_folder_path.write().unwrap().add_file(new_file);
-                                    profiler.add_added_file(file_size);
-                                } else {
-                                    profiler.add_skipped_file(fs_obj.path().to_str().unwrap(), format!("Unsupported file type"), file_size);
-                                }
-                            },
-                            None => {
-                                profiler.add_skipped_file(fs_obj.path().to_str().unwrap(), format!("No file extension"), file_size);
-                            }
+                    let name = match fs_obj.file_name().into_string() {
+                        Ok(name) => name,
+                        Err(_) => {
+                            profiler.add_failed_fs_object(&fs_obj.path().to_string_lossy(), NON_UTF8_NAME.to_string());
+                            continue;
+                        }
+                    };
+                    let kind = match AssetCollector::get_entry_kind(fs_obj, walk, ancestors) {
+                        Ok(kind) => kind,
+                        Err(e) => {
+                            profiler.add_failed_fs_object(os_folder_path.to_str().unwrap(), format!("{name}: {e}"));
+                            continue;
                         }
+                    };
+                    if matches!(kind, EntryKind::File(_)) && (name == TOC_IGNORE_FILE_NAME || FileSettings::is_sidecar_name(&name)) {
+                        continue;
+                    }
+                    if ignore.is_ignored(&fs_obj.path(), matches!(kind, EntryKind::Folder(_))) {
+                        let file_size = if let EntryKind::File(file_size) = kind { file_size } else { 0 };
+                        profiler.add_skipped_file(fs_obj.path().to_str().unwrap(), SkipReason::IgnoredByTocIgnore, file_size);
+                        continue;
+                    }
+                    match kind {
+                        EntryKind::Folder(canonical_path) => {
+                            let mut inner_path = PathBuf::from(os_folder_path);
+                            inner_path.push(&name);
+                            let mut new_dir = TocDirectory::new_rc(Some(name));
+                            toc_folder_path.add_directory(new_dir.clone());
+                            let inner_ancestors: Vec<PathBuf> = ancestors.iter().cloned().chain(canonical_path).collect();
+                            AssetCollector::add_folder(&inner_path,&mut new_dir, walk, &ignore, &inner_ancestors, &mut profiler);
+                            profiler.add_directory();
+                        },
+                        EntryKind::File(file_size) => match AssetCollector::check_file_size(file_size, walk) {
+                            Ok(()) => AssetCollector::add_file(&fs_obj.path(), &name, file_size, toc_folder_path, walk.chunk_types, profiler),
+                            Err(reason) => profiler.add_skipped_file(fs_obj.path().to_str().unwrap(), reason, file_size),
+                        },
+                        EntryKind::Skipped(reason) => profiler.add_skipped_file(fs_obj.path().to_str().unwrap(), reason, 0),
                     }
                 },
                 Err(e) => profiler.add_failed_fs_object(os_folder_path.to_str().unwrap(), e.to_string())
             }
         }
     }
+
+    // What a folder entry should be collected as. Symlinks are left out unless following them is turned on, in which case
+    // they're treated like whatever they point to. When following, ancestors holds the canonical path of each folder from the
+    // root down to the one being read, so that a symlink back up to one of them is skipped instead of being walked forever.
+    // Symlinks to folders outside of that chain are fine, even if they're also linked (or copied) somewhere else in the input
+    fn get_entry_kind(fs_obj: &DirEntry, walk: &FolderWalkSettings, ancestors: &[PathBuf]) -> io::Result<EntryKind> {
+        let name = fs_obj.file_name().to_string_lossy().into_owned();
+        if AssetCollector::is_unsafe_name(&name) {
+            return Ok(EntryKind::Skipped(SkipReason::UnsafeName));
+        }
+        // .tocignore is hidden too, but add_folder needs to see it
+        if !walk.options.include_hidden && name != TOC_IGNORE_FILE_NAME && AssetCollector::is_hidden_name(&name) {
+            return Ok(EntryKind::Skipped(SkipReason::HiddenFile));
+        }
+        let file_type = fs_obj.file_type()?;
+        if file_type.is_symlink() {
+            if !walk.options.follow_symlinks {
+                return Ok(EntryKind::Skipped(SkipReason::SymlinkNotFollowed));
+            }
+            let target = fs::canonicalize(fs_obj.path())?; // fails if the link is broken
+            Ok(if target.is_dir() {
+                if ancestors.contains(&target) { EntryKind::Skipped(SkipReason::SymlinkCycle) } else { EntryKind::Folder(Some(target)) }
+            } else if target.is_file() {
+                EntryKind::File(Metadata::get_file_size(&File::open(&target)?))
+            } else {
+                EntryKind::Skipped(SkipReason::SpecialFile(Metadata::get_special_file_kind(&fs::metadata(&target)?.file_type())))
+            })
+        } else if file_type.is_dir() {
+            // a real folder's canonical path is just its parent's plus its name
+            Ok(EntryKind::Folder(ancestors.last().map(|parent| parent.join(fs_obj.file_name()))))
+        } else if file_type.is_file() {
+            Ok(EntryKind::File(Metadata::get_object_size(fs_obj)))
+        } else {
+            Ok(EntryKind::Skipped(SkipReason::SpecialFile(Metadata::get_special_file_kind(&file_type))))
+        }
+    }
+
+    // read_dir's order is whatever the filesystem keeps entries in, which can differ between machines for the same files. Sorting
+    // by name makes the flattened order (and so the container's bytes) only depend on the files themselves
+    fn list_folder(os_folder_path: &Path, walk: &FolderWalkSettings) -> io::Result<Vec<io::Result<DirEntry>>> {
+        let mut file_entries: Vec<_> = fs::read_dir(os_folder_path)?.collect();
+        if walk.options.sorted {
+            file_entries.sort_by_key(|file_entry| file_entry.as_ref().ok().map(|fs_obj| fs_obj.file_name()));
+        }
+        Ok(file_entries)
+    }
+
+    // Read the whole folder on the rayon thread pool, without touching the tree. Subfolders are searched in parallel, and each
+    // file's header check happens here too since that's the other slow part on network drives. Entries stay in list_folder's order
+    // so that add_found_entries builds exactly the same tree as add_folder would
+    #[cfg(feature = "parallel")]
+    fn find_entries(os_folder_path: &Path, walk: &FolderWalkSettings, ignore: &TocIgnore, ancestors: &[PathBuf]) -> Vec<FoundEntry> {
+        let mut found = vec![];
+        let ignore = ignore.with_folder(os_folder_path).unwrap_or_else(|e| {
+            found.push(FoundEntry::Failed(os_folder_path.join(TOC_IGNORE_FILE_NAME).to_str().unwrap().to_string(), e.to_string()));
+            ignore.clone()
+        });
+        // same as add_folder, a folder that can't be listed is reported and the rest of the tree carries on
+        let file_entries = match AssetCollector::list_folder(os_folder_path, walk) {
+            Ok(file_entries) => file_entries,
+            Err(e) => {
+                found.push(FoundEntry::Failed(os_folder_path.to_str().unwrap().to_string(), e.to_string()));
+                return found;
+            }
+        };
+        found.par_extend(file_entries.into_par_iter().filter_map(|file_entry| match file_entry {
+            Ok(fs_obj) => {
+                let name = match fs_obj.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(_) => return Some(FoundEntry::Failed(fs_obj.path().to_string_lossy().into_owned(), NON_UTF8_NAME.to_string()))
+                };
+                let kind = match AssetCollector::get_entry_kind(&fs_obj, walk, ancestors) {
+                    Ok(kind) => kind,
+                    Err(e) => return Some(FoundEntry::Failed(os_folder_path.to_str().unwrap().to_string(), format!("{name}: {e}")))
+                };
+                if matches!(kind, EntryKind::File(_)) && (name == TOC_IGNORE_FILE_NAME || FileSettings::is_sidecar_name(&name)) {
+                    None
+                } else if ignore.is_ignored(&fs_obj.path(), matches!(kind, EntryKind::Folder(_))) {
+                    // ignored folders are reported the same way as ignored files
+                    let file_size = if let EntryKind::File(file_size) = kind { file_size } else { 0 };
+                    Some(FoundEntry::File(fs_obj.path(), name, file_size, Err(SkipReason::IgnoredByTocIgnore)))
+                } else {
+                    match kind {
+                        EntryKind::Folder(canonical_path) => {
+                            let inner_ancestors: Vec<PathBuf> = ancestors.iter().cloned().chain(canonical_path).collect();
+                            Some(FoundEntry::Folder(AssetCollector::find_entries(&fs_obj.path(), walk, &ignore, &inner_ancestors), name))
+                        },
+                        EntryKind::File(file_size) => match AssetCollector::check_file_size(file_size, walk) {
+                            Ok(()) => match AssetCollector::check_file(&fs_obj.path(), &name, file_size, walk.chunk_types) {
+                                Ok(check) => Some(FoundEntry::File(fs_obj.path(), name, file_size, check)),
+                                Err(e) => Some(FoundEntry::Failed(fs_obj.path().to_str().unwrap().to_string(), e.to_string()))
+                            },
+                            Err(reason) => Some(FoundEntry::File(fs_obj.path(), name, file_size, Err(reason)))
+                        },
+                        EntryKind::Skipped(reason) => Some(FoundEntry::File(fs_obj.path(), name, 0, Err(reason))),
+                    }
+                }
+            },
+            Err(e) => Some(FoundEntry::Failed(os_folder_path.to_str().unwrap().to_string(), e.to_string()))
+        }));
+        found
+    }
+
+    // Link the results of find_entries into the tree. This is the only part that modifies it, so it stays on one thread
+    #[cfg(feature = "parallel")]
+    fn add_found_entries(entries: Vec<FoundEntry>, toc_folder_path: &TocDirectorySyncRef, profiler: &mut AssetCollectorProfiler) {
+        for entry in entries {
+            match entry {
+                FoundEntry::Folder(entries, name) => {
+                    let new_dir = TocDirectory::new_rc(Some(name));
+                    toc_folder_path.add_directory(new_dir.clone());
+                    AssetCollector::add_found_entries(entries, &new_dir, profiler);
+                    profiler.add_directory();
+                },
+                FoundEntry::File(os_file_path, name, file_size, check) => AssetCollector::link_file(&os_file_path, &name, file_size, toc_folder_path, check, profiler),
+                FoundEntry::Failed(os_path, error) => profiler.add_failed_fs_object(&os_path, error),
+            }
+        }
+    }
+
+    // Validate a single file and add it into toc_folder_path. This is shared between folder and manifest collection so that both
+    // apply the same extension and uasset format checks
+    fn add_file(os_file_path: &Path, name: &str, file_size: u64, toc_folder_path: &TocDirectorySyncRef, chunk_types: &ChunkTypeRegistry, profiler: &mut AssetCollectorProfiler) {
+        match AssetCollector::check_file(os_file_path, name, file_size, chunk_types) {
+            Ok(check) => AssetCollector::link_file(os_file_path, name, file_size, toc_folder_path, check, profiler),
+            Err(e) => profiler.add_failed_fs_object(os_file_path.to_str().unwrap(), e.to_string())
+        }
+    }
+
+    // Names that would let a path built from the tree (chunk ids, the directory index) point above the folder it's in. Real folders
+    // can't normally have these, but they can come out of crafted archives or manifests, and the tree is joined with /
+    fn is_unsafe_name(name: &str) -> bool {
+        name == "." || name == ".." || name.contains(['/', '\\'])
+    }
+
+    // Dotfiles/folders (.DS_Store, .git, editor temp files), files that Windows drops into folders, and ~ backups. None of
+    // these are meant to be shipped, even when their extension happens to be one that gets packaged
+    pub fn is_hidden_name(name: &str) -> bool {
+        const OS_METADATA_FILES: [&str; 3] = ["thumbs.db", "ehthumbs.db", "desktop.ini"];
+        name.starts_with('.') || name.ends_with('~') || OS_METADATA_FILES.contains(&name.to_ascii_lowercase().as_str())
+    }
+
+    // Checked on the size from read_dir, before anything else about the file
+    fn check_file_size(file_size: u64, walk: &FolderWalkSettings) -> Result<(), SkipReason> {
+        if walk.options.size_range.contains(&file_size) { Ok(()) } else { Err(SkipReason::SizeOutOfRange) }
+    }
+
+    // Whether a file can be packaged. Only reads from disk, so this is safe to call from any thread. The outer error is for files
+    // that couldn't be opened, which are reported as failed instead of skipped
+    fn check_file(os_file_path: &Path, name: &str, file_size: u64, chunk_types: &ChunkTypeRegistry) -> io::Result<Result<IoChunkType4, SkipReason>> {
+        AssetCollector::check_file_with(name, file_size, chunk_types, || File::open(os_file_path))
+    }
+
+    // Same as check_file, for files that aren't on disk. open is only called if the file's header needs to be checked
+    fn check_file_with<R: Read + Seek>(name: &str, file_size: u64, chunk_types: &ChunkTypeRegistry, open: impl FnOnce() -> io::Result<R>) -> io::Result<Result<IoChunkType4, SkipReason>> {
+        let chunk_type = match chunk_types.get_file_chunk_type(name) {
+            Ok(chunk_type) => chunk_type,
+            Err(reason) => return Ok(Err(reason))
+        };
+        if file_size == 0 {
+            // an empty file has no compression blocks, which would leave a chunk in the TOC with nothing backing it
+            return Ok(Err(SkipReason::EmptyFile));
+        }
+        if chunk_type == IoChunkType4::ExportBundleData { // export bundles - requires checking file header to ensure that it doesn't have the cooked asset signature
+            let mut current_file = open()?;
+            match io_package::get_asset_header_kind::<R, byteorder::NativeEndian>(&mut current_file) {
+                io_package::AssetHeaderKind::IoStore => (),
+                io_package::AssetHeaderKind::LegacyCooked => return Ok(Err(SkipReason::LegacyCookedAsset)),
+                io_package::AssetHeaderKind::Unreadable => return Ok(Err(SkipReason::NotTocAssetFormat)),
+            }
+        }
+        Ok(Ok(chunk_type))
+    }
+
+    fn link_file(os_file_path: &Path, name: &str, file_size: u64, toc_folder_path: &TocDirectorySyncRef, check: Result<IoChunkType4, SkipReason>, profiler: &mut AssetCollectorProfiler) {
+        match check {
+            Ok(chunk_type) => {
+                log::trace!("{} added", os_file_path.display());
+                let new_file = TocFile::new_rc(name, file_size, os_file_path.to_str().unwrap(), chunk_type);
+                toc_folder_path.write().unwrap().add_file(new_file);
+                profiler.add_added_file(file_size);
+            },
+            Err(reason) => {
+                if reason == SkipReason::NotTocAssetFormat || reason == SkipReason::LegacyCookedAsset {
+                    log::debug!("{name} skipped: {reason}");
+                }
+                profiler.add_skipped_file(os_file_path.to_str().unwrap(), reason, file_size);
+            }
+        }
+    }
+
+    // Build the tree from a manifest instead of a folder. Each line is "source_path<TAB>virtual_path", where virtual_path is the
+    // file's location inside of the package (e.g. P3R/Content/Characters/Foo.uasset). Blank lines and lines starting with # are ignored
+    pub fn from_manifest(path: &str, chunk_types: &ChunkTypeRegistry) -> Result<Self, TocError> {
+        let manifest = fs::read_to_string(path).map_err(|_| TocError::InputNotFound(path.to_string()))?;
+        let root_dir = TocDirectory::new_rc(None);
+        let mut profiler = AssetCollectorProfiler::new(path.to_string());
+
+        for (line_number, line) in manifest.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (source_path, virtual_path) = line.split_once('\t')
+                .ok_or_else(|| TocError::InvalidManifest(line_number + 1, "expected \"source_path<TAB>virtual_path\"".to_string()))?;
+            let mut components: Vec<&str> = virtual_path.split(['/', '\\']).filter(|c| !c.is_empty()).collect();
+            let name = components.pop()
+                .ok_or_else(|| TocError::InvalidManifest(line_number + 1, "virtual path is empty".to_string()))?;
+
+            let file_size = match File::open(source_path) {
+                Ok(source_file) => Metadata::get_file_size(&source_file),
+                Err(e) => {
+                    profiler.add_failed_fs_object(source_path, e.to_string());
+                    continue;
+                }
+            };
+            if components.iter().chain([&name]).any(|c| AssetCollector::is_unsafe_name(c)) {
+                profiler.add_skipped_file(source_path, SkipReason::UnsafeName, file_size);
+                continue;
+            }
+            let mut toc_folder_path = root_dir.clone();
+            for component in components {
+                toc_folder_path = AssetCollector::get_or_add_directory(&toc_folder_path, component, &mut profiler);
+            }
+            AssetCollector::add_file(Path::new(source_path), name, file_size, &toc_folder_path, chunk_types, &mut profiler);
+        }
+
+        Ok(Self::from_tree(root_dir, profiler))
+    }
+
+    // Build the tree from the files in a zip archive, using each entry's path inside the archive as its path in the package (so the
+    // archive should have the same layout as an input folder). Entry paths take the place of os paths, and the archive is read
+    // again when the container is written
+    #[cfg(feature = "zip")]
+    pub fn from_zip(path: &str, chunk_types: &ChunkTypeRegistry) -> Result<Self, TocError> {
+        let mut archive = zip::ZipArchive::new(File::open(path).map_err(|_| TocError::InputNotFound(path.to_string()))?)
+            .map_err(|e| TocError::InvalidZip(path.to_string(), e.to_string()))?;
+        let root_dir = TocDirectory::new_rc(None);
+        let mut profiler = AssetCollectorProfiler::new(path.to_string());
+
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index).map_err(|e| TocError::InvalidZip(path.to_string(), e.to_string()))?;
+            if entry.is_dir() {
+                continue; // folders are created from the paths of the files inside of them, same as manifests
+            }
+            let entry_path = entry.name().to_string();
+            let mut components: Vec<&str> = entry_path.split(['/', '\\']).filter(|c| !c.is_empty()).collect();
+            let name = match components.pop() {
+                Some(name) => name,
+                None => continue
+            };
+            let file_size = entry.size();
+            if components.iter().chain([&name]).any(|c| AssetCollector::is_unsafe_name(c)) {
+                profiler.add_skipped_file(&entry_path, SkipReason::UnsafeName, file_size);
+                continue;
+            }
+            // zip entries can't seek, so only the part of the header that's checked is read
+            let check = AssetCollector::check_file_with(name, file_size, chunk_types, || {
+                let mut header = Vec::with_capacity(io_package::ASSET_TYPE_CHECK_SIZE);
+                entry.by_ref().take(io_package::ASSET_TYPE_CHECK_SIZE as u64).read_to_end(&mut header)?;
+                Ok(io::Cursor::new(header))
+            });
+            let check = match check {
+                Ok(check) => check,
+                Err(e) => {
+                    profiler.add_failed_fs_object(&entry_path, e.to_string());
+                    continue;
+                }
+            };
+            let mut toc_folder_path = root_dir.clone();
+            for component in components {
+                toc_folder_path = AssetCollector::get_or_add_directory(&toc_folder_path, component, &mut profiler);
+            }
+            AssetCollector::link_file(Path::new(&entry_path), name, file_size, &toc_folder_path, check, &mut profiler);
+        }
+
+        Ok(Self::from_tree(root_dir, profiler))
+    }
+
+    // Folders that only differ in case (Content/ and content/) can both come from a case-sensitive filesystem, a manifest or a
+    // zip, but the game looks paths up without case, so it would only ever see one of them. Merge them instead of losing files
+    fn from_tree(root_dir: TocDirectorySyncRef, mut profiler: AssetCollectorProfiler) -> Self {
+        let merged = TocDirectory::merge_case_insensitive_children(&root_dir);
+        for (path, merged_into) in &merged {
+            log::warn!("{} and {} only differ in case, the game sees them as one folder so their contents were merged into {}", merged_into, path, merged_into);
+        }
+        profiler.remove_directories(merged.len() as u64);
+        Self {
+            root_dir,
+            profiler,
+        }
+    }
+
+    // Find the child directory called name, creating it if this is the first time it's been seen
+    fn get_or_add_directory(parent: &TocDirectorySyncRef, name: &str, profiler: &mut AssetCollectorProfiler) -> TocDirectorySyncRef {
+        let mut next_child = parent.read().unwrap().first_child.clone();
+        while let Some(child) = next_child {
+            if child.read().unwrap().name.as_deref() == Some(name) {
+                return child;
+            }
+            next_child = child.read().unwrap().next_sibling.clone();
+        }
+        let new_dir = TocDirectory::new_rc(Some(name.to_string()));
+        parent.add_directory(new_dir.clone());
+        profiler.add_directory();
+        new_dir
+    }
+
+    // options only apply to folders, manifest entries are always opened through any symlinks and are packaged whatever they're
+    // called or however big they are
+    pub fn from_source(source: &AssetSource, chunk_types: &ChunkTypeRegistry, options: &FolderOptions) -> Result<Self, TocError> {
+        match source {
+            AssetSource::Folder(path) => AssetCollector::from_folder(path, chunk_types, options),
+            AssetSource::Manifest(path) => AssetCollector::from_manifest(path, chunk_types),
+            #[cfg(feature = "zip")]
+            AssetSource::Zip(path) => AssetCollector::from_zip(path, chunk_types),
+        }
+    }
+}
+
+// Settings that stay the same for every folder in a walk
+struct FolderWalkSettings<'a> {
+    chunk_types: &'a ChunkTypeRegistry,
+    options: &'a FolderOptions,
+}
+
+// What to pick up while walking an input folder
+#[derive(Debug, Clone)]
+pub struct FolderOptions {
+    pub follow_symlinks: bool,
+    pub include_hidden: bool, // don't skip names that is_hidden_name matches
+    pub size_range: RangeInclusive<u64>, // files with sizes outside of this are skipped
+    pub sorted: bool, // list each folder in name order instead of the order the filesystem gives
+    pub check_bulk_siblings: bool, // report assets without bulk data next to them and the other way round, see check_bulk_siblings
+}
+
+impl Default for FolderOptions {
+    fn default() -> Self {
+        Self { follow_symlinks: false, include_hidden: false, size_range: 0..=u64::MAX, sorted: false, check_bulk_siblings: false }
+    }
+}
+
+enum EntryKind {
+    Folder(Option<PathBuf>), // canonical path, only worked out when following symlinks
+    File(u64), // size
+    Skipped(SkipReason),
+}
+
+// Something find_entries came across while walking a folder in parallel
+#[cfg(feature = "parallel")]
+enum FoundEntry {
+    Folder(Vec<FoundEntry>, String), // contents, name
+    File(PathBuf, String, u64, Result<IoChunkType4, SkipReason>), // os path, name, size, chunk type if it can be packaged
+    Failed(String, String), // os path, error
+}
+
+// Where the files that get packaged come from
+pub enum AssetSource {
+    Folder(String), // walk a folder, which becomes the root of the package
+    Manifest(String), // explicit list of source files and their virtual paths
+    #[cfg(feature = "zip")]
+    Zip(String), // zip archive laid out like an input folder
+}
+
+impl AssetSource {
+    pub fn get_path(&self) -> &str {
+        match self {
+            AssetSource::Folder(path) | AssetSource::Manifest(path) => path,
+            #[cfg(feature = "zip")]
+            AssetSource::Zip(path) => path,
+        }
+    }
 }
 
 // Create tree of assets that can be used to build a TOC
@@ -98,6 +729,11 @@ impl AssetCollector
 //      |    |    | (refs from child -> parent)
 //      v    |    | (owns from parent -> child and in sibling and file linked lists)
 //      B -> C -> D
+//
+// The locks are what let a finished tree be shared between threads. Each append happens under the directory's write lock (and
+// then the lock of its last child or file), so appending from several threads can't break the lists, but their order would
+// depend on which thread got the lock first, and that order ends up in the TOC. Trees are linked from one thread: the parallel
+// walk (find_entries) only reads folders on the thread pool, and TocDirectoryBuilder does the same for anyone building a tree
 
 pub struct TocDirectory {
     pub name:           Option<String>, // leaf name only (directory name or file name)
@@ -139,6 +775,19 @@ impl TocDirectory {
             None => false
         }
     }
+    // Names of each directory from the root down to dir (the root itself has no name)
+    pub fn get_path_components(dir: &TocDirectorySyncRef) -> Vec<String> {
+        // travel upwards through parents to build the path
+        let mut path_comps: Vec<String> = vec![];
+        let mut next_parent = Some(dir.clone());
+        while let Some(curr_parent) = next_parent {
+            if let Some(t) = curr_parent.read().unwrap().name.as_ref() {
+                path_comps.insert(0, t.to_owned());
+            }
+            next_parent = curr_parent.read().unwrap().parent.upgrade();
+        }
+        path_comps
+    }
     // Add a file child into directory that doesn't currently contain any other files
     fn add_file(&mut self, file: TocFileSyncRef) {
         if self.has_files() {
@@ -149,6 +798,120 @@ impl TocDirectory {
         }
         self.last_file = Arc::downgrade(&file);
     }
+    // File directly inside of dir called name, ignoring case
+    fn find_file(dir: &TocDirectorySyncRef, name: &str) -> Option<TocFileSyncRef> {
+        let mut next_file = dir.read().unwrap().first_file.clone();
+        while let Some(file) = next_file {
+            if file.read().unwrap().name.eq_ignore_ascii_case(name) {
+                return Some(file);
+            }
+            next_file = file.read().unwrap().next.clone();
+        }
+        None
+    }
+    // Subdirectory directly inside of dir called name, ignoring case
+    fn find_child(dir: &TocDirectorySyncRef, name: &str) -> Option<TocDirectorySyncRef> {
+        let mut next_child = dir.read().unwrap().first_child.clone();
+        while let Some(child) = next_child {
+            if child.read().unwrap().name.as_ref().is_some_and(|child_name| child_name.eq_ignore_ascii_case(name)) {
+                return Some(child);
+            }
+            next_child = child.read().unwrap().next_sibling.clone();
+        }
+        None
+    }
+    // Move the files and subdirectories of each subdirectory whose name only differs in case from an earlier one into that earlier
+    // one, all the way down. Returns the path of each directory that was merged away, and the path it was merged into
+    fn merge_case_insensitive_children(dir: &TocDirectorySyncRef) -> Vec<(String, String)> {
+        let mut merged = vec![];
+        let mut kept: Vec<TocDirectorySyncRef> = vec![];
+        let mut next_child = dir.write().unwrap().first_child.take();
+        while let Some(child) = next_child {
+            next_child = child.write().unwrap().next_sibling.take();
+            let name = child.read().unwrap().name.clone().unwrap_or_default();
+            match kept.iter().find(|kept| kept.read().unwrap().name.as_ref().is_some_and(|kept| kept.eq_ignore_ascii_case(&name))) {
+                Some(merge_into) => {
+                    merged.push((TocDirectory::get_path_components(&child).join("/"), TocDirectory::get_path_components(merge_into).join("/")));
+                    TocDirectory::move_contents(&child, merge_into);
+                },
+                None => kept.push(child)
+            }
+        }
+        dir.write().unwrap().last_child = Weak::new();
+        for child in kept {
+            dir.add_directory(child.clone());
+            merged.append(&mut TocDirectory::merge_case_insensitive_children(&child));
+        }
+        merged
+    }
+    // Append the files and subdirectories of from to the ones in to, leaving from empty
+    fn move_contents(from: &TocDirectorySyncRef, to: &TocDirectorySyncRef) {
+        let mut next_file = from.write().unwrap().first_file.take();
+        while let Some(file) = next_file {
+            next_file = file.write().unwrap().next.take();
+            to.write().unwrap().add_file(file);
+        }
+        let mut next_child = from.write().unwrap().first_child.take();
+        while let Some(child) = next_child {
+            next_child = child.write().unwrap().next_sibling.take();
+            to.add_directory(child);
+        }
+    }
+    // Unlink every subdirectory of dir that has no files in its subtree, returning the number of directories removed
+    fn prune_empty_children(dir: &TocDirectorySyncRef) -> u64 {
+        let mut removed = 0;
+        let mut kept = vec![];
+        let mut next_child = dir.write().unwrap().first_child.take();
+        while let Some(child) = next_child {
+            next_child = child.write().unwrap().next_sibling.take();
+            removed += TocDirectory::prune_empty_children(&child);
+            let is_empty = { let child = child.read().unwrap(); !child.has_files() && !child.has_children() };
+            if is_empty {
+                removed += 1;
+            } else {
+                kept.push(child);
+            }
+        }
+        dir.write().unwrap().last_child = Weak::new();
+        for child in kept {
+            dir.add_directory(child);
+        }
+        removed
+    }
+}
+
+// A directory that's still being put together, as plain owned values with no locks or links. Subtrees can be built on separate
+// threads and handed to their parent with add_directory, then build links the whole tree on the calling thread. Files and
+// subdirectories keep the order they were added in, whichever thread built them
+pub struct TocDirectoryBuilder {
+    name: Option<String>,
+    children: Vec<TocDirectoryBuilder>,
+    files: Vec<TocFile>,
+}
+
+impl TocDirectoryBuilder {
+    pub fn new(name: Option<String>) -> Self {
+        Self { name, children: vec![], files: vec![] }
+    }
+    pub fn add_file(&mut self, name: &str, file_size: u64, os_path: &str, chunk_type: IoChunkType4) {
+        self.files.push(TocFile::new(name, file_size, os_path, chunk_type));
+    }
+    pub fn add_directory(&mut self, dir: TocDirectoryBuilder) {
+        self.children.push(dir);
+    }
+    pub fn build(self) -> TocDirectorySyncRef {
+        let dir = TocDirectory::new_rc(self.name);
+        {
+            let mut dir = dir.write().unwrap();
+            for file in self.files {
+                dir.add_file(Arc::new(RwLock::new(file)));
+            }
+        }
+        for child in self.children {
+            dir.add_directory(child.build());
+        }
+        dir
+    }
 }
 
 trait TocDir {
@@ -176,20 +939,22 @@ pub struct TocFile {
     pub name: String,
     pub file_size: u64,
     pub os_file_path: String,
+    pub chunk_type: IoChunkType4, // from the extension, decided once when the file was collected
 }
 
 impl TocFile {
-    fn new(name: &str, file_size: u64, os_path: &str) -> Self {
+    fn new(name: &str, file_size: u64, os_path: &str, chunk_type: IoChunkType4) -> Self {
         Self {
             next: None,
             name: String::from(name),
             file_size,
-            os_file_path: String::from(os_path)
+            os_file_path: OsPath::normalize(os_path),
+            chunk_type
         }
     }
     #[inline] // convenience function to create reference counted toc files
-    pub fn new_rc(name: &str, file_size: u64, os_path: &str) -> Arc<RwLock<Self>> {
-        Arc::new(RwLock::new(TocFile::new(name, file_size, os_path)))
+    pub fn new_rc(name: &str, file_size: u64, os_path: &str, chunk_type: IoChunkType4) -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(TocFile::new(name, file_size, os_path, chunk_type)))
     }
 
     pub fn add_sibling(&mut self, sibling: TocFileSyncRef) {
@@ -198,20 +963,84 @@ impl TocFile {
     }
 }
 
+// Totals from collecting the input, kept separately from the collector so they can outlive it (e.g. in TocError::WriteFailed)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollectionStats {
+    pub directory_count: u64,
+    pub file_count: u64,
+    pub file_size: u64,
+    pub skipped_count: u64,
+}
+
 #[derive(Debug, PartialEq)]
 struct AssetCollectorProfilerFailedFsObject {
     os_path: String,
     reason: String
 }
 
+// Why a file in the input wasn't packaged. Display gives the reason as it's shown in the collection summary
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    UnsupportedFileType,
+    NoFileExtension,
+    NotTocAssetFormat, // uasset/umap that's too short to have a package header, so it's probably corrupt
+    LegacyCookedAsset, // uasset/umap with the package file magic, which means it was cooked for .pak files rather than IoStore
+    IgnoredByTocIgnore, // matched a pattern in a .tocignore (see toc_ignore.rs). Also used for ignored folders
+    SymlinkNotFollowed,
+    EmptyFile,
+    SymlinkCycle, // symlink to a folder that contains it
+    UnsafeName, // . or .. (or a name containing a path separator) that could escape the package root
+    HiddenFile, // dotfile or OS metadata file, see is_hidden_name. Also used for hidden folders
+    SpecialFile(SpecialFileKind), // neither a file nor a folder (e.g. a named pipe left in a build folder)
+    SizeOutOfRange, // smaller or larger than FolderOptions::size_range allows
+}
+
+impl SkipReason {
+    // Skips the user asked for (a .tocignore pattern or the size range), as opposed to files that were left out because
+    // they couldn't be packaged. --strict only fails on the latter
+    pub fn is_requested(&self) -> bool {
+        matches!(self, SkipReason::IgnoredByTocIgnore | SkipReason::SizeOutOfRange)
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            SkipReason::UnsupportedFileType => "Unsupported file type",
+            SkipReason::NoFileExtension => "No file extension",
+            SkipReason::NotTocAssetFormat => "Was not in TOC-specific uasset format (file is too short, it may be corrupt)",
+            SkipReason::LegacyCookedAsset => "Cooked for .pak files instead of IoStore, re-cook it with IoStore enabled",
+            SkipReason::IgnoredByTocIgnore => "Ignored by .tocignore",
+            SkipReason::SymlinkNotFollowed => "Symlink (use --follow-symlinks to include it)",
+            SkipReason::EmptyFile => "File is empty",
+            SkipReason::SymlinkCycle => "Symlink to a folder that contains it",
+            SkipReason::UnsafeName => "Path contains . or .. (or a path separator inside of a name), which could escape the package root",
+            SkipReason::HiddenFile => "Hidden or OS metadata file (use --include-hidden to include it)",
+            SkipReason::SpecialFile(SpecialFileKind::NamedPipe) => "Named pipe, only regular files can be packaged",
+            SkipReason::SpecialFile(SpecialFileKind::Socket) => "Socket, only regular files can be packaged",
+            SkipReason::SpecialFile(SpecialFileKind::BlockDevice) => "Block device, only regular files can be packaged",
+            SkipReason::SpecialFile(SpecialFileKind::CharacterDevice) => "Character device, only regular files can be packaged",
+            SkipReason::SpecialFile(SpecialFileKind::Unknown) => "Not a regular file or folder",
+            SkipReason::SizeOutOfRange => "File size is outside of the --min-size/--max-size range",
+        })
+    }
+}
+
 #[derive(Debug, PartialEq)]
 struct AssetCollectorSkippedFileEntry {
     os_path: String,
-    reason: String,
+    reason: SkipReason,
 }
 
 #[derive(Debug, PartialEq)]
-struct AssetCollectorProfiler {
+struct AssetCollectorMissingSibling {
+    os_path: String,
+    sibling_name: String, // the file that should be next to it
+}
+
+// What happened while collecting, printed by print_stats. Read it with AssetCollector::get_profiler
+#[derive(Debug, PartialEq)]
+pub struct AssetCollectorProfiler {
     os_path: String,
     failed_file_system_objects: Vec<AssetCollectorProfilerFailedFsObject>,
     directory_count: u64,
@@ -219,12 +1048,14 @@ struct AssetCollectorProfiler {
     added_files_size: u64,
     replaced_files_count: u64,
     replaced_files_size: u64,
-    skipped_files: Vec<AssetCollectorSkippedFileEntry>,
+    skipped_files: Vec<AssetCollectorSkippedFileEntry>, // os path and reason for every file that was found but not packaged
     skipped_file_size: u64,
+    legacy_cooked_count: u64, // skipped files that were cooked for .pak files, see SkipReason::LegacyCookedAsset
+    missing_siblings: Vec<AssetCollectorMissingSibling>, // only filled in with FolderOptions::check_bulk_siblings
 }
 
 impl AssetCollectorProfiler {
-    pub fn new(root_path: String) -> Self {
+    fn new(root_path: String) -> Self {
         Self {
             os_path: root_path,
             failed_file_system_objects: vec![],
@@ -235,6 +1066,8 @@ impl AssetCollectorProfiler {
             replaced_files_size: 0,
             skipped_files: vec![],
             skipped_file_size: 0,
+            legacy_cooked_count: 0,
+            missing_siblings: vec![],
         }
     }
 
@@ -242,43 +1075,591 @@ impl AssetCollectorProfiler {
         80
     }
 
+    // Source folder (or manifest/archive) that was collected from
+    pub fn get_os_path(&self) -> &str {
+        &self.os_path
+    }
+    pub fn get_directory_count(&self) -> u64 {
+        self.directory_count
+    }
+    pub fn get_added_files_count(&self) -> u64 {
+        self.added_files_count
+    }
+    pub fn get_added_files_size(&self) -> u64 {
+        self.added_files_size
+    }
+    pub fn get_replaced_files_count(&self) -> u64 {
+        self.replaced_files_count
+    }
+    pub fn get_replaced_files_size(&self) -> u64 {
+        self.replaced_files_size
+    }
+    pub fn get_skipped_files(&self) -> impl Iterator<Item = (&str, SkipReason)> {
+        self.skipped_files.iter().map(|skipped| (skipped.os_path.as_str(), skipped.reason))
+    }
+    pub fn get_skipped_count(&self, reason: SkipReason) -> usize {
+        self.skipped_files.iter().filter(|skipped| skipped.reason == reason).count()
+    }
+    pub fn get_skipped_file_size(&self) -> u64 {
+        self.skipped_file_size
+    }
+    // Folder that each failure happened in, and what went wrong
+    pub fn get_failed_fs_objects(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.failed_file_system_objects.iter().map(|failed| (failed.os_path.as_str(), failed.reason.as_str()))
+    }
+    pub fn get_legacy_cooked_count(&self) -> u64 {
+        self.legacy_cooked_count
+    }
+    // os path of each packaged file whose asset or bulk data is missing, and the name of the file that's missing
+    pub fn get_missing_siblings(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.missing_siblings.iter().map(|missing| (missing.os_path.as_str(), missing.sibling_name.as_str()))
+    }
+
     pub fn print(&self) {
-        println!("{}", "#".repeat(AssetCollectorProfiler::get_terminal_length()));
-        println!("Collecting assets from: {}", self.os_path);
-        println!("{}", "=".repeat(AssetCollectorProfiler::get_terminal_length()));
-        println!("{} directories added", self.directory_count);
-        println!("{} added files ({} KB)", self.added_files_count, self.added_files_size / 1024);
-        println!("{} replaced files ({} KB)", self.replaced_files_count, self.replaced_files_size / 1024);
+        log::info!("{}", "#".repeat(AssetCollectorProfiler::get_terminal_length()));
+        log::info!("Collecting assets from: {}", self.os_path);
+        log::info!("{}", "=".repeat(AssetCollectorProfiler::get_terminal_length()));
+        log::info!("{} directories added", self.directory_count);
+        log::info!("{} added files ({} KB)", self.added_files_count, self.added_files_size / 1024);
+        log::info!("{} replaced files ({} KB)", self.replaced_files_count, self.replaced_files_size / 1024);
         if self.skipped_files.len() > 0 {
-            println!("{}", "-".repeat(AssetCollectorProfiler::get_terminal_length()));
-            println!("SKIPPED: {} FILES", self.skipped_files.len());
+            log::info!("{}", "-".repeat(AssetCollectorProfiler::get_terminal_length()));
+            log::info!("SKIPPED: {} FILES", self.skipped_files.len());
             for i in &self.skipped_files {
-                println!("File: {}, reason: {}", i.os_path, i.reason);
+                log::info!("File: {}, reason: {}", i.os_path, i.reason);
+            }
+        }
+        if self.legacy_cooked_count > 0 {
+            // almost always means the whole project was cooked without IoStore, rather than a few bad files
+            log::warn!("{} assets were cooked for .pak files instead of IoStore and were skipped. Re-cook the project with \"Use Io Store\" enabled (Project Settings > Packaging)", self.legacy_cooked_count);
+        }
+        if !self.missing_siblings.is_empty() {
+            log::info!("{}", "-".repeat(AssetCollectorProfiler::get_terminal_length()));
+            log::warn!("MISSING ASSET OR BULK DATA: {} FILES", self.missing_siblings.len());
+            for i in &self.missing_siblings {
+                log::warn!("File: {}, {} isn't next to it", i.os_path, i.sibling_name);
             }
         }
         if self.failed_file_system_objects.len() > 0 {
-            println!("{}", "-".repeat(AssetCollectorProfiler::get_terminal_length()));
-            println!("FAILED TO LOAD: {} FILES", self.failed_file_system_objects.len());
+            log::info!("{}", "-".repeat(AssetCollectorProfiler::get_terminal_length()));
+            log::warn!("FAILED TO LOAD: {} FILES", self.failed_file_system_objects.len());
             for i in &self.failed_file_system_objects {
-                println!("Inside folder \"{}\", reason \"{}\"", i.os_path, i.reason);
+                log::warn!("Inside folder \"{}\", reason \"{}\"", i.os_path, i.reason);
             }
         }
-        println!("{}", "=".repeat(AssetCollectorProfiler::get_terminal_length()));
+        log::info!("{}", "=".repeat(AssetCollectorProfiler::get_terminal_length()));
     }
 
-    pub fn add_failed_fs_object(&mut self, parent_dir: &str, reason: String) {
-        self.failed_file_system_objects.push(AssetCollectorProfilerFailedFsObject { os_path: parent_dir.to_owned(), reason })
+    fn add_failed_fs_object(&mut self, parent_dir: &str, reason: String) {
+        self.failed_file_system_objects.push(AssetCollectorProfilerFailedFsObject { os_path: OsPath::normalize(parent_dir), reason })
     }
 
-    pub fn add_skipped_file(&mut self, os_path: &str, reason: String, size: u64) {
-        self.skipped_files.push(AssetCollectorSkippedFileEntry { os_path: os_path.to_owned(), reason });
+    fn add_skipped_file(&mut self, os_path: &str, reason: SkipReason, size: u64) {
+        self.skipped_files.push(AssetCollectorSkippedFileEntry { os_path: OsPath::normalize(os_path), reason });
         self.skipped_file_size += size;
+        if reason == SkipReason::LegacyCookedAsset {
+            self.legacy_cooked_count += 1;
+        }
     }
-    pub fn add_directory(&mut self) {
+    fn add_missing_sibling(&mut self, os_path: &str, sibling_name: String) {
+        self.missing_siblings.push(AssetCollectorMissingSibling { os_path: OsPath::normalize(os_path), sibling_name });
+    }
+    fn add_directory(&mut self) {
         self.directory_count += 1;
     }
-    pub fn add_added_file(&mut self, size: u64) {
+    fn remove_directories(&mut self, count: u64) {
+        self.directory_count -= count;
+    }
+    fn add_added_file(&mut self, size: u64) {
         self.added_files_count += 1;
         self.added_files_size += size;
     }
-}
\ No newline at end of file
+    // A file that takes the place of one that was already added, so the number of files stays the same
+    fn replace_file(&mut self, old_size: u64, new_size: u64) {
+        self.added_files_size = self.added_files_size - old_size + new_size;
+        self.replaced_files_count += 1;
+        self.replaced_files_size += old_size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixture::TempDir;
+
+    #[test]
+    fn prune_empty_is_transitive() {
+        // root -> A -> B -> C (all empty), root -> D -> E (E has a file), root -> F (empty)
+        let root = TocDirectory::new_rc(None);
+        let mut profiler = AssetCollectorProfiler::new(String::new());
+        let mut chain = root.clone();
+        for name in ["A", "B", "C"] {
+            chain = AssetCollector::get_or_add_directory(&chain, name, &mut profiler);
+        }
+        let d = AssetCollector::get_or_add_directory(&root, "D", &mut profiler);
+        let e = AssetCollector::get_or_add_directory(&d, "E", &mut profiler);
+        e.write().unwrap().add_file(TocFile::new_rc("Foo.uasset", 0, "Foo.uasset", IoChunkType4::ExportBundleData));
+        AssetCollector::get_or_add_directory(&root, "F", &mut profiler);
+
+        let mut collector = AssetCollector { root_dir: root.clone(), profiler };
+        collector.prune_empty_directories();
+
+        let first_child = root.read().unwrap().first_child.clone().unwrap();
+        assert_eq!(first_child.read().unwrap().name.as_deref(), Some("D"));
+        assert!(first_child.read().unwrap().next_sibling.is_none());
+        assert!(Arc::ptr_eq(&first_child.read().unwrap().first_child.clone().unwrap(), &e));
+        assert_eq!(collector.profiler.directory_count, 2);
+
+        // adding after pruning has to link onto the remaining child, not a removed one
+        let g = AssetCollector::get_or_add_directory(&root, "G", &mut collector.profiler);
+        assert!(Arc::ptr_eq(&first_child.read().unwrap().next_sibling.clone().unwrap(), &g));
+    }
+
+    #[test]
+    fn builder_subtrees_can_be_built_on_other_threads() {
+        let subtrees: Vec<TocDirectoryBuilder> = std::thread::scope(|scope| {
+            let threads: Vec<_> = ["A", "B", "C"].into_iter().map(|name| scope.spawn(move || {
+                let mut dir = TocDirectoryBuilder::new(Some(name.to_string()));
+                for i in 0..3 {
+                    dir.add_file(&format!("{name}{i}.ubulk"), i, &format!("{name}{i}.ubulk"), IoChunkType4::BulkData);
+                }
+                let mut inner = TocDirectoryBuilder::new(Some("Inner".to_string()));
+                inner.add_file("Foo.uasset", 0x10, "Foo.uasset", IoChunkType4::ExportBundleData);
+                dir.add_directory(inner);
+                dir
+            })).collect();
+            threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+        });
+        let mut root = TocDirectoryBuilder::new(None);
+        root.add_file("Root.ubulk", 0x20, "Root.ubulk", IoChunkType4::BulkData);
+        for subtree in subtrees {
+            root.add_directory(subtree);
+        }
+
+        let collector = AssetCollector { root_dir: root.build(), profiler: AssetCollectorProfiler::new(String::new()) };
+        let files: Vec<_> = collector.iter_files().map(|(path, _)| path.to_str().unwrap().replace('\\', "/")).collect();
+        let mut expected = vec!["Root.ubulk".to_string()];
+        for name in ["A", "B", "C"] {
+            expected.extend((0..3).map(|i| format!("{name}/{name}{i}.ubulk")));
+            expected.push(format!("{name}/Inner/Foo.uasset"));
+        }
+        assert_eq!(files, expected);
+    }
+
+    #[test]
+    fn unlistable_folder_is_reported() {
+        // stands in for a subfolder that's lost its permissions (or been deleted) since its parent was listed
+        let folder = TempDir::new("unlistable");
+        let missing = folder.join("Gone");
+        let root = TocDirectory::new_rc(None);
+        let mut profiler = AssetCollectorProfiler::new(String::new());
+        let (chunk_types, options) = (ChunkTypeRegistry::new(), FolderOptions::default());
+        let walk = FolderWalkSettings { chunk_types: &chunk_types, options: &options };
+        #[cfg(feature = "parallel")]
+        AssetCollector::add_found_entries(AssetCollector::find_entries(&missing, &walk, &TocIgnore::default(), &[]), &root, &mut profiler);
+        #[cfg(not(feature = "parallel"))]
+        AssetCollector::add_folder(&missing, &root, &walk, &TocIgnore::default(), &[], &mut profiler);
+
+        let failed: Vec<_> = profiler.get_failed_fs_objects().collect();
+        assert_eq!(failed.len(), 1);
+        assert!(failed[0].0.ends_with("Gone"));
+    }
+
+    #[test]
+    fn unopenable_asset_is_reported() {
+        // a .uasset has its header checked, which needs the file to still be there
+        let folder = TempDir::new("unopenable");
+        let missing = folder.join("Foo.uasset");
+        let root = TocDirectory::new_rc(None);
+        let mut profiler = AssetCollectorProfiler::new(String::new());
+        AssetCollector::add_file(&missing, "Foo.uasset", 0x10, &root, &ChunkTypeRegistry::new(), &mut profiler);
+
+        let failed: Vec<_> = profiler.get_failed_fs_objects().collect();
+        assert_eq!(failed.len(), 1);
+        assert!(failed[0].0.ends_with("Foo.uasset"));
+        assert!(root.read().unwrap().first_file.is_none());
+    }
+
+    #[test]
+    fn file_as_input_folder_is_an_error() {
+        let folder = TempDir::new("input-file");
+        let file = folder.join("Foo.uasset");
+        fs::write(&file, [0u8; 0x10]).unwrap();
+        let result = AssetCollector::from_folder(file.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions::default());
+        assert!(matches!(result, Err(TocError::NotADirectory(path)) if path == file.to_str().unwrap()));
+    }
+
+    #[test]
+    fn overlay_replaces_base_files() {
+        let base = TempDir::with_content("overlay-base", &[("A.ubulk", &[0u8; 0x10]), ("B.ubulk", &[0u8; 0x20]), ("Sub/C.ubulk", &[0u8; 0x30])]);
+        let overlay = TempDir::with_content("overlay", &[("b.ubulk", &[0u8; 0x25]), ("D.ubulk", &[0u8; 0x40]), ("New/E.ubulk", &[0u8; 0x50])]);
+
+        let (chunk_types, options) = (ChunkTypeRegistry::new(), FolderOptions::default());
+        let base_files: Vec<_> = AssetCollector::from_folder(base.to_str().unwrap(), &chunk_types, &options).unwrap().iter_files().collect();
+        let collector = AssetCollector::from_folders(base.to_str().unwrap(), overlay.to_str().unwrap(), &chunk_types, &options).unwrap();
+
+        assert_eq!(collector.profiler.replaced_files_count, 1);
+        assert_eq!(collector.profiler.replaced_files_size, 0x20);
+        assert_eq!(collector.profiler.added_files_count, 5);
+        assert_eq!(collector.profiler.added_files_size, 0x10 + 0x25 + 0x30 + 0x40 + 0x50);
+        assert_eq!(collector.profiler.directory_count, 4); // P3R, Content, Sub, New
+        // the replaced file stays where B was, new files and folders go after base's
+        let path = |path: &str| path.split('/').collect::<PathBuf>();
+        let mut expected: Vec<_> = base_files.into_iter()
+            .map(|(file_path, size)| if file_path == path("P3R/Content/B.ubulk") { (path("P3R/Content/b.ubulk"), 0x25) } else { (file_path, size) })
+            .collect();
+        expected.insert(2, (path("P3R/Content/D.ubulk"), 0x40));
+        expected.push((path("P3R/Content/New/E.ubulk"), 0x50));
+        assert_eq!(collector.iter_files().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn missing_bulk_siblings_are_reported() {
+        // Paired has both halves (in different case), Lonely has no bulk data, Orphan and Sub/Paired have no asset
+        let folder = TempDir::with_content("bulk-siblings", &[("Paired.uasset", &[0u8; 0x10]), ("paired.UBULK", &[0; 0x10]), ("Lonely.uasset", &[0; 0x10]), ("Orphan.uptnl", &[0; 0x10]), ("Sub/Paired.ubulk", &[0; 0x10])]);
+
+        let options = FolderOptions { check_bulk_siblings: true, ..Default::default() };
+        let checked = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &options).unwrap();
+        let unchecked = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions::default()).unwrap();
+
+        let mut missing: Vec<_> = checked.get_profiler().get_missing_siblings().map(|(os_path, sibling)| (Path::new(os_path).file_name().unwrap().to_str().unwrap().to_string(), sibling.to_string())).collect();
+        missing.sort();
+        assert_eq!(missing, [("Lonely.uasset", "Lonely.ubulk"), ("Orphan.uptnl", "Orphan.uasset"), ("Paired.ubulk", "Paired.uasset")].map(|(a, b)| (a.to_string(), b.to_string())));
+        assert_eq!(checked.get_profiler().get_added_files_count(), 5);
+        assert_eq!(unchecked.get_profiler().get_missing_siblings().count(), 0);
+    }
+
+    #[test]
+    fn sorted_folders_are_in_name_order() {
+        let files = ["Zeta.ubulk", "Alpha.ubulk", "Mid/B.ubulk", "Mid/A.ubulk", "Beta.ubulk"].map(|path| (path, &[0u8; 0x10][..]));
+        let folder = TempDir::with_content("sorted", &files);
+
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions { sorted: true, ..Default::default() }).unwrap();
+
+        // files come before subfolders in the tree, each in name order
+        let files: Vec<_> = collector.iter_files().map(|(path, _)| path).collect();
+        let expected: Vec<PathBuf> = ["Alpha.ubulk", "Beta.ubulk", "Zeta.ubulk", "Mid/A.ubulk", "Mid/B.ubulk"].iter()
+            .map(|path| ["P3R", "Content"].into_iter().chain(path.split('/')).collect())
+            .collect();
+        assert_eq!(files, expected);
+    }
+
+    #[test]
+    fn extensions_are_case_insensitive() {
+        let files = ["Lower.ubulk", "Upper.UBULK", "Mixed.UpTnL", "Other.TXT"].map(|name| (name, &[0u8; 0x10][..]));
+        let folder = TempDir::with_content("extension-case", &files);
+
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions::default()).unwrap();
+
+        assert_eq!(collector.profiler.added_files_count, 3);
+        assert_eq!(collector.profiler.skipped_files.len(), 1);
+        let skipped = collector.get_skipped_files();
+        assert!(skipped[0].0.ends_with("Other.TXT"));
+        assert_eq!(skipped[0].1, SkipReason::UnsupportedFileType);
+    }
+
+    #[test]
+    fn registered_extensions_are_collected() {
+        let files = ["Foo.ubulk", "Foo.uexp2", "Foo.txt"].map(|name| (name, &[0u8; 0x10][..]));
+        let folder = TempDir::with_content("registered-extension", &files);
+
+        let mut chunk_types = ChunkTypeRegistry::new();
+        chunk_types.extend(HashMap::from([(".UEXP2".to_string(), IoChunkType4::BulkData)]));
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &chunk_types, &FolderOptions::default()).unwrap();
+
+        assert_eq!(collector.profiler.added_files_count, 2);
+        assert_eq!(chunk_types.get_chunk_type("uexp2"), Some(IoChunkType4::BulkData));
+        assert_eq!(chunk_types.get_chunk_type("txt"), None);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_walk_matches_sequential() {
+        fn list_tree(dir: &TocDirectorySyncRef, prefix: &str, out: &mut Vec<String>) {
+            let dir = dir.read().unwrap();
+            let mut next_file = dir.first_file.clone();
+            while let Some(file) = next_file {
+                out.push(format!("{prefix}{}", file.read().unwrap().name));
+                next_file = file.read().unwrap().next.clone();
+            }
+            let mut next_child = dir.first_child.clone();
+            while let Some(child) = next_child {
+                let name = child.read().unwrap().name.clone().unwrap();
+                out.push(format!("{prefix}{name}/"));
+                list_tree(&child, &format!("{prefix}{name}/"), out);
+                next_child = child.read().unwrap().next_sibling.clone();
+            }
+        }
+
+        let paths: Vec<String> = (0..8).flat_map(|i| ["A.ubulk", "B.uptnl", "C.txt"].into_iter().flat_map(move |name| [format!("Dir{i}/{name}"), format!("Dir{i}/Inner/{name}")])).collect();
+        let mut files: Vec<(&str, &[u8])> = paths.iter().map(|path| (path.as_str(), &[0u8; 0x10][..])).collect();
+        let ignore_path = format!("Dir5/{TOC_IGNORE_FILE_NAME}");
+        files.push((&ignore_path, b"Inner/B.uptnl\n"));
+        let folder = TempDir::with_content("parallel-walk", &files);
+        fs::write(folder.join(TOC_IGNORE_FILE_NAME), "Dir3/\n").unwrap();
+
+        let chunk_types = ChunkTypeRegistry::new();
+        let sequential_root = TocDirectory::new_rc(None);
+        let mut sequential_profiler = AssetCollectorProfiler::new(String::new());
+        let walk = FolderWalkSettings { chunk_types: &chunk_types, options: &FolderOptions::default() };
+        AssetCollector::add_folder(&folder.to_path_buf(), &sequential_root, &walk, &TocIgnore::default(), &[], &mut sequential_profiler);
+        let parallel = AssetCollector::from_folder(folder.to_str().unwrap(), &chunk_types, &FolderOptions::default()).unwrap();
+
+        let (mut sequential_tree, mut parallel_tree) = (vec![], vec![]);
+        list_tree(&sequential_root, "", &mut sequential_tree);
+        list_tree(&parallel.root_dir, "", &mut parallel_tree);
+        assert_eq!(sequential_tree, parallel_tree);
+        assert_eq!(sequential_profiler.added_files_count, parallel.profiler.added_files_count);
+        assert_eq!(sequential_profiler.skipped_files.len(), parallel.profiler.skipped_files.len());
+    }
+
+    #[test]
+    fn tocignore_skips_matches() {
+        let folder = TempDir::with_content("tocignore", &[
+            ("Debug/A.ubulk", &[0u8; 0x10]), ("Foo/A.ubulk", &[0u8; 0x10]), ("Foo/B.ubulk", &[0u8; 0x10]), ("Foo/C.uptnl", &[0u8; 0x10]),
+            (&format!("Foo/{TOC_IGNORE_FILE_NAME}"), b"B.*\n"),
+        ]);
+        fs::write(folder.join(TOC_IGNORE_FILE_NAME), "# not shipped\nDebug/\n").unwrap();
+
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions::default()).unwrap();
+
+        assert_eq!(collector.profiler.added_files_count, 2);
+        let mut skipped = collector.get_skipped_files();
+        skipped.sort_by_key(|(os_path, _)| *os_path);
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped[0].0.ends_with("Debug"));
+        assert!(skipped[1].0.ends_with("B.ubulk"));
+        assert!(skipped.iter().all(|(_, reason)| *reason == SkipReason::IgnoredByTocIgnore));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_cycles_are_skipped() {
+        let root = TempDir::with_content("symlinks", &[("Foo/A.ubulk", &[0u8; 0x10])]);
+        let content = root.join("P3R").join("Content");
+        let shared = TempDir::new("symlinks-shared");
+        fs::write(shared.join("B.ubulk"), [0u8; 0x10]).unwrap();
+        std::os::unix::fs::symlink(&shared, content.join("Shared")).unwrap();
+        std::os::unix::fs::symlink(&root, content.join("Foo").join("Loop")).unwrap();
+
+        let following = AssetCollector::from_folder(root.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions { follow_symlinks: true, ..Default::default() }).unwrap();
+        let not_following = AssetCollector::from_folder(root.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions::default()).unwrap();
+
+        assert_eq!(following.profiler.added_files_count, 2);
+        let skipped = following.get_skipped_files();
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped[0].0.ends_with("Loop"));
+        assert_eq!(skipped[0].1, SkipReason::SymlinkCycle);
+
+        assert_eq!(not_following.profiler.added_files_count, 1);
+        let skipped = not_following.get_skipped_files();
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped.iter().all(|(_, reason)| *reason == SkipReason::SymlinkNotFollowed));
+    }
+
+    #[test]
+    fn iter_files_yields_virtual_paths() {
+        let folder = TempDir::new("iter-files");
+        let source = folder.join("Bar.ubulk");
+        fs::write(&source, [0u8; 0x10]).unwrap();
+        let manifest = folder.join("manifest.txt");
+        let source = source.to_str().unwrap();
+        fs::write(&manifest, format!("{source}\tP3R/Content/Foo/A.ubulk\n{source}\tP3R/Content/B.ubulk\n{source}\tC.ubulk\n")).unwrap();
+
+        let collector = AssetCollector::from_manifest(manifest.to_str().unwrap(), &ChunkTypeRegistry::new()).unwrap();
+
+        let files: Vec<(PathBuf, u64)> = collector.iter_files().collect();
+        assert_eq!(files, vec![
+            (PathBuf::from("C.ubulk"), 0x10),
+            (["P3R", "Content", "B.ubulk"].iter().collect(), 0x10),
+            (["P3R", "Content", "Foo", "A.ubulk"].iter().collect(), 0x10),
+        ]);
+    }
+
+    #[test]
+    fn folders_that_only_differ_in_case_are_merged() {
+        let folder = TempDir::new("case-merge");
+        let source = folder.join("Bar.ubulk");
+        fs::write(&source, [0u8; 0x10]).unwrap();
+        let manifest = folder.join("manifest.txt");
+        let source = source.to_str().unwrap();
+        let lines = ["P3R/Content/A.ubulk", "P3R/content/B.ubulk", "P3R/content/Sub/C.ubulk", "P3R/Content/sub/D.ubulk"];
+        fs::write(&manifest, lines.iter().map(|line| format!("{source}\t{line}\n")).collect::<String>()).unwrap();
+
+        let collector = AssetCollector::from_manifest(manifest.to_str().unwrap(), &ChunkTypeRegistry::new()).unwrap();
+
+        // the first spelling of each folder is kept, and merging Content/content brings Sub/sub together too
+        let files: Vec<PathBuf> = collector.iter_files().map(|(path, _)| path).collect();
+        assert_eq!(files, vec![
+            ["P3R", "Content", "A.ubulk"].iter().collect::<PathBuf>(),
+            ["P3R", "Content", "B.ubulk"].iter().collect(),
+            ["P3R", "Content", "sub", "D.ubulk"].iter().collect(),
+            ["P3R", "Content", "sub", "C.ubulk"].iter().collect(),
+        ]);
+        assert_eq!(collector.get_profiler().get_directory_count(), 3);
+    }
+
+    #[test]
+    fn traversal_names_are_skipped() {
+        for name in [".", "..", "Foo/..", "..\\Bar.ubulk"] {
+            assert!(AssetCollector::is_unsafe_name(name), "{}", name);
+        }
+        assert!(!AssetCollector::is_unsafe_name("..Foo.ubulk"));
+
+        let folder = TempDir::new("traversal-names");
+        let source = folder.join("Bar.ubulk");
+        fs::write(&source, [0u8; 0x10]).unwrap();
+        let manifest = folder.join("manifest.txt");
+        let source = source.to_str().unwrap();
+        fs::write(&manifest, format!("{source}\tP3R/Content/../../Evil.ubulk\n{source}\tP3R/Content/./Bar.ubulk\n{source}\tP3R/Content/Bar.ubulk\n")).unwrap();
+
+        let collector = AssetCollector::from_manifest(manifest.to_str().unwrap(), &ChunkTypeRegistry::new()).unwrap();
+
+        assert_eq!(collector.get_skipped_files(), vec![(source, SkipReason::UnsafeName), (source, SkipReason::UnsafeName)]);
+        let files: Vec<PathBuf> = collector.iter_files().map(|(path, _)| path).collect();
+        assert_eq!(files, vec![["P3R", "Content", "Bar.ubulk"].iter().collect::<PathBuf>()]);
+    }
+
+    #[test]
+    fn empty_files_are_skipped() {
+        let folder = TempDir::with_content("empty-files", &[("Empty.uasset", &[]), ("Empty.ubulk", &[]), ("Full.ubulk", &[0u8; 0x10])]);
+
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions::default()).unwrap();
+
+        assert_eq!(collector.profiler.added_files_count, 1);
+        let skipped = collector.get_skipped_files();
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped.iter().all(|(_, reason)| *reason == SkipReason::EmptyFile));
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn zip_entries_are_collected() {
+        use std::io::Write;
+        let folder = TempDir::new("zip-input");
+        let archive_path = folder.join("Mod.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&archive_path).unwrap());
+        for (name, size) in [("P3R/Content/Foo/A.ubulk", 0x10), ("P3R/Content/Foo/Short.uasset", 3), ("P3R/Content/Readme.txt", 0x10)] {
+            writer.start_file(name, zip::write::SimpleFileOptions::default()).unwrap();
+            writer.write_all(&vec![0u8; size]).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let collector = AssetCollector::from_zip(archive_path.to_str().unwrap(), &ChunkTypeRegistry::new()).unwrap();
+
+        assert_eq!(collector.profiler.added_files_count, 1);
+        assert_eq!(collector.profiler.directory_count, 3);
+        let mut skipped = collector.get_skipped_files();
+        skipped.sort_by_key(|(os_path, _)| *os_path);
+        assert_eq!(skipped, vec![
+            ("P3R/Content/Foo/Short.uasset", SkipReason::NotTocAssetFormat),
+            ("P3R/Content/Readme.txt", SkipReason::UnsupportedFileType),
+        ]);
+    }
+
+    #[test]
+    fn legacy_cooked_asset_is_reported() {
+        let mut legacy = io_package::UASSET_MAGIC.to_ne_bytes().to_vec();
+        legacy.extend([0u8; 0x10]);
+        let folder = TempDir::with_content("legacy-cooked-asset", &[("Legacy.uasset", &legacy), ("Short.uasset", &[0u8; 3])]);
+
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions::default()).unwrap();
+
+        let mut skipped = collector.get_skipped_files();
+        skipped.sort_by_key(|(os_path, _)| *os_path);
+        assert_eq!(skipped.iter().map(|(_, reason)| *reason).collect::<Vec<_>>(), vec![SkipReason::LegacyCookedAsset, SkipReason::NotTocAssetFormat]);
+        assert_eq!(collector.profiler.legacy_cooked_count, 1);
+    }
+
+    #[test]
+    fn hidden_files_are_skipped() {
+        let files = ["Foo.ubulk", ".backup/Foo.ubulk", "Bar.ubulk~", "Thumbs.db"].map(|path| (path, &[0u8; 0x10][..]));
+        let folder = TempDir::with_content("hidden-files", &files);
+
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions::default()).unwrap();
+        let included = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions { include_hidden: true, ..Default::default() }).unwrap();
+
+        assert_eq!(collector.get_profiler().get_added_files_count(), 1);
+        assert_eq!(collector.get_profiler().get_skipped_count(SkipReason::HiddenFile), 3);
+        assert_eq!(collector.get_profiler().get_skipped_files().count(), 3);
+        assert_eq!(included.profiler.added_files_count, 2);
+        assert!(included.get_skipped_files().iter().all(|(_, reason)| *reason != SkipReason::HiddenFile));
+    }
+
+    #[test]
+    fn tree_lists_files_before_folders() {
+        let folder = TempDir::with_content("tree", &[("Foo.ubulk", &[0u8; 0x10]), ("Dir/Bar.ubulk", &[0u8; 0x20])]);
+
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions::default()).unwrap();
+
+        let mut tree = vec![];
+        collector.write_tree(&mut tree).unwrap();
+        let tree = String::from_utf8(tree).unwrap();
+        let lines: Vec<&str> = tree.lines().skip(1).collect();
+        assert_eq!(lines, [
+            "└── P3R/",
+            "    └── Content/",
+            "        ├── Foo.ubulk (16 bytes)",
+            "        └── Dir/",
+            "            └── Bar.ubulk (32 bytes)",
+        ]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_files_are_reported() {
+        let folder = TempDir::with_content("special-files", &[("Foo.ubulk", &[0u8; 0x10])]);
+        let content = folder.join("P3R").join("Content");
+        let _socket = std::os::unix::net::UnixListener::bind(content.join("Build.sock")).unwrap();
+
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions::default()).unwrap();
+
+        assert_eq!(collector.profiler.added_files_count, 1);
+        let skipped = collector.get_skipped_files();
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped[0].0.ends_with("Build.sock"));
+        assert_eq!(skipped[0].1, SkipReason::SpecialFile(SpecialFileKind::Socket));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn non_utf8_names_are_reported() {
+        use std::os::unix::ffi::OsStrExt;
+        let folder = TempDir::with_content("non-utf8-names", &[("Foo.ubulk", &[0u8; 0x10])]);
+        let content = folder.join("P3R").join("Content");
+        fs::write(content.join(std::ffi::OsStr::from_bytes(b"Bad\xff.ubulk")), [0u8; 0x10]).unwrap();
+
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions::default()).unwrap();
+
+        assert_eq!(collector.profiler.added_files_count, 1);
+        let failed: Vec<_> = collector.profiler.get_failed_fs_objects().collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].1, NON_UTF8_NAME);
+    }
+
+    #[test]
+    fn files_outside_size_range_are_skipped() {
+        let folder = TempDir::with_content("size-range", &[("Small.ubulk", &[0u8; 0x10]), ("Medium.ubulk", &[0u8; 0x100]), ("Large.ubulk", &[0u8; 0x1000])]);
+
+        let options = FolderOptions { size_range: 0x100..=0x100, ..Default::default() };
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &options).unwrap();
+
+        assert_eq!(collector.iter_files().map(|(path, _)| path).collect::<Vec<_>>(), [Path::new("P3R/Content/Medium.ubulk")]);
+        let skipped = collector.get_skipped_files();
+        assert_eq!(skipped.len(), 2);
+        assert!(skipped.iter().all(|(_, reason)| *reason == SkipReason::SizeOutOfRange));
+        assert_eq!(collector.profiler.skipped_file_size, 0x1010);
+    }
+
+    #[test]
+    fn truncated_asset_is_skipped() {
+        let folder = TempDir::with_content("truncated-asset", &[("Short.uasset", &[0u8; 3])]);
+
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &ChunkTypeRegistry::new(), &FolderOptions::default()).unwrap();
+
+        assert_eq!(collector.profiler.added_files_count, 0);
+        let skipped = collector.get_skipped_files();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].1, SkipReason::NotTocAssetFormat);
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+    time::UNIX_EPOCH
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::string::Hasher16;
+
+// A compressed block as it came out of the encoder, before encryption. The ucas offset isn't kept, blocks are written again
+// through TocFactory::write_block so they get new offsets (and are encrypted) like any other block
+pub struct CachedBlock {
+    pub data: Vec<u8>,
+    pub uncompressed_len: usize,
+    pub compression_method: u8,
+}
+
+// Compressed blocks of files from earlier builds, so a rebuild only has to recompress the files that changed. There's one entry
+// per source file and compression settings, named after a hash of the two. The entry starts with the full key, which also has
+// the file's modified time and size in it, so an entry for an older version of the file (or a hash collision) is a miss and gets
+// replaced once the file has been compressed again
+pub struct BlockCache {
+    dir: PathBuf,
+}
+
+impl BlockCache {
+    pub fn new(dir: &str) -> Self {
+        Self { dir: PathBuf::from(dir) }
+    }
+
+    // settings should have everything that changes the compressed bytes (block size, compression method and level)
+    pub fn get_key(os_path: &str, settings: &str) -> io::Result<String> {
+        let metadata = fs::metadata(os_path)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).map_or(0, |modified| modified.as_nanos());
+        Ok(format!("{}|{}|{}|{}", settings, os_path, modified, metadata.len()))
+    }
+
+    fn get_entry_path(&self, key: &str) -> PathBuf {
+        // everything up to the modified time, so newer versions of a file overwrite the old entry
+        let name = key.rsplitn(3, '|').last().unwrap();
+        self.dir.join(format!("{:016x}.blocks", Hasher16::get_cityhash64(name)))
+    }
+
+    // Anything wrong with an entry (missing, written for another key, cut short) is treated as a miss
+    pub fn get(&self, key: &str) -> Option<Vec<CachedBlock>> {
+        let mut reader = BufReader::new(File::open(self.get_entry_path(key)).ok()?);
+        Self::read_entry(&mut reader, key).ok()?
+    }
+
+    fn read_entry<R: Read>(reader: &mut R, key: &str) -> io::Result<Option<Vec<CachedBlock>>> {
+        let mut stored_key = vec![0; reader.read_u32::<LittleEndian>()? as usize];
+        reader.read_exact(&mut stored_key)?;
+        if stored_key != key.as_bytes() {
+            return Ok(None);
+        }
+        let block_count = reader.read_u32::<LittleEndian>()?;
+        let mut blocks = Vec::with_capacity(block_count as usize);
+        for _ in 0..block_count {
+            let uncompressed_len = reader.read_u32::<LittleEndian>()? as usize;
+            let compression_method = reader.read_u8()?;
+            let mut data = vec![0; reader.read_u32::<LittleEndian>()? as usize];
+            reader.read_exact(&mut data)?;
+            blocks.push(CachedBlock { data, uncompressed_len, compression_method });
+        }
+        Ok(Some(blocks))
+    }
+
+    // Written to a temp file first, so a build that's stopped halfway never leaves a partial entry behind
+    pub fn insert(&self, key: &str, blocks: &[CachedBlock]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let entry_path = self.get_entry_path(key);
+        let temp_path = entry_path.with_extension(format!("{}.tmp", std::process::id()));
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        writer.write_u32::<LittleEndian>(key.len() as u32)?;
+        writer.write_all(key.as_bytes())?;
+        writer.write_u32::<LittleEndian>(blocks.len() as u32)?;
+        for block in blocks {
+            writer.write_u32::<LittleEndian>(block.uncompressed_len as u32)?;
+            writer.write_u8(block.compression_method)?;
+            writer.write_u32::<LittleEndian>(block.data.len() as u32)?;
+            writer.write_all(&block.data)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temp_path, &entry_path)
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering}
+};
+
+use crate::io_toc::IoStoreTocCompressedBlockEntry;
+
+static SPILL_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// A container's compression block entries, collected while the ucas is written. In memory, or a temp file for millions of blocks
+pub struct BlockTable {
+    blocks: Vec<IoStoreTocCompressedBlockEntry>, // only used when the table is in memory
+    spill: Option<(PathBuf, BufWriter<File>)>,
+    len: usize,
+    compressed_size: u64, // total of every block's compressed size
+}
+
+impl BlockTable {
+    pub fn in_memory() -> Self {
+        Self { blocks: vec![], spill: None, len: 0, compressed_size: 0 }
+    }
+
+    pub fn on_disk() -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("toc-maker-blocks-{}-{}", std::process::id(), SPILL_FILE_COUNT.fetch_add(1, Ordering::Relaxed)));
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path)?;
+        Ok(Self { blocks: vec![], spill: Some((path, BufWriter::new(file))), len: 0, compressed_size: 0 })
+    }
+
+    pub fn push(&mut self, block: IoStoreTocCompressedBlockEntry) -> io::Result<()> {
+        self.len += 1;
+        self.compressed_size += block.get_compressed_size() as u64;
+        match self.spill.as_mut() {
+            Some((_, writer)) => block.to_buffer::<_, byteorder::NativeEndian>(writer).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+            None => self.blocks.push(block)
+        }
+        Ok(())
+    }
+
+    pub fn extend<I: IntoIterator<Item = IoStoreTocCompressedBlockEntry>>(&mut self, blocks: I) -> io::Result<()> {
+        for block in blocks {
+            self.push(block)?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[cfg_attr(not(feature = "json"), allow(dead_code))]
+    pub fn get_compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+
+    // Write every entry into the utoc, in the order they were added
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        match self.spill.as_mut() {
+            Some((_, spilled)) => {
+                spilled.flush()?;
+                let file = spilled.get_mut();
+                file.seek(SeekFrom::Start(0))?;
+                io::copy(file, writer)?;
+                Ok(())
+            },
+            None => IoStoreTocCompressedBlockEntry::list_to_buffer::<_, byteorder::NativeEndian>(&self.blocks, writer)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+        }
+    }
+}
+
+impl Drop for BlockTable {
+    fn drop(&mut self) {
+        if let Some((path, writer)) = self.spill.take() {
+            drop(writer); // has to be closed before it can be deleted on Windows
+            let _ = fs::remove_file(path);
+        }
+    }
+}
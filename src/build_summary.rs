@@ -0,0 +1,40 @@
+use std::{fs::File, io};
+
+use serde::Serialize;
+
+use crate::error::TocError;
+
+// Machine readable summary of a build, written with --json so that CI can check what went into a container
+#[derive(Serialize)]
+pub struct BuildSummary {
+    pub file_count: usize,
+    pub directory_count: usize,
+    pub compression_method: &'static str,
+    pub compression_block_size: u32,
+    pub total_file_size: u64, // uncompressed size of every packaged file
+    pub total_compressed_size: u64, // size of every block written to the ucas, not including alignment
+    pub ucas_size: u64, // whole ucas (every partition) including alignment, see BuildReport
+    pub files: Vec<BuildSummaryFile>,
+    pub skipped_files: Vec<BuildSummarySkippedFile>,
+}
+
+#[derive(Serialize)]
+pub struct BuildSummaryFile {
+    pub os_path: String,
+    pub chunk_id: String, // hex, see IoChunkId::to_hex
+    pub size: u64,
+}
+
+#[derive(Serialize)]
+pub struct BuildSummarySkippedFile {
+    pub os_path: String,
+    pub reason: String,
+}
+
+impl BuildSummary {
+    pub fn write_to(&self, path: &str) -> Result<(), TocError> {
+        let summary_file = File::create(path)?;
+        serde_json::to_writer_pretty(summary_file, self).map_err(io::Error::from)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf}
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+// A container's output files (utoc, ucas partitions, pak) in one file, for shipping a mod as a single download. The game can't
+// read it, it has to be split back into the original files with unpack (toc-maker unpack) first. Little endian throughout:
+//   magic (8 bytes), version (u32), file count (u32)
+//   for each file: name length (u16), name (UTF-8, a file name without any folders), offset from the start of the bundle (u64),
+//   size (u64)
+//   the contents of each file, in the same order
+pub const BUNDLE_MAGIC: [u8; 8] = *b"TOCBNDL\0";
+pub const BUNDLE_VERSION: u32 = 1;
+pub const BUNDLE_EXTENSION: &str = ".tocbundle";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleEntry {
+    pub name: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+// files is the name to store each file under, and where to read it from
+pub fn write_bundle<W: Write>(writer: &mut W, files: &[(String, PathBuf)]) -> io::Result<()> {
+    let index_size: u64 = 16 + files.iter().map(|(name, _)| 2 + name.len() as u64 + 16).sum::<u64>();
+    let mut entries = Vec::with_capacity(files.len());
+    let mut offset = index_size;
+    for (name, path) in files {
+        if !is_safe_name(name) || name.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{name} can't be stored in a bundle")));
+        }
+        let size = fs::metadata(path)?.len();
+        entries.push(BundleEntry { name: name.clone(), offset, size });
+        offset += size;
+    }
+
+    writer.write_all(&BUNDLE_MAGIC)?;
+    writer.write_u32::<LittleEndian>(BUNDLE_VERSION)?;
+    writer.write_u32::<LittleEndian>(entries.len() as u32)?;
+    for entry in &entries {
+        writer.write_u16::<LittleEndian>(entry.name.len() as u16)?;
+        writer.write_all(entry.name.as_bytes())?;
+        writer.write_u64::<LittleEndian>(entry.offset)?;
+        writer.write_u64::<LittleEndian>(entry.size)?;
+    }
+    for ((_, path), entry) in files.iter().zip(&entries) {
+        // a file that changed size since the index was written would throw every offset after it off
+        let copied = io::copy(&mut File::open(path)?.take(entry.size), writer)?;
+        if copied != entry.size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} got shorter while it was being bundled", path.display())));
+        }
+    }
+    Ok(())
+}
+
+pub fn read_index<R: Read>(reader: &mut R) -> io::Result<Vec<BundleEntry>> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != BUNDLE_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a toc-maker bundle"));
+    }
+    let version = reader.read_u32::<LittleEndian>()?;
+    if version != BUNDLE_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported bundle version {version}")));
+    }
+    let count = reader.read_u32::<LittleEndian>()?;
+    let mut entries = vec![];
+    for _ in 0..count {
+        let mut name = vec![0; reader.read_u16::<LittleEndian>()? as usize];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Bundle has a file name that isn't UTF-8"))?;
+        let offset = reader.read_u64::<LittleEndian>()?;
+        let size = reader.read_u64::<LittleEndian>()?;
+        entries.push(BundleEntry { name, offset, size });
+    }
+    Ok(entries)
+}
+
+// Write every file in the bundle into out_folder, returning their paths. Names that would end up outside of out_folder are
+// refused before anything is written
+pub fn unpack<R: Read + Seek>(reader: &mut R, out_folder: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = read_index(reader)?;
+    if let Some(entry) = entries.iter().find(|entry| !is_safe_name(&entry.name)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bundle has an unsafe file name: {}", entry.name)));
+    }
+    fs::create_dir_all(out_folder)?;
+    let mut written = vec![];
+    for entry in entries {
+        let path = out_folder.join(&entry.name);
+        reader.seek(SeekFrom::Start(entry.offset))?;
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let copied = io::copy(&mut reader.by_ref().take(entry.size), &mut writer)?;
+        if copied != entry.size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Bundle ends partway through {}", entry.name)));
+        }
+        writer.flush()?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+// Only plain file names, so a crafted bundle can't write anywhere other than the folder it's unpacked into
+pub(crate) fn is_safe_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', ':'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixture::TempDir;
+
+    #[test]
+    fn bundle_unpacks_to_the_same_files() {
+        let folder = TempDir::new("bundle");
+        fs::create_dir_all(folder.join("out")).unwrap();
+        let files = [("Mod.utoc", vec![1u8; 0x40]), ("Mod.ucas", (0..0x1234).map(|i| i as u8).collect()), ("Mod.pak", vec![])];
+        let sources: Vec<(String, PathBuf)> = files.iter().map(|(name, data)| {
+            let path = folder.join(name);
+            fs::write(&path, data).unwrap();
+            (name.to_string(), path)
+        }).collect();
+
+        let mut bundle = vec![];
+        write_bundle(&mut bundle, &sources).unwrap();
+        let written = unpack(&mut io::Cursor::new(&bundle), &folder.join("out")).unwrap();
+        let unpacked: Vec<Vec<u8>> = written.iter().map(|path| fs::read(path).unwrap()).collect();
+        let unsafe_name = write_bundle(&mut vec![], &[("../Mod.utoc".to_string(), sources[0].1.clone())]);
+
+        assert_eq!(written.len(), files.len());
+        assert!(files.iter().zip(unpacked).all(|((_, data), unpacked)| *data == unpacked));
+        assert!(unsafe_name.is_err());
+    }
+}
@@ -1,20 +1,207 @@
+pub enum Command {
+    Build(Config),
+    Verify(VerifyConfig),
+    Unpack(UnpackConfig),
+    Extract(ExtractConfig),
+    Id(IdConfig),
+    SelfTest,
+}
+
+impl Command {
+    pub fn new(args: std::env::Args) -> Result<Self, String> {
+        let mut args = args.skip(1).peekable(); //Skip executable path
+        match args.peek().map(|s| s.as_str()) {
+            Some("verify") => {
+                args.next();
+                Ok(Command::Verify(VerifyConfig::new(args)?))
+            },
+            Some("unpack") => {
+                args.next();
+                Ok(Command::Unpack(UnpackConfig::new(args)?))
+            },
+            Some("extract") => {
+                args.next();
+                Ok(Command::Extract(ExtractConfig::new(args)?))
+            },
+            Some("id") => {
+                args.next();
+                Ok(Command::Id(IdConfig::new(args)?))
+            },
+            Some("selftest") => {
+                args.next();
+                match args.next() {
+                    Some(arg) => Err(format!("Unexpected argument: {arg}")),
+                    None => Ok(Command::SelfTest)
+                }
+            },
+            _ => Ok(Command::Build(Config::new(args, std::env::vars())?))
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub inpath: String,
     pub outpath: String,
     pub use_zlib: bool,
+    pub zlib_level: Option<u32>,
+    pub compressed_extensions: Option<Vec<String>>,
+    pub single_block_extensions: Option<Vec<String>>,
+    pub cache_dir: Option<String>,
+    pub compression_name: Option<String>,
     pub hash_metadata: bool,
+    pub partition_size: Option<u64>,
+    pub block_size: Option<u32>,
+    pub auto_block_size: bool, // pick the block size from the input's file sizes, unless --block-size is given
+    pub mount_point: Option<String>,
+    pub root_prefix: Option<String>,
+    pub use_manifest: bool,
+    #[cfg(feature = "zip")]
+    pub use_zip: bool,
+    pub prune_empty: bool,
+    pub follow_symlinks: bool,
+    pub include_hidden: bool,
+    pub deterministic: bool, // walk input folders in name order
+    pub check_bulk_siblings: bool,
+    pub print_tree: bool, // print what would be packaged instead of building
+    pub estimate: bool, // print the projected size and compression time instead of building
+    pub min_file_size: u64,
+    pub block_table_on_disk: bool,
+    pub max_file_size: u64,
+    pub allow_empty: bool,
+    pub expected_files: Option<usize>,
+    pub strict: bool,
+    pub profile_files: bool,
+    pub container_header: bool,
+    pub ue_version: toc_maker::io_toc::UeVersion,
+    pub sort_order: toc_maker::toc_factory::FileSortOrder,
+    pub endianness: toc_maker::toc_factory::Endianness,
+    pub update_existing: bool,
+    pub ucas_to_stdout: bool,
+    pub bundle: bool, // write everything into one .tocbundle instead of separate files
+    pub no_clobber: bool,
+    pub split_by_subfolder: bool, // one container per folder in the input folder, written into the output folder
+    pub container_name: Option<String>,
+    pub log_level: log::LevelFilter,
+    pub log_path: Option<String>, // also write every message, and the settings used, to this file
+    #[cfg(feature = "json")]
+    pub summary_path: Option<String>,
+    #[cfg(feature = "checksums")]
+    pub checksums_path: Option<String>,
+    #[cfg(feature = "loose")]
+    pub loose_pak: bool, // also write the files into a standard pak, <output path>_loose.pak
+    #[cfg(feature = "block_crc")]
+    pub block_crc_path: Option<String>,
+    #[cfg(feature = "aes")]
+    pub aes_key: Option<toc_maker::encryption::AesKey>,
+    #[cfg(feature = "aes")]
+    pub aes_key_guid: toc_maker::io_toc::GUID,
 }
 
-impl Config {
-    pub fn new(mut args: std::env::Args) -> Result<Self, String> {
-        args.next(); //Skip executable path
+// Options that can be given a default with an environment variable, named TOCMAKER_ and the flag in upper case (--compress-ext
+// is TOCMAKER_COMPRESS_EXT). The bool is whether the flag takes a value, flags that don't are turned on with 1 or true
+const ENV_OPTIONS: [(&str, bool); 34] = [
+    ("--zlib", false), ("--level", true), ("--compress-ext", true), ("--cache-dir", true), ("--compression-name", true),
+    ("--meta", false), ("--prune-empty", false), ("--follow-symlinks", false), ("--include-hidden", false), ("--min-size", true),
+    ("--max-size", true), ("--allow-empty", false), ("--expect-files", true), ("--strict", false), ("--no-clobber", false),
+    ("--profile-files", false), ("--no-container-header", false), ("--ue-version", true), ("--sort", true), ("--endian", true),
+    ("--name", true), ("--block-table-on-disk", false), ("--partition-size", true), ("--block-size", true), ("--mount", true),
+    ("--aes-key", true), ("--aes-key-guid", true), ("--block-crc", true), ("--auto-block-size", false), ("--log", true), ("--root-prefix", true),
+    ("--store-ext", true), ("--deterministic", false), ("--check-bulk", false),
+];
 
+fn get_env_name(flag: &str) -> String {
+    format!("TOCMAKER_{}", flag.trim_start_matches('-').to_uppercase().replace('-', "_"))
+}
+
+// The environment variables that are set, as the flags they stand for. These go in front of the command line, so a flag that's
+// passed as well is read last and wins
+fn get_env_args<V: Iterator<Item = (String, String)>>(vars: V) -> Result<Vec<String>, String> {
+    let vars: std::collections::HashMap<String, String> = vars.collect();
+    let mut env_args = vec![];
+    for (flag, takes_value) in ENV_OPTIONS {
+        let name = get_env_name(flag);
+        let Some(value) = vars.get(&name) else { continue };
+        if takes_value {
+            env_args.push(flag.to_string());
+            env_args.push(value.clone());
+            continue;
+        }
+        match value.to_ascii_lowercase().as_str() {
+            "1" | "true" => env_args.push(flag.to_string()),
+            "0" | "false" | "" => (),
+            _ => return Err(format!("{name} should be 1, true, 0 or false, not {value}"))
+        }
+    }
+    Ok(env_args)
+}
+
+impl Config {
+    // vars are the environment variables, see ENV_OPTIONS
+    pub fn new<I: Iterator<Item = String>, V: Iterator<Item = (String, String)>>(args: I, vars: V) -> Result<Self, String> {
+        let mut args = get_env_args(vars)?.into_iter().chain(args);
         let mut inpath = None;
         let mut outpath = None;
         #[allow(unused_mut)]
         let mut use_zlib = false;
         #[allow(unused_mut)]
+        let mut zlib_level = None;
+        #[allow(unused_mut)]
+        let mut compressed_extensions = None;
+        #[allow(unused_mut)]
+        let mut cache_dir = None;
+        #[allow(unused_mut)]
+        let mut compression_name = None;
+        #[allow(unused_mut)]
         let mut hash_metadata = false;
+        let mut single_block_extensions = None;
+        let mut partition_size = None;
+        let mut block_size = None;
+        let mut auto_block_size = false;
+        let mut mount_point = None;
+        let mut root_prefix = None;
+        let mut use_manifest = false;
+        #[cfg(feature = "zip")]
+        let mut use_zip = false;
+        let mut prune_empty = false;
+        let mut follow_symlinks = false;
+        let mut include_hidden = false;
+        let mut deterministic = false;
+        let mut check_bulk_siblings = false;
+        let mut print_tree = false;
+        let mut estimate = false;
+        let mut min_file_size = 0;
+        let mut block_table_on_disk = false;
+        let mut max_file_size = u64::MAX;
+        let mut allow_empty = false;
+        let mut expected_files = None;
+        let mut strict = false;
+        let mut profile_files = false;
+        let mut container_header = true;
+        let mut ue_version = toc_maker::io_toc::UeVersion::UE4_27;
+        let mut sort_order = toc_maker::toc_factory::FileSortOrder::Flatten;
+        let mut endianness = toc_maker::toc_factory::Endianness::Native;
+        let mut update_existing = false;
+        let mut ucas_to_stdout = false;
+        let mut bundle = false;
+        let mut no_clobber = false;
+        let mut split_by_subfolder = false;
+        let mut container_name = None;
+        let mut order = None;
+        let mut log_level = log::LevelFilter::Info;
+        let mut log_path = None;
+        #[cfg(feature = "json")]
+        let mut summary_path = None;
+        #[cfg(feature = "checksums")]
+        let mut checksums_path = None;
+        #[cfg(feature = "loose")]
+        let mut loose_pak = false;
+        #[cfg(feature = "block_crc")]
+        let mut block_crc_path = None;
+        #[cfg(feature = "aes")]
+        let mut aes_key = None;
+        #[cfg(feature = "aes")]
+        let mut aes_key_guid = 0;
         
         while let Some(arg) = args.next() {
             if !arg.starts_with('-') {
@@ -26,18 +213,289 @@ impl Config {
                     return Err(format!("Unexpected argument: {arg}"));
                 }
             } else {
+                if let Some(feature) = get_missing_feature(&arg) {
+                    return Err(format!("{arg}: this build was compiled without {feature} support; rebuild with --features {feature}"));
+                }
+
                 #[cfg(feature = "zlib")]
                 if arg == "-z" || arg == "--zlib" {
                     use_zlib = true;
                     continue;
                 }
 
+                #[cfg(feature = "zlib")]
+                if arg == "--level" {
+                    let value = args.next().ok_or("Must specify a level after --level")?;
+                    zlib_level = Some(value.parse().ok().filter(|level| *level <= 9).ok_or(format!("zlib level should be 0 to 9, not {value}"))?);
+                    use_zlib = true;
+                    continue;
+                }
+
+                #[cfg(feature = "zlib")]
+                if arg == "--compress-ext" {
+                    let value = args.next().ok_or("Must specify a list of extensions after --compress-ext")?;
+                    compressed_extensions = Some(value.split(',').map(|e| e.trim().to_string()).filter(|e| !e.is_empty()).collect());
+                    use_zlib = true;
+                    continue;
+                }
+
+                #[cfg(feature = "zlib")]
+                if arg == "--cache-dir" {
+                    cache_dir = Some(args.next().ok_or("Must specify a folder after --cache-dir")?);
+                    continue;
+                }
+
+                #[cfg(feature = "zlib")]
+                if arg == "--compression-name" {
+                    compression_name = Some(args.next().ok_or("Must specify a name after --compression-name")?);
+                    continue;
+                }
+
                 #[cfg(feature = "hash_meta")]
                 if arg == "-m" || arg == "--meta" {
                     hash_metadata = true;
                     continue;
                 }
 
+                #[cfg(feature = "json")]
+                if arg == "--json" {
+                    summary_path = Some(args.next().ok_or("Must specify a path after --json")?);
+                    continue;
+                }
+
+                #[cfg(feature = "checksums")]
+                if arg == "--checksums" {
+                    checksums_path = Some(args.next().ok_or("Must specify a path after --checksums")?);
+                    continue;
+                }
+
+                #[cfg(feature = "block_crc")]
+                if arg == "--block-crc" {
+                    block_crc_path = Some(args.next().ok_or("Must specify a path after --block-crc")?);
+                    continue;
+                }
+
+                #[cfg(feature = "aes")]
+                if arg == "--aes-key" {
+                    let value = args.next().ok_or("Must specify a key after --aes-key")?;
+                    let key = parse_hex(&value).ok_or(format!("Invalid AES key: {value}"))?;
+                    aes_key = Some(key.try_into().map_err(|_| format!("AES key must be {} bytes", toc_maker::encryption::AES_KEY_SIZE))?);
+                    continue;
+                }
+
+                #[cfg(feature = "aes")]
+                if arg == "--aes-key-guid" {
+                    let value = args.next().ok_or("Must specify a guid after --aes-key-guid")?;
+                    let guid = parse_hex(&value).ok_or(format!("Invalid AES key guid: {value}"))?;
+                    aes_key_guid = toc_maker::io_toc::GUID::from_be_bytes(guid.try_into().map_err(|_| "AES key guid must be 16 bytes")?);
+                    continue;
+                }
+
+                if arg == "--store-ext" {
+                    let value = args.next().ok_or("Must specify a list of extensions after --store-ext")?;
+                    single_block_extensions = Some(value.split(',').map(|e| e.trim().to_string()).filter(|e| !e.is_empty()).collect());
+                    continue;
+                }
+
+                if arg == "--manifest" {
+                    use_manifest = true;
+                    continue;
+                }
+
+                #[cfg(feature = "zip")]
+                if arg == "--zip" {
+                    use_zip = true;
+                    continue;
+                }
+
+                if arg == "--prune-empty" {
+                    prune_empty = true;
+                    continue;
+                }
+
+                if arg == "--follow-symlinks" {
+                    follow_symlinks = true;
+                    continue;
+                }
+
+                if arg == "--include-hidden" {
+                    include_hidden = true;
+                    continue;
+                }
+
+                if arg == "--deterministic" {
+                    deterministic = true;
+                    continue;
+                }
+
+                if arg == "--check-bulk" {
+                    check_bulk_siblings = true;
+                    continue;
+                }
+
+                if arg == "--tree" {
+                    print_tree = true;
+                    continue;
+                }
+
+                if arg == "--estimate" {
+                    estimate = true;
+                    continue;
+                }
+
+                if arg == "--block-table-on-disk" {
+                    block_table_on_disk = true;
+                    continue;
+                }
+
+                if arg == "--min-size" || arg == "--max-size" {
+                    let value = args.next().ok_or(format!("Must specify a size after {arg}"))?;
+                    let size = parse_size(&value).ok_or(format!("Invalid size: {value}"))?;
+                    if arg == "--min-size" { min_file_size = size } else { max_file_size = size }
+                    continue;
+                }
+
+                if arg == "--allow-empty" {
+                    allow_empty = true;
+                    continue;
+                }
+
+                if arg == "--expect-files" {
+                    let value = args.next().ok_or("Must specify a number after --expect-files")?;
+                    expected_files = Some(value.parse::<usize>().map_err(|_| format!("Invalid file count: {value}"))?);
+                    continue;
+                }
+
+                if arg == "--strict" {
+                    strict = true;
+                    continue;
+                }
+
+                if arg == "--profile-files" {
+                    profile_files = true;
+                    continue;
+                }
+
+                if arg == "--no-container-header" {
+                    container_header = false;
+                    continue;
+                }
+
+                if arg == "--ue-version" {
+                    let value = args.next().ok_or("Must specify a version after --ue-version")?;
+                    ue_version = match value.as_str() {
+                        "4.27" => toc_maker::io_toc::UeVersion::UE4_27,
+                        "5" => toc_maker::io_toc::UeVersion::UE5,
+                        _ => return Err(format!("Unsupported UE version: {value}"))
+                    };
+                    continue;
+                }
+
+                if arg == "--sort" {
+                    let value = args.next().ok_or("Must specify an order after --sort")?;
+                    sort_order = match value.as_str() {
+                        "flatten" => toc_maker::toc_factory::FileSortOrder::Flatten,
+                        "ue" => toc_maker::toc_factory::FileSortOrder::Unreal,
+                        _ => return Err(format!("Unknown sort order: {value}"))
+                    };
+                    continue;
+                }
+
+                if arg == "--endian" {
+                    let value = args.next().ok_or("Must specify big, little or native after --endian")?;
+                    endianness = match value.as_str() {
+                        "native" => toc_maker::toc_factory::Endianness::Native,
+                        "little" => toc_maker::toc_factory::Endianness::Little,
+                        "big" => toc_maker::toc_factory::Endianness::Big,
+                        _ => return Err(format!("Unknown endianness: {value}"))
+                    };
+                    continue;
+                }
+
+                if arg == "--update" {
+                    update_existing = true;
+                    continue;
+                }
+
+                if arg == "--ucas-stdout" {
+                    ucas_to_stdout = true;
+                    continue;
+                }
+
+                if arg == "--bundle" {
+                    bundle = true;
+                    continue;
+                }
+
+                #[cfg(feature = "loose")]
+                if arg == "--loose" {
+                    loose_pak = true;
+                    continue;
+                }
+
+                if arg == "--no-clobber" {
+                    no_clobber = true;
+                    continue;
+                }
+
+                if arg == "--split-by-subfolder" {
+                    split_by_subfolder = true;
+                    continue;
+                }
+
+                if arg == "--name" {
+                    container_name = Some(args.next().ok_or("Must specify a name after --name")?);
+                    continue;
+                }
+
+                if arg == "--order" {
+                    let value = args.next().ok_or("Must specify a number after --order")?;
+                    order = Some(value.parse::<u32>().map_err(|_| format!("Invalid order: {value}"))?);
+                    continue;
+                }
+
+                if arg == "--partition-size" {
+                    let value = args.next().ok_or("Must specify a size after --partition-size")?;
+                    partition_size = Some(parse_size(&value).ok_or(format!("Invalid partition size: {value}"))?);
+                    continue;
+                }
+
+                if arg == "--block-size" {
+                    let value = args.next().ok_or("Must specify a size after --block-size")?;
+                    block_size = Some(parse_size(&value).and_then(|size| u32::try_from(size).ok()).ok_or(format!("Invalid block size: {value}"))?);
+                    continue;
+                }
+
+                if arg == "--auto-block-size" {
+                    auto_block_size = true;
+                    continue;
+                }
+
+                if arg == "--mount" {
+                    mount_point = Some(args.next().ok_or("Must specify a path after --mount")?);
+                    continue;
+                }
+
+                if arg == "--root-prefix" {
+                    root_prefix = Some(args.next().ok_or("Must specify a path after --root-prefix")?);
+                    continue;
+                }
+
+                if arg == "-q" || arg == "--quiet" {
+                    log_level = log::LevelFilter::Warn;
+                    continue;
+                }
+
+                if arg == "-v" || arg == "--verbose" {
+                    log_level = log::LevelFilter::Trace;
+                    continue;
+                }
+
+                if arg == "--log" {
+                    log_path = Some(args.next().ok_or("Must specify a path after --log")?);
+                    continue;
+                }
+
                 if arg == "-h" || arg == "--help" {
                     return Err(String::new());
                 }
@@ -46,11 +504,128 @@ impl Config {
             }
         }
 
+        #[cfg(feature = "zip")]
+        if use_zip && use_manifest {
+            return Err("--zip and --manifest can't be used together".to_string());
+        }
+
+        #[cfg(feature = "checksums")]
+        if checksums_path.is_some() && update_existing {
+            return Err("--checksums can't be used with --update".to_string());
+        }
+
+        #[cfg(feature = "loose")]
+        if loose_pak && update_existing {
+            return Err("--loose can't be used with --update".to_string());
+        }
+
+        // partitions and updates need the ucas to be a file
+        if ucas_to_stdout && (update_existing || partition_size.is_some()) {
+            return Err("--ucas-stdout can't be used with --update or --partition-size".to_string());
+        }
+        if bundle && (update_existing || ucas_to_stdout) {
+            return Err("--bundle can't be used with --update or --ucas-stdout".to_string());
+        }
+        // an update has to keep the block size the container was built with
+        if auto_block_size && update_existing {
+            return Err("--auto-block-size can't be used with --update".to_string());
+        }
+        if no_clobber && update_existing {
+            return Err("--no-clobber can't be used with --update".to_string());
+        }
+        // each container is named after its subfolder, and gets its own set of outputs
+        if split_by_subfolder {
+            #[cfg(feature = "zip")]
+            if use_zip {
+                return Err("--split-by-subfolder can't be used with --zip".to_string());
+            }
+            if use_manifest || update_existing || ucas_to_stdout {
+                return Err("--split-by-subfolder can't be used with --manifest, --update or --ucas-stdout".to_string());
+            }
+            if container_name.is_some() || order.is_some() {
+                return Err("--split-by-subfolder can't be used with --name or --order, each container is named after its folder".to_string());
+            }
+            #[cfg(feature = "json")]
+            if summary_path.is_some() {
+                return Err("--split-by-subfolder can't be used with --json".to_string());
+            }
+            #[cfg(feature = "checksums")]
+            if checksums_path.is_some() {
+                return Err("--split-by-subfolder can't be used with --checksums".to_string());
+            }
+            #[cfg(feature = "block_crc")]
+            if block_crc_path.is_some() {
+                return Err("--split-by-subfolder can't be used with --block-crc".to_string());
+            }
+        }
+
+        let inpath = inpath.ok_or("Must specify input path")?;
+        // nothing is written with --tree or --estimate, so they don't need somewhere to write to
+        let mut outpath: String = match outpath {
+            Some(outpath) => get_output_stem(outpath),
+            None if print_tree || estimate => String::new(),
+            None => return Err("Must specify output path".to_string())
+        };
+        if let Some(order) = order {
+            outpath = toc_maker::toc_factory::get_patch_output_stem(&outpath, order);
+        }
+
         Ok(Self {
-            inpath: inpath.ok_or("Must specify input path")?,
-            outpath: outpath.ok_or("Must specify output path")?,
+            inpath,
+            outpath,
             use_zlib,
+            zlib_level,
+            compressed_extensions,
+            single_block_extensions,
+            cache_dir,
+            compression_name,
             hash_metadata,
+            partition_size,
+            block_size,
+            auto_block_size,
+            mount_point,
+            root_prefix,
+            use_manifest,
+            #[cfg(feature = "zip")]
+            use_zip,
+            prune_empty,
+            follow_symlinks,
+            include_hidden,
+            deterministic,
+            check_bulk_siblings,
+            print_tree,
+            estimate,
+            min_file_size,
+            block_table_on_disk,
+            max_file_size,
+            allow_empty,
+            expected_files,
+            strict,
+            profile_files,
+            container_header,
+            ue_version,
+            sort_order,
+            endianness,
+            update_existing,
+            ucas_to_stdout,
+            bundle,
+            no_clobber,
+            split_by_subfolder,
+            container_name,
+            log_level,
+            log_path,
+            #[cfg(feature = "json")]
+            summary_path,
+            #[cfg(feature = "checksums")]
+            checksums_path,
+            #[cfg(feature = "loose")]
+            loose_pak,
+            #[cfg(feature = "block_crc")]
+            block_crc_path,
+            #[cfg(feature = "aes")]
+            aes_key,
+            #[cfg(feature = "aes")]
+            aes_key_guid,
         })
     }
 
@@ -61,25 +636,547 @@ Creates a utoc, ucas, and pak file using files in the input directory. Built
 and tested using UE4.27 (no guarantees on other verions).
 
 Usage:     toc-maker [options] <input path> <output path>
+           toc-maker verify [--ignore-order] <generated utoc> <reference utoc>
+           toc-maker unpack <bundle> [output folder]
+           toc-maker extract [options] <utoc> [output folder]
+           toc-maker id <virtual path>...
+           toc-maker selftest
 
     <input path>    Path to folder containing files that should be packaged 
                     into the IoStore output. Directory structure matters - this
                     folder will be considered the root of the output package.
+                    Files and folders matching a pattern in a .tocignore file
+                    (same syntax as .gitignore, without !) are skipped before
+                    their file type is checked.
+                    A <file>.tocmeta next to a file overrides how it's
+                    compressed, with lines of "compression = store" or 
+                    "compression = zlib" and "level = <0-9>". These win over
+                    --compress-ext and --store-ext, which win over -z/--level.
 
     <output path>   Path to the desired output. Output will be used as the file
-                    stem for newly created .utoc, .ucas, and .pak files.
+                    stem for newly created .utoc, .ucas, and .pak files. A
+                    .utoc, .ucas or .pak on the end is left off, and missing
+                    folders are created.
 
     Options:
 
+      Some options need a feature that has to be compiled in (e.g. -z needs
+      zlib). Prebuilt binaries may not have all of them.
+
       -h, --help    Show this help and exit.
 
+      -q, --quiet   Only print warnings and errors.
+
+      -v, --verbose Also print each file as it is collected, and the chunk id
+                    (in hex, like the game's log) that it's packaged with.
+
+      --log <path>  Also write everything the build prints to the given file,
+                    along with the settings it was run with and what -v would
+                    add, whatever -q or -v say for the console. Handy to 
+                    attach to a bug report.
+
       -z, --zlib    Compress output data using zlib. Can substantially reduce 
                     package size when including textures/models.
 
+      --level <0-9> zlib compression level, from 0 (store) to 9 (smallest and
+                    slowest). Default is 6. Turns on -z.
+
+      --compress-ext <extensions>
+                    Only compress files with these extensions, separated by 
+                    commas (e.g. ubulk,uptnl). Other files are stored 
+                    uncompressed. Turns on -z.
+
+      --store-ext <extensions>
+                    Store files with these extensions (separated by commas)
+                    as they are, in a single uncompressed block, e.g. audio
+                    that's already compressed. Only for formats the game
+                    streams itself. Files over 16M are split into blocks.
+
+      --cache-dir <path>
+                    Keep the compressed blocks of each file in this folder,
+                    and reuse them for files that haven't changed (same 
+                    modified time and size) on the next build with the same
+                    compression settings. Needs -z.
+
+      --compression-name <name>
+                    ***ADVANCED - CAN MAKE THE CONTAINER UNREADABLE*** Record
+                    this compression method name in the utoc instead of 
+                    "zlib" (up to 31 ASCII characters). Data is still zlib 
+                    compressed. Only for games that register their zlib 
+                    decoder under another name - if the game doesn't know 
+                    the name, none of the compressed files will load. 
+                    Needs -z.
+
       -m, --meta    Hash file contents and include in toc meta. Doesn't seem to
                     be verified, but may help if you have issues loading 
                     content. ***INCREASES EXECUTION TIME***
 
+      --json <path> Write a JSON summary of the build (file and directory 
+                    counts, sizes, chunk ids of each file and skipped files)
+                    to the given path.
+
+      --checksums <path>
+                    Write the SHA256 of each output file to the given path, 
+                    one "<hash>  <file name>" line per file (the same format
+                    as sha256sum). Can't be used with --update.
+
+      --block-crc <path>
+                    Write the CRC32 of every compression block in the ucas
+                    to the given path, one "<offset> <size> <crc32>" line
+                    per block. Much faster than --meta, but only useful for
+                    checking the ucas with external tools.
+
+      --aes-key <hex>
+                    Encrypt the ucas with the given AES-256 key (64 hex 
+                    characters, optional 0x prefix).
+
+      --aes-key-guid <hex>
+                    Guid of the key used with --aes-key, written into the 
+                    utoc header (32 hex characters). Defaults to zero, which
+                    is the game's primary key.
+
+      --manifest    Treat the input path as a manifest file instead of a folder.
+                    Each line of the manifest is a source file path and the 
+                    path it should have inside the package, separated by a 
+                    tab (e.g. "C:/work/Foo.uasset<TAB>P3R/Content/Foo.uasset").
+                    Blank lines and lines starting with # are ignored.
+
+      --zip         Treat the input path as a zip archive laid out the same 
+                    way as an input folder, instead of a folder.
+
+      --prune-empty Leave directories that contain no files (directly or in 
+                    any subdirectory) out of the directory index.
+
+      --follow-symlinks
+                    Package the files and folders that symlinks in the input
+                    folder point to. Without this, symlinks are skipped. Links
+                    to a folder that contains them are always skipped.
+
+      --include-hidden
+                    Package dotfiles and folders, OS metadata files (e.g.
+                    Thumbs.db, desktop.ini) and files ending in ~. These are
+                    skipped by default.
+
+      --deterministic
+                    Package the input folder's files in name order instead of
+                    the order the filesystem lists them in, so that the same
+                    files build the same container on any machine. Nothing
+                    else in a container depends on when or where it's built.
+
+      --check-bulk  Warn about assets in the input folder that don't have a
+                    .ubulk with the same name next to them, and bulk data
+                    (.ubulk, .uptnl) without its .uasset. Assets without bulk
+                    data are fine as long as they were cooked without it.
+
+      --min-size <size>, --max-size <size>
+                    Only package files from the input folder that are at 
+                    least/at most this big. Sizes are in bytes, or with a K, M
+                    or G suffix (e.g. 10M). Other files are listed as skipped.
+
+      --tree        Print the files and folders that would be packaged (with
+                    their sizes) like the tree command, then exit without
+                    writing anything. The output path can be left out.
+
+      --estimate    Compress the first block of each file and print the 
+                    projected ucas size and compression time, then exit 
+                    without writing anything. Try it with different --level
+                    values to see if a slower level is worth it. The output
+                    path can be left out.
+
+      --allow-empty Build the container even if no files were found to package.
+                    Without this, an empty input is treated as an error.
+
+      --expect-files <n>
+                    Warn if fewer than n files end up in the container. There
+                    is always a warning when most of the files found were 
+                    skipped, or the files add up to less than 4K.
+
+      --strict      Fail (listing them) if any file in the input would be
+                    skipped or any folder couldn't be read, instead of only
+                    printing them in the summary. Files left out by a
+                    .tocignore or --min-size/--max-size are still allowed.
+
+      --profile-files
+                    Time how long each file takes to compress and write, and
+                    list the 10 slowest after the build.
+
+      --no-container-header
+                    Don't add the container header chunk after the files. Only
+                    for patch containers that don't need their own, since the
+                    game won't know about any packages in them without it.
+
+      --ue-version <version>
+                    Engine version to make chunk ids for, 4.27 (default) or 5.
+                    UE5 numbers chunk types differently. Only the chunk ids 
+                    change, the container header is still written for 4.27 
+                    so UE5 builds usually need --no-container-header too.
+
+      --sort <order>
+                    Order to write files into the ucas in. flatten (default)
+                    keeps the order of the directory index, ue matches UE's 
+                    cooked containers (bulk data last, then grouped by folder,
+                    then by size) so output can be diffed against them.
+
+      --endian <order>
+                    Byte order to write the container in: native (default,
+                    same as this machine), little or big. Big-endian console
+                    targets need big.
+
+      --update      Update the container at the output path instead of building
+                    a new one. Files that haven't changed since it was built 
+                    keep their existing data, new and modified files are 
+                    appended to the ucas. Compression settings must match the
+                    original build.
+
+      --ucas-stdout Write the ucas to stdout (e.g. to pipe it somewhere) 
+                    instead of next to the utoc. The utoc and pak are still
+                    written to the output path, and messages go to stderr.
+
+      --bundle      Write a single <output path>.tocbundle holding the utoc,
+                    ucas (and any partitions) and pak, instead of the separate
+                    files. The game can't load it, split it back apart with
+                    toc-maker unpack first.
+
+      --loose       Also write the files into a standard (non-IoStore) pak,
+                    <output path>_loose.pak, with the same mount point and
+                    paths. Mount it instead of the container to check whether
+                    a problem only happens with IoStore. Can't be used with
+                    --update.
+
+      --split-by-subfolder
+                    Build one container for each folder in the input folder,
+                    named after it and written into the output folder (e.g.
+                    <input>/ModA becomes <output>/ModA.utoc). Each folder is
+                    packaged as if it was the input. Can't be used with
+                    --name, --order, --manifest, --update, --ucas-stdout,
+                    --json, --checksums or --block-crc.
+
+      --no-clobber  Fail instead of replacing a container that's already at
+                    the output path. Without it, existing outputs are
+                    replaced (with a warning) once the new ones are written.
+
+      --name <name> Name of the container, e.g. pakchunk120 (default is 
+                    pakchunk999). Its hash identifies the container, so mods
+                    that are loaded together should each use a different name.
+                    UE4.27 containers have no GUID (only the key guid from 
+                    --aes-key-guid), so the same input, options and name 
+                    always build the same container.
+
+      --order <n>   Mount order of the container, added to the output name as
+                    _<n>_P (e.g. Mod_2_P.utoc). UE4.27 has no mount order in 
+                    the utoc or container header, it takes it from the .pak 
+                    name instead. When two containers have the same asset, the
+                    one with the higher order wins.
+
+      --block-table-on-disk
+                    Keep the table of compression blocks in a temp file 
+                    while the ucas is written, instead of in memory. Only
+                    needed for very large containers (millions of blocks).
+
+      --partition-size <size>
+                    Split the ucas into partition files (.ucas, _s1.ucas, 
+                    _s2.ucas, ...) no larger than the given size, with an
+                    optional K, M or G suffix. Must be a multiple of the
+                    compression block size (256K).
+
+      --block-size <size>
+                    Size of each compression block (default 256K). Must be a
+                    power of two, with an optional K or M suffix.
+
+      --auto-block-size
+                    Pick the block size from the sizes of the files being 
+                    packaged (64K to 1M): smaller when most files are small
+                    assets, bigger when most are bulk data. The size chosen
+                    is printed. --block-size overrides it. Can't be used 
+                    with --update.
+
+      --mount <path>
+                    Mount point written into the utoc (default ../../../).
+                    Must end with a / and be at most 260 characters.
+
+      --root-prefix <path>
+                    Package path that the input folder stands for, e.g. 
+                    MyPlugin to package Foo/Bar.uasset as /MyPlugin/Foo/Bar.
+                    Chunk ids are hashed from these paths. Without it, the 
+                    input has to be laid out like the game's content 
+                    (P3R/Content/Foo/Bar.uasset), which is packaged as 
+                    /Game/Foo/Bar. Only chunk ids change, the files keep their
+                    paths under the mount point.
+
+    Environment:
+
+      Most options can also be set with an environment variable named after
+      the flag: TOCMAKER_ and the flag in upper case, with - replaced by _
+      (e.g. TOCMAKER_COMPRESS_EXT=ubulk, TOCMAKER_BLOCK_SIZE=64K). Options
+      without a value are turned on with 1 or true. A flag on the command 
+      line takes precedence over the environment, which takes precedence
+      over the built-in default. Flags without a value can't be turned
+      back off on the command line once the environment turns them on.
+
+
+    Verify:
+
+      Compares the header, chunk ids, compression block count and compression
+      method table of a generated utoc against a known-good reference utoc 
+      and reports the first difference, with byte offsets into both files.
+
+      --ignore-order
+                    Only require both TOCs to contain the same set of chunk
+                    ids, in any order.
+
+
+    Unpack:
+
+      Splits a .tocbundle made with --bundle back into the files it was made
+      from, in the output folder (default is the bundle's folder).
+
+
+    Extract:
+
+      Writes the files in a container back out to the output folder, at the
+      paths in its directory index (default is a folder named after the
+      utoc, next to it). The ucas partitions are found next to the utoc.
+      Blocks can be uncompressed or zlib.
+
+      --aes-key <hex>
+                    Key to decrypt an encrypted container with, 32 bytes.
+
+
+    Id:
+
+      Prints the chunk id (in hex, like the game's log) that a file would be
+      packaged with by default, given its path in the input folder, e.g. 
+      P3R/Content/Foo/Bar.uasset. Nothing is read from disk.
+
+
+    Selftest:
+
+      Builds a small container from files made up on the spot, reads it 
+      back and checks every file comes out the same. If this passes but a 
+      build doesn't, the problem is more likely in the input than in 
+      toc-maker.
+
         "#
     }
-}
\ No newline at end of file
+}
+
+pub struct VerifyConfig {
+    pub generated_path: String,
+    pub reference_path: String,
+    pub ignore_order: bool,
+}
+
+impl VerifyConfig {
+    pub fn new<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut generated_path = None;
+        let mut reference_path = None;
+        let mut ignore_order = false;
+
+        while let Some(arg) = args.next() {
+            if arg == "--ignore-order" {
+                ignore_order = true;
+            } else if arg.starts_with('-') {
+                return Err(format!("Unexpected argument: {arg}"));
+            } else if matches!(generated_path, None) {
+                generated_path = Some(arg);
+            } else if matches!(reference_path, None) {
+                reference_path = Some(arg);
+            } else {
+                return Err(format!("Unexpected argument: {arg}"));
+            }
+        }
+
+        Ok(Self {
+            generated_path: generated_path.ok_or("Must specify the generated utoc path")?,
+            reference_path: reference_path.ok_or("Must specify the reference utoc path")?,
+            ignore_order,
+        })
+    }
+}
+
+pub struct IdConfig {
+    pub virtual_paths: Vec<String>,
+}
+
+impl IdConfig {
+    pub fn new<I: Iterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut virtual_paths = vec![];
+        for arg in args {
+            if arg.starts_with('-') {
+                return Err(format!("Unexpected argument: {arg}"));
+            }
+            virtual_paths.push(arg);
+        }
+        if virtual_paths.is_empty() {
+            return Err("Must specify at least one path".to_string());
+        }
+        Ok(Self { virtual_paths })
+    }
+}
+
+pub struct UnpackConfig {
+    pub bundle_path: String,
+    pub out_folder: Option<String>,
+}
+
+impl UnpackConfig {
+    pub fn new<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut bundle_path = None;
+        let mut out_folder = None;
+
+        while let Some(arg) = args.next() {
+            if arg.starts_with('-') {
+                return Err(format!("Unexpected argument: {arg}"));
+            } else if matches!(bundle_path, None) {
+                bundle_path = Some(arg);
+            } else if matches!(out_folder, None) {
+                out_folder = Some(arg);
+            } else {
+                return Err(format!("Unexpected argument: {arg}"));
+            }
+        }
+
+        Ok(Self {
+            bundle_path: bundle_path.ok_or("Must specify the bundle path")?,
+            out_folder,
+        })
+    }
+}
+
+pub struct ExtractConfig {
+    pub container_stem: String,
+    pub out_folder: Option<String>,
+    #[cfg(feature = "aes")]
+    pub aes_key: Option<toc_maker::encryption::AesKey>,
+}
+
+impl ExtractConfig {
+    pub fn new<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut container_stem = None;
+        let mut out_folder = None;
+        #[cfg(feature = "aes")]
+        let mut aes_key = None;
+
+        while let Some(arg) = args.next() {
+            #[cfg(feature = "aes")]
+            if arg == "--aes-key" {
+                let value = args.next().ok_or("Must specify a key after --aes-key")?;
+                let key = parse_hex(&value).ok_or(format!("Invalid AES key: {value}"))?;
+                aes_key = Some(key.try_into().map_err(|_| format!("AES key must be {} bytes", toc_maker::encryption::AES_KEY_SIZE))?);
+                continue;
+            }
+
+            if let Some(feature) = get_missing_feature(&arg) {
+                return Err(format!("{arg}: this build was compiled without {feature} support; rebuild with --features {feature}"));
+            } else if arg.starts_with('-') {
+                return Err(format!("Unexpected argument: {arg}"));
+            } else if matches!(container_stem, None) {
+                container_stem = Some(get_output_stem(arg));
+            } else if matches!(out_folder, None) {
+                out_folder = Some(arg);
+            } else {
+                return Err(format!("Unexpected argument: {arg}"));
+            }
+        }
+
+        Ok(Self {
+            container_stem: container_stem.ok_or("Must specify the utoc path")?,
+            out_folder,
+            #[cfg(feature = "aes")]
+            aes_key,
+        })
+    }
+}
+
+// Feature that an optional flag needs, if this build was compiled without it. Those flags are only parsed when the feature is
+// there, so this stops them from being reported as an unexpected argument
+fn get_missing_feature(arg: &str) -> Option<&'static str> {
+    let (feature, enabled) = match arg {
+        "-z" | "--zlib" | "--level" | "--compress-ext" | "--cache-dir" | "--compression-name" => ("zlib", cfg!(feature = "zlib")),
+        "-m" | "--meta" => ("hash_meta", cfg!(feature = "hash_meta")),
+        "--json" => ("json", cfg!(feature = "json")),
+        "--checksums" => ("checksums", cfg!(feature = "checksums")),
+        "--block-crc" => ("block_crc", cfg!(feature = "block_crc")),
+        "--aes-key" | "--aes-key-guid" => ("aes", cfg!(feature = "aes")),
+        "--zip" => ("zip", cfg!(feature = "zip")),
+        "--loose" => ("loose", cfg!(feature = "loose")),
+        _ => return None
+    };
+    if enabled { None } else { Some(feature) }
+}
+
+// The output path is a stem that .utoc, .ucas and .pak are added to, so a path to one of those files (Mod.utoc) is taken to mean
+// its stem rather than making Mod.utoc.utoc
+fn get_output_stem(outpath: String) -> String {
+    for extension in [".utoc", ".ucas", ".pak"] {
+        if outpath.len() > extension.len() && outpath.to_ascii_lowercase().ends_with(extension) {
+            return outpath[..outpath.len() - extension.len()].to_string();
+        }
+    }
+    outpath
+}
+
+// Read a size in bytes, with an optional K, M or G suffix (powers of 1024)
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, unit) = match value.char_indices().last()? {
+        (i, 'K' | 'k') => (&value[..i], 1 << 10),
+        (i, 'M' | 'm') => (&value[..i], 1 << 20),
+        (i, 'G' | 'g') => (&value[..i], 1 << 30),
+        _ => (value, 1)
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+// Read a hex string (with or without a 0x prefix) into bytes, most significant first
+#[allow(dead_code)]
+fn parse_hex(value: &str) -> Option<Vec<u8>> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    (0..digits.len()).step_by(2).map(|i| u8::from_str_radix(digits.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(args: &[&str], vars: &[(&str, &str)]) -> Result<Config, String> {
+        Config::new(args.iter().map(|arg| arg.to_string()), vars.iter().map(|(name, value)| (name.to_string(), value.to_string())))
+    }
+
+    #[test]
+    fn env_vars_are_read_as_flags() {
+        let config = config(&["In", "Out"], &[("TOCMAKER_STRICT", "TRUE"), ("TOCMAKER_PRUNE_EMPTY", "0"), ("TOCMAKER_NO_CLOBBER", ""), ("TOCMAKER_NAME", "Mod"), ("PATH", "/bin")]).unwrap();
+
+        assert!(config.strict);
+        assert!(!config.prune_empty);
+        assert!(!config.no_clobber);
+        assert_eq!(config.container_name.as_deref(), Some("Mod"));
+    }
+
+    #[test]
+    fn invalid_env_bool_is_an_error() {
+        let result = config(&["In", "Out"], &[("TOCMAKER_STRICT", "yes")]);
+        assert!(matches!(result, Err(e) if e.contains("TOCMAKER_STRICT")));
+    }
+
+    #[test]
+    fn sizes_take_a_unit_suffix() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("64k"), Some(64 << 10));
+        assert_eq!(parse_size("2M"), Some(2 << 20));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        for invalid in ["", "K", "1.5M", "-1", "10T", "99999999999999999999G"] {
+            assert_eq!(parse_size(invalid), None, "{}", invalid);
+        }
+        let config = config(&["In", "Out", "--partition-size", "512M"], &[]).unwrap();
+        assert_eq!(config.partition_size, Some(512 << 20));
+    }
+
+    #[test]
+    fn flags_win_over_env_vars() {
+        let config = config(&["In", "Out", "--name", "FromFlag"], &[("TOCMAKER_NAME", "FromEnv")]).unwrap();
+        assert_eq!(config.container_name.as_deref(), Some("FromFlag"));
+    }
+}
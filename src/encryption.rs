@@ -0,0 +1,31 @@
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes256
+};
+
+// IoStore containers are encrypted with AES-256 in ECB mode, one 16 byte block at a time
+pub const AES_BLOCK_SIZE: usize = 0x10;
+pub const AES_KEY_SIZE: usize = 0x20;
+
+pub type AesKey = [u8; AES_KEY_SIZE];
+
+// Encrypt a block as it's stored in the ucas, padded up to AES_BLOCK_SIZE (the block entry keeps the unpadded size)
+pub fn encrypt_padded(key: &AesKey, data: &[u8]) -> Vec<u8> {
+    let cipher = Aes256::new(GenericArray::from_slice(key));
+    let padded_len = data.len().next_multiple_of(AES_BLOCK_SIZE);
+    let mut encrypted = Vec::with_capacity(padded_len);
+    encrypted.extend_from_slice(data);
+    encrypted.resize(padded_len, 0);
+    for block in encrypted.chunks_exact_mut(AES_BLOCK_SIZE) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+    encrypted
+}
+
+// Decrypt a block read back from the ucas, which has to be whole AES blocks (the stored size, not the block entry's size)
+pub fn decrypt_in_place(key: &AesKey, data: &mut [u8]) {
+    let cipher = Aes256::new(GenericArray::from_slice(key));
+    for block in data.chunks_exact_mut(AES_BLOCK_SIZE) {
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+}
@@ -0,0 +1,97 @@
+use std::{error::Error, fmt, io};
+
+use crate::{asset_collector::CollectionStats, io_toc::{IO_COMPRESSED_BLOCK_LENGTH_MAX, IO_OFFSET_LENGTH_MAX}};
+
+#[derive(Debug)]
+pub enum TocError {
+    InputNotFound(String), // source folder path
+    NotADirectory(String), // source folder path
+    InvalidSettings(&'static str),
+    InvalidManifest(usize, String), // line number, reason
+    InvalidFileSettings(String, String), // sidecar path, reason
+    NoChunkType(String, String), // os path, why no chunk id can be made for it
+    NotGameLayout(String), // virtual path without the extension
+    DuplicateChunkIds(Vec<Vec<String>>), // os paths of each group of files that share a chunk id
+    NoAssets(String), // source folder or manifest path
+    InvalidExistingContainer(String, String), // utoc path, reason
+    OutputInsideInput(String, String), // output path, source folder
+    InvalidZip(String, String), // archive path, reason
+    ContainerTooLarge(u64, u64), // end of the last chunk (uncompressed), compression block count
+    UcasTooLarge(u64), // where the block that didn't fit would have ended in the ucas, counting every partition
+    BlockTooLarge(usize), // stored or uncompressed size of a block
+    DirectoryIndexSizeMismatch(u32, usize), // size in the header, bytes actually serialized
+    FilesNotPackaged(Vec<(String, String)>), // os path and reason for each file or folder, from strict mode
+    WriteFailed(Box<WriteFailure>),
+    Cancelled,
+    Io(io::Error),
+}
+
+// A build that failed partway through writing the ucas. Collection had already finished by then, so its totals are kept
+// along with which file was being written
+#[derive(Debug)]
+pub struct WriteFailure {
+    pub stats: CollectionStats,
+    pub file_index: usize, // position in write order, starting from 0
+    pub os_path: String,
+    pub error: TocError,
+}
+
+impl fmt::Display for TocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TocError::InputNotFound(path) => write!(f, "Input path \"{}\" does not exist", path),
+            TocError::NotADirectory(path) => write!(f, "Input path \"{}\" is a file, the input has to be a folder (the one that has the game's folder in it, e.g. P3R)", path),
+            TocError::InvalidSettings(reason) => write!(f, "{}", reason),
+            TocError::InvalidManifest(line, reason) => write!(f, "Invalid manifest entry on line {}: {}", line, reason),
+            TocError::InvalidFileSettings(path, reason) => write!(f, "Invalid file settings in \"{}\": {}", path, reason),
+            TocError::NoChunkType(path, reason) => write!(f, "Can't make a chunk id for \"{}\": {}", path, reason),
+            TocError::NotGameLayout(path) => write!(f, "\"{}\" isn't under a <Project>/Content folder like the game's content is, so it has no /Game path. Use --root-prefix to package it under another mount root", path),
+            TocError::DuplicateChunkIds(groups) => {
+                write!(f, "Multiple files would be packaged with the same chunk id, only one of each would load:")?;
+                for group in groups {
+                    write!(f, "\n    {}", group.join(", "))?;
+                }
+                Ok(())
+            },
+            TocError::NoAssets(path) => write!(f, "No files that can be packaged were found in \"{}\". If an empty container is intended, use --allow-empty", path),
+            TocError::InvalidExistingContainer(path, reason) => write!(f, "Can't update \"{}\": {}", path, reason),
+            TocError::OutputInsideInput(output, source) => write!(f, "Output \"{}\" is inside of the input folder \"{}\", choose an output path outside of it", output, source),
+            TocError::InvalidZip(path, reason) => write!(f, "Couldn't read zip archive \"{}\": {}", path, reason),
+            TocError::ContainerTooLarge(end, blocks) => write!(f, "Files add up to {} bytes in {} compression blocks, but a container can only address {} bytes in {} blocks. Split them between multiple containers",
+                end, blocks, IO_OFFSET_LENGTH_MAX, u32::MAX),
+            TocError::UcasTooLarge(end) => write!(f, "The ucas would reach {} bytes, but block offsets can only go up to {} bytes (partition N starts at N * the partition size). Split the files between multiple containers",
+                end, IO_OFFSET_LENGTH_MAX + 1),
+            TocError::BlockTooLarge(size) => write!(f, "A compression block would be {} bytes, but blocks can be at most {} bytes. This is a bug in toc-maker, please report it", size, IO_COMPRESSED_BLOCK_LENGTH_MAX),
+            TocError::DirectoryIndexSizeMismatch(expected, actual) => write!(f, "Directory index was {} bytes, but the TOC header says it's {}. This is a bug in toc-maker, please report it", actual, expected),
+            TocError::FilesNotPackaged(files) => {
+                write!(f, "{} files or folders in the input weren't packaged, which isn't allowed with --strict:", files.len())?;
+                for (os_path, reason) in files {
+                    write!(f, "\n    {}: {}", os_path, reason)?;
+                }
+                Ok(())
+            },
+            TocError::WriteFailed(failure) => write!(f, "Collected {} files ({} KB), failed while writing file #{} ({}): {}",
+                failure.stats.file_count, failure.stats.file_size / 1024, failure.file_index + 1, failure.os_path, failure.error),
+            TocError::Cancelled => write!(f, "Build was cancelled before the container was finished"),
+            TocError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for TocError {}
+
+impl From<io::Error> for TocError {
+    fn from(value: io::Error) -> Self {
+        TocError::Io(value)
+    }
+}
+
+// The serializers in io_toc return boxed errors, which come from the writer underneath
+impl From<Box<dyn Error>> for TocError {
+    fn from(value: Box<dyn Error>) -> Self {
+        match value.downcast::<io::Error>() {
+            Ok(e) => TocError::Io(*e),
+            Err(e) => TocError::Io(io::Error::new(io::ErrorKind::Other, e.to_string()))
+        }
+    }
+}
@@ -579,11 +579,29 @@ impl ObjectExport2 {
     }
 }
 
-// Check that the first bytes of the file don't contain the magic used for cooked assets
+// Bytes from the start of the file that get_asset_header_kind reads. Only this much is read, so callers don't need to buffer
+pub const ASSET_TYPE_CHECK_SIZE: usize = 4;
+
+// What the first bytes of a uasset/umap say about how it was cooked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssetHeaderKind {
+    IoStore, // no package file magic, which is how packages cooked for IoStore start
+    LegacyCooked, // starts with the package file magic, so it was cooked for .pak files instead of IoStore
+    Unreadable, // too short to hold the check, so it's truncated (or not an asset at all)
+}
+
+pub fn get_asset_header_kind<R: Read + Seek, E: byteorder::ByteOrder>(reader: &mut R) -> AssetHeaderKind {
+    let mut header = [0u8; ASSET_TYPE_CHECK_SIZE];
+    if reader.seek(SeekFrom::Start(0)).is_err() || reader.read_exact(&mut header).is_err() {
+        return AssetHeaderKind::Unreadable;
+    }
+    if E::read_u32(&header) == UASSET_MAGIC { AssetHeaderKind::LegacyCooked } else { AssetHeaderKind::IoStore }
+}
+
+// Check that the first bytes of the file don't contain the magic used for cooked assets. Files too short to hold the check
+// can't be a valid asset either
 pub fn is_valid_asset_type<R: Read + Seek, E: byteorder::ByteOrder>(reader: &mut R) -> bool {
-    reader.seek(SeekFrom::Start(0));
-    let magic_check = reader.read_u32::<E>().unwrap();
-    magic_check != UASSET_MAGIC
+    get_asset_header_kind::<R, E>(reader) == AssetHeaderKind::IoStore
 }
 
 #[cfg(test)]
@@ -4,6 +4,7 @@ use crate::string::{FString32NoHash, FStringSerializer, Hasher16};
 use sha1::{Sha1, Digest};
 use std::{
     error::Error,
+    fmt,
     io::{Cursor, Read, Seek, SeekFrom, Write}
 };
 
@@ -68,6 +69,10 @@ pub const COMPRESSION_METHOD_NAME_LENGTH: u32 = 32;
 
 pub trait IoStoreTocHeaderCommon {
     fn new(container_id: u64, entries: u32, compressed_blocks: u32, compression_method_name_count: u32, compression_block_size: u32, dir_index_size: u32) -> impl IoStoreTocHeaderCommon;
+    // Partitions are separate ucas files (.ucas, _s1.ucas, ...). Block offsets are partition_index * partition_size + offset into that partition
+    fn set_partitions(&mut self, partition_count: u32, partition_size: u64);
+    // Mark the container as encrypted with the key identified by key_guid (a zero guid is the game's primary key)
+    fn set_encryption_key_guid(&mut self, key_guid: GUID);
     fn to_buffer<W: Write, E: byteorder::ByteOrder>(&self, writer: &mut W) -> Result<(), Box<dyn Error>>;
 }
 
@@ -112,6 +117,14 @@ impl IoStoreTocHeaderCommon for IoStoreTocHeaderType3 {
             reserved: [0; 6]
         }
     }
+    fn set_partitions(&mut self, partition_count: u32, partition_size: u64) {
+        self.partition_count = partition_count;
+        self.partition_size = partition_size;
+    }
+    fn set_encryption_key_guid(&mut self, key_guid: GUID) {
+        self.encryption_key_guid = key_guid;
+        self.container_flags |= io_container_flags::ENCRYPTED;
+    }
     fn to_buffer<W: Write, E: byteorder::ByteOrder>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
         writer.write_all(self.toc_magic.as_slice())?; // 0x0
         writer.write_u8(self.version.into())?;
@@ -138,7 +151,93 @@ impl IoStoreTocHeaderCommon for IoStoreTocHeaderType3 {
     }
 }
 
+impl IoStoreTocHeaderType3 {
+    pub fn from_buffer<R: Read + Seek, E: byteorder::ByteOrder>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+        let mut toc_magic = [0; 0x10];
+        reader.read_exact(&mut toc_magic)?; // 0x0
+        if toc_magic != IO_STORE_TOC_MAGIC {
+            return Err("Not a utoc file (TOC magic is missing)".into());
+        }
+        let version = reader.read_u8()?;
+        if version != u8::from(IoStoreTocVersion::PartitionSize) {
+            return Err(format!("Unsupported TOC version {} (only UE 4.27 TOCs can be read)", version).into());
+        }
+        reader.seek(SeekFrom::Current(3))?; // padding
+        let toc_header_size = reader.read_u32::<E>()?;
+        let toc_entry_count = reader.read_u32::<E>()?;
+        let toc_compressed_block_entry_count = reader.read_u32::<E>()?;
+        let toc_compressed_block_entry_size = reader.read_u32::<E>()?;
+        let compression_method_name_count = reader.read_u32::<E>()?;
+        let compression_method_name_length = reader.read_u32::<E>()?;
+        let compression_block_size = reader.read_u32::<E>()?;
+        if compression_block_size == 0 {
+            return Err("Compression block size is 0, so no chunk can be found in the blocks".into());
+        }
+        let directory_index_size = reader.read_u32::<E>()?;
+        let partition_count = reader.read_u32::<E>()?;
+        let container_id = reader.read_u64::<E>()?;
+        let encryption_key_guid = reader.read_u128::<E>()?;
+        let container_flags = reader.read_u8()?;
+        reader.seek(SeekFrom::Current(7))?; // padding
+        let partition_size = reader.read_u64::<E>()?;
+        reader.seek(SeekFrom::Current(6 * 8))?; // reserved
+        Ok(Self {
+            toc_magic,
+            version: IoStoreTocVersion::from(version),
+            toc_header_size,
+            toc_entry_count,
+            toc_compressed_block_entry_count,
+            toc_compressed_block_entry_size,
+            compression_method_name_count,
+            compression_method_name_length,
+            compression_block_size,
+            directory_index_size,
+            partition_count,
+            container_id,
+            encryption_key_guid,
+            container_flags,
+            partition_size,
+            reserved: [0; 6]
+        })
+    }
+    pub fn get_header_size(&self) -> u32 {
+        self.toc_header_size
+    }
+    pub fn get_entry_count(&self) -> u32 {
+        self.toc_entry_count
+    }
+    pub fn get_compressed_block_entry_count(&self) -> u32 {
+        self.toc_compressed_block_entry_count
+    }
+    pub fn get_compressed_block_entry_size(&self) -> u32 {
+        self.toc_compressed_block_entry_size
+    }
+    pub fn get_compression_method_name_count(&self) -> u32 {
+        self.compression_method_name_count
+    }
+    pub fn get_compression_method_name_length(&self) -> u32 {
+        self.compression_method_name_length
+    }
+    pub fn get_compression_block_size(&self) -> u32 {
+        self.compression_block_size
+    }
+    pub fn get_directory_index_size(&self) -> u32 {
+        self.directory_index_size
+    }
+    pub fn get_partition_count(&self) -> u32 {
+        self.partition_count
+    }
+    pub fn get_container_flags(&self) -> u8 {
+        self.container_flags
+    }
+    pub fn get_partition_size(&self) -> u64 {
+        self.partition_size
+    }
+}
+
 // IO CHUNK ID
+pub const IO_CHUNK_ID_SERIALIZED_SIZE: u64 = 0xc;
+
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
 #[repr(u8)]
 #[allow(dead_code)]
@@ -254,17 +353,71 @@ impl From<IoChunkType5> for u8 {
     }
 }
 
+// Engine version that chunk ids are made for. UE5 renumbered the chunk types, so the same kind of chunk gets a different type
+// byte depending on which engine is going to load it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UeVersion {
+    #[default]
+    UE4_27,
+    UE5,
+}
+
+// Chunk type from whichever engine version the chunk id is for
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
+pub enum IoChunkType {
+    UE4(IoChunkType4),
+    UE5(IoChunkType5),
+}
+
+impl IoChunkType {
+    // chunk_type as the given engine version numbers it. Only the kinds that files get packaged as (plus the container header)
+    // have a UE5 equivalent filled in so far
+    pub fn for_version(chunk_type: IoChunkType4, ue_version: UeVersion) -> Option<Self> {
+        match ue_version {
+            UeVersion::UE4_27 => Some(IoChunkType::UE4(chunk_type)),
+            UeVersion::UE5 => match chunk_type {
+                IoChunkType4::ExportBundleData => Some(IoChunkType5::ExportBundleData),
+                IoChunkType4::BulkData => Some(IoChunkType5::BulkData),
+                IoChunkType4::OptionalBulkData => Some(IoChunkType5::OptionalBulkData),
+                IoChunkType4::MemoryMappedBulkData => Some(IoChunkType5::MemoryMappedBulkData),
+                IoChunkType4::ContainerHeader => Some(IoChunkType5::ContainerHeader),
+                _ => None
+            }.map(IoChunkType::UE5)
+        }
+    }
+
+    pub fn is_bulk_data(&self) -> bool {
+        matches!(self,
+            IoChunkType::UE4(IoChunkType4::BulkData | IoChunkType4::OptionalBulkData | IoChunkType4::MemoryMappedBulkData) |
+            IoChunkType::UE5(IoChunkType5::BulkData | IoChunkType5::OptionalBulkData | IoChunkType5::MemoryMappedBulkData)
+        )
+    }
+}
+
+impl From<IoChunkType> for u8 {
+    fn from(value: IoChunkType) -> Self {
+        match value {
+            IoChunkType::UE4(chunk_type) => chunk_type.into(),
+            IoChunkType::UE5(chunk_type) => chunk_type.into(),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord)]
 #[repr(C/* , align(4)*/)] // Unreal Engine 4.25+ onwards
 pub struct IoChunkId {
     //id: [u8; 0xc]
     hash: u64,
     index: u16,
-    obj_type: IoChunkType4
+    obj_type: IoChunkType
 }
 
 impl IoChunkId {
     pub fn new(path: &str, chunk_type: IoChunkType4) -> Self {
+        Self::new_with_type(path, IoChunkType::UE4(chunk_type))
+    }
+    // The path is hashed the same way for every engine version, only the type byte changes
+    pub fn new_with_type(path: &str, chunk_type: IoChunkType) -> Self {
         let hash = Hasher16::get_cityhash64(path); // ChunkId
         let index = 0;
         let obj_type = chunk_type;
@@ -272,8 +425,16 @@ impl IoChunkId {
     }
     #[inline]
     pub fn new_from_hash(hash: u64, obj_type: IoChunkType4) -> Self {
+        Self { hash, index: 0, obj_type: IoChunkType::UE4(obj_type) }
+    }
+    #[inline]
+    pub fn new_from_hash_with_type(hash: u64, obj_type: IoChunkType) -> Self {
         Self { hash, index: 0, obj_type }
     }
+    #[inline]
+    pub fn get_type(&self) -> IoChunkType {
+        self.obj_type
+    }
     // TODO: split to_buffer off as a trait method
     pub fn to_buffer<W: Write, E: byteorder::ByteOrder>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
         writer.write_u64::<E>(self.hash)?; // 0x0
@@ -282,22 +443,38 @@ impl IoChunkId {
         writer.write_u8(self.obj_type.into())?; // 0xb
         Ok(())
     }
+    // Serialized bytes as uppercase hex, the same way UE prints chunk ids
+    pub fn to_hex(&self) -> String {
+        let mut bytes = Cursor::new(vec![]);
+        self.to_buffer::<_, byteorder::NativeEndian>(&mut bytes).unwrap();
+        bytes.into_inner().iter().map(|b| format!("{:02X}", b)).collect()
+    }
     pub fn list_to_buffer<W: Write, E: byteorder::ByteOrder>(list: &Vec<IoChunkId>, writer: &mut W) -> Result<(), Box<dyn Error>> {
         for i in list {
             i.to_buffer::<W, E>(writer)?;
         }
         Ok(())
     }
-    #[allow(dead_code)]
-    pub fn from_buffer<R: Read + Seek, E: byteorder::ByteOrder>(reader: &mut R) -> Self {
-        let hash = reader.read_u64::<E>().unwrap();
-        let index = reader.read_u16::<E>().unwrap();
-        reader.seek(SeekFrom::Current(1)).unwrap();
-        let obj_type = IoChunkType4::from(reader.read_u8().unwrap());
-        Self { hash, index, obj_type }
+    pub fn from_buffer<R: Read + Seek, E: byteorder::ByteOrder>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+        let hash = reader.read_u64::<E>()?;
+        let index = reader.read_u16::<E>()?;
+        reader.seek(SeekFrom::Current(1))?;
+        let type_byte = reader.read_u8()?;
+        if !(1..=u8::from(IoChunkType4::ContainerHeader)).contains(&type_byte) {
+            return Err(format!("Invalid chunk type {type_byte}").into());
+        }
+        let obj_type = IoChunkType::UE4(IoChunkType4::from(type_byte)); // only UE4.27 TOCs are read
+        Ok(Self { hash, index, obj_type })
     }
 } 
 
+// Hex (see to_hex), so chunk ids in the game's log can be searched for in toc-maker's output
+impl fmt::Display for IoChunkId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
 // IO OFFSET + LENGTH
 #[derive(Debug, Copy, Clone)]
 #[repr(C)]
@@ -305,11 +482,11 @@ pub struct IoOffsetAndLength {
     data: [u8; 0xa]
 }
 
-pub const IO_OFFSET_LENGTH_MAX: u64 = 0xFFFFFFFFFF; // 5 bytes (~1.1 TB)
+pub const IO_OFFSET_LENGTH_MAX: u64 = 0xFFFFFFFFFF; // 5 bytes (1 TiB), for chunk offsets and ucas block offsets
 pub const IO_COMPRESSED_BLOCK_LENGTH_MAX: u32 = 0xFFFFFF; // 3 bytes (16.7 MB)
 
 impl IoOffsetAndLength {
-    // TODO: proper error handling for offset/length values above IO_OFFSET_LENGTH_MAX
+    // Values above IO_OFFSET_LENGTH_MAX are truncated, TocFactory::check_container_size makes sure a build never gets there
     pub fn new(offset: u64, length: u64) -> Self {
         type ByteBlock = Cursor<[u8; 0xa]>;
         let mut byte_builder = Cursor::new([0; 0xa]);
@@ -319,6 +496,16 @@ impl IoOffsetAndLength {
         byte_builder.write_all(length_comp);
         Self {data: byte_builder.into_inner()}
     }
+    pub fn get_offset(&self) -> u64 {
+        let mut offset = [0; 8];
+        offset[3..8].copy_from_slice(&self.data[0..5]);
+        u64::from_be_bytes(offset)
+    }
+    pub fn get_length(&self) -> u64 {
+        let mut length = [0; 8];
+        length[3..8].copy_from_slice(&self.data[5..10]);
+        u64::from_be_bytes(length)
+    }
     pub fn to_buffer<W: Write, E: byteorder::ByteOrder>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
         writer.write_all(self.data.as_slice())?;
         Ok(())
@@ -329,12 +516,19 @@ impl IoOffsetAndLength {
         }
         Ok(())
     }
+    pub fn from_buffer<R: Read, E: byteorder::ByteOrder>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+        let mut data = [0; 0xa];
+        reader.read_exact(&mut data)?;
+        Ok(Self { data })
+    }
 }
 
 // (UE 5 ONLY) Perfect Hash
 
 // IO Compression Blocks
-#[derive(Debug)]
+pub const COMPRESSED_BLOCK_SIZE_MAX: u32 = 0xFFFFFF; // compressed and uncompressed sizes are 3 bytes each
+
+#[derive(Debug, Clone)]
 #[repr(C)]
 pub struct IoStoreTocCompressedBlockEntry {
     data: [u8; 0xc] // 5 bytes offset, 3 bytes for size/uncompressed size, 1 byte for compression
@@ -343,17 +537,25 @@ pub struct IoStoreTocCompressedBlockEntry {
 
 impl IoStoreTocCompressedBlockEntry {
     pub fn new(offset: u64, length: u32, uncompressed_length: u32, compression_method: u8) -> Self {
-        type ByteBlock = Cursor<[u8; 0xc]>;
-        type E = byteorder::NativeEndian;
-        let mut byte_builder = Cursor::new([0; 0xc]);
-        byte_builder.write_u32::<E>(offset as u32).unwrap();
-        byte_builder.seek(SeekFrom::Current(1)); // 0x4
-        let cmp_size = &length.to_ne_bytes()[0..3];
-        byte_builder.write_all(cmp_size).unwrap(); // cmp_size
-        let decmp_size = &uncompressed_length.to_ne_bytes()[0..3];
-        byte_builder.write_all(decmp_size).unwrap(); // decmp_size
-        byte_builder.write_u8(compression_method).unwrap();
-        Self { data: byte_builder.into_inner() }
+        let mut data = [0; 0xc];
+        data[0..5].copy_from_slice(&offset.to_le_bytes()[..5]); // offset, 40 bits
+        data[5..8].copy_from_slice(&length.to_le_bytes()[..3]); // cmp_size
+        data[8..11].copy_from_slice(&uncompressed_length.to_le_bytes()[..3]); // decmp_size
+        data[11] = compression_method;
+        Self { data }
+    }
+    pub fn get_offset(&self) -> u64 {
+        u64::from_le_bytes([self.data[0], self.data[1], self.data[2], self.data[3], self.data[4], 0, 0, 0])
+    }
+    pub fn get_compressed_size(&self) -> u32 {
+        u32::from_le_bytes([self.data[5], self.data[6], self.data[7], 0])
+    }
+    pub fn get_uncompressed_size(&self) -> u32 {
+        u32::from_le_bytes([self.data[8], self.data[9], self.data[10], 0])
+    }
+    // index into the compression method table, plus one. 0 is an uncompressed block
+    pub fn get_compression_method(&self) -> u8 {
+        self.data[11]
     }
     pub fn to_buffer<W: Write, E: byteorder::ByteOrder>(&self, writer: &mut W) -> Result<(), Box<dyn Error>> {
         writer.write_all(self.data.as_slice())?;
@@ -365,6 +567,11 @@ impl IoStoreTocCompressedBlockEntry {
         }
         Ok(())
     }
+    pub fn from_buffer<R: Read, E: byteorder::ByteOrder>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+        let mut data = [0; 0xc];
+        reader.read_exact(&mut data)?;
+        Ok(Self { data })
+    }
 }
 
 // (usually, compression info and signature data would be included here, but we have no reason to
@@ -391,6 +598,15 @@ impl IoDirectoryIndexEntry {
         Ok(())
     }
 
+    pub fn from_buffer<R: Read, E: byteorder::ByteOrder>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            name: reader.read_u32::<E>()?,
+            first_child: reader.read_u32::<E>()?,
+            next_sibling: reader.read_u32::<E>()?,
+            first_file: reader.read_u32::<E>()?
+        })
+    }
+
     pub fn list_to_buffer<W: Write, E: byteorder::ByteOrder>(list: &Vec<IoDirectoryIndexEntry>, writer: &mut W) -> Result<(), Box<dyn Error>> {
         writer.write_u32::<E>(list.len() as u32)?;
         for i in list {
@@ -454,7 +670,7 @@ pub struct IoFileResource {
 // META (WIP)
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct IoStoreTocEntryMeta {
     hash: [u8; 0x20],
@@ -470,13 +686,12 @@ impl IoStoreTocEntryMeta {
     // hash for it, which is a very slow operation. If this is *required* for any game, a cache will be created to store previously calculated hashes
     // (I tested this on a blank UE project and had a serialization time of about 130 ms for 1 file)
     #[cfg(feature = "hash_meta")]
-    pub fn new_with_hash<R: std::io::Read>(mut source: &mut R) -> Self {
+    pub fn new_with_hash<R: std::io::Read>(source: &mut R) -> std::io::Result<Self> {
         let mut hasher = Sha1::new();
-        std::io::copy(&mut source, &mut hasher);
-        let mut data: Cursor<[u8; 0x20]> = Cursor::new([0; 0x20]);
-        data.write_all(&hasher.finalize().to_vec());
-        let hash = data.into_inner();
-        Self::new_inner(hash)
+        std::io::copy(source, &mut hasher)?;
+        let mut hash = [0; 0x20];
+        hash[..0x14].copy_from_slice(&hasher.finalize());
+        Ok(Self::new_inner(hash))
     }
     #[inline]
     fn new_inner(hash: [u8; 32]) -> Self {
@@ -495,6 +710,12 @@ impl IoStoreTocEntryMeta {
         }
         Ok(())
     }
+    pub fn from_buffer<R: Read, E: byteorder::ByteOrder>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+        let mut hash = [0; 0x20];
+        reader.read_exact(&mut hash)?;
+        let flags = reader.read_u8()?;
+        Ok(Self { hash, flags })
+    }
 }
 
 pub struct ContainerHeader {
@@ -506,7 +727,25 @@ impl ContainerHeader {
     pub fn new(container_id: u64) -> Self {
         Self { container_id, packages: vec![] }
     }
+    // Size of the serialized header. Store entries are always CONTAINER_HEADER_PACKAGE_SERIALIZED_SIZE since their import lists
+    // aren't written
+    pub fn get_serialized_size(&self) -> u64 {
+        // container id, package count, names, name hashes (+ algorithm id), package ids, store entries, culture map, redirects
+        let fixed_size = 8 + 4 + 4 + 4 + 8 + 4 + 4 + 4 + 4;
+        fixed_size + (8 + crate::io_package::CONTAINER_HEADER_PACKAGE_SERIALIZED_SIZE) * self.packages.len() as u64
+    }
+    // Write the container header into writer and also return a copy of the bytes, for when they're needed afterwards
+    // (meta hashing, encryption)
     pub fn to_buffer<W: Write, E: byteorder::ByteOrder>(&self, writer: &mut W) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut container_header_writer: Cursor<Vec<u8>> = Cursor::new(Vec::with_capacity(self.get_serialized_size() as usize));
+        self.to_stream::<_, E>(&mut container_header_writer)?;
+        let serialized = container_header_writer.into_inner();
+        writer.write_all(&serialized)?;
+        Ok(serialized)
+    }
+    // Serialize straight into writer without building the whole header in memory first, so that large package lists don't need a
+    // second copy. Returns the number of bytes written (always get_serialized_size)
+    pub fn to_stream<W: Write, E: byteorder::ByteOrder>(&self, writer: &mut W) -> Result<u64, Box<dyn Error>> {
         // Container Header:
         // - ContainerId + Package Name Count (number of ExportBundleData)
         // - Names array - empty
@@ -515,35 +754,43 @@ impl ContainerHeader {
         // - Store Entries - store entry data for each export bundle (import ids => graph package ids)
         // Culture Package Map - empty
         // Package Redirects - empty
-        // Padding to align it to nearest 0x10
-        let mut container_header_writer: Cursor<Vec<u8>> = Cursor::new(vec![]);
-        container_header_writer.write_u64::<E>(self.container_id)?;
-        container_header_writer.write_u32::<E>(self.packages.len() as u32)?;
+        writer.write_u64::<E>(self.container_id)?;
+        writer.write_u32::<E>(self.packages.len() as u32)?;
         // TODO: Implement TArray to_buffer
-        container_header_writer.write_u32::<E>(0)?; // TArray<u8> Names
-        container_header_writer.write_u32::<E>(8)?; // TArray<u8> NameHashes
-        container_header_writer.write_u64::<E>(crate::string::NAME_HASH_ALGORITHM)?;
-        container_header_writer.write_u32::<E>(self.packages.len() as u32)?; // TArray<FPackageId> PackageIds
+        writer.write_u32::<E>(0)?; // TArray<u8> Names
+        writer.write_u32::<E>(8)?; // TArray<u8> NameHashes
+        writer.write_u64::<E>(crate::string::NAME_HASH_ALGORITHM)?;
+        writer.write_u32::<E>(self.packages.len() as u32)?; // TArray<FPackageId> PackageIds
         for i in &self.packages {
-            container_header_writer.write_u64::<E>(i.hash)?;
+            writer.write_u64::<E>(i.hash)?;
         }
-        //println!("Written {} package ids into container header", self.packages.len());
         let import_list_base_offset = crate::io_package::CONTAINER_HEADER_PACKAGE_SERIALIZED_SIZE * self.packages.len() as u64; // TArray->data, len is written further down
         let mut import_list_already_written_offset = 0;
-        let mut store_entry_writer: Cursor<Vec<u8>> = Cursor::new(vec![]);
+        writer.write_u32::<E>(import_list_base_offset as u32)?; // TArray<u8> StoreEntries
+        // store entries seek around inside of themselves, so each one goes through a small buffer on the way
+        let mut store_entry_writer: Cursor<Vec<u8>> = Cursor::new(Vec::with_capacity(crate::io_package::CONTAINER_HEADER_PACKAGE_SERIALIZED_SIZE as usize));
         for i in &self.packages {
+            store_entry_writer.get_mut().clear();
+            store_entry_writer.set_position(0);
             i.to_buffer_store_entry::<Cursor<Vec<u8>>, E>(&mut store_entry_writer, import_list_base_offset, &mut import_list_already_written_offset)?;
+            writer.write_all(store_entry_writer.get_ref())?;
         }
-        let store_entry_writer = store_entry_writer.into_inner();
-        container_header_writer.write_u32::<E>(store_entry_writer.len() as u32)?;
-        container_header_writer.write_all(&store_entry_writer);
-        container_header_writer.write_u32::<E>(0)?; // CulturePackageMap
-        container_header_writer.write_u32::<E>(0)?; // PackageRedirectss
-        let serialized = container_header_writer.into_inner();
-        writer.write_all(&serialized); // Write into main buffer, then align to the nearest 0x10
-        //PartitionSerializer::new(0x10).to_buffer_alignment::<W, E>(writer);
-        //writer.seek(SeekFrom::Current(-1));
-        //writer.write(&[0x0])?;
-        Ok(serialized)
+        writer.write_u32::<E>(0)?; // CulturePackageMap
+        writer.write_u32::<E>(0)?; // PackageRedirectss
+        Ok(self.get_serialized_size())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_offset_keeps_all_40_bits() {
+        let offset = 3 * 0x8000_0000u64 + 0x1234; // in _s3.ucas with 2 GiB partitions
+        let block = IoStoreTocCompressedBlockEntry::new(offset, 0x1000, 0x2000, 1);
+        assert_eq!(block.get_offset(), offset);
+        assert_eq!(block.get_compressed_size(), 0x1000);
+        assert_eq!(block.get_uncompressed_size(), 0x2000);
+        assert_eq!(block.get_compression_method(), 1);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,36 @@
+// toc-maker as a library, for tools that want to build containers without running the binary. TocFactoryBuilder is the place
+// to start, the io_toc types and TocReader are there for reading back what was written
+
+pub mod asset_collector;
+pub mod toc_factory;
+pub mod io_package;
+pub mod io_toc;
+pub mod string;
+pub mod platform;
+pub mod alignment;
+pub mod error;
+pub mod toc_reader;
+pub mod toc_verify;
+pub mod toc_extract;
+pub mod bundle;
+pub mod self_test;
+mod toc_update;
+mod block_table;
+mod block_cache;
+mod toc_ignore;
+#[cfg(test)]
+mod test_fixture;
+pub mod toc_meta;
+#[cfg(feature = "aes")]
+pub mod encryption;
+#[cfg(feature = "json")]
+pub mod build_summary;
+#[cfg(feature = "async")]
+mod toc_async;
+#[cfg(feature = "loose")]
+pub mod loose_pak;
+
+pub use asset_collector::{AssetCollector, AssetSource, ChunkTypeRegistry, SkipReason};
+pub use error::TocError;
+pub use toc_factory::{FlattenedToc, TocFactory, TocFactoryBuilder};
+pub use toc_reader::TocReader;
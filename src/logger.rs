@@ -0,0 +1,66 @@
+use std::{fs::File, io::{self, LineWriter, Write}, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Mutex}};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+// Simple logger for the command line. Info and below are printed as-is to stdout (same as the output before the log crate was
+// used), warnings and errors go to stderr. When stdout has the ucas in it, everything goes to stderr.
+// With --log, every message (down to trace, whatever the console shows) is also written to a file, one line at a time so the
+// file has everything up to the point a build died
+struct ConsoleLogger;
+
+static ALL_TO_STDERR: AtomicBool = AtomicBool::new(false);
+static CONSOLE_LEVEL: AtomicUsize = AtomicUsize::new(0); // LevelFilter as usize, since log::max_level is raised for the file
+static LOG_FILE: Mutex<Option<LineWriter<File>>> = Mutex::new(None);
+
+impl Log for ConsoleLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Some(log_file) = LOG_FILE.lock().unwrap().as_mut() {
+            // losing the log file isn't worth failing the build over
+            let _ = writeln!(log_file, "{:<5} {}", record.level(), record.args());
+        }
+        if record.level() as usize > CONSOLE_LEVEL.load(Ordering::Relaxed) {
+            return;
+        }
+        match record.level() {
+            Level::Error | Level::Warn => eprintln!("{}: {}", record.level(), record.args()),
+            _ if ALL_TO_STDERR.load(Ordering::Relaxed) => eprintln!("{}", record.args()),
+            _ => println!("{}", record.args())
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(log_file) = LOG_FILE.lock().unwrap().as_mut() {
+            let _ = log_file.flush();
+        }
+    }
+}
+
+static LOGGER: ConsoleLogger = ConsoleLogger;
+
+pub fn init(level: LevelFilter, all_to_stderr: bool, log_path: Option<&str>) -> io::Result<()> {
+    ALL_TO_STDERR.store(all_to_stderr, Ordering::Relaxed);
+    CONSOLE_LEVEL.store(level as usize, Ordering::Relaxed);
+    if let Some(log_path) = log_path {
+        let log_file = File::create(log_path).map_err(|e| io::Error::new(e.kind(), format!("Couldn't create log file \"{log_path}\": {e}")))?;
+        *LOG_FILE.lock().unwrap() = Some(LineWriter::new(log_file));
+    }
+    // only fails if a logger was already set
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(if log_path.is_some() { LevelFilter::Trace } else { level });
+    }
+    Ok(())
+}
+
+// For the error a build ends with, which main prints itself rather than logging
+pub fn write_to_file(message: &str) {
+    if let Some(log_file) = LOG_FILE.lock().unwrap().as_mut() {
+        let _ = writeln!(log_file, "{:<5} {}", Level::Error, message);
+    }
+}
@@ -0,0 +1,252 @@
+use std::{collections::BTreeMap, io::{self, Write}};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use sha1::{Digest, Sha1};
+
+use crate::string::{FString32NoHash, FStringSerializer, FStringSerializerExpectedLength};
+
+// Standard (non-IoStore) pak files, for mounting the same files loose next to a container. Written as UE4.27 writes them
+// (PakFile_Version_Fnv64BugFix), without compression or encryption since it's only meant for comparing against the container.
+// Layout:
+//      for each file: entry header, then the file's bytes
+//      primary index: mount point, entry count, path hash seed, where the two indices below are, then every entry
+//      path hash index: FNV64 hash of each path -> entry, plus an (empty) pruned directory index
+//      full directory index: each directory -> its files -> entry
+//      footer (FPakInfo)
+pub const PAK_FILE_MAGIC: u32 = 0x5A6F12E1;
+pub const PAK_FILE_VERSION: i32 = 11; // PakFile_Version_Fnv64BugFix, the newest version UE4.27 writes
+const COMPRESSION_METHOD_NAME_COUNT: usize = 5; // names in the footer, all empty since nothing is compressed
+const COMPRESSION_METHOD_NAME_LENGTH: usize = 32;
+
+// One file's FPakEntry. The same entry is written in front of the file's data and in the index, except that the one in front of
+// the data always has an offset of 0 (UnrealPak only fills it in for the index)
+struct PakEntry {
+    offset: u64, // of the entry header, the data starts right after it
+    size: u64,
+    hash: [u8; 20], // SHA1 of the data
+}
+
+impl PakEntry {
+    const SERIALIZED_SIZE: u64 = 53;
+
+    fn to_buffer<W: Write>(&self, offset: u64, writer: &mut W) -> io::Result<()> {
+        writer.write_u64::<LittleEndian>(offset)?;
+        writer.write_u64::<LittleEndian>(self.size)?; // size in the pak
+        writer.write_u64::<LittleEndian>(self.size)?; // uncompressed size
+        writer.write_u32::<LittleEndian>(0)?; // compression method index, 0 is none so there's no block list
+        writer.write_all(&self.hash)?;
+        writer.write_u8(0)?; // flags (encrypted, deleted)
+        writer.write_u32::<LittleEndian>(0)?; // compression block size
+        Ok(())
+    }
+}
+
+// Writes a pak one file at a time, keeping only the entries in memory. Paths are relative to the mount point, with / between
+// folders (P3R/Content/Foo.uasset)
+pub struct PakWriter<'a, W: Write> {
+    writer: &'a mut W,
+    mount_point: String,
+    offset: u64,
+    entries: Vec<(String, PakEntry)>,
+}
+
+impl<'a, W: Write> PakWriter<'a, W> {
+    pub fn new(writer: &'a mut W, mount_point: &str) -> Self {
+        Self { writer, mount_point: mount_point.to_string(), offset: 0, entries: vec![] }
+    }
+
+    pub fn add_file(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        let entry = PakEntry { offset: self.offset, size: data.len() as u64, hash: Sha1::digest(data).into() };
+        entry.to_buffer(0, self.writer)?;
+        self.writer.write_all(data)?;
+        self.offset += PakEntry::SERIALIZED_SIZE + data.len() as u64;
+        self.entries.push((path.trim_start_matches('/').to_string(), entry));
+        Ok(())
+    }
+
+    // Write the indices and footer after the files, returning the size of the whole pak
+    pub fn finish(self) -> io::Result<u64> {
+        // the seed is read back from the index, so any value works. UnrealPak uses the CRC of the pak's name
+        let path_hash_seed = 0;
+        let mut path_hash_index = vec![];
+        path_hash_index.write_i32::<LittleEndian>(self.entries.len() as i32)?;
+        for (index, (path, _)) in self.entries.iter().enumerate() {
+            path_hash_index.write_u64::<LittleEndian>(get_path_hash(path, path_hash_seed))?;
+            path_hash_index.write_i32::<LittleEndian>(get_list_location(index))?;
+        }
+        path_hash_index.write_i32::<LittleEndian>(0)?; // pruned directory index, only needed when the full one is left out
+
+        let full_directory_index = self.get_full_directory_index()?;
+        // the primary index comes first, then the other two, like UnrealPak lays them out
+        let primary_index_offset = self.offset;
+        let primary_index_size = FString32NoHash::get_expected_length(&self.mount_point) + 4 + 8 + 2 * (4 + 8 + 8 + 20) + 4 + 4
+            + self.entries.len() as u64 * PakEntry::SERIALIZED_SIZE;
+        let path_hash_index_offset = primary_index_offset + primary_index_size;
+        let full_directory_index_offset = path_hash_index_offset + path_hash_index.len() as u64;
+
+        let mut primary_index = vec![];
+        FString32NoHash::to_buffer::<_, LittleEndian>(&self.mount_point, &mut primary_index).unwrap();
+        primary_index.write_i32::<LittleEndian>(self.entries.len() as i32)?;
+        primary_index.write_u64::<LittleEndian>(path_hash_seed)?;
+        for (offset, index) in [(path_hash_index_offset, &path_hash_index), (full_directory_index_offset, &full_directory_index)] {
+            primary_index.write_u32::<LittleEndian>(1)?; // bool, it's there
+            primary_index.write_u64::<LittleEndian>(offset)?;
+            primary_index.write_u64::<LittleEndian>(index.len() as u64)?;
+            primary_index.write_all(&Sha1::digest(index))?;
+        }
+        primary_index.write_i32::<LittleEndian>(0)?; // encoded entries (bytes), every entry goes in the plain list below instead
+        primary_index.write_i32::<LittleEndian>(self.entries.len() as i32)?;
+        for (_, entry) in &self.entries {
+            entry.to_buffer(entry.offset, &mut primary_index)?;
+        }
+        debug_assert_eq!(primary_index.len() as u64, primary_index_size);
+
+        self.writer.write_all(&primary_index)?;
+        self.writer.write_all(&path_hash_index)?;
+        self.writer.write_all(&full_directory_index)?;
+        // footer
+        self.writer.write_u128::<LittleEndian>(0)?; // encryption key guid
+        self.writer.write_u8(0)?; // index isn't encrypted
+        self.writer.write_u32::<LittleEndian>(PAK_FILE_MAGIC)?;
+        self.writer.write_i32::<LittleEndian>(PAK_FILE_VERSION)?;
+        self.writer.write_u64::<LittleEndian>(primary_index_offset)?;
+        self.writer.write_u64::<LittleEndian>(primary_index_size)?;
+        self.writer.write_all(&Sha1::digest(&primary_index))?;
+        self.writer.write_all(&[0; COMPRESSION_METHOD_NAME_COUNT * COMPRESSION_METHOD_NAME_LENGTH])?;
+        Ok(full_directory_index_offset + full_directory_index.len() as u64 + get_footer_size())
+    }
+
+    // Every folder that has a file somewhere under it, including the mount point itself ("/"), mapped to the files directly inside
+    // of it. Folders end in a / like UnrealPak writes them
+    fn get_full_directory_index(&self) -> io::Result<Vec<u8>> {
+        let mut directories: BTreeMap<String, Vec<(&str, i32)>> = BTreeMap::new();
+        for (index, (path, _)) in self.entries.iter().enumerate() {
+            let (directory, name) = match path.rsplit_once('/') {
+                Some((directory, name)) => (format!("{directory}/"), name),
+                None => ("/".to_string(), path.as_str())
+            };
+            let mut parent = directory.as_str();
+            while let Some((ancestor, _)) = parent.trim_end_matches('/').rsplit_once('/') {
+                parent = &directory[..ancestor.len() + 1];
+                directories.entry(parent.to_string()).or_default();
+            }
+            directories.entry("/".to_string()).or_default();
+            directories.entry(directory).or_default().push((name, get_list_location(index)));
+        }
+        let mut index = vec![];
+        index.write_i32::<LittleEndian>(directories.len() as i32)?;
+        for (directory, files) in &directories {
+            FString32NoHash::to_buffer::<_, LittleEndian>(directory, &mut index).unwrap();
+            index.write_i32::<LittleEndian>(files.len() as i32)?;
+            for (name, location) in files {
+                FString32NoHash::to_buffer::<_, LittleEndian>(name, &mut index).unwrap();
+                index.write_i32::<LittleEndian>(*location)?;
+            }
+        }
+        Ok(index)
+    }
+}
+
+// Size of the FPakInfo at the end of the file
+pub fn get_footer_size() -> u64 {
+    16 + 1 + 4 + 4 + 8 + 8 + 20 + (COMPRESSION_METHOD_NAME_COUNT * COMPRESSION_METHOD_NAME_LENGTH) as u64
+}
+
+// FPakFile::HashPath: FNV64 of the path in lower case, as UTF-16. UE's FNV adds the seed to the offset basis instead of using it
+// as the basis
+fn get_path_hash(path: &str, seed: u64) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64.wrapping_add(seed);
+    for c in path.to_lowercase().encode_utf16() {
+        for byte in c.to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x00000100000001b3);
+        }
+    }
+    hash
+}
+
+// FPakEntryLocation for an entry in the primary index's plain list. Locations from 0 up are offsets into the encoded entries
+fn get_list_location(index: usize) -> i32 {
+    -(index as i32) - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ReadBytesExt;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use crate::string::FStringDeserializer;
+
+    #[test]
+    fn files_can_be_found_through_the_index() {
+        let files = [("P3R/Content/A.uasset", vec![1u8; 0x20]), ("P3R/Content/Sub/B.ubulk", (0..0x123).map(|i| i as u8).collect())];
+        let mut pak = vec![];
+        let mut writer = PakWriter::new(&mut pak, "../../../");
+        for (path, data) in &files {
+            writer.add_file(path, data).unwrap();
+        }
+        let size = writer.finish().unwrap();
+        assert_eq!(size, pak.len() as u64);
+
+        let mut reader = Cursor::new(&pak);
+        reader.seek(SeekFrom::End(-(get_footer_size() as i64) + 17)).unwrap();
+        assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), PAK_FILE_MAGIC);
+        assert_eq!(reader.read_i32::<LittleEndian>().unwrap(), PAK_FILE_VERSION);
+        let (index_offset, index_size) = (reader.read_u64::<LittleEndian>().unwrap(), reader.read_u64::<LittleEndian>().unwrap());
+        let mut index_hash = [0; 20];
+        reader.read_exact(&mut index_hash).unwrap();
+        let primary_index = &pak[index_offset as usize..(index_offset + index_size) as usize];
+        assert_eq!(index_hash, <[u8; 20]>::from(Sha1::digest(primary_index)));
+
+        let mut index = Cursor::new(primary_index);
+        assert_eq!(FString32NoHash::from_buffer::<_, LittleEndian>(&mut index).unwrap().as_deref(), Some("../../../"));
+        assert_eq!(index.read_i32::<LittleEndian>().unwrap(), files.len() as i32);
+        let seed = index.read_u64::<LittleEndian>().unwrap();
+        // path hash index
+        assert_eq!(index.read_u32::<LittleEndian>().unwrap(), 1);
+        let path_hash_offset = index.read_u64::<LittleEndian>().unwrap() as usize;
+        index.seek(SeekFrom::Current(8 + 20)).unwrap();
+        let mut path_hashes = Cursor::new(&pak[path_hash_offset..]);
+        assert_eq!(path_hashes.read_i32::<LittleEndian>().unwrap(), files.len() as i32);
+        let mut locations = vec![];
+        for (path, _) in &files {
+            assert_eq!(path_hashes.read_u64::<LittleEndian>().unwrap(), get_path_hash(&path.to_ascii_uppercase(), seed));
+            locations.push(path_hashes.read_i32::<LittleEndian>().unwrap());
+        }
+        // full directory index and encoded entries
+        assert_eq!(index.read_u32::<LittleEndian>().unwrap(), 1);
+        index.seek(SeekFrom::Current(8 + 8 + 20)).unwrap();
+        assert_eq!(index.read_i32::<LittleEndian>().unwrap(), 0);
+        assert_eq!(index.read_i32::<LittleEndian>().unwrap(), files.len() as i32);
+
+        let mut entries = vec![];
+        for _ in &files {
+            let offset = index.read_u64::<LittleEndian>().unwrap();
+            let size = index.read_u64::<LittleEndian>().unwrap();
+            index.seek(SeekFrom::Current(8 + 4 + 20 + 1 + 4)).unwrap();
+            entries.push((offset, size));
+        }
+        for ((_, data), location) in files.iter().zip(locations) {
+            let (offset, size) = entries[(-location - 1) as usize];
+            let start = (offset + PakEntry::SERIALIZED_SIZE) as usize;
+            assert_eq!(&pak[start..start + size as usize], data.as_slice());
+        }
+    }
+
+    #[test]
+    fn directory_index_has_every_parent() {
+        let mut pak = vec![];
+        let mut writer = PakWriter::new(&mut pak, "../../../");
+        writer.add_file("P3R/Content/Sub/B.ubulk", &[1]).unwrap();
+        let mut index = Cursor::new(writer.get_full_directory_index().unwrap());
+        let mut directories = vec![];
+        for _ in 0..index.read_i32::<LittleEndian>().unwrap() {
+            directories.push(FString32NoHash::from_buffer::<_, LittleEndian>(&mut index).unwrap().unwrap());
+            for _ in 0..index.read_i32::<LittleEndian>().unwrap() {
+                FString32NoHash::from_buffer::<_, LittleEndian>(&mut index).unwrap();
+                index.read_i32::<LittleEndian>().unwrap();
+            }
+        }
+        assert_eq!(directories, ["/", "P3R/", "P3R/Content/", "P3R/Content/Sub/"]);
+    }
+}
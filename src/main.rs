@@ -1,48 +1,366 @@
-use std::{env, error::Error, fs::File, io::Write, process};
-
-mod asset_collector;
-mod toc_factory;
-mod io_package;
-mod io_toc;
-mod string;
-mod platform;
-mod alignment;
+use std::{env, error::Error, fs::{self, File}, io::{self, BufReader, Write}, path::Path, process};
+
+// Command line shim over the library in lib.rs
 mod config;
+mod temp_output;
+mod logger;
 
-use config::Config;
-use toc_factory::TocFactory;
+use config::{Command, Config, ExtractConfig, IdConfig, UnpackConfig, VerifyConfig};
+use temp_output::TempOutputs;
+use toc_maker::{bundle, self_test, AssetCollector, toc_extract::ContainerExtractor, toc_factory::{self, TocFactoryBuilder, DEFAULT_ZLIB_LEVEL}, toc_verify, ChunkTypeRegistry, TocReader};
 
 fn main() {
-    let config = Config::new(env::args()).unwrap_or_else(|err| {
+    let command = Command::new(env::args()).unwrap_or_else(|err| {
         eprintln!("{}", err);
         eprintln!("{}", Config::usage());
         process::exit(1);
     });
 
-    if let Err(e) = execute(config) {
+    let result = match command {
+        Command::Build(config) => execute(config),
+        Command::Verify(config) => verify(config),
+        Command::Unpack(config) => unpack(config),
+        Command::Extract(config) => extract(config),
+        Command::Id(config) => id(config),
+        Command::SelfTest => selftest(),
+    };
+    if let Err(e) = result {
+        logger::write_to_file(&format!("Application error: {}", e));
         eprintln!("Application error: {}", e);
         process::exit(1);
     }
 }
 
-fn execute(config: Config) -> Result<(), Box<dyn Error>> {
+fn verify(config: VerifyConfig) -> Result<(), Box<dyn Error>> {
+    type EN = byteorder::NativeEndian;
+    let generated = TocReader::from_buffer::<_, EN>(&mut BufReader::new(File::open(&config.generated_path)?))?;
+    let reference = TocReader::from_buffer::<_, EN>(&mut BufReader::new(File::open(&config.reference_path)?))?;
+    match toc_verify::verify_toc(&generated, &reference, config.ignore_order) {
+        Some(divergence) => Err(divergence.to_string().into()),
+        None => {
+            println!("{} matches {}", config.generated_path, config.reference_path);
+            Ok(())
+        }
+    }
+}
+
+fn unpack(config: UnpackConfig) -> Result<(), Box<dyn Error>> {
+    let out_folder = match config.out_folder.as_ref() {
+        Some(out_folder) => Path::new(out_folder),
+        None => Path::new(&config.bundle_path).parent().unwrap_or(Path::new("."))
+    };
+    let mut reader = BufReader::new(File::open(&config.bundle_path)?);
+    for path in bundle::unpack(&mut reader, out_folder)? {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+fn id(config: IdConfig) -> Result<(), Box<dyn Error>> {
+    for virtual_path in &config.virtual_paths {
+        let normalized = virtual_path.replace('\\', "/");
+        let chunk_id = ChunkTypeRegistry::split_file_name(&normalized).and_then(|(stem, extension)| toc_factory::chunk_id_for(stem, extension))
+            .ok_or_else(|| format!("{virtual_path} has no chunk id, it needs an extension that's packaged and to be laid out like the game's content (P3R/Content/...)"))?;
+        println!("{chunk_id} {virtual_path}");
+    }
+    Ok(())
+}
+
+fn selftest() -> Result<(), Box<dyn Error>> {
+    let checked = self_test::run().map_err(|e| format!("Self test failed: {e}"))?;
+    for line in checked {
+        println!("{line}");
+    }
+    println!("Self test passed (toc-maker {})", env!("CARGO_PKG_VERSION"));
+    Ok(())
+}
+
+fn extract(config: ExtractConfig) -> Result<(), Box<dyn Error>> {
+    type EN = byteorder::NativeEndian;
+    let out_folder = config.out_folder.clone().unwrap_or_else(|| config.container_stem.clone());
+    let toc = TocReader::from_buffer::<_, EN>(&mut BufReader::new(File::open(config.container_stem.clone() + ".utoc")?))?;
+    // same names write_files_partitioned is given: Mod.ucas, Mod_s1.ucas, ...
+    let mut partitions = vec![];
+    for index in 0..toc.header.get_partition_count().max(1) {
+        let path = if index == 0 { format!("{}.ucas", config.container_stem) } else { format!("{}_s{index}.ucas", config.container_stem) };
+        partitions.push(BufReader::new(File::open(&path).map_err(|e| format!("Couldn't open {path}: {e}"))?));
+    }
     #[allow(unused_mut)]
-    let mut factory = TocFactory::new(config.inpath);
+    let mut extractor = ContainerExtractor::new(toc, partitions);
+    #[cfg(feature = "aes")]
+    if let Some(aes_key) = config.aes_key {
+        extractor.set_aes_key(aes_key);
+    }
+    for path in extractor.extract_to::<EN>(Path::new(&out_folder))? {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+// Builder for whichever kind of input the input path is
+fn source_builder(config: &Config) -> TocFactoryBuilder {
+    #[cfg(feature = "zip")]
+    if config.use_zip {
+        return TocFactoryBuilder::from_zip(config.inpath.clone());
+    }
+    if config.use_manifest { TocFactoryBuilder::from_manifest(config.inpath.clone()) } else { TocFactoryBuilder::new(config.inpath.clone()) }
+}
+
+fn execute(config: Config) -> Result<(), Box<dyn Error>> {
+    logger::init(config.log_level, config.ucas_to_stdout, config.log_path.as_deref())?;
+    log_settings(&config);
+    if config.split_by_subfolder {
+        for config in split_by_subfolder(&config)? {
+            log::info!("Building {} from {}", config.outpath, config.inpath);
+            build(config)?;
+        }
+        return Ok(());
+    }
+    build(config)
+}
+
+// One build for each folder directly inside the input folder, with that folder as the input. Each is written to
+// <output folder>/<folder name> and named after its folder, so every container gets its own container id. Hidden folders (.git)
+// are left out unless --include-hidden is given, the same as they would be when collecting
+fn split_by_subfolder(config: &Config) -> Result<Vec<Config>, Box<dyn Error>> {
+    let mut names = vec![];
+    for entry in fs::read_dir(&config.inpath).map_err(|e| format!("Couldn't read input folder \"{}\": {}", config.inpath, e))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let name = entry.file_name().into_string().map_err(|name| format!("Folder name isn't valid UTF-8: {}", name.to_string_lossy()))?;
+        if config.include_hidden || !AssetCollector::is_hidden_name(&name) {
+            names.push(name);
+        }
+    }
+    if names.is_empty() {
+        return Err(format!("There are no folders in {} to build containers from", config.inpath).into());
+    }
+    // always in the same order, whatever order the filesystem lists them in
+    names.sort();
+    Ok(names.into_iter().map(|name| Config {
+        inpath: Path::new(&config.inpath).join(&name).to_str().unwrap().to_string(),
+        outpath: Path::new(&config.outpath).join(&name).to_str().unwrap().to_string(),
+        container_name: Some(name),
+        split_by_subfolder: false,
+        ..config.clone()
+    }).collect())
+}
+
+fn build(config: Config) -> Result<(), Box<dyn Error>> {
+    // before anything is collected, so --no-clobber fails straight away. --tree and --estimate don't write anything
+    if !(config.update_existing || config.print_tree || config.estimate) {
+        check_existing_outputs(&config)?;
+    }
+    let mut builder = source_builder(&config);
     if config.use_zlib {
-        factory.use_zlib_compression();
+        builder = builder.zlib(config.zlib_level.unwrap_or(DEFAULT_ZLIB_LEVEL));
+    }
+    if let Some(compressed_extensions) = config.compressed_extensions {
+        builder = builder.compressed_extensions(compressed_extensions);
+    }
+    if let Some(single_block_extensions) = config.single_block_extensions {
+        builder = builder.single_block_extensions(single_block_extensions);
+    }
+    if let Some(cache_dir) = config.cache_dir.as_ref() {
+        builder = builder.block_cache(cache_dir);
+    }
+    if let Some(compression_name) = config.compression_name.as_ref() {
+        builder = builder.compression_method_name(compression_name);
     }
-    if config.hash_metadata {
-        factory.include_metadata_hashes();
+    builder = builder.meta_hashing(config.hash_metadata).prune_empty(config.prune_empty).allow_empty(config.allow_empty).strict(config.strict)
+        .follow_symlinks(config.follow_symlinks).include_hidden(config.include_hidden).sorted(config.deterministic).check_bulk_siblings(config.check_bulk_siblings)
+        .file_size_range(config.min_file_size..=config.max_file_size).block_table_on_disk(config.block_table_on_disk).profile_files(config.profile_files).sort_order(config.sort_order)
+        .container_header(config.container_header).ue_version(config.ue_version).endianness(config.endianness);
+    #[cfg(feature = "aes")]
+    if let Some(aes_key) = config.aes_key {
+        builder = builder.encryption_key(aes_key, config.aes_key_guid);
     }
-    let mut utoc_stream = File::create(config.outpath.clone() + ".utoc")?;
-    let mut ucas_stream = File::create(config.outpath.clone() + ".ucas")?;
-    factory.write_files(&mut utoc_stream, &mut ucas_stream)?;
+    if let Some(expected_files) = config.expected_files {
+        builder = builder.expected_files(expected_files);
+    }
+    if let Some(container_name) = config.container_name.as_ref() {
+        builder = builder.container_name(container_name);
+    }
+    if let Some(block_size) = config.block_size {
+        builder = builder.block_size(block_size);
+    }
+    if let Some(mount_point) = config.mount_point.as_ref() {
+        builder = builder.mount_point(mount_point);
+    }
+    if let Some(root_prefix) = config.root_prefix.as_ref() {
+        builder = builder.root_prefix(root_prefix);
+    }
+    if let Some(partition_size) = config.partition_size {
+        builder = builder.partition_size(partition_size);
+    }
+    #[cfg(feature = "json")]
+    if let Some(summary_path) = config.summary_path {
+        builder = builder.json_summary(summary_path);
+    }
+    #[cfg(feature = "block_crc")]
+    if let Some(block_crc_path) = config.block_crc_path {
+        builder = builder.block_crcs(block_crc_path);
+    }
+    let mut factory = builder.build()?;
+    if config.auto_block_size && config.block_size.is_none() {
+        let block_size = factory.use_auto_block_size()?;
+        log::info!("Using a compression block size of {:#x} ({}K), picked from the sizes of the input files", block_size, block_size / 1024);
+    }
+    if config.print_tree {
+        factory.write_tree(&mut std::io::stdout().lock())?;
+        return Ok(());
+    }
+    if config.estimate {
+        let estimate = factory.estimate()?;
+        println!("{} files, {} bytes", estimate.file_count, estimate.input_size);
+        println!("Estimated ucas size: {} bytes ({:.1}% of the input), from compressing {} bytes", estimate.ucas_size,
+            estimate.ucas_size as f64 * 100.0 / estimate.input_size.max(1) as f64, estimate.sampled_size);
+        println!("Estimated compression time: {:.1}s", estimate.compress_time.as_secs_f64());
+        return Ok(());
+    }
+    prepare_output_folder(&config.outpath)?;
+    factory.check_output_path(&config.outpath)?;
+    if config.update_existing {
+        let report = factory.update_existing(&(config.outpath.clone() + ".utoc"), &(config.outpath + ".ucas"))?;
+        log::info!("ucas is now {} bytes", report.ucas_size);
+        return Ok(());
+    }
+    // everything is written to .tmp files first, then renamed once the whole container has been written successfully
+    let mut outputs = TempOutputs::new();
+    #[cfg(feature = "checksums")]
+    if config.checksums_path.is_some() {
+        outputs.hash_outputs();
+    }
+    // before the container, which takes the factory
+    #[cfg(feature = "loose")]
+    if config.loose_pak {
+        let mut loose_stream = io::BufWriter::new(outputs.create(&(config.outpath.clone() + LOOSE_PAK_SUFFIX))?);
+        let pak_size = factory.write_loose_pak(&mut loose_stream)?;
+        loose_stream.flush()?;
+        log::info!("Loose pak is {} bytes", pak_size);
+    }
+    {
+        let mut utoc_stream = outputs.create(&(config.outpath.clone() + ".utoc"))?;
+        let report = if config.ucas_to_stdout {
+            let mut ucas_stream = io::BufWriter::new(io::stdout().lock());
+            let report = factory.write_files(&mut utoc_stream, &mut ucas_stream)?;
+            ucas_stream.flush()?;
+            report
+        } else {
+            let mut ucas_stream = outputs.create(&(config.outpath.clone() + ".ucas"))?;
+            factory.write_files_partitioned(&mut utoc_stream, &mut ucas_stream, |index| outputs.create(&format!("{}_s{index}.ucas", config.outpath)))?
+        };
+        log::info!("ucas is {} bytes in {} partition(s)", report.ucas_size, report.partition_count);
+
+        let mut pak_stream = outputs.create(&(config.outpath.clone() + ".pak"))?;
+        pak_stream.write(&PAKFILE)?;
+    }
+    #[cfg(feature = "checksums")]
+    if let Some(checksums_path) = config.checksums_path.as_ref() {
+        outputs.write_checksums(checksums_path)?;
+    }
+    if config.bundle {
+        outputs.commit_as_bundle(&(config.outpath + bundle::BUNDLE_EXTENSION))?;
+    } else {
+        outputs.commit()?;
+    }
+    Ok(())
+}
 
-    let mut pak_stream = File::create(config.outpath + ".pak")?;
-    pak_stream.write(&PAKFILE)?;
+// What the build was asked to do, so a log (see --log) can be read without the command line that made it. Only shown on the
+// console with -v. The AES key itself is left out
+fn log_settings(config: &Config) {
+    log::debug!("toc-maker {}", env!("CARGO_PKG_VERSION"));
+    log::debug!("Input: {}{}", config.inpath, if config.use_manifest { " (manifest)" } else { "" });
+    log::debug!("Output: {}", config.outpath);
+    match (config.use_zlib, config.compressed_extensions.as_ref()) {
+        (false, _) => log::debug!("Compression: none"),
+        (true, None) => log::debug!("Compression: zlib level {}", config.zlib_level.unwrap_or(DEFAULT_ZLIB_LEVEL)),
+        (true, Some(extensions)) => log::debug!("Compression: zlib level {}, only {}", config.zlib_level.unwrap_or(DEFAULT_ZLIB_LEVEL), extensions.join(",")),
+    }
+    match config.block_size {
+        Some(block_size) => log::debug!("Block size: {:#x}", block_size),
+        None if config.auto_block_size => log::debug!("Block size: auto"),
+        None => log::debug!("Block size: default"),
+    }
+    log::debug!("Container name: {}", config.container_name.as_deref().unwrap_or(toc_maker::toc_factory::DEFAULT_CONTAINER_NAME));
+    log::debug!("Mount point: {}", config.mount_point.as_deref().unwrap_or(toc_maker::toc_factory::DEFAULT_MOUNT_POINT));
+    if let Some(root_prefix) = config.root_prefix.as_ref() {
+        log::debug!("Root prefix: {}", root_prefix);
+    }
+    if let Some(extensions) = config.single_block_extensions.as_ref() {
+        log::debug!("Stored in a single block: {}", extensions.join(","));
+    }
+    log::debug!("UE version: {:?}, sort: {:?}, endianness: {:?}", config.ue_version, config.sort_order, config.endianness);
+    log::debug!("File sizes: {} to {} bytes", config.min_file_size, config.max_file_size);
+    log::debug!("Container header: {}, meta hashes: {}, prune empty: {}, follow symlinks: {}, include hidden: {}, deterministic: {}, check bulk: {}, strict: {}",
+        config.container_header, config.hash_metadata, config.prune_empty, config.follow_symlinks, config.include_hidden, config.deterministic,
+        config.check_bulk_siblings, config.strict);
+    if let Some(partition_size) = config.partition_size {
+        log::debug!("Partition size: {} bytes", partition_size);
+    }
+    #[cfg(feature = "aes")]
+    if config.aes_key.is_some() {
+        log::debug!("Encrypted, key guid {:032x}", config.aes_key_guid);
+    }
+    if config.update_existing {
+        log::debug!("Updating the existing container");
+    }
+    if config.split_by_subfolder {
+        log::debug!("One container per folder in the input");
+    }
+}
+
+// Create the folder the output goes in and make sure it can be written to, before spending any time collecting and compressing
+fn prepare_output_folder(output_stem: &str) -> Result<(), Box<dyn Error>> {
+    let output_folder = match Path::new(output_stem).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new(".")
+    };
+    fs::create_dir_all(output_folder)
+        .map_err(|e| format!("Couldn't create output folder \"{}\": {}", output_folder.display(), e))?;
+    let probe_path = format!("{output_stem}.probe.tmp");
+    File::create(&probe_path).map_err(|e| format!("Can't write to output folder \"{}\": {}", output_folder.display(), e))?;
+    fs::remove_file(&probe_path)?;
     Ok(())
 }
 
+// Outputs from an earlier build at the same path. They're only replaced once the new container has been written in full (see
+// TempOutputs), and with --no-clobber the build stops before anything is written
+fn check_existing_outputs(config: &Config) -> Result<(), Box<dyn Error>> {
+    let mut outputs = vec![];
+    if config.bundle {
+        outputs.push(config.outpath.clone() + bundle::BUNDLE_EXTENSION);
+    } else {
+        outputs.extend([".utoc", ".pak"].map(|extension| config.outpath.clone() + extension));
+        if !config.ucas_to_stdout {
+            outputs.push(config.outpath.clone() + ".ucas");
+            outputs.extend((1..).map(|index| format!("{}_s{index}.ucas", config.outpath)).take_while(|path| Path::new(path).exists()));
+        }
+    }
+    #[cfg(feature = "checksums")]
+    outputs.extend(config.checksums_path.clone());
+    #[cfg(feature = "loose")]
+    if config.loose_pak {
+        outputs.push(config.outpath.clone() + LOOSE_PAK_SUFFIX);
+    }
+    let existing: Vec<String> = outputs.into_iter().filter(|path| Path::new(path).exists()).collect();
+    if existing.is_empty() {
+        return Ok(());
+    }
+    if config.no_clobber {
+        return Err(format!("Output already exists and --no-clobber was given: {}", existing.join(", ")).into());
+    }
+    log::warn!("Overwriting existing container at {} ({})", config.outpath, existing.join(", "));
+    Ok(())
+}
+
+// Written next to the container with --loose, see TocFactory::write_loose_pak
+#[cfg(feature = "loose")]
+const LOOSE_PAK_SUFFIX: &str = "_loose.pak";
+
 const PAKFILE: [u8; 339] = [
     0x02, 0x00, 0x00, 0x00, 0x2f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0b, 0xaa, 0x61, 0x1e, 0x00, 0x00,
     0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x6a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00,
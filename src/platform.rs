@@ -1,4 +1,4 @@
-use std::fs::{ DirEntry, File };
+use std::fs::{ DirEntry, File, FileType };
 
 #[cfg(target_os = "linux")]
 use std::os::linux;
@@ -9,6 +9,16 @@ use std::os::unix;
 #[cfg(target_os = "windows")]
 use std::os::windows;
 
+// Types of file system object that can't be packaged (or walked into). Only unix tells these apart
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpecialFileKind {
+    NamedPipe,
+    Socket,
+    BlockDevice,
+    CharacterDevice,
+    Unknown,
+}
+
 pub struct Metadata;
 
 impl Metadata {
@@ -19,7 +29,6 @@ impl Metadata {
     }
 
     #[cfg(target_os = "linux")]
-    #[allow(dead_code)]
     pub fn get_file_size(fs_obj: &File) -> u64 {
         let meta = fs_obj.metadata().unwrap();
         linux::fs::MetadataExt::st_size(&meta)
@@ -48,4 +57,43 @@ impl Metadata {
         let meta = fs_obj.metadata().unwrap();
         windows::fs::MetadataExt::file_size(&meta)
     }
-}
\ No newline at end of file
+
+    // For whatever read_dir finds that's neither a file, folder or symlink
+    #[cfg(unix)]
+    pub fn get_special_file_kind(file_type: &FileType) -> SpecialFileKind {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            SpecialFileKind::NamedPipe
+        } else if file_type.is_socket() {
+            SpecialFileKind::Socket
+        } else if file_type.is_block_device() {
+            SpecialFileKind::BlockDevice
+        } else if file_type.is_char_device() {
+            SpecialFileKind::CharacterDevice
+        } else {
+            SpecialFileKind::Unknown
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn get_special_file_kind(_file_type: &FileType) -> SpecialFileKind {
+        SpecialFileKind::Unknown
+    }
+}
+
+// Os paths are stored and reported with '/' separators regardless of host, so skip lists, duplicate reports and build summaries
+// look the same everywhere. Windows accepts either separator when opening files, but on other platforms '\' is a valid filename
+// character so it's left alone. Chunk ids never depend on os paths - they're hashed from the names in the TOC tree
+pub struct OsPath;
+
+impl OsPath {
+    #[cfg(target_os = "windows")]
+    pub fn normalize(path: &str) -> String {
+        path.replace('\\', "/")
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    pub fn normalize(path: &str) -> String {
+        path.to_string()
+    }
+}
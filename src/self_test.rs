@@ -0,0 +1,89 @@
+use std::{error::Error, fs, io::Cursor, path::Path};
+
+use crate::{
+    asset_collector::ChunkTypeRegistry,
+    toc_extract::ContainerExtractor,
+    toc_factory::{self, TocFactoryBuilder},
+    toc_reader::TocReader
+};
+
+// Block size for the fixture build, small enough that the bulk data is split over a few blocks
+const SELF_TEST_BLOCK_SIZE: u32 = 0x1000;
+
+// Files the self test packages, laid out like a game's content folder. The assets only have to get past the collector's header
+// check (they don't start with the legacy package magic), nothing in toc-maker reads any further into them. The bulk data goes
+// over a few blocks, with a pattern that still compresses
+fn get_fixture() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        ("P3R/Content/SelfTest/A.uasset", b"toc-maker self test asset A".to_vec()),
+        ("P3R/Content/SelfTest/A.ubulk", (0..SELF_TEST_BLOCK_SIZE * 2 + 0x123).map(|i| (i % 251) as u8).collect()),
+        ("P3R/Content/SelfTest/Sub/B.uasset", b"toc-maker self test asset B".to_vec()),
+    ]
+}
+
+// Build a container from the fixture and read it back, checking that every file comes out with the chunk id chunk_id_for gives
+// it and exactly the bytes that went in. This goes through the collector, flattener and writer the same way a normal build does
+// (compressed when zlib is compiled in), then the reader and extractor, without needing any of the user's files. Returns what
+// was checked, one line per file
+pub fn run() -> Result<Vec<String>, Box<dyn Error>> {
+    let folder = std::env::temp_dir().join(format!("toc-maker-selftest-{}", std::process::id()));
+    let result = run_in(&folder);
+    // the result matters more than a leftover temp folder
+    let _ = fs::remove_dir_all(&folder);
+    result
+}
+
+fn run_in(folder: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let fixture = get_fixture();
+    for (virtual_path, data) in &fixture {
+        let os_path = folder.join(virtual_path);
+        fs::create_dir_all(os_path.parent().unwrap())?;
+        fs::write(os_path, data)?;
+    }
+
+    #[allow(unused_mut)]
+    let mut builder = TocFactoryBuilder::new(folder.to_str().ok_or("Temp folder path isn't UTF-8")?.to_string())
+        .container_name("SelfTest")
+        .block_size(SELF_TEST_BLOCK_SIZE);
+    #[cfg(feature = "zlib")]
+    {
+        builder = builder.zlib(toc_factory::DEFAULT_ZLIB_LEVEL);
+    }
+    let (mut utoc, mut ucas) = (vec![], vec![]);
+    let report = builder.build()?.write_files(&mut utoc, &mut ucas)?;
+    if report.ucas_size != ucas.len() as u64 {
+        return Err(format!("Build reported a {} byte ucas, but wrote {} bytes", report.ucas_size, ucas.len()).into());
+    }
+
+    let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut Cursor::new(utoc))?;
+    let chunk_ids = toc.chunk_ids.clone();
+    // one entry per file plus the container header
+    if chunk_ids.len() != fixture.len() + 1 {
+        return Err(format!("Container has {} chunks, expected {}", chunk_ids.len(), fixture.len() + 1).into());
+    }
+    let mut extractor = ContainerExtractor::new(toc, vec![Cursor::new(ucas)]);
+    let index = extractor.get_directory_index::<byteorder::NativeEndian>()?;
+    let mut checked = vec![];
+    for (virtual_path, data) in &fixture {
+        let file = index.files.iter().find(|file| file.path == *virtual_path).ok_or_else(|| format!("{virtual_path} is missing from the directory index"))?;
+        let (stem, extension) = ChunkTypeRegistry::split_file_name(virtual_path).unwrap();
+        let expected_id = toc_factory::chunk_id_for(stem, extension).unwrap();
+        let chunk_id = chunk_ids[file.toc_entry as usize];
+        if chunk_id != expected_id {
+            return Err(format!("{virtual_path} has chunk id {chunk_id}, expected {expected_id}").into());
+        }
+        if extractor.read_chunk(file.toc_entry as usize)? != *data {
+            return Err(format!("{virtual_path} doesn't read back the same as it was written").into());
+        }
+        checked.push(format!("{virtual_path}: chunk id {chunk_id}, {} bytes", data.len()));
+    }
+    Ok(checked)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn self_test_passes() {
+        assert_eq!(super::run().unwrap().len(), super::get_fixture().len());
+    }
+}
@@ -75,11 +75,19 @@ pub const NAME_HASH_ALGORITHM: u64 = 0xC1640000; // FNameHash::AlgorithmId
 // Used in a couple places, mostly in PAK package headers (see FolderName, SavedByEngineVersion in FPackageFilePackageSummary). 
 // Serialized version of Unreal Engine's FString
 pub struct FString32NoHash;
-    // 0x0: len: u32
-    // 0x4: data: [u8; len]
+    // 0x0: len: i32
+    // 0x4: data: [u8; len], or [u16; -len] when len is negative
+    // Like UE's FString, anything that isn't plain ASCII is stored as UTF-16 with a negative length (in characters). The length
+    // counts the null terminator, except for an empty string which is just a length of 0 with no terminator
 impl FString32NoHash {
     fn from_buffer_inner<R: Read + Seek, E: byteorder::ByteOrder>(reader: &mut R) -> Result<Option<String>, Box<dyn Error>> {
-        let len = reader.read_u32::<E>()?; // length
+        let len = reader.read_i32::<E>()?; // length
+        if len < 0 {
+            let mut buf = vec![0; (-(len as i64) - 1) as usize]; // same again, UTF-16 this time
+            reader.read_u16_into::<E>(&mut buf)?;
+            reader.seek(SeekFrom::Current(2))?;
+            return Ok(Some(String::from_utf16(&buf)?));
+        }
         if len < 1 {
             return Ok(None); // we correctly parsed it, there's just nothing there lol
         }
@@ -90,13 +98,23 @@ impl FString32NoHash {
     }
 
     fn to_buffer_text_inner<W: Write, E: byteorder::ByteOrder>(rstr: &str, writer: &mut W) -> Result<(), Box<dyn Error>> {
+        let rstr = rstr.strip_suffix('\0').unwrap_or(rstr);
+        if rstr.is_empty() {
+            writer.write_u32::<E>(0)?;
+            return Ok(());
+        }
+        if !rstr.is_ascii() {
+            let chars: Vec<u16> = rstr.encode_utf16().chain([0]).collect();
+            writer.write_i32::<E>(-(chars.len() as i32))?;
+            for c in chars {
+                writer.write_u16::<E>(c)?;
+            }
+            return Ok(());
+        }
         // add an extra byte for null terminator
-        let len = (rstr.len() + if !rstr.ends_with("\0") { 1 } else { 0 }) as u32;
-        writer.write_u32::<E>(len)?;
+        writer.write_u32::<E>((rstr.len() + 1) as u32)?;
         writer.write_all(rstr.as_bytes())?;
-        if !rstr.ends_with("\0") {
-            writer.write_u8(b'\0')?;
-        }
+        writer.write_u8(b'\0')?;
         Ok(())
     }
 }
@@ -122,7 +140,14 @@ impl FStringSerializerText for FString32NoHash {
 }
 impl FStringSerializerExpectedLength for FString32NoHash {
     fn get_expected_length(value: &str) -> u64 {
-        let str_len = (value.len() + if !value.ends_with("\0") { 1 } else { 0 }) as u64; // include null terminator
+        let value = value.strip_suffix('\0').unwrap_or(value);
+        let str_len = if value.is_empty() {
+            0 // no null terminator either
+        } else if value.is_ascii() {
+            value.len() as u64 + 1 // include null terminator
+        } else {
+            (value.encode_utf16().count() as u64 + 1) * 2
+        };
         str_len + 4 // 4 bytes at beginning to define string length
     }
 }
@@ -236,7 +261,16 @@ impl Hasher8 {
     }
 }
 
+// Hashes a package path into the 64 bit part of a chunk id. Hasher16 is the one UE uses, this is so tests (or engine forks
+// that hash paths differently) can swap it out. Send and Sync so a factory can be built on another thread (see write_files_async)
+pub trait Hasher: Send + Sync {
+    fn hash_path(&self, path: &str) -> u64;
+}
+
 // TODO: Switch IoStoreObjectIndex to use Hasher16 as a base implementation
+// CityHash64 (v1.1) of a lowercased string, hashed as UTF-16LE (how Unreal hashes TCHAR strings on Windows). This is what gives us
+// chunk ids (IoChunkId::new), package ids for imports and the container's toc name hash, so every asset we pack depends on it
+// producing the same value as the engine. Known values are checked in the tests at the bottom of this file
 pub struct Hasher16;
 impl Hasher16 {
     pub fn get_cityhash64(bytes: &str) -> u64 {
@@ -250,6 +284,12 @@ impl Hasher16 {
     }
 }
 
+impl Hasher for Hasher16 {
+    fn hash_path(&self, path: &str) -> u64 {
+        Hasher16::get_cityhash64(path)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct FMappedName(u32, u32); // NameIndex, ExtraIndex
 // first field is index in name map
@@ -275,4 +315,98 @@ impl From<FMappedName> for u64 {
     fn from(value: FMappedName) -> Self {
         value.0 as u64 | (value.1 as u64) << 0x20
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use byteorder::ReadBytesExt;
+    use super::{FString32NoHash, FStringDeserializer, FStringSerializer, FStringSerializerExpectedLength, Hasher16};
+
+    #[test]
+    fn fstring32_non_ascii_is_utf16() {
+        type EN = byteorder::LittleEndian;
+        for (value, expected) in [
+            ("Cafe", b"\x05\0\0\0Cafe\0".to_vec()),
+            ("Caf\u{e9}", b"\xfb\xff\xff\xffC\0a\0f\0\xe9\0\0\0".to_vec()),
+        ] {
+            let mut bytes = vec![];
+            FString32NoHash::to_buffer::<_, EN>(value, &mut bytes).unwrap();
+            assert_eq!(bytes, expected, "{}", value);
+            assert_eq!(FString32NoHash::get_expected_length(value), bytes.len() as u64, "{}", value);
+            assert_eq!(FString32NoHash::from_buffer::<_, EN>(&mut Cursor::new(bytes)).unwrap().as_deref(), Some(value));
+        }
+    }
+
+    #[test]
+    fn fstring32_matches_ue_layout() {
+        type EN = byteorder::LittleEndian;
+        let longest = "a".repeat(crate::toc_factory::MAX_MOUNT_POINT_LENGTH);
+        let mut longest_bytes = (longest.len() as u32 + 1).to_le_bytes().to_vec();
+        longest_bytes.extend(longest.as_bytes());
+        longest_bytes.push(0);
+        for (value, expected) in [
+            ("", b"\0\0\0\0".to_vec()),
+            ("a", b"\x02\0\0\0a\0".to_vec()),
+            ("Content", b"\x08\0\0\0Content\0".to_vec()),
+            ("../../../", b"\x0a\0\0\0../../../\0".to_vec()),
+            ("\u{e9}", b"\xfe\xff\xff\xff\xe9\0\0\0".to_vec()),
+            (&longest, longest_bytes),
+        ] {
+            let mut bytes = vec![];
+            FString32NoHash::to_buffer::<_, EN>(value, &mut bytes).unwrap();
+            assert_eq!(bytes, expected, "{}", value);
+            assert_eq!(FString32NoHash::get_expected_length(value), bytes.len() as u64, "{}", value);
+        }
+        // a terminator already on the string isn't written twice
+        let mut bytes = vec![];
+        FString32NoHash::to_buffer::<_, EN>("a\0", &mut bytes).unwrap();
+        assert_eq!(bytes, b"\x02\0\0\0a\0");
+        assert_eq!(FString32NoHash::get_expected_length("a\0"), 6);
+    }
+
+    #[test]
+    fn fstring32_reads_back_to_back() {
+        // an off by one anywhere in a string shifts everything after it, so read a run of them and check the marker at the end
+        type EN = byteorder::BigEndian;
+        let values = ["", "a", "Caf\u{e9}", "", "Content"];
+        let mut bytes = vec![];
+        for value in values {
+            FString32NoHash::to_buffer::<_, EN>(value, &mut bytes).unwrap();
+        }
+        bytes.extend(0xdeadbeefu32.to_be_bytes());
+        assert_eq!(&bytes[..4], &[0, 0, 0, 0]); // length prefix follows the given endianness
+        let mut reader = Cursor::new(bytes);
+        for value in values {
+            let read = FString32NoHash::from_buffer::<_, EN>(&mut reader).unwrap();
+            assert_eq!(read.unwrap_or_default(), value);
+        }
+        assert_eq!(reader.read_u32::<EN>().unwrap(), 0xdeadbeef);
+    }
+
+    // Reference values from CityHash64 v1.1 over the lowercase UTF-16LE bytes. These cover each of CityHash's length
+    // branches (4-8, 9-16, 17-32, 33-64 and 64+ bytes)
+    const HASHER16_VECTORS: [(&str, u64); 8] = [
+        ("", 0x9ae16a3b2f90404f),
+        ("P3R", 0x7f60dfc999f265a5),
+        ("/Game", 0x960efb8343f9df70),
+        ("pakchunk999", 0x45f9ac6f10aa2e9e),
+        ("/Script/CoreUObject", 0x21febf02cdde2af3),
+        ("/Game/Xrd777/UI/Tables/DatUITextTable", 0x95fcfb7878561b8a),
+        ("/Game/Xrd777/Field/Data/DataTable/Texts/DT_FldPlaceName", 0x2d3fbf0419c2aa64),
+        ("/Game/Xrd777/Characters/Player/PC0001/Models/SK_PC0001_C001", 0x5773e3c85fe008db),
+    ];
+
+    #[test]
+    fn hasher16_known_values() {
+        for (input, expected) in HASHER16_VECTORS {
+            assert_eq!(Hasher16::get_cityhash64(input), expected, "hash of {:?}", input);
+        }
+    }
+
+    #[test]
+    fn hasher16_ignores_case() {
+        assert_eq!(Hasher16::get_cityhash64("/Game/Xrd777/UI/Tables/DatUITextTable"), Hasher16::get_cityhash64("/game/xrd777/ui/tables/datuitexttable"));
+        assert_eq!(Hasher16::get_cityhash64("PAKCHUNK999"), Hasher16::get_cityhash64("pakchunk999"));
+    }
+}
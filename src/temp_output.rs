@@ -0,0 +1,132 @@
+use std::{fs::{self, File}, io::{self, BufWriter, Write}, path::{Path, PathBuf}};
+#[cfg(feature = "checksums")]
+use std::{cell::RefCell, rc::Rc};
+
+use toc_maker::bundle;
+
+#[cfg(feature = "checksums")]
+use sha2::{Digest, Sha256};
+
+const TEMP_OUTPUT_EXTENSION: &str = ".tmp";
+
+// Output files that are written next to where they're meant to go (Foo.utoc -> Foo.utoc.tmp) and only moved into place once
+// everything has been written, so that a build that fails or panics partway through never leaves a half written container
+// where the game will try to load it. Anything that hasn't been committed is deleted on drop, including while unwinding
+pub struct TempOutputs {
+    paths: Vec<String>, // final paths, the temp file is the same with TEMP_OUTPUT_EXTENSION on the end
+    committed: bool,
+    #[cfg(feature = "checksums")]
+    checksums: Option<Rc<RefCell<Vec<(String, String)>>>>, // file name and sha256 of each output, added as each one is closed
+}
+
+impl TempOutputs {
+    pub fn new() -> Self {
+        Self {
+            paths: vec![],
+            committed: false,
+            #[cfg(feature = "checksums")]
+            checksums: None
+        }
+    }
+
+    // Hash everything written to outputs created from here on, so that write_checksums can list them
+    #[cfg(feature = "checksums")]
+    pub fn hash_outputs(&mut self) {
+        self.checksums = Some(Rc::new(RefCell::new(vec![])));
+    }
+
+    pub fn create(&mut self, path: &str) -> io::Result<TempOutput> {
+        let file = File::create(format!("{path}{TEMP_OUTPUT_EXTENSION}"))?;
+        self.paths.push(path.to_string());
+        Ok(TempOutput {
+            file,
+            #[cfg(feature = "checksums")]
+            hasher: self.checksums.as_ref().map(|checksums| {
+                let name = Path::new(path).file_name().map_or(path.to_string(), |name| name.to_string_lossy().to_string());
+                (Sha256::new(), name, checksums.clone())
+            }),
+        })
+    }
+
+    // Write a "<sha256>  <file name>" line (the same format as sha256sum) for every hashed output into a new output at path.
+    // The hashes are only complete once the outputs have been closed, so this has to come after they're dropped
+    #[cfg(feature = "checksums")]
+    pub fn write_checksums(&mut self, path: &str) -> io::Result<()> {
+        let mut checksums = self.checksums.as_ref().map_or(vec![], |checksums| checksums.borrow().clone());
+        checksums.sort();
+        let mut file = File::create(format!("{path}{TEMP_OUTPUT_EXTENSION}"))?;
+        self.paths.push(path.to_string());
+        for (name, hash) in checksums {
+            writeln!(file, "{hash}  {name}")?;
+        }
+        Ok(())
+    }
+
+    // Replace the final files with the temp ones. Files have to be closed before this is called on Windows
+    pub fn commit(mut self) -> io::Result<()> {
+        for path in &self.paths {
+            fs::rename(format!("{path}{TEMP_OUTPUT_EXTENSION}"), path)?;
+        }
+        self.committed = true;
+        Ok(())
+    }
+
+    // Put everything that's been written into a single bundle at path (see toc_maker::bundle) instead of committing the files
+    // themselves, which are deleted. Files have to be closed before this is called
+    pub fn commit_as_bundle(self, path: &str) -> io::Result<()> {
+        let files: Vec<(String, PathBuf)> = self.paths.iter()
+            .map(|path| (Path::new(path).file_name().map_or(path.to_string(), |name| name.to_string_lossy().to_string()), PathBuf::from(format!("{path}{TEMP_OUTPUT_EXTENSION}"))))
+            .collect();
+        let mut bundle_outputs = TempOutputs::new();
+        {
+            let mut writer = BufWriter::new(bundle_outputs.create(path)?);
+            bundle::write_bundle(&mut writer, &files)?;
+            writer.flush()?;
+        }
+        bundle_outputs.commit()
+        // dropping self deletes the temp files, since they were never committed
+    }
+}
+
+impl Drop for TempOutputs {
+    fn drop(&mut self) {
+        if !self.committed {
+            for path in &self.paths {
+                let _ = fs::remove_file(format!("{path}{TEMP_OUTPUT_EXTENSION}"));
+            }
+        }
+    }
+}
+
+// One of the files from TempOutputs. Data is hashed on the way through when checksums are turned on, so the ucas doesn't need
+// to be read back in afterwards
+pub struct TempOutput {
+    file: File,
+    #[cfg(feature = "checksums")]
+    hasher: Option<(Sha256, String, Rc<RefCell<Vec<(String, String)>>>)>, // running hash, file name, where to put the result
+}
+
+impl Write for TempOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        #[cfg(feature = "checksums")]
+        if let Some((hasher, _, _)) = self.hasher.as_mut() {
+            hasher.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(feature = "checksums")]
+impl Drop for TempOutput {
+    fn drop(&mut self) {
+        if let Some((hasher, name, checksums)) = self.hasher.take() {
+            let hash = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+            checksums.borrow_mut().push((name, hash));
+        }
+    }
+}
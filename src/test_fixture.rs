@@ -0,0 +1,54 @@
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering}
+};
+
+static TEMP_DIR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// A new folder in the system temp folder for one test, deleted on drop so it's cleaned up even when an assert fails. The pid
+// keeps separate test runs apart and the counter keeps tests in the same run apart
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("toc-maker-{name}-{}-{}", std::process::id(), TEMP_DIR_COUNT.fetch_add(1, Ordering::Relaxed)));
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    // A temp folder laid out like a mod: each file is written to P3R/Content under its relative path, making any folders on the way
+    pub fn with_content(name: &str, files: &[(&str, &[u8])]) -> Self {
+        let dir = Self::new(name);
+        let content = dir.join("P3R").join("Content");
+        fs::create_dir_all(&content).unwrap();
+        for (relative_path, data) in files {
+            let path = content.join(relative_path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+        dir
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
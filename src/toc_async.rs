@@ -0,0 +1,86 @@
+use std::io::{self, Write};
+
+use tokio::{io::{AsyncWrite, AsyncWriteExt}, sync::mpsc};
+
+use crate::{error::TocError, toc_factory::{BuildReport, TocFactory}};
+
+// How much of the ucas is handed to the async side at once, and how many of those can be waiting to be written before the build
+// stops to let the writer catch up
+const UCAS_BUFFER_SIZE: usize = 0x100000;
+const UCAS_BUFFERS_IN_FLIGHT: usize = 4;
+
+impl TocFactory {
+    // Same as write_files, for callers on a tokio runtime. The build itself (collecting, reading source files, compressing) runs
+    // on the blocking pool with spawn_blocking, which is also where tokio::fs would do its reads, so the runtime's own threads
+    // only ever wait on the output streams. The ucas is streamed out as it's built, the utoc is held in memory until the end
+    // since its header isn't known until every file has been written. The bytes are exactly the same as write_files'
+    pub async fn write_files_async<WTOC, WCAS>(self, utoc_stream: &mut WTOC, ucas_stream: &mut WCAS) -> Result<BuildReport, TocError>
+    where
+        WTOC: AsyncWrite + Unpin,
+        WCAS: AsyncWrite + Unpin
+    {
+        let (sender, mut receiver) = mpsc::channel(UCAS_BUFFERS_IN_FLIGHT);
+        let build = tokio::task::spawn_blocking(move || {
+            let mut utoc = vec![];
+            let mut ucas = ChannelWriter { sender, buffer: Vec::with_capacity(UCAS_BUFFER_SIZE) };
+            let report = self.write_files(&mut utoc, &mut ucas)?;
+            ucas.flush()?;
+            Ok::<_, TocError>((utoc, report))
+        });
+        // if writing fails, the receiver is dropped and the build stops at its next send
+        while let Some(bytes) = receiver.recv().await {
+            ucas_stream.write_all(&bytes).await?;
+        }
+        let (utoc, report) = build.await.map_err(|e| TocError::Io(io::Error::other(e)))??;
+        ucas_stream.flush().await?;
+        utoc_stream.write_all(&utoc).await?;
+        utoc_stream.flush().await?;
+        Ok(report)
+    }
+}
+
+// Collects what the build writes into the ucas and sends it to write_files_async a buffer at a time
+struct ChannelWriter {
+    sender: mpsc::Sender<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= UCAS_BUFFER_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let buffer = std::mem::replace(&mut self.buffer, Vec::with_capacity(UCAS_BUFFER_SIZE));
+        self.sender.blocking_send(buffer).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "ucas writer stopped"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{test_fixture::TempDir, toc_factory::TocFactoryBuilder};
+
+    #[test]
+    fn async_output_matches_sync() {
+        let folder = TempDir::with_content("async", &[("A.ubulk", &[1u8; 0x123]), ("Dir/B.ubulk", &(0..0x2345).map(|i| (i % 7) as u8).collect::<Vec<u8>>())]);
+
+        let build = || TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).block_size(0x1000).build().unwrap();
+        let (mut utoc, mut ucas) = (vec![], vec![]);
+        let report = build().write_files(&mut utoc, &mut ucas).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let (mut async_utoc, mut async_ucas) = (vec![], vec![]);
+        let async_report = runtime.block_on(build().write_files_async(&mut async_utoc, &mut async_ucas)).unwrap();
+
+        assert_eq!(utoc, async_utoc);
+        assert_eq!(ucas, async_ucas);
+        assert_eq!(report.ucas_size, async_report.ucas_size);
+    }
+}
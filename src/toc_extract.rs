@@ -0,0 +1,287 @@
+use std::{
+    error::Error,
+    fs,
+    io::{Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf}
+};
+
+use byteorder::ReadBytesExt;
+#[cfg(feature = "zlib")]
+use flate2::read::ZlibDecoder;
+
+use crate::{
+    bundle::is_safe_name,
+    io_toc::{io_container_flags, IoDirectoryIndexEntry, IoStoreTocCompressedBlockEntry},
+    string::{FString32NoHash, FStringDeserializer},
+    toc_reader::TocReader
+};
+#[cfg(feature = "aes")]
+use crate::encryption::{self, AesKey};
+
+// A file from the directory index: where it is under the mount point (folders separated by /) and the TOC entry its chunk is in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedFile {
+    pub path: String,
+    pub toc_entry: u32,
+}
+
+// The directory index of a utoc, with each file's path put back together from the directory entries and the string pool
+#[derive(Debug)]
+pub struct DirectoryIndex {
+    pub mount_point: String,
+    pub files: Vec<IndexedFile>,
+}
+
+impl DirectoryIndex {
+    pub fn from_buffer<E: byteorder::ByteOrder>(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut reader = Cursor::new(data);
+        let mount_point = FString32NoHash::from_buffer::<_, E>(&mut reader)?.unwrap_or_default();
+        // counts come straight from the file, so nothing is reserved up front in case they're garbage
+        let mut directories = vec![];
+        for _ in 0..reader.read_u32::<E>()? {
+            directories.push(IoDirectoryIndexEntry::from_buffer::<_, E>(&mut reader)?);
+        }
+        let mut files = vec![];
+        for _ in 0..reader.read_u32::<E>()? {
+            // name, next file, user data (the TOC entry)
+            files.push((reader.read_u32::<E>()?, reader.read_u32::<E>()?, reader.read_u32::<E>()?));
+        }
+        let mut names = vec![];
+        for _ in 0..reader.read_u32::<E>()? {
+            names.push(FString32NoHash::from_buffer::<_, E>(&mut reader)?.unwrap_or_default());
+        }
+
+        // Every name ends up as part of a path on disk, so anything that could climb out of the output folder is refused
+        let get_name = |index: u32| -> Result<&str, Box<dyn Error>> {
+            let name = names.get(index as usize).ok_or_else(|| format!("Directory index refers to missing name {index}"))?;
+            if !is_safe_name(name) {
+                return Err(format!("Directory index has an unsafe name: {name}").into());
+            }
+            Ok(name)
+        };
+        let mut indexed_files = vec![];
+        let mut visited = vec![false; directories.len()];
+        let mut pending = if directories.is_empty() { vec![] } else { vec![(0, String::new())] }; // root has no name
+        while let Some((directory_index, path)) = pending.pop() {
+            if std::mem::replace(&mut visited[directory_index], true) {
+                return Err("Directory index lists a folder more than once".into());
+            }
+            let directory = &directories[directory_index];
+            let mut file_index = directory.first_file;
+            for _ in 0..files.len() {
+                if file_index == u32::MAX {
+                    break;
+                }
+                let (name, next_file, toc_entry) = *files.get(file_index as usize).ok_or_else(|| format!("Directory index refers to missing file {file_index}"))?;
+                indexed_files.push(IndexedFile { path: format!("{path}{}", get_name(name)?), toc_entry });
+                file_index = next_file;
+            }
+            // both lists are bounded by their length, so a crafted index that links back into itself can't loop forever
+            let mut child_index = directory.first_child;
+            for _ in 0..directories.len() {
+                if child_index == u32::MAX {
+                    break;
+                }
+                let child = directories.get(child_index as usize).ok_or_else(|| format!("Directory index refers to missing folder {child_index}"))?;
+                pending.push((child_index as usize, format!("{path}{}/", get_name(child.name)?)));
+                child_index = child.next_sibling;
+            }
+        }
+        Ok(Self { mount_point, files: indexed_files })
+    }
+}
+
+// Reads files back out of a built container, the inverse of TocFactory::write_files. partitions are the ucas files in order
+// (Mod.ucas, Mod_s1.ucas, ...). Uncompressed and zlib blocks can be read, other compression methods are an error
+pub struct ContainerExtractor<R> {
+    toc: TocReader,
+    partitions: Vec<R>,
+    #[cfg(feature = "aes")]
+    aes_key: Option<AesKey>,
+}
+
+impl<R: Read + Seek> ContainerExtractor<R> {
+    pub fn new(toc: TocReader, partitions: Vec<R>) -> Self {
+        Self {
+            toc,
+            partitions,
+            #[cfg(feature = "aes")]
+            aes_key: None,
+        }
+    }
+
+    #[cfg(feature = "aes")]
+    pub fn set_aes_key(&mut self, key: AesKey) {
+        self.aes_key = Some(key);
+    }
+
+    fn is_encrypted(&self) -> bool {
+        self.toc.header.get_container_flags() & io_container_flags::ENCRYPTED != 0
+    }
+
+    pub fn get_directory_index<E: byteorder::ByteOrder>(&self) -> Result<DirectoryIndex, Box<dyn Error>> {
+        if self.toc.header.get_container_flags() & io_container_flags::INDEXED == 0 {
+            return Err("Container has no directory index, so there are no file names to extract to".into());
+        }
+        let mut data = self.toc.directory_index.clone();
+        // encrypted along with the blocks, see TocFactory::write_container_as
+        if self.is_encrypted() {
+            self.decrypt_block(&mut data)?;
+        }
+        DirectoryIndex::from_buffer::<E>(&data)
+    }
+
+    // Read a chunk through the same offsets and compression blocks the engine would use
+    pub fn read_chunk(&mut self, toc_entry: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let offset_and_length = self.toc.offsets_and_lengths.get(toc_entry).ok_or_else(|| format!("TOC has no entry {toc_entry}"))?;
+        let (offset, length) = (offset_and_length.get_offset(), offset_and_length.get_length());
+        let block_size = self.toc.header.get_compression_block_size() as u64;
+        let (first_block, last_block) = ((offset / block_size) as usize, (offset + length).div_ceil(block_size) as usize);
+        let blocks = self.toc.compression_blocks.get(first_block..last_block)
+            .ok_or_else(|| format!("TOC entry {toc_entry} refers to compression blocks past the end of the block table"))?.to_vec();
+        let mut data = Vec::with_capacity((last_block - first_block) * block_size as usize);
+        for block in &blocks {
+            data.extend_from_slice(&self.read_block(block)?);
+        }
+        let start = (offset % block_size) as usize;
+        if data.len() < start + length as usize {
+            return Err(format!("TOC entry {toc_entry} is longer than its compression blocks").into());
+        }
+        data.truncate(start + length as usize);
+        data.drain(..start);
+        Ok(data)
+    }
+
+    fn read_block(&mut self, block: &IoStoreTocCompressedBlockEntry) -> Result<Vec<u8>, Box<dyn Error>> {
+        // block offsets are in the virtual partition space, see UcasPartitionWriter
+        let (partition, offset) = match self.toc.header.get_partition_count() {
+            0 | 1 => (0, block.get_offset()),
+            _ => {
+                let partition_size = self.toc.header.get_partition_size();
+                ((block.get_offset() / partition_size) as usize, block.get_offset() % partition_size)
+            }
+        };
+        let compressed_size = block.get_compressed_size() as usize;
+        // encrypted blocks are stored padded to the AES block size
+        let stored_size = if self.is_encrypted() { compressed_size.next_multiple_of(0x10) } else { compressed_size };
+        let reader = self.partitions.get_mut(partition).ok_or_else(|| format!("Block at {:#x} is in partition {partition}, which wasn't given", block.get_offset()))?;
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; stored_size];
+        reader.read_exact(&mut data)?;
+        if self.is_encrypted() {
+            self.decrypt_block(&mut data)?;
+            data.truncate(compressed_size);
+        }
+
+        let uncompressed_size = block.get_uncompressed_size() as usize;
+        let data = match block.get_compression_method() {
+            0 => data,
+            method => {
+                let name = self.toc.compression_methods.get(method as usize - 1).map_or("", |name| name.as_str());
+                Self::decompress_block(name, &data, uncompressed_size)?
+            }
+        };
+        if data.len() != uncompressed_size {
+            return Err(format!("Block at {:#x} is {} bytes once decompressed, not {}", block.get_offset(), data.len(), uncompressed_size).into());
+        }
+        Ok(data)
+    }
+
+    #[allow(unused_variables)]
+    fn decrypt_block(&self, data: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        #[cfg(feature = "aes")]
+        if let Some(key) = self.aes_key.as_ref() {
+            encryption::decrypt_in_place(key, data);
+            return Ok(());
+        }
+        Err("Container is encrypted, an AES key is needed to read it".into())
+    }
+
+    #[allow(unused_variables)]
+    fn decompress_block(method: &str, data: &[u8], uncompressed_size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        #[cfg(feature = "zlib")]
+        if method.eq_ignore_ascii_case("zlib") {
+            let mut decompressed = Vec::with_capacity(uncompressed_size);
+            ZlibDecoder::new(data).read_to_end(&mut decompressed)?;
+            return Ok(decompressed);
+        }
+        Err(format!("Blocks compressed with \"{method}\" can't be read").into())
+    }
+
+    // Write every file in the directory index into out_folder, at its path under the mount point. Returns the paths written
+    pub fn extract_to<E: byteorder::ByteOrder>(&mut self, out_folder: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let index = self.get_directory_index::<E>()?;
+        let mut written = vec![];
+        for file in index.files {
+            let path = out_folder.join(&file.path);
+            let data = self.read_chunk(file.toc_entry as usize).map_err(|e| format!("Couldn't read {}: {}", file.path, e))?;
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(&path, data)?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixture::TempDir;
+    use std::fs::File;
+    use crate::toc_factory::TocFactoryBuilder;
+
+    #[test]
+    fn extracted_files_match_the_input() {
+        let a: Vec<u8> = (0..0x2345).map(|i| (i % 7) as u8).collect();
+        let files: [(&str, &[u8]); 3] = [("A.ubulk", &a), ("Foo/B.ubulk", &[3u8; 0x1800]), ("Foo/C.ubulk", &[5u8; 0x10])];
+        let source = TempDir::with_content("extract", &files);
+        let folder = TempDir::new("extract-out");
+
+        // small blocks and partitions, so chunks span several blocks and the ucas is split
+        #[allow(unused_mut)]
+        let mut builder = TocFactoryBuilder::new(source.to_str().unwrap().to_string()).block_size(0x1000).partition_size(0x2000);
+        #[cfg(feature = "zlib")]
+        {
+            builder = builder.zlib(9);
+        }
+        let utoc_path = folder.join("Mod.utoc");
+        let partition_path = |index: u32| folder.join(if index == 0 { "Mod.ucas".to_string() } else { format!("Mod_s{index}.ucas") });
+        builder.build().unwrap().write_files_partitioned(&mut File::create(&utoc_path).unwrap(), &mut File::create(partition_path(0)).unwrap(), |index| File::create(partition_path(index))).unwrap();
+
+        type EN = byteorder::NativeEndian;
+        let toc = TocReader::from_buffer::<_, EN>(&mut File::open(&utoc_path).unwrap()).unwrap();
+        let partitions = (0..toc.header.get_partition_count()).map(|index| File::open(partition_path(index)).unwrap()).collect();
+        let mut extractor = ContainerExtractor::new(toc, partitions);
+        let written = extractor.extract_to::<EN>(&folder.join("out")).unwrap();
+        let extracted: Vec<Vec<u8>> = files.iter().map(|(path, _)| fs::read(folder.join("out").join("P3R/Content").join(path)).unwrap()).collect();
+
+        assert_eq!(written.len(), files.len());
+        assert!(files.iter().zip(extracted).all(|((_, data), extracted)| *data == extracted));
+    }
+
+    #[cfg(feature = "aes")]
+    #[test]
+    fn encrypted_index_is_read_with_the_key() {
+        let source = TempDir::with_content("extract-aes", &[("A.ubulk", &[9u8; 0x123])]);
+        let folder = TempDir::new("extract-aes-out");
+
+        let key = [0x5a; 32];
+        let utoc_path = folder.join("Mod.utoc");
+        let builder = TocFactoryBuilder::new(source.to_str().unwrap().to_string()).encryption_key(key, 1);
+        builder.build().unwrap().write_files(&mut File::create(&utoc_path).unwrap(), &mut File::create(folder.join("Mod.ucas")).unwrap()).unwrap();
+
+        type EN = byteorder::NativeEndian;
+        let toc = TocReader::from_buffer::<_, EN>(&mut File::open(&utoc_path).unwrap()).unwrap();
+        let index_len = toc.directory_index.len();
+        let mut extractor = ContainerExtractor::new(toc, vec![File::open(folder.join("Mod.ucas")).unwrap()]);
+        let without_key = extractor.get_directory_index::<EN>().is_err();
+        extractor.set_aes_key(key);
+        let written = extractor.extract_to::<EN>(&folder.join("out")).unwrap();
+        let extracted = fs::read(folder.join("out").join("P3R/Content/A.ubulk")).unwrap();
+
+        assert_eq!(index_len % 0x10, 0);
+        assert!(without_key);
+        assert_eq!(written.len(), 1);
+        assert_eq!(extracted, vec![9u8; 0x123]);
+    }
+}
@@ -1,46 +1,278 @@
 use std::{
-    fs::File, 
-    io::{Read, Write}, 
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions}, 
+    io::{self, BufReader, Read, Seek, SeekFrom, Write}, 
     mem, 
-    ops::Deref, 
-    time::Instant
+    ops::{Deref, RangeInclusive}, 
+    path::Path, 
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::{Duration, Instant}
 };
 
 #[cfg(feature = "zlib")]
 use flate2::{write::ZlibEncoder, Compression};
 
+#[cfg(feature = "aes")]
+use crate::encryption::{self, AesKey};
+
 use crate::{
-    alignment::{AlignableNum, AlignableStream}, asset_collector::{
-        AssetCollector, TocDirectorySyncRef, TocFile, SUITABLE_FILE_EXTENSIONS, 
+    alignment::{AlignableNum, AlignableStream}, block_cache::{BlockCache, CachedBlock}, block_table::BlockTable, error::{TocError, WriteFailure}, asset_collector::{
+        AssetCollector, AssetSource, ChunkTypeRegistry, CollectionStats, FolderOptions, TocDirectory, TocDirectorySyncRef, TocFile, 
     }, io_toc::{
-        ContainerHeader, IoChunkId, IoChunkType4, IoDirectoryIndexEntry, IoFileIndexEntry, IoOffsetAndLength, IoStoreTocCompressedBlockEntry, IoStoreTocEntryMeta, IoStoreTocHeaderCommon, IoStoreTocHeaderType3, IoStringPool, COMPRESSION_METHOD_NAME_LENGTH, IO_FILE_INDEX_ENTRY_SERIALIZED_SIZE
-    }, string::{FString32NoHash, FStringSerializer, FStringSerializerExpectedLength, Hasher16}
+        ContainerHeader, IoChunkId, IoChunkType, IoChunkType4, IoDirectoryIndexEntry, IoFileIndexEntry, IoOffsetAndLength, IoStoreTocCompressedBlockEntry, IoStoreTocEntryMeta, IoStoreTocHeaderCommon, IoStoreTocHeaderType3, IoStringPool, COMPRESSED_BLOCK_SIZE_MAX, COMPRESSION_METHOD_NAME_LENGTH, IO_COMPRESSED_BLOCK_LENGTH_MAX, IO_FILE_INDEX_ENTRY_SERIALIZED_SIZE, IO_OFFSET_LENGTH_MAX, UeVersion
+    }, string::{FString32NoHash, FStringSerializer, FStringSerializerExpectedLength, Hasher, Hasher16},
+    io_toc::io_container_flags, toc_meta::FileSettings, toc_reader::TocReader, toc_update::ExistingContainer
 };
+#[cfg(feature = "aes")]
+use crate::io_toc::GUID;
+#[cfg(feature = "loose")]
+use crate::loose_pak::PakWriter;
+#[cfg(feature = "json")]
+use crate::build_summary::{BuildSummary, BuildSummaryFile, BuildSummarySkippedFile};
+
+pub const DEFAULT_COMPRESSION_BLOCK_SIZE: u32 = 0x40000;
+// Smallest block alignment a container can use. UE reads every block as Align(CompressedSize, 16) bytes (the AES block size),
+// encrypted or not, so encrypted blocks are padded to 16 bytes in the ucas and every block starts on a 16 byte boundary
+pub const MIN_COMPRESSION_BLOCK_ALIGNMENT: u32 = 0x10;
+// Packs blocks as tightly as the reader allows, 0x800 sectors would add up to 2KB per file
+pub const DEFAULT_COMPRESSION_BLOCK_ALIGNMENT: u32 = MIN_COMPRESSION_BLOCK_ALIGNMENT;
+// Range get_auto_block_size picks from. UE4.27's own default is the smallest, bigger blocks compress better but make the game
+// read (and decompress) more than it asked for when it only wants the start of a chunk
+pub const MIN_AUTO_BLOCK_SIZE: u32 = 0x10000;
+pub const MAX_AUTO_BLOCK_SIZE: u32 = 0x100000;
+pub const DEFAULT_MOUNT_POINT: &str = "../../../";
+// Longest mount point allowed, in UTF-16 characters. The engine joins it with the path of each file and can't open anything past
+// its path limit (FPlatformMisc::GetMaxPathLength, MAX_PATH on Windows), so a mount point that long already leaves no room
+pub const MAX_MOUNT_POINT_LENGTH: usize = 260;
+pub const DEFAULT_CONTAINER_NAME: &str = "pakchunk999";
+pub const DEFAULT_ZLIB_LEVEL: u32 = 6; // same as flate2's Compression::default()
+pub const SMALL_CONTAINER_SIZE: u64 = 0x1000; // warn about containers with less file data than this, see warn_if_suspiciously_small
+
+// UE4.27 doesn't store a mount order in the utoc or the container header. A container is mounted with the same order as the
+// pak file next to it, which the engine works out from the pak's file name: names ending in _<N>_P get 100 * (N + 1) added on top
+// of the order for the folder they're in (just _P, or N = 0, gets 100). So between containers in the same folder, a higher N wins
+// over a lower one, and any of them win over a container without _P
+pub fn get_patch_output_stem(output_stem: &str, order: u32) -> String {
+    format!("{output_stem}_{order}_P")
+}
+
+// What a finished build wrote, so callers (installers, size checks in CI) don't have to stat the output files
+#[derive(Debug, Clone, Copy)]
+pub struct BuildReport {
+    pub stats: CollectionStats,
+    pub ucas_size: u64, // bytes in the ucas once the build is done, across every partition and including padding between blocks
+    pub partition_count: u32,
+}
+
+// Projected result of a build, from TocFactory::estimate. Only the first block of each compressed file is actually compressed, and
+// the rest of the file is assumed to compress as well as it did
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeEstimate {
+    pub file_count: usize,
+    pub input_size: u64, // total size of the files that would be packaged
+    pub sampled_size: u64, // bytes that were compressed to make the estimate
+    pub ucas_size: u64, // projected, including padding between blocks and the container header
+    pub compress_time: Duration, // projected time spent compressing, which is most of a zlib build
+}
+
+// Compression block size that suits a set of files, for builds where nobody has picked one. Most of what the game reads from a
+// container full of small files (uassets are mostly package headers) is a whole file at a time, so blocks around the size of a
+// typical file waste little and keep seeks cheap. Containers that are mostly bulk data stream big files, where bigger blocks
+// compress better. The median file size is used so a few huge textures don't decide it for hundreds of small assets
+pub fn get_auto_block_size(file_sizes: impl IntoIterator<Item = u64>) -> u32 {
+    let mut file_sizes: Vec<u64> = file_sizes.into_iter().collect();
+    if file_sizes.is_empty() {
+        return DEFAULT_COMPRESSION_BLOCK_SIZE;
+    }
+    file_sizes.sort_unstable();
+    let median = file_sizes[file_sizes.len() / 2];
+    median.next_power_of_two().clamp(MIN_AUTO_BLOCK_SIZE as u64, MAX_AUTO_BLOCK_SIZE as u64) as u32
+}
+
+// Order that chunks are written to the ucas (and listed in the TOC) in. The file index always stays in flatten order, with each
+// entry's user_data pointing at wherever its chunk ended up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FileSortOrder {
+    #[default]
+    Flatten, // same order as the file index
+    Unreal, // like UE's cooked containers: bulk data after everything else, then grouped by folder, then smallest first
+}
+
+// Byte order for the TOC and container header. Consoles with big-endian CPUs need Big no matter what the build machine is.
+// The packed fields (IoOffsetAndLength, IoStoreTocCompressedBlockEntry) and strings are laid out byte by byte, so they don't change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Endianness {
+    #[default]
+    Native, // whatever the machine running toc-maker uses
+    Little,
+    Big,
+}
+
+// Turn the tree from an AssetCollector into the directory index, file index and string table, the same way write_files does.
+// For tools that want to look at (or change) the layout of a container without writing one. See get_package_path for root_prefix
+pub fn flatten(dir: TocDirectorySyncRef, ue_version: UeVersion, hasher: &dyn Hasher, root_prefix: Option<&str>) -> Result<FlattenedToc, TocError> {
+    TocFlattener::flatten(dir, ue_version, hasher, root_prefix)
+}
+
+// Package path (e.g. /Game/Foo/Bar) that a file's chunk id is hashed from, given the folder it's in (relative to the input root,
+// ending in /) and its name without the extension.
+// Without a root prefix, the input has to be laid out like a cooked game (TocError::NotGameLayout otherwise): the first folder is
+// the project (P3R/Content/Foo) and stands for /Game, and /Content is dropped, so P3R/Content/Foo/Bar becomes /Game/Foo/Bar. With one,
+// the prefix is put in front of the path as it is, so plugins and other mount roots can be matched: with a prefix of MyPlugin,
+// Foo/Bar becomes /MyPlugin/Foo/Bar
+pub fn get_package_path(dir_path: &str, stem: &str, root_prefix: Option<&str>) -> Result<String, TocError> {
+    if let Some(root_prefix) = root_prefix {
+        let root_prefix = root_prefix.trim_matches(['/', '\\']);
+        let dir_path = dir_path.trim_start_matches('/');
+        return Ok(if root_prefix.is_empty() { format!("/{dir_path}{stem}") } else { format!("/{root_prefix}/{dir_path}{stem}") });
+    }
+    let path = dir_path.to_string() + stem;
+    get_game_package_path(&path).ok_or_else(|| TocError::NotGameLayout(path.trim_start_matches('/').to_string()))
+}
+
+// The /Game mapping from get_package_path, or None if path doesn't have a Content folder under its first one
+fn get_game_package_path(path: &str) -> Option<String> {
+    let path = if path.starts_with("Game") { path.to_string() } else { "Game/".to_string() + path.split_once('/')?.1 };
+    let (before, after) = path.split_once("/Content")?;
+    Some("/".to_owned() + before + after)
+}
+
+// Chunk id of the file at virtual_path (its path under the mount point without the extension, e.g. P3R/Content/Foo/Bar) with
+// the given chunk type. This is what TocFlattener gives every file: the hash of its package path (see get_package_path), with
+// the chunk type in the last byte
+pub fn get_chunk_id(virtual_path: &str, chunk_type: IoChunkType, hasher: &dyn Hasher, root_prefix: Option<&str>) -> Result<IoChunkId, TocError> {
+    let split = virtual_path.rfind('/').map_or(0, |i| i + 1);
+    let package_path = get_package_path(&virtual_path[..split], &virtual_path[split..], root_prefix)?;
+    // The hash is the package id (FPackageId::FromName on the package path), so a .ubulk or .uptnl gets the same one as
+    // its .uasset sibling and only the type byte differs. 4.27 always creates bulk data chunks with bulk index 0
+    // (CreateIoChunkId(PackageId, 0, EIoChunkType::BulkData)), which is what new_from_hash_with_type leaves the index as
+    Ok(IoChunkId::new_from_hash_with_type(hasher.hash_path(&package_path), chunk_type))
+}
+
+// Chunk id a file would be packaged with by default (UE4.27, no root prefix), for checking what the game will look for without
+// building a container. None if the extension isn't one that gets packaged, or the path isn't laid out like the game's content
+pub fn chunk_id_for(virtual_path: &str, extension: &str) -> Option<IoChunkId> {
+    let virtual_path = virtual_path.trim_start_matches('/');
+    let chunk_type = ChunkTypeRegistry::new().get_chunk_type(extension.trim_start_matches('.'))?;
+    get_chunk_id(virtual_path, IoChunkType::for_version(chunk_type, UeVersion::UE4_27)?, &Hasher16, None).ok()
+}
+
+// The directory index, file index and string table of a container before they're serialized. Names, children, siblings and
+// files are linked by index, with u32::MAX for none. Files are in flatten order, which isn't the order chunks are written in
+// (see TocFlattener::sort_chunks)
+pub struct FlattenedToc {
+    directories: Vec<IoDirectoryIndexEntry>,
+    files: Vec<IoFileIndexEntry>,
+    names: Vec<String>,
+}
+
+impl FlattenedToc {
+    // The first one is the mount point, which doesn't have a name
+    pub fn get_directories(&self) -> &[IoDirectoryIndexEntry] {
+        &self.directories
+    }
+
+    pub fn get_files(&self) -> &[IoFileIndexEntry] {
+        &self.files
+    }
 
-pub const DEFAULT_COMPRESSION_BLOCK_ALIGNMENT: u32 = 0x10;
+    // String table that directory and file names index into
+    pub fn get_names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn get_name(&self, index: u32) -> Option<&str> {
+        self.names.get(index as usize).map(|name| name.as_str())
+    }
+
+    // Path of each file under the mount point (e.g. P3R/Content/Foo/Bar.uasset), in the same order as get_files
+    pub fn get_file_paths(&self) -> Vec<String> {
+        let mut paths = vec![String::new(); self.files.len()];
+        if !self.directories.is_empty() {
+            self.add_file_paths(0, "", &mut paths);
+        }
+        paths
+    }
+
+    fn add_file_paths(&self, dir_index: u32, parent_path: &str, paths: &mut Vec<String>) {
+        let dir = &self.directories[dir_index as usize];
+        let dir_path = match self.get_name(dir.name) {
+            Some(name) => format!("{}{}/", parent_path, name),
+            None => parent_path.to_string()
+        };
+        let mut next_file = dir.first_file;
+        while next_file != u32::MAX {
+            let file = &self.files[next_file as usize];
+            paths[next_file as usize] = dir_path.clone() + self.get_name(file.name).unwrap_or_default();
+            next_file = file.next_file;
+        }
+        if dir.first_child != u32::MAX {
+            self.add_file_paths(dir.first_child, &dir_path, paths);
+        }
+        if dir.next_sibling != u32::MAX {
+            self.add_file_paths(dir.next_sibling, parent_path, paths);
+        }
+    }
 
-struct TocFlattener {
+    pub fn into_parts(self) -> (Vec<IoDirectoryIndexEntry>, Vec<IoFileIndexEntry>, Vec<String>) {
+        (self.directories, self.files, self.names)
+    }
+}
+
+// Does the work for flatten. Public so sort_chunks can be used on its own
+pub struct TocFlattener<'a> {
     // Used to set the correct directory/file/string indices when flattening TocDirectory tree into Directory Index entries
     io_dir_entries: Vec<IoDirectoryIndexEntry>,
     io_file_entries: Vec<IoFileIndexEntry>,
     entry_names: Vec<String>,
+    ue_version: UeVersion,
+    hasher: &'a dyn Hasher, // chunk id hash of each file's package path
+    root_prefix: Option<&'a str>, // see get_package_path
 }
 
-impl TocFlattener {
-    pub fn flatten(dir: TocDirectorySyncRef) -> (Vec<IoDirectoryIndexEntry>, Vec<IoFileIndexEntry>, Vec<String>) {
+impl<'a> TocFlattener<'a> {
+    fn flatten(dir: TocDirectorySyncRef, ue_version: UeVersion, hasher: &'a dyn Hasher, root_prefix: Option<&'a str>) -> Result<FlattenedToc, TocError> {
         let mut flattener = Self {
             io_dir_entries: vec![],
             io_file_entries: vec![],
             entry_names: vec![],
+            ue_version,
+            hasher,
+            root_prefix,
         };
 
-        flattener.flatten_dir(dir);
+        for path in Self::find_name_collisions(&dir) {
+            log::warn!("{} is both a file and a folder, tools that look files up by path will only find one of them", path);
+        }
+        flattener.flatten_dir(dir)?;
+
+        Ok(FlattenedToc { directories: flattener.io_dir_entries, files: flattener.io_file_entries, names: flattener.entry_names })
+    }
 
-        
-        (flattener.io_dir_entries, flattener.io_file_entries, flattener.entry_names)
+    // Paths of every folder that has a file with exactly the same name next to it. Folders can't do that, but manifests and zips
+    // can (Foo.uasset and Foo.uasset/Bar.uasset). A folder next to a file with the same stem (Foo/ and Foo.uasset) is fine, and
+    // common in cooked content: file entries keep their extension, and their chunk ids are hashed from different paths
+    fn find_name_collisions(dir: &TocDirectorySyncRef) -> Vec<String> {
+        let mut collisions = vec![];
+        let mut file_names = vec![];
+        let mut next_file = dir.read().unwrap().first_file.clone();
+        while let Some(file) = next_file {
+            file_names.push(file.read().unwrap().name.clone());
+            next_file = file.read().unwrap().next.clone();
+        }
+        let mut next_child = dir.read().unwrap().first_child.clone();
+        while let Some(child) = next_child {
+            if child.read().unwrap().name.as_ref().is_some_and(|name| file_names.contains(name)) {
+                collisions.push(TocDirectory::get_path_components(&child).join("/"));
+            }
+            collisions.append(&mut Self::find_name_collisions(&child));
+            next_child = child.read().unwrap().next_sibling.clone();
+        }
+        collisions
     }
 
-    fn flatten_dir(&mut self, dir: TocDirectorySyncRef) {
+    fn flatten_dir(&mut self, dir: TocDirectorySyncRef) -> Result<(), TocError> {
         let mut io_dir_entry = IoDirectoryIndexEntry {
             name: match dir.read().unwrap().name.as_ref() {
                 Some(t) => self.get_name_index(t),
@@ -55,19 +287,8 @@ impl TocFlattener {
         if let Some(first_file) = dir.read().unwrap().first_file.clone() {
             io_dir_entry.first_file = self.io_file_entries.len() as u32;
             
-            let dir_hash_path = {
-                // travel upwards through parents to build hash path
-                // calculate hash after validation so it's easier to remove incorrectly formatted uassets
-                let mut path_comps: Vec<String> = vec![];
-                let mut next_parent = Some(dir.clone());
-                while let Some(curr_parent) = next_parent {
-                    if let Some(t) = curr_parent.read().unwrap().name.as_ref() {
-                        path_comps.insert(0, t.to_owned());
-                    }
-                    next_parent = curr_parent.read().unwrap().parent.upgrade();
-                }
-                path_comps.join("/") + "/"
-            };
+            // calculate hash after validation so it's easier to remove incorrectly formatted uassets
+            let dir_hash_path = TocDirectory::get_path_components(&dir).join("/") + "/";
 
             let mut next_file = Some(first_file);
             while let Some(curr_file) = next_file {
@@ -78,8 +299,9 @@ impl TocFlattener {
                     user_data: self.io_file_entries.len() as u32,
                     file_size: curr_file.file_size,
                     os_path: curr_file.os_file_path.clone(),
-                    chunk_id: TocFlattener::get_file_hash(&dir_hash_path, curr_file.deref())
+                    chunk_id: TocFlattener::get_file_hash(&dir_hash_path, curr_file.deref(), self.ue_version, self.hasher, self.root_prefix)?
                 };
+                log::trace!("{}{} has chunk id {}", dir_hash_path, curr_file.name, flat_file.chunk_id);
                 self.io_file_entries.push(flat_file);
                 next_file = curr_file.next.clone();
             }
@@ -94,7 +316,7 @@ impl TocFlattener {
             let first_child_index = self.io_dir_entries.len() as u32;
             let io_dir_entry = self.io_dir_entries.get_mut(curr_dir_pos).unwrap();
             io_dir_entry.first_child = first_child_index;
-            self.flatten_dir(first_child);
+            self.flatten_dir(first_child)?;
         }
 
         // Then move on to the next sibling
@@ -102,9 +324,32 @@ impl TocFlattener {
             let next_sibling_index = self.io_dir_entries.len() as u32;
             let io_dir_entry = self.io_dir_entries.get_mut(curr_dir_pos).unwrap();
             io_dir_entry.next_sibling = next_sibling_index;
-            self.flatten_dir(next_sibling);
+            self.flatten_dir(next_sibling)?;
         }
+        Ok(())
+    }
 
+    // Order the chunks of the flattened files should be written in, as indices into files. Each file's user_data is updated to
+    // point at its position in that order
+    pub fn sort_chunks(directories: &[IoDirectoryIndexEntry], files: &mut [IoFileIndexEntry], sort_order: FileSortOrder) -> Vec<usize> {
+        let mut chunk_order: Vec<usize> = (0..files.len()).collect();
+        if sort_order == FileSortOrder::Unreal {
+            // files are only linked from their folder's first_file, so walk each folder's list to find which one they're in
+            let mut parents = vec![0; files.len()];
+            for (dir_index, dir) in directories.iter().enumerate() {
+                let mut next_file = dir.first_file;
+                while next_file != u32::MAX {
+                    parents[next_file as usize] = dir_index;
+                    next_file = files[next_file as usize].next_file;
+                }
+            }
+            // stable, so files that tie keep their flatten order
+            chunk_order.sort_by_key(|&i| (files[i].chunk_id.get_type().is_bulk_data(), parents[i], files[i].file_size));
+        }
+        for (chunk_index, &file_index) in chunk_order.iter().enumerate() {
+            files[file_index].user_data = chunk_index as u32;
+        }
+        chunk_order
     }
 
     fn get_name_index(&mut self, test: &str) -> u32 {
@@ -117,45 +362,125 @@ impl TocFlattener {
         }) as u32
     }
 
-    fn get_file_hash(dir_path: &str, curr_file: &TocFile) -> IoChunkId {
-        let (stem, extension) = curr_file.name.split_once('.').expect("Should always be a filename with an extension.");
-        let chunk_type = if SUITABLE_FILE_EXTENSIONS.contains(&extension) {
-            match extension {
-                "uasset" | "umap" => IoChunkType4::ExportBundleData, //.uasset, .umap
-                "ubulk" => IoChunkType4::BulkData, // .ubulk
-                "uptnl" => IoChunkType4::OptionalBulkData, // .uptnl
-                _ => panic!("CRITICAL ERROR: Did not get a supported file extension. This should've been handled earlier")
-            }
-        } else {
-            // this file should've been skipped, see add_folder in asset_collector.rs
-            panic!("CRITICAL ERROR: Did not get a supported file extension. This should've been handled earlier")
-        };
-        let mut dir_path = dir_path.to_string() + stem;
-        if !dir_path.starts_with("Game") {
-            dir_path = "Game/".to_string() + dir_path.split_once('/').unwrap().1;
-        }
-        let path_to_replace_split = dir_path.split_once("/Content").unwrap();
-        let path_to_replace = "/".to_owned() + path_to_replace_split.0 + path_to_replace_split.1;
-        IoChunkId::new(&path_to_replace, chunk_type)
+    // Trees from the collector always have a stem and a chunk type the factory checked, but ones built by hand may not
+    fn get_file_hash(dir_path: &str, curr_file: &TocFile, ue_version: UeVersion, hasher: &dyn Hasher, root_prefix: Option<&str>) -> Result<IoChunkId, TocError> {
+        let no_chunk_type = |reason: String| TocError::NoChunkType(curr_file.os_file_path.clone(), reason);
+        let (stem, _) = ChunkTypeRegistry::split_file_name(&curr_file.name).ok_or_else(|| no_chunk_type("its name has no extension".to_string()))?;
+        let chunk_type = IoChunkType::for_version(curr_file.chunk_type, ue_version)
+            .ok_or_else(|| no_chunk_type(format!("{:?} has no {:?} equivalent", curr_file.chunk_type, ue_version)))?;
+        get_chunk_id(&(dir_path.to_string() + stem), chunk_type, hasher, root_prefix)
     }
 }
 
+// How the blocks of one file are written, worked out by write_compressed_file
+#[derive(Debug, Clone, Copy)]
+struct BlockSettings {
+    compress: bool,
+    level: u32, // zlib level
+    alignment: u32, // in the ucas, see TocFactory::get_block_alignment
+}
+
 pub struct TocFactory {
-    source_folder: String,
+    source: AssetSource,
     use_zlib: bool,
+    zlib_level: u32,
+    compression_method_name: Option<String>, // recorded in the TOC instead of "zlib", see set_compression_method_name
+    compressed_extensions: Option<Vec<String>>, // lowercase, None compresses every file
+    whole_block_extensions: Vec<String>, // lowercase, files stored uncompressed in a single block, see store_as_single_block
     hash_meta: bool,
+    mount_point: String,
+    container_name: String,
+    chunk_types: ChunkTypeRegistry,
     max_compression_block_size: u32,
+    offset_alignment: Option<u32>, // what chunk offsets in IoOffsetAndLength are aligned to, None for max_compression_block_size
     compression_block_alignment: u32,
+    chunk_type_block_alignments: BTreeMap<IoChunkType4, u32>, // in place of compression_block_alignment for these chunk types
+    partition_size: Option<u64>,
+    prune_empty: bool,
+    folder_options: FolderOptions,
+    allow_empty: bool,
+    expected_file_count: Option<usize>, // warn if fewer files than this are packaged
+    strict: bool, // fail if anything in the input was skipped (other than on purpose, see SkipReason::is_requested)
+    profile_files: bool,
+    container_header: bool, // append the container header chunk after the files
+    ue_version: UeVersion,
+    sort_order: FileSortOrder,
+    endianness: Endianness,
+    block_table_on_disk: bool, // collect compression block entries in a temp file instead of memory, see BlockTable
+    block_cache: Option<BlockCache>,
+    chunk_id_hasher: Box<dyn Hasher>,
+    root_prefix: Option<String>, // package path prefix in place of the /Game mapping, see get_package_path
+    cancel_token: Option<Arc<AtomicBool>>, // set from another thread to stop the build, see set_cancel_token
+    #[cfg(feature = "json")]
+    summary_path: Option<String>,
+    #[cfg(feature = "aes")]
+    encryption_key: Option<(AesKey, GUID)>,
+    #[cfg(feature = "zip")]
+    zip_archive: std::cell::RefCell<Option<zip::ZipArchive<File>>>, // opened on the first file written from a zip source
+    #[cfg(feature = "block_crc")]
+    block_crc_path: Option<String>,
+    #[cfg(feature = "block_crc")]
+    block_crcs: std::cell::RefCell<Vec<(u64, usize, u32)>>, // ucas offset, stored size and CRC32 of each block written
 }
 
 impl TocFactory {
     pub fn new(source_folder: String) -> Self {
+        Self::from_source(AssetSource::Folder(source_folder))
+    }
+
+    // Package the files listed in a manifest (see AssetCollector::from_manifest) instead of a folder
+    pub fn from_manifest(manifest_path: String) -> Self {
+        Self::from_source(AssetSource::Manifest(manifest_path))
+    }
+
+    // Package the files inside of a zip archive, which should be laid out the same as an input folder
+    #[cfg(feature = "zip")]
+    pub fn from_zip(archive_path: String) -> Self {
+        Self::from_source(AssetSource::Zip(archive_path))
+    }
+
+    fn from_source(source: AssetSource) -> Self {
         Self { 
-            source_folder,
+            source,
             use_zlib: false,
+            zlib_level: DEFAULT_ZLIB_LEVEL,
+            compression_method_name: None,
+            compressed_extensions: None,
+            whole_block_extensions: vec![],
             hash_meta: false,
-            max_compression_block_size: 0x40000, // default for UE 4.26/4.27 is 0x10000 - used for offset + length offset
-            compression_block_alignment: DEFAULT_COMPRESSION_BLOCK_ALIGNMENT, // 0x800 is default for UE 4.27
+            mount_point: DEFAULT_MOUNT_POINT.to_string(),
+            container_name: DEFAULT_CONTAINER_NAME.to_string(),
+            chunk_types: ChunkTypeRegistry::new(),
+            max_compression_block_size: DEFAULT_COMPRESSION_BLOCK_SIZE, // default for UE 4.26/4.27 is 0x10000 - used for offset + length offset
+            offset_alignment: None,
+            compression_block_alignment: DEFAULT_COMPRESSION_BLOCK_ALIGNMENT,
+            chunk_type_block_alignments: BTreeMap::new(),
+            partition_size: None, // single ucas file
+            prune_empty: false,
+            folder_options: FolderOptions::default(),
+            allow_empty: false,
+            expected_file_count: None,
+            strict: false,
+            profile_files: false,
+            container_header: true,
+            ue_version: UeVersion::UE4_27,
+            sort_order: FileSortOrder::Flatten,
+            endianness: Endianness::Native,
+            block_table_on_disk: false,
+            block_cache: None,
+            chunk_id_hasher: Box::new(Hasher16),
+            root_prefix: None,
+            cancel_token: None,
+            #[cfg(feature = "json")]
+            summary_path: None,
+            #[cfg(feature = "aes")]
+            encryption_key: None,
+            #[cfg(feature = "zip")]
+            zip_archive: std::cell::RefCell::new(None),
+            #[cfg(feature = "block_crc")]
+            block_crc_path: None,
+            #[cfg(feature = "block_crc")]
+            block_crcs: std::cell::RefCell::new(vec![]),
         }
     }
 
@@ -163,43 +488,585 @@ impl TocFactory {
         self.use_zlib = true;
     }
 
+    // ***ADVANCED, CAN PRODUCE CONTAINERS THE GAME CAN'T READ*** Record a different compression method name in the TOC. The
+    // blocks are still compressed with zlib, only the name changes. This is for games that register zlib (or a compatible
+    // decoder) under their own name, where a container that says "zlib" fails to load or is decoded with the wrong method.
+    // The game looks the name up in its compression registry, so a name it doesn't know (or one for a format that can't read
+    // zlib data) makes every compressed chunk unreadable. Needs zlib compression
+    pub fn set_compression_method_name(&mut self, name: &str) {
+        self.compression_method_name = Some(name.to_string());
+    }
+
+    fn get_compression_method_name(&self) -> &str {
+        self.compression_method_name.as_deref().unwrap_or("zlib")
+    }
+
+    // With zlib compression, only compress files with these extensions (e.g. ["ubulk"]) and store the rest uncompressed
+    pub fn compress_only_extensions(&mut self, extensions: Vec<String>) {
+        self.compressed_extensions = Some(extensions.iter().map(|e| e.trim_start_matches('.').to_ascii_lowercase()).collect());
+    }
+
+    // Store files with these extensions (e.g. ["bnk"] for audio banks that are already compressed) as they are, in one
+    // uncompressed block covering the whole file, instead of splitting them into max_compression_block_size blocks. Only for
+    // formats the game streams itself: a chunk is normally read block by block, and the entries that follow a big single
+    // block are empty placeholders (see align_chunk_offset). Files bigger than a block entry can describe (16MB) are split as usual
+    pub fn store_as_single_block(&mut self, extensions: Vec<String>) {
+        self.whole_block_extensions = extensions.iter().map(|e| e.trim_start_matches('.').to_ascii_lowercase()).collect();
+    }
+
     pub fn include_metadata_hashes(&mut self) {
         self.hash_meta = true;
     }
 
-    pub fn write_files<WTOC: Write, WCAS: AlignableStream>(self, mut utoc_stream: &mut WTOC, mut ucas_stream: &mut WCAS) -> Result<(), &'static str> {
-        type EN = byteorder::NativeEndian;
-        let asset_collector = AssetCollector::from_folder(&self.source_folder)?;
+    // Encrypt the ucas blocks and directory index with AES-256, key_guid goes in the TOC header (zero for the primary key)
+    #[cfg(feature = "aes")]
+    pub fn set_encryption_key(&mut self, key: AesKey, key_guid: GUID) {
+        self.encryption_key = Some((key, key_guid));
+    }
+
+    // Name the container is identified by (in UE4.27, the pakchunk name, e.g. pakchunk120). Its hash is used as the container id and
+    // for the container header's chunk id, so containers that are mounted together each need a different name
+    pub fn set_container_name(&mut self, container_name: &str) {
+        self.container_name = container_name.to_string();
+    }
+
+    // Package files with these extensions (e.g. "uexp2" => IoChunkType4::BulkData) on top of the default uasset/umap/ubulk/uptnl
+    // mappings. Extensions are matched case-insensitively
+    pub fn add_chunk_types(&mut self, chunk_types: HashMap<String, IoChunkType4>) {
+        self.chunk_types.extend(chunk_types);
+    }
+
+    // Drop directories with no files anywhere beneath them so they don't take up space in the directory index
+    pub fn prune_empty_directories(&mut self) {
+        self.prune_empty = true;
+    }
+
+    // Collect the files and folders that symlinks in the source folder point to, instead of skipping the links
+    pub fn follow_symlinks(&mut self) {
+        self.folder_options.follow_symlinks = true;
+    }
+
+    // Collect dotfiles and OS metadata files (Thumbs.db, desktop.ini...) in the source folder instead of skipping them
+    pub fn include_hidden_files(&mut self) {
+        self.folder_options.include_hidden = true;
+    }
+
+    // Walk the source folder in name order, so the container doesn't depend on the order the filesystem lists files in
+    pub fn sort_folders(&mut self) {
+        self.folder_options.sorted = true;
+    }
+
+    // Report assets in the source folder that don't have a .ubulk next to them, and bulk data without its asset, as warnings
+    pub fn check_bulk_siblings(&mut self) {
+        self.folder_options.check_bulk_siblings = true;
+    }
+
+    // Only collect files from the source folder whose size is within size_range, e.g. to leave big bulk data in the base
+    // container when making a patch
+    pub fn set_file_size_range(&mut self, size_range: RangeInclusive<u64>) {
+        self.folder_options.size_range = size_range;
+    }
+
+    // Time how long each file takes to compress and write, and show the slowest ones along with the rest of the build timings
+    pub fn profile_files(&mut self) {
+        self.profile_files = true;
+    }
+
+    // Leave out the container header chunk that normally comes after the files. Only for containers that don't need it (e.g.
+    // small patch containers whose packages are already registered by another container's header)
+    pub fn omit_container_header(&mut self) {
+        self.container_header = false;
+    }
+
+    // Engine version to number chunk types for. Only chunk ids change, the TOC and container header are always written the way
+    // UE4.27 expects them
+    pub fn set_ue_version(&mut self, ue_version: UeVersion) {
+        self.ue_version = ue_version;
+    }
+
+    // Write chunks into the ucas in this order instead of the order files appear in the directory index
+    pub fn set_sort_order(&mut self, sort_order: FileSortOrder) {
+        self.sort_order = sort_order;
+    }
+
+    // Byte order to write the container in (and read it in, when updating one)
+    pub fn set_endianness(&mut self, endianness: Endianness) {
+        self.endianness = endianness;
+    }
+
+    // Keep the compression block table in a temp file instead of memory while the ucas is written, for millions of blocks
+    pub fn block_table_on_disk(&mut self) {
+        self.block_table_on_disk = true;
+    }
+
+    // Keep the compressed blocks of every file in cache_dir, and reuse them for files that haven't changed (same path, modified
+    // time and size) the next time a container is built with the same compression settings. Only compressed files are cached
+    pub fn cache_compressed_blocks_in(&mut self, cache_dir: &str) {
+        self.block_cache = Some(BlockCache::new(cache_dir));
+    }
+
+    // Hash package paths into chunk ids with something other than UE's lowercase UTF-16 CityHash64 (Hasher16)
+    pub fn set_chunk_id_hasher(&mut self, hasher: Box<dyn Hasher>) {
+        self.chunk_id_hasher = hasher;
+    }
+
+    // Hash chunk ids from root_prefix followed by each file's path in the input (e.g. MyPlugin/Foo/Bar for Foo/Bar.uasset), instead
+    // of expecting a Project/Content/... layout and mapping it to /Game. See get_package_path
+    pub fn set_root_prefix(&mut self, root_prefix: &str) {
+        self.root_prefix = Some(root_prefix.trim_matches(['/', '\\']).replace('\\', "/"));
+    }
+
+    // Write a container even if no files were collected, rather than returning TocError::NoAssets
+    pub fn allow_empty_container(&mut self) {
+        self.allow_empty = true;
+    }
+
+    // Warn if fewer than count files end up in the container. Catches an input path that points at the wrong folder (or a
+    // .tocignore that matches too much) when most of the expected files were left out, but not all of them
+    pub fn expect_file_count(&mut self, count: usize) {
+        self.expected_file_count = Some(count);
+    }
+
+    // Return TocError::FilesNotPackaged instead of building if any file was skipped or any folder couldn't be read, so a CI
+    // build can't ship a container that's quietly missing something. .tocignore and size range skips are still allowed
+    pub fn fail_on_skipped_files(&mut self) {
+        self.strict = true;
+    }
+
+    // Write a JSON summary of the build (see BuildSummary) to summary_path once the container has been written
+    #[cfg(feature = "json")]
+    pub fn write_summary_to(&mut self, summary_path: String) {
+        self.summary_path = Some(summary_path);
+    }
+
+    // Write the CRC32 of every block's stored bytes (after compression and encryption) to block_crc_path, one
+    // "<ucas offset> <stored size> <crc32>" line per block. There's no room for a checksum in a compression block entry, so
+    // the game never sees these, they're only for checking a ucas with external tools. Much cheaper than hash_meta's SHA1 of
+    // every file, which reads each file a second time: on a 512MB uncompressed build (release, ~0.65s without either),
+    // hash_meta added ~0.45s while block CRCs were within run to run noise.
+    // Blocks reused by update_existing weren't written by this build and aren't listed
+    #[cfg(feature = "block_crc")]
+    pub fn write_block_crcs_to(&mut self, block_crc_path: String) {
+        self.block_crc_path = Some(block_crc_path);
+    }
+
+    // Stop the build once token is set (e.g. from a cancel button on another thread). It's checked before each file is written, so
+    // the build ends within one file of it being set, with TocError::Cancelled. Whatever was written to the outputs by then is
+    // an incomplete container, so they should be written somewhere temporary and thrown away on an error
+    pub fn set_cancel_token(&mut self, token: Arc<AtomicBool>) {
+        self.cancel_token = Some(token);
+    }
+
+    fn check_cancelled(&self) -> Result<(), TocError> {
+        match &self.cancel_token {
+            Some(token) if token.load(Ordering::Relaxed) => Err(TocError::Cancelled),
+            _ => Ok(())
+        }
+    }
+
+    // Split the ucas into multiple partition files once a partition would grow beyond partition_size bytes.
+    // Requires write_files_partitioned so that new partition streams can be opened
+    pub fn set_partition_size(&mut self, partition_size: u64) {
+        self.partition_size = Some(partition_size);
+    }
+
+    // Align the blocks of files with this chunk type to alignment in the ucas, instead of the container's block alignment (e.g. a
+    // small alignment for uassets, which are mostly one small block each, and a big one for bulk data).
+    // The block size itself can't be set per chunk type. UE4.27 finds the block an offset is in by dividing it by the one
+    // CompressionBlockSize in the TOC header, so every block but the last of each chunk has to be exactly that size. Where a block
+    // is stored in the ucas is only ever read from its block entry, so that's free to change between chunks
+    pub fn set_block_alignment_for(&mut self, chunk_type: IoChunkType4, alignment: u32) {
+        self.chunk_type_block_alignments.insert(chunk_type, alignment);
+    }
+
+    // Collect the input and switch to the block size get_auto_block_size picks for it, returning that size. It's brought down
+    // as far as it has to be to still fit the offset alignment and partition size, if those were set
+    pub fn use_auto_block_size(&mut self) -> Result<u32, TocError> {
+        let asset_collector = AssetCollector::from_source(&self.source, &self.chunk_types, &self.folder_options)?;
+        let mut block_size = get_auto_block_size(asset_collector.iter_files().map(|(_, file_size)| file_size));
+        if let Some(offset_alignment) = self.offset_alignment {
+            block_size = block_size.min(offset_alignment);
+        }
+        if let Some(partition_size) = self.partition_size {
+            while block_size > self.get_max_block_alignment() && partition_size % block_size as u64 != 0 {
+                block_size /= 2;
+            }
+        }
+        self.max_compression_block_size = block_size;
+        self.validate()?;
+        Ok(block_size)
+    }
+
+    // Check that the options set on this factory can actually be used by this build. Called by TocFactoryBuilder::build and
+    // before writing anything
+    fn validate(&self) -> Result<(), TocError> {
+        if self.use_zlib && !cfg!(feature = "zlib") {
+            return Err(TocError::InvalidSettings("zlib compression requires building with the zlib feature"));
+        }
+        if self.compressed_extensions.is_some() && !self.use_zlib {
+            return Err(TocError::InvalidSettings("Choosing which file extensions to compress requires zlib compression"));
+        }
+        if self.zlib_level > 9 {
+            return Err(TocError::InvalidSettings("zlib compression level must be between 0 and 9"));
+        }
+        if let Some(name) = &self.compression_method_name {
+            if !self.use_zlib {
+                return Err(TocError::InvalidSettings("A compression method name can only be set with zlib compression"));
+            }
+            // names are stored in a fixed size ANSI field, keep room for the null terminator
+            if name.is_empty() || name.len() >= COMPRESSION_METHOD_NAME_LENGTH as usize || !name.is_ascii() {
+                return Err(TocError::InvalidSettings("Compression method name must be 1 to 31 ASCII characters"));
+            }
+        }
+        if self.block_cache.is_some() && !self.use_zlib {
+            return Err(TocError::InvalidSettings("The block cache only holds compressed blocks, so it needs zlib compression"));
+        }
+        if self.hash_meta && !cfg!(feature = "hash_meta") {
+            return Err(TocError::InvalidSettings("Metadata hashing requires building with the hash_meta feature"));
+        }
+        // align_to and the block layout only work with powers of two
+        if !self.max_compression_block_size.is_power_of_two() {
+            return Err(TocError::InvalidSettings("Compression block size must be a power of two"));
+        }
+        let block_alignments = || std::iter::once(self.compression_block_alignment).chain(self.chunk_type_block_alignments.values().copied());
+        if !block_alignments().all(u32::is_power_of_two) {
+            return Err(TocError::InvalidSettings("Compression block alignment must be a power of two"));
+        }
+        if block_alignments().any(|alignment| alignment < MIN_COMPRESSION_BLOCK_ALIGNMENT) {
+            return Err(TocError::InvalidSettings("Compression block alignment must be at least 16, UE reads every block in whole AES blocks"));
+        }
+        if let Some(offset_alignment) = self.offset_alignment {
+            // chunks can't start partway through a block, since the block before it belongs to another chunk
+            if !offset_alignment.is_power_of_two() || offset_alignment < self.max_compression_block_size {
+                return Err(TocError::InvalidSettings("Offset alignment must be a power of two, no smaller than the compression block size"));
+            }
+        }
+        if self.max_compression_block_size < self.get_max_block_alignment() {
+            return Err(TocError::InvalidSettings("Compression block size can't be smaller than the compression block alignment"));
+        }
+        if self.chunk_types.chunk_types().any(|chunk_type| IoChunkType::for_version(chunk_type, self.ue_version).is_none()) {
+            return Err(TocError::InvalidSettings("A file extension is mapped to a chunk type that has no equivalent for the chosen UE version"));
+        }
+        if self.chunk_types.contains_chunk_type(IoChunkType4::ContainerHeader) {
+            return Err(TocError::InvalidSettings("Files can't be packaged as container header chunks, that chunk is generated"));
+        }
+        if let Some(root_prefix) = &self.root_prefix {
+            if root_prefix.is_empty() || root_prefix.chars().any(char::is_control) || root_prefix.split('/').any(|c| c.is_empty() || c == "." || c == "..") {
+                return Err(TocError::InvalidSettings("Root prefix must be a path like MyPlugin or MyMod/Content, without empty, . or .. parts"));
+            }
+        }
+        if self.container_name.is_empty() {
+            return Err(TocError::InvalidSettings("Container name can't be empty"));
+        }
+        if !self.mount_point.ends_with('/') {
+            return Err(TocError::InvalidSettings("Mount point must end with a '/'"));
+        }
+        // the mount point is written like any other FString in the index (ANSI, or UTF-16 if it isn't ASCII), and a null partway
+        // through would end it early when the engine reads it back, leaving the rest of the index out of step
+        if self.mount_point.chars().any(char::is_control) {
+            return Err(TocError::InvalidSettings("Mount point can't contain null or other control characters"));
+        }
+        if self.mount_point.encode_utf16().count() > MAX_MOUNT_POINT_LENGTH {
+            return Err(TocError::InvalidSettings("Mount point is longer than the engine's path limit (260 characters)"));
+        }
+        if let Some(partition_size) = self.partition_size {
+            if partition_size == 0 || partition_size % self.max_compression_block_size as u64 != 0 {
+                return Err(TocError::InvalidSettings("Partition size must be a non-zero multiple of the compression block size"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_files<WTOC: Write, WCAS: AlignableStream>(self, utoc_stream: &mut WTOC, ucas_stream: &mut WCAS) -> Result<BuildReport, TocError> {
+        self.write_files_partitioned(utoc_stream, ucas_stream, |_| -> io::Result<WCAS> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "Partitioned output requires write_files_partitioned"))
+        })
+    }
+
+    // Same as write_files, but open_partition is called with the partition index (1, 2, ...) whenever the ucas rolls over into a new
+    // partition file. ucas_stream is always partition 0
+    pub fn write_files_partitioned<WTOC, WCAS, F>(self, utoc_stream: &mut WTOC, ucas_stream: &mut WCAS, open_partition: F) -> Result<BuildReport, TocError>
+    where
+        WTOC: Write,
+        WCAS: AlignableStream,
+        F: FnMut(u32) -> io::Result<WCAS>
+    {
+        self.validate()?;
+        let mut ucas_stream = UcasPartitionWriter::new(ucas_stream, open_partition, self.partition_size);
+        self.write_container(utoc_stream, &mut ucas_stream, None)
+    }
+
+    // Print what would be packaged (see AssetCollector::write_tree) without writing a container, e.g. to check that the input
+    // path is the folder that should become the root of the package
+    pub fn write_tree<W: Write>(&self, writer: &mut W) -> Result<(), TocError> {
+        self.validate()?;
+        self.collect()?.write_tree(writer)?;
+        Ok(())
+    }
+
+    // Write the same files as the container into a standard pak (see loose_pak), under the same mount point and paths, so the game
+    // can load them without IoStore. Returns the pak's size
+    #[cfg(feature = "loose")]
+    pub fn write_loose_pak<W: Write>(&self, writer: &mut W) -> Result<u64, TocError> {
+        self.validate()?;
+        let flattened = flatten(self.collect()?.get_toc_tree(), self.ue_version, self.chunk_id_hasher.as_ref(), self.root_prefix.as_deref())?;
+        let mut pak = PakWriter::new(writer, &self.mount_point);
+        for (file, path) in flattened.get_files().iter().zip(flattened.get_file_paths()) {
+            self.check_cancelled()?;
+            pak.add_file(&path, &self.read_whole_file(file)?)?;
+        }
+        Ok(pak.finish()?)
+    }
+
+    // Estimate how big the ucas will be and how long compressing it will take, without writing anything. The first block of each
+    // file that would be compressed is compressed (and thrown away), and the rest of the file is projected from how well it did.
+    // Files that aren't compressed are counted exactly
+    pub fn estimate(&self) -> Result<SizeEstimate, TocError> {
+        self.validate()?;
+        let (_, files, _) = flatten(self.collect()?.get_toc_tree(), self.ue_version, self.chunk_id_hasher.as_ref(), self.root_prefix.as_deref())?.into_parts();
+        let block_size = self.max_compression_block_size as u64;
+        let mut estimate = SizeEstimate { file_count: files.len(), ..Default::default() };
+        let mut data = vec![0u8; block_size as usize];
+        let mut compressed_input_size = 0;
+        let mut sample_time = Duration::ZERO;
+        let mut offset = 0u64;
+        for file in &files {
+            estimate.input_size += file.file_size;
+            let settings = self.get_file_settings(file)?;
+            let block_alignment = self.get_block_alignment(file) as u64;
+            if self.is_stored_whole(file, &settings) {
+                offset = offset.align_to(block_alignment) + self.get_stored_size(file.file_size);
+                continue;
+            }
+            let ratio = if self.should_compress(file, &settings) {
+                let len = self.read_first_block(file, &mut data)?;
+                let started = Instant::now();
+                // stored raw when it doesn't shrink, see write_blocks_from
+                let compressed_len = self.compress_block(&data[..len], settings.level.unwrap_or(self.zlib_level))?.len().min(len);
+                sample_time += started.elapsed();
+                estimate.sampled_size += len as u64;
+                compressed_input_size += file.file_size;
+                compressed_len as f64 / len.max(1) as f64
+            } else {
+                1.0
+            };
+            // same layout as write_block, every block starts on the file's block alignment
+            let mut remaining = file.file_size;
+            while remaining > 0 {
+                let block_len = remaining.min(block_size);
+                offset = offset.align_to(block_alignment) + self.get_stored_size((block_len as f64 * ratio).round() as u64);
+                remaining -= block_len;
+            }
+        }
+        if self.container_header {
+            offset = offset.align_to(block_size) + self.get_stored_size(ContainerHeader::new(0).get_serialized_size());
+        }
+        estimate.ucas_size = offset;
+        if estimate.sampled_size > 0 {
+            estimate.compress_time = sample_time.mul_f64(compressed_input_size as f64 / estimate.sampled_size as f64);
+        }
+        Ok(estimate)
+    }
+
+    // Size of a block once it's in the ucas, which is bigger than the block when it's encrypted
+    fn get_stored_size(&self, block_len: u64) -> u64 {
+        #[cfg(feature = "aes")]
+        if self.is_encrypting() {
+            return block_len.align_to(encryption::AES_BLOCK_SIZE as u64);
+        }
+        block_len
+    }
+
+    // Read up to one compression block from the start of file, returning how much was read
+    fn read_first_block(&self, file: &IoFileIndexEntry, data: &mut [u8]) -> Result<usize, TocError> {
+        #[cfg(feature = "zip")]
+        if let AssetSource::Zip(archive_path) = &self.source {
+            return self.with_zip_entry(archive_path, file, |entry| Ok(Self::read_block(entry, data)?));
+        }
+        Ok(Self::read_block(&mut File::open(&file.os_path)?, data)?)
+    }
+
+    fn collect(&self) -> Result<AssetCollector, TocError> {
+        let mut asset_collector = AssetCollector::from_source(&self.source, &self.chunk_types, &self.folder_options)?;
+        if self.prune_empty {
+            asset_collector.prune_empty_directories();
+        }
         asset_collector.print_stats();
-        let mut profiler = TocBuilderProfiler::new();
+        if self.strict {
+            let profiler = asset_collector.get_profiler();
+            let not_packaged: Vec<(String, String)> = profiler.get_failed_fs_objects()
+                .map(|(os_path, reason)| (os_path.to_string(), reason.to_string()))
+                .chain(profiler.get_skipped_files().filter(|(_, reason)| !reason.is_requested())
+                    .map(|(os_path, reason)| (os_path.to_string(), reason.to_string())))
+                .collect();
+            if !not_packaged.is_empty() {
+                return Err(TocError::FilesNotPackaged(not_packaged));
+            }
+        }
+        Ok(asset_collector)
+    }
+
+    // Make sure none of the output files (stem + .utoc, .ucas, .pak, _s1.ucas...) would end up inside of the folder that's being
+    // packaged, otherwise the next build would pick up (or read from, in the middle of writing it) the container we made last time.
+    // Manifest sources list their files explicitly, so there's nothing to check there
+    pub fn check_output_path(&self, output_stem: &str) -> Result<(), TocError> {
+        let source_folder = match &self.source {
+            AssetSource::Folder(path) => path,
+            AssetSource::Manifest(_) => return Ok(()),
+            #[cfg(feature = "zip")]
+            AssetSource::Zip(_) => return Ok(()),
+        };
+        let source_folder = fs::canonicalize(source_folder).map_err(|_| TocError::InputNotFound(source_folder.to_string()))?;
+        let output_stem = Path::new(output_stem);
+        // the output files don't need to exist yet, but the folder they go in has to
+        let output_folder = match output_stem.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => fs::canonicalize(parent)?,
+            _ => std::env::current_dir()?,
+        };
+        if output_folder.starts_with(&source_folder) {
+            return Err(TocError::OutputInsideInput(output_stem.to_string_lossy().into_owned(), source_folder.to_string_lossy().into_owned()));
+        }
+        Ok(())
+    }
+
+    // Update a container that was built earlier in place. Files that are the same size and haven't been modified since the utoc
+    // was written keep their data in the ucas, everything else is compressed and appended to the end of it. The directory index
+    // is rebuilt from the source, so files that are no longer there are dropped from the TOC (but their data stays in the ucas)
+    pub fn update_existing(self, utoc_path: &str, ucas_path: &str) -> Result<BuildReport, TocError> {
+        self.validate()?;
+        let invalid = |reason: String| TocError::InvalidExistingContainer(utoc_path.to_string(), reason);
+        let mut utoc_reader = BufReader::new(File::open(utoc_path)?);
+        let toc = match self.endianness {
+            Endianness::Native => TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut utoc_reader),
+            Endianness::Little => TocReader::from_buffer::<_, byteorder::LittleEndian>(&mut utoc_reader),
+            Endianness::Big => TocReader::from_buffer::<_, byteorder::BigEndian>(&mut utoc_reader),
+        }.map_err(|e| invalid(e.to_string()))?;
+        if toc.header.get_partition_count() > 1 || self.partition_size.is_some() {
+            return Err(invalid("Partitioned containers can't be updated".to_string()));
+        }
+        if self.is_encrypting() || toc.header.get_container_flags() & io_container_flags::ENCRYPTED != 0 {
+            return Err(invalid("Encrypted containers can't be updated".to_string()));
+        }
+        if toc.header.get_compression_block_size() != self.max_compression_block_size {
+            return Err(invalid(format!("Container was built with a compression block size of {:#x}, not {:#x}", toc.header.get_compression_block_size(), self.max_compression_block_size)));
+        }
+        let compression_methods: Vec<String> = if self.use_zlib { vec![self.get_compression_method_name().to_string()] } else { vec![] };
+        if toc.compression_methods != compression_methods {
+            return Err(invalid(format!("Container was built with compression methods {:?}, not {:?}", toc.compression_methods, compression_methods)));
+        }
+
+        let built_at = fs::metadata(utoc_path)?.modified()?;
+        let mut ucas_file = OpenOptions::new().write(true).open(ucas_path)?;
+        let data_end = ucas_file.seek(SeekFrom::End(0))?;
+        let existing = ExistingContainer::from_toc(&toc, built_at, data_end).map_err(invalid)?;
+
+        // new TOC is built in memory so that the old one is left alone if anything goes wrong
+        let mut utoc_stream = vec![];
+        let mut ucas_stream = UcasPartitionWriter::new(&mut ucas_file, |_| -> io::Result<File> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "Partitioned containers can't be updated"))
+        }, None);
+        let report = self.write_container(&mut utoc_stream, &mut ucas_stream, Some(&existing))?;
+        fs::write(utoc_path, utoc_stream)?;
+        Ok(report)
+    }
+
+    // Collect, flatten and write out the container. With existing set, ucas_stream is positioned at the end of an existing ucas
+    // and unchanged chunks point back at their existing data instead of being written again
+    fn write_container<WTOC, W, F>(&self, utoc_stream: &mut WTOC, ucas_stream: &mut UcasPartitionWriter<W, F>, existing: Option<&ExistingContainer>) -> Result<BuildReport, TocError>
+    where
+        WTOC: Write,
+        W: Write,
+        F: FnMut(u32) -> io::Result<W>
+    {
+        match self.endianness {
+            Endianness::Native => self.write_container_as::<byteorder::NativeEndian, _, _, _>(utoc_stream, ucas_stream, existing),
+            Endianness::Little => self.write_container_as::<byteorder::LittleEndian, _, _, _>(utoc_stream, ucas_stream, existing),
+            Endianness::Big => self.write_container_as::<byteorder::BigEndian, _, _, _>(utoc_stream, ucas_stream, existing),
+        }
+    }
+
+    fn write_container_as<EN, WTOC, W, F>(&self, mut utoc_stream: &mut WTOC, ucas_stream: &mut UcasPartitionWriter<W, F>, existing: Option<&ExistingContainer>) -> Result<BuildReport, TocError>
+    where
+        EN: byteorder::ByteOrder,
+        WTOC: Write,
+        W: Write,
+        F: FnMut(u32) -> io::Result<W>
+    {
+        let asset_collector = self.collect()?;
+        let stats = asset_collector.get_stats();
+        // files left out by a .tocignore or the size range were meant to be, so they don't count towards looking wrong
+        let unrequested_skips = asset_collector.get_profiler().get_skipped_files().filter(|(_, reason)| !reason.is_requested()).count();
+        #[cfg(feature = "json")]
+        let skipped_files: Vec<BuildSummarySkippedFile> = asset_collector.get_skipped_files().into_iter()
+            .map(|(os_path, reason)| BuildSummarySkippedFile { os_path: os_path.to_string(), reason: reason.to_string() })
+            .collect();
+        let mut profiler = TocBuilderProfiler::new(self.profile_files);
         let (
             directories,
-            files,
+            mut files,
             names
-        ) = TocFlattener::flatten(asset_collector.get_toc_tree());
+        ) = flatten(asset_collector.get_toc_tree(), self.ue_version, self.chunk_id_hasher.as_ref(), self.root_prefix.as_deref())?.into_parts();
         profiler.set_flatten_time();
+        if files.is_empty() && !self.allow_empty {
+            // almost always means the input path was wrong, so don't write a container with only a container header in it
+            return Err(TocError::NoAssets(self.source.get_path().to_string()));
+        }
+        for warning in self.get_small_container_warnings(&files, unrequested_skips) {
+            log::warn!("{}", warning);
+        }
+        Self::check_duplicate_chunk_ids(&files)?;
+        self.check_container_size(files.iter().map(|file| file.file_size))?;
+        if self.ue_version != UeVersion::UE4_27 && self.container_header {
+            log::warn!("The container header is written in the UE4.27 format, which UE5 can't read. Use --no-container-header if it isn't needed");
+        }
+        let chunk_order = TocFlattener::sort_chunks(&directories, &mut files, self.sort_order);
+        // read every sidecar before anything is written, so a broken one doesn't stop the build halfway through
+        let file_settings = files.iter().map(|file| self.get_file_settings(file)).collect::<Result<Vec<_>, _>>()?;
+        for (file, _) in files.iter().zip(&file_settings).filter(|(file, settings)| settings.compress.is_none() && file.file_size > COMPRESSED_BLOCK_SIZE_MAX as u64 && self.has_extension_in(file, &self.whole_block_extensions)) {
+            log::warn!("{} is too big to store in a single block ({} bytes, the most is {}), splitting it into blocks instead", file.os_path, file.file_size, COMPRESSED_BLOCK_SIZE_MAX);
+        }
 
-        let toc_name_hash = Hasher16::get_cityhash64("pakchunk999"); // This can be anything - in UE4.27, this is the pakchunk number, e.g. pakchunk120
-        let mount_point = "../../../";
+        // A container is identified by the CityHash64 of its name alone, nothing random or timestamped goes into it
+        let toc_name_hash = Hasher16::get_cityhash64(&self.container_name);
+        let mount_point = self.mount_point.as_str();
 
         // CAS STUFF
         let container_header = ContainerHeader::new(toc_name_hash);
-        let mut compression_blocks = vec![];
+        let mut compression_blocks = if self.block_table_on_disk { BlockTable::on_disk()? } else { BlockTable::in_memory() };
         let mut offsets_and_lengths = vec![];
         let mut metas = vec![];
         let mut uncompressed_offset = 0u64;
-        let mut compressed_offset = 0u64;
-        for file in files.iter() {
+        let mut compressed_offset = existing.map_or(0, |existing| existing.get_data_end());
+        // keep the collection totals and where the build got to, so a failure halfway through a big build can be tracked down
+        let write_failed = |file_index: usize, file: &IoFileIndexEntry, error: TocError| {
+            TocError::WriteFailed(Box::new(WriteFailure { stats, file_index, os_path: file.os_path.clone(), error }))
+        };
+        for (file_index, (file, settings)) in chunk_order.iter().map(|&i| (&files[i], &file_settings[i])).enumerate() {
+            self.check_cancelled()?;
             // File offsets and lengths relates to uncompressed data
-            uncompressed_offset = uncompressed_offset.align_to(self.max_compression_block_size);
+            self.align_chunk_offset(&mut uncompressed_offset, &mut compression_blocks, compressed_offset)?;
             offsets_and_lengths.push(IoOffsetAndLength::new(uncompressed_offset, file.file_size));
             uncompressed_offset += file.file_size;
 
+            if let Some(existing_chunk) = existing.and_then(|existing| existing.get_unchanged_chunk(file)) {
+                log::debug!("{} is unchanged, reusing its existing data", file.os_path);
+                profiler.add_chunk(file.chunk_id.get_type(), &existing_chunk.compression_blocks);
+                compression_blocks.extend(existing_chunk.compression_blocks.iter().cloned())?;
+                metas.push(existing_chunk.meta.clone());
+                continue;
+            }
+
             // Compression splits the file into "max_compression_block_size" sized chunks and compresses them.
-            // These compressed chunks are then written to the file one by one, with chunk start locations aligned to compression_block_alignment
+            // These compressed chunks are then written to the file one by one, with chunk start locations aligned to the file's block alignment
             // This is what goes into the compression_blocks array - chunk start, then compressed size, then uncompressed size
-            let mut compressed_chunks = self.write_compressed_file(&file, &mut compressed_offset, ucas_stream);
-            compression_blocks.append(&mut compressed_chunks);
+            let file_started = profiler.is_profiling_files().then(Instant::now);
+            let compressed_chunks = self.write_compressed_file(&file, settings, &mut compressed_offset, ucas_stream)
+                .map_err(|e| write_failed(file_index, file, e))?;
+            if let Some(file_started) = file_started {
+                profiler.add_file_time(&file.os_path, file_started);
+            }
+            profiler.add_chunk(file.chunk_id.get_type(), &compressed_chunks);
+            compression_blocks.extend(compressed_chunks)?;
 
             // Seems like everything was still loading fine even without the header packages here?
             // if file.chunk_id.get_type() == IoChunkType4::ExportBundleData {
@@ -215,118 +1082,794 @@ impl TocFactory {
 
             if self.hash_meta {
                 #[cfg(feature = "hash_meta")]
-                metas.push(IoStoreTocEntryMeta::new_with_hash(&mut File::open(std::path::Path::new(&file.os_path)).unwrap())); // Generate meta - SHA1 hash of the file's contents (doesn't seem to be required)
+                metas.push(self.hash_file(file).map_err(|e| write_failed(file_index, file, e))?); // Generate meta - SHA1 hash of the file's contents (doesn't seem to be required)
             } else {
                 metas.push(IoStoreTocEntryMeta::new_empty()); // Empty meta seems to work okay
             }
         }
 
         //Container header is last thing to write to file
-        let container_header = container_header.to_buffer::<WCAS, EN>(&mut ucas_stream).unwrap(); // write our container header in the buffer
-        offsets_and_lengths.push(IoOffsetAndLength::new(uncompressed_offset.align_to(self.max_compression_block_size), container_header.len() as u64));
-        ucas_stream.align_to(&mut compressed_offset, self.max_compression_block_size);
-        ucas_stream.write(&container_header);
-        compression_blocks.push(IoStoreTocCompressedBlockEntry::new(compressed_offset, container_header.len() as u32, container_header.len() as u32, 0));
+        if self.container_header {
+            let container_header_size = container_header.get_serialized_size();
+            self.align_chunk_offset(&mut uncompressed_offset, &mut compression_blocks, compressed_offset)?;
+            offsets_and_lengths.push(IoOffsetAndLength::new(uncompressed_offset, container_header_size));
+            ucas_stream.align_to(&mut compressed_offset, self.max_compression_block_size);
+            // the bytes are only kept around when they need to be encrypted or hashed, otherwise they go straight into the ucas
+            let container_header_bytes = if self.is_encrypting() || self.hash_meta {
+                Some(container_header.to_buffer::<_, EN>(&mut io::sink())?)
+            } else {
+                None
+            };
+            match container_header_bytes.as_ref() {
+                Some(container_header_bytes) => {
+                    let stored_container_header = self.encrypt_block(container_header_bytes);
+                    ucas_stream.reserve(&mut compressed_offset, stored_container_header.len() as u64)?;
+                    ucas_stream.write_all(&stored_container_header)?;
+                },
+                None => {
+                    ucas_stream.reserve(&mut compressed_offset, container_header_size)?;
+                    container_header.to_stream::<_, EN>(ucas_stream)?;
+                }
+            }
+            compression_blocks.push(IoStoreTocCompressedBlockEntry::new(compressed_offset, container_header_size as u32, container_header_size as u32, 0))?;
 
-        if self.hash_meta {
-            #[cfg(feature = "hash_meta")]
-            metas.push(IoStoreTocEntryMeta::new_with_hash(&mut std::io::Cursor::new(container_header))); // Generate meta - SHA1 hash of the file's contents (doesn't seem to be required)
-        } else {
-            metas.push(IoStoreTocEntryMeta::new_empty()); // Empty meta seems to work okay
+            if self.hash_meta {
+                #[cfg(feature = "hash_meta")]
+                metas.push(IoStoreTocEntryMeta::new_with_hash(&mut std::io::Cursor::new(container_header_bytes.unwrap()))?); // Generate meta - SHA1 hash of the file's contents (doesn't seem to be required)
+            } else {
+                metas.push(IoStoreTocEntryMeta::new_empty()); // Empty meta seems to work okay
+            }
         }
 
         // TOC STUFF
         // Get DirectoryIndexSize = mount point + Directory Entries + File Entries + Strings
         // Each section contains a u32 to note the object count
-        let mount_point_bytes = (mem::size_of::<u32>() + mount_point.len() + 1) as u32;
+        // The index is never compressed, neither 4.27 nor UE5 has a header flag for it (Compressed only refers to chunk data)
+        let mount_point_bytes = FString32NoHash::get_expected_length(mount_point) as u32;
         let directory_index_bytes = (directories.len() * std::mem::size_of::<IoDirectoryIndexEntry>() + mem::size_of::<u32>()) as u32;
         let file_index_bytes = (files.len() * IO_FILE_INDEX_ENTRY_SERIALIZED_SIZE + mem::size_of::<u32>()) as u32;
         let mut string_index_bytes = mem::size_of::<u32>() as u32;
         names.iter().for_each(|name| string_index_bytes += FString32NoHash::get_expected_length(name) as u32);
         let directory_index_size = mount_point_bytes + directory_index_bytes + file_index_bytes + string_index_bytes;
 
-        let toc_header = IoStoreTocHeaderType3::new(
+        // Checked against directory_index_size, since a mismatch would make the engine read the wrong bytes as the index
+        let mut directory_index = Vec::with_capacity(directory_index_size as usize);
+        FString32NoHash::to_buffer::                    <_, EN>(mount_point, &mut directory_index)?; // Mount Point
+        IoDirectoryIndexEntry::list_to_buffer::         <_, EN>(&directories, &mut directory_index)?; // FIoDirectoryIndexEntry
+        IoFileIndexEntry::list_to_buffer::              <_, EN>(&files, &mut directory_index)?; // FIoFileIndexEntry
+        IoStringPool::list_to_buffer::                  <_, EN>(&names, &mut directory_index)?; // FIoStringIndexEntry
+        if directory_index.len() != directory_index_size as usize {
+            return Err(TocError::DirectoryIndexSizeMismatch(directory_index_size, directory_index.len()));
+        }
+        // the engine decrypts the index of an encrypted container, the header gets the padded size
+        let directory_index = self.encrypt_block(&directory_index).into_owned();
+
+        let mut toc_header = IoStoreTocHeaderType3::new(
             toc_name_hash, 
-            files.len() as u32 + 1, // + 1 for container header
+            files.len() as u32 + self.container_header as u32, // + 1 for container header
             compression_blocks.len() as u32,
             if self.use_zlib { 1 } else { 0 },
             self.max_compression_block_size,
-            directory_index_size
+            directory_index.len() as u32
         );
+        if let Some(partition_size) = self.partition_size {
+            toc_header.set_partitions(ucas_stream.partition_count(), partition_size);
+        }
+        #[cfg(feature = "aes")]
+        if let Some((_, key_guid)) = self.encryption_key {
+            toc_header.set_encryption_key_guid(key_guid);
+        }
         // FIoStoreTocHeader
-        toc_header.to_buffer::                          <WTOC, EN>(&mut utoc_stream).unwrap(); // FIoStoreTocHeader
-        IoChunkId::list_to_buffer::                     <WTOC, EN>(&files.iter().map(|f| f.chunk_id).chain([IoChunkId::new_from_hash(toc_name_hash, IoChunkType4::ContainerHeader)]).collect(), &mut utoc_stream).unwrap(); // FIoChunkId
-        IoOffsetAndLength::list_to_buffer::             <WTOC, EN>(&offsets_and_lengths, &mut utoc_stream).unwrap(); // FIoOffsetAndLength
-        IoStoreTocCompressedBlockEntry::list_to_buffer::<WTOC, EN>(&compression_blocks, &mut utoc_stream).unwrap(); // FIoStoreTocCompressedBlockEntry
+        toc_header.to_buffer::                          <WTOC, EN>(&mut utoc_stream)?; // FIoStoreTocHeader
+        IoChunkId::list_to_buffer::                     <WTOC, EN>(&chunk_order.iter().map(|&i| files[i].chunk_id).chain(self.container_header.then(|| self.get_container_header_chunk_id(toc_name_hash))).collect(), &mut utoc_stream)?; // FIoChunkId
+        IoOffsetAndLength::list_to_buffer::             <WTOC, EN>(&offsets_and_lengths, &mut utoc_stream)?; // FIoOffsetAndLength
+        compression_blocks.write_to(&mut utoc_stream)?; // FIoStoreTocCompressedBlockEntry
         if self.use_zlib {
             let mut compression_names = [0u8; COMPRESSION_METHOD_NAME_LENGTH as usize];
-            compression_names[..4].copy_from_slice(b"zlib");
-            utoc_stream.write(&compression_names).unwrap();
+            let name = self.get_compression_method_name().as_bytes();
+            compression_names[..name.len()].copy_from_slice(name);
+            utoc_stream.write_all(&compression_names)?;
         }
         // compression methods go here if we want to do any compressing
-        FString32NoHash::to_buffer::                    <WTOC, EN>(mount_point, &mut utoc_stream).unwrap(); // Mount Point
-        IoDirectoryIndexEntry::list_to_buffer::         <WTOC, EN>(&directories, &mut utoc_stream).unwrap(); // FIoDirectoryIndexEntry
-        IoFileIndexEntry::list_to_buffer::              <WTOC, EN>(&files, &mut utoc_stream).unwrap(); // FIoFileIndexEntry
-        IoStringPool::list_to_buffer::                  <WTOC, EN>(&names, &mut utoc_stream).unwrap(); // FIoStringIndexEntry
-        IoStoreTocEntryMeta::list_to_buffer::           <WTOC, EN>(&metas, &mut utoc_stream).unwrap(); // FIoStoreTocEntryMeta
+        utoc_stream.write_all(&directory_index)?;
+        IoStoreTocEntryMeta::list_to_buffer::           <WTOC, EN>(&metas, &mut utoc_stream)?; // FIoStoreTocEntryMeta
 
         profiler.set_serialize_time();
         profiler.display_results();
+        let report = BuildReport {
+            stats,
+            ucas_size: existing.map_or(0, |existing| existing.get_data_end()) + ucas_stream.get_bytes_written(),
+            partition_count: ucas_stream.partition_count(),
+        };
 
-        Ok(())
-    }
-
-    fn write_compressed_file<W: AlignableStream>(&self, file: &IoFileIndexEntry, offset: &mut u64, destination: &mut W) -> Vec<IoStoreTocCompressedBlockEntry> {
-        let compression_block_count = (file.file_size / self.max_compression_block_size as u64) + 1; // need at least 1 compression block
-        let mut gen_blocks = Vec::with_capacity(compression_block_count as usize);
-        let compression_method = if self.use_zlib { 1 } else { 0 };
-
-        let mut reader = File::open(&file.os_path).unwrap();
-        let mut data = vec![0u8; self.max_compression_block_size as usize];
-        while let Ok(len) = reader.read(&mut data) {
-            if len == 0 { break }
-
-            #[allow(unused_mut)]
-            let mut compressed_len = len;
-
-            #[cfg(feature = "zlib")]
-            if self.use_zlib {
-                let mut e = ZlibEncoder::new(Vec::with_capacity(self.max_compression_block_size as usize), Compression::default());
-                e.write_all(&data[..len]).unwrap();
-                let compressed_bytes = e.finish().unwrap();
-
-                compressed_len = compressed_bytes.len();
-                data[..compressed_len].copy_from_slice(&compressed_bytes);
+        #[cfg(feature = "block_crc")]
+        if let Some(block_crc_path) = self.block_crc_path.as_ref() {
+            let mut crc_stream = io::BufWriter::new(File::create(block_crc_path)?);
+            for (offset, stored_size, crc) in self.block_crcs.borrow().iter() {
+                writeln!(crc_stream, "{:#x} {:#x} {:08x}", offset, stored_size, crc)?;
             }
+            crc_stream.flush()?;
+        }
 
-            destination.align_to(offset, self.compression_block_alignment);
-            gen_blocks.push(IoStoreTocCompressedBlockEntry::new(*offset, compressed_len as u32, len as u32, compression_method));
-            *offset += destination.write(&data[..compressed_len]).unwrap() as u64;
+        #[cfg(feature = "json")]
+        if let Some(summary_path) = self.summary_path.as_ref() {
+            BuildSummary {
+                file_count: files.len(),
+                directory_count: directories.len(),
+                compression_method: if self.use_zlib { "zlib" } else { "None" },
+                compression_block_size: self.max_compression_block_size,
+                total_file_size: files.iter().map(|f| f.file_size).sum(),
+                total_compressed_size: compression_blocks.get_compressed_size(),
+                ucas_size: report.ucas_size,
+                files: files.iter().map(|f| BuildSummaryFile { os_path: f.os_path.clone(), chunk_id: f.chunk_id.to_hex(), size: f.file_size }).collect(),
+                skipped_files,
+            }.write_to(summary_path)?;
         }
 
-        gen_blocks
+        Ok(report)
     }
-}
 
-// TODO: Set the mount point further up in mods where the file structure doesn't diverge at root
+    // Move uncompressed_offset up to the next chunk start, with empty entries for skipped blocks (UE indexes blocks by offset)
+    fn align_chunk_offset(&self, uncompressed_offset: &mut u64, compression_blocks: &mut BlockTable, compressed_offset: u64) -> io::Result<()> {
+        *uncompressed_offset = uncompressed_offset.align_to(self.offset_alignment.unwrap_or(self.max_compression_block_size));
+        let first_block = (*uncompressed_offset / self.max_compression_block_size as u64) as usize;
+        while compression_blocks.len() < first_block {
+            compression_blocks.push(IoStoreTocCompressedBlockEntry::new(compressed_offset, 0, 0, 0))?;
+        }
+        Ok(())
+    }
 
+    fn get_container_header_chunk_id(&self, toc_name_hash: u64) -> IoChunkId {
+        let chunk_type = IoChunkType::for_version(IoChunkType4::ContainerHeader, self.ue_version).expect("Every UE version has a container header");
+        IoChunkId::new_from_hash_with_type(toc_name_hash, chunk_type)
+    }
+
+    // An empty container is an error (TocError::NoAssets), but a container with only a few of the files that were meant to be in
+    // it builds fine and then does nothing in game. These usually mean the input path is wrong, so they're warned about loudly
+    fn get_small_container_warnings(&self, files: &[IoFileIndexEntry], unrequested_skips: usize) -> Vec<String> {
+        let mut warnings = vec![];
+        if files.is_empty() {
+            return warnings;
+        }
+        if let Some(expected) = self.expected_file_count.filter(|expected| files.len() < *expected) {
+            warnings.push(format!("Only {} file(s) were packaged, fewer than the {} expected. Check the input path", files.len(), expected));
+        }
+        if unrequested_skips > files.len() {
+            warnings.push(format!("Only {} of the {} files found were packaged, the rest were skipped. Check the input path and the skipped files above", files.len(), files.len() + unrequested_skips));
+        }
+        let total_size: u64 = files.iter().map(|file| file.file_size).sum();
+        if total_size < SMALL_CONTAINER_SIZE {
+            warnings.push(format!("The packaged files only add up to {} bytes, which is very small for a mod. Check the input path", total_size));
+        }
+        warnings
+    }
+
+    // Files whose paths normalize to the same string (e.g. case-variant folders) get the same chunk id, and only one would load
+    fn check_duplicate_chunk_ids(files: &[IoFileIndexEntry]) -> Result<(), TocError> {
+        let mut paths_by_chunk_id: BTreeMap<IoChunkId, Vec<String>> = BTreeMap::new();
+        for file in files {
+            paths_by_chunk_id.entry(file.chunk_id).or_default().push(file.os_path.clone());
+        }
+        let duplicates: Vec<Vec<String>> = paths_by_chunk_id.into_values().filter(|paths| paths.len() > 1).collect();
+        if !duplicates.is_empty() {
+            return Err(TocError::DuplicateChunkIds(duplicates));
+        }
+        Ok(())
+    }
+
+    // Offsets and lengths are stored in 5 bytes and the header's block count in a u32, so anything past either would wrap around
+    // and leave chunks pointing at the wrong data. With the smallest block sizes (under 0x100) the block count runs out first,
+    // otherwise it's the 5 byte offset (1 TiB of chunk data, including the padding between chunks). Checked before anything
+    // is written, so an oversized build fails straight away. The ucas has the same 1 TiB cap on its block offsets, counting
+    // the gaps partitions leave (partition N starts at N * partition_size), which write_block checks as blocks are written
+    fn check_container_size(&self, chunk_sizes: impl Iterator<Item = u64>) -> Result<(), TocError> {
+        let offset_alignment = self.offset_alignment.unwrap_or(self.max_compression_block_size) as u64;
+        let mut end = 0u64;
+        for chunk_size in chunk_sizes.chain(self.container_header.then(|| ContainerHeader::new(0).get_serialized_size())) {
+            end = end.saturating_add(offset_alignment - 1) / offset_alignment * offset_alignment;
+            end = end.saturating_add(chunk_size);
+        }
+        let block_count = end.div_ceil(self.max_compression_block_size as u64);
+        if end > IO_OFFSET_LENGTH_MAX || block_count > u32::MAX as u64 {
+            return Err(TocError::ContainerTooLarge(end, block_count));
+        }
+        Ok(())
+    }
+
+    fn write_compressed_file<W, F>(&self, file: &IoFileIndexEntry, settings: &FileSettings, offset: &mut u64, destination: &mut UcasPartitionWriter<W, F>) -> Result<Vec<IoStoreTocCompressedBlockEntry>, TocError>
+    where
+        W: Write,
+        F: FnMut(u32) -> io::Result<W>
+    {
+        let compression_block_count = (file.file_size / self.max_compression_block_size as u64) + 1; // need at least 1 compression block
+        let mut gen_blocks = Vec::with_capacity(compression_block_count as usize);
+        let compress = self.should_compress(file, settings);
+        let level = settings.level.unwrap_or(self.zlib_level);
+        let alignment = self.get_block_alignment(file);
+        let block_settings = BlockSettings { compress, level, alignment };
+        #[allow(unused_variables)]
+        let compression_method = if compress { 1 } else { 0 };
+
+        if self.is_stored_whole(file, settings) {
+            let data = self.read_whole_file(file)?;
+            gen_blocks.push(self.write_block(&data, data.len(), 0, alignment, offset, destination)?);
+            return Ok(gen_blocks);
+        }
+
+        #[cfg(feature = "zip")]
+        if let AssetSource::Zip(archive_path) = &self.source {
+            gen_blocks.append(&mut self.with_zip_entry(archive_path, file, |mut entry| self.write_blocks_from(&mut entry, block_settings, offset, destination, None))?);
+            return Ok(gen_blocks);
+        }
+
+        // Uncompressed files aren't worth caching, they'd only be copied out of one file instead of another
+        let cache_entry = match self.block_cache.as_ref().filter(|_| compress) {
+            Some(cache) => Some((cache, BlockCache::get_key(&file.os_path, &format!("{:#x} zlib {}", self.max_compression_block_size, level))?)),
+            None => None
+        };
+        if let Some(cached_blocks) = cache_entry.as_ref().and_then(|(cache, key)| cache.get(key)) {
+            log::debug!("{} is unchanged, using its cached blocks", file.os_path);
+            for block in cached_blocks {
+                gen_blocks.push(self.write_block(&block.data, block.uncompressed_len, block.compression_method, alignment, offset, destination)?);
+            }
+            return Ok(gen_blocks);
+        }
+
+        // Without compression, blocks can be written straight out of the mapped file instead of being copied into a buffer first.
+        // Empty files are left to the buffered path since they can't be mapped (and produce no blocks anyway)
+        #[cfg(feature = "mmap")]
+        if !compress && file.file_size > 0 {
+            let source = File::open(&file.os_path)?;
+            // safety: input files aren't expected to be modified while they're being packaged
+            let mapped = unsafe { memmap2::Mmap::map(&source)? };
+            for block in mapped.chunks(self.max_compression_block_size as usize) {
+                gen_blocks.push(self.write_block(block, block.len(), compression_method, alignment, offset, destination)?);
+            }
+            return Ok(gen_blocks);
+        }
+
+        let mut reader = File::open(&file.os_path)?;
+        let mut new_cached_blocks = cache_entry.is_some().then(Vec::new);
+        gen_blocks.append(&mut self.write_blocks_from(&mut reader, block_settings, offset, destination, new_cached_blocks.as_mut())?);
+        if let (Some((cache, key)), Some(new_cached_blocks)) = (cache_entry, new_cached_blocks) {
+            // the container is fine without it, the file just gets compressed again next time
+            if let Err(e) = cache.insert(&key, &new_cached_blocks) {
+                log::warn!("Couldn't add {} to the block cache: {}", file.os_path, e);
+            }
+        }
+        Ok(gen_blocks)
+    }
+
+    // All of a file's bytes, from wherever the source keeps them
+    fn read_whole_file(&self, file: &IoFileIndexEntry) -> Result<Vec<u8>, TocError> {
+        match &self.source {
+            #[cfg(feature = "zip")]
+            AssetSource::Zip(archive_path) => self.with_zip_entry(archive_path, file, |entry| {
+                let mut data = Vec::with_capacity(file.file_size as usize);
+                entry.read_to_end(&mut data)?;
+                Ok(data)
+            }),
+            _ => Ok(fs::read(&file.os_path)?)
+        }
+    }
+
+    // Meta with the SHA1 of a file's bytes, from wherever the source keeps them
+    #[cfg(feature = "hash_meta")]
+    fn hash_file(&self, file: &IoFileIndexEntry) -> Result<IoStoreTocEntryMeta, TocError> {
+        match &self.source {
+            #[cfg(feature = "zip")]
+            AssetSource::Zip(archive_path) => self.with_zip_entry(archive_path, file, |mut entry| Ok(IoStoreTocEntryMeta::new_with_hash(&mut entry)?)),
+            _ => Ok(IoStoreTocEntryMeta::new_with_hash(&mut File::open(&file.os_path)?)?)
+        }
+    }
+
+    // Zip sources read each file out of the archive, which is kept open between files
+    #[cfg(feature = "zip")]
+    fn with_zip_entry<T>(&self, archive_path: &str, file: &IoFileIndexEntry, read: impl FnOnce(&mut dyn Read) -> Result<T, TocError>) -> Result<T, TocError> {
+        let mut archive = self.zip_archive.borrow_mut();
+        if archive.is_none() {
+            *archive = Some(zip::ZipArchive::new(File::open(archive_path)?).map_err(|e| TocError::InvalidZip(archive_path.to_string(), e.to_string()))?);
+        }
+        let mut entry = archive.as_mut().unwrap().by_name(&file.os_path).map_err(|e| TocError::InvalidZip(archive_path.to_string(), e.to_string()))?;
+        read(&mut entry)
+    }
+
+    // Split everything in reader into compression blocks and write them. Every block except the last has to be exactly
+    // max_compression_block_size, so short reads are topped up before a block is written. Blocks are also copied into cached_blocks
+    // when it's given, to be stored in the block cache
+    fn write_blocks_from<R, W, F>(&self, reader: &mut R, settings: BlockSettings, offset: &mut u64, destination: &mut UcasPartitionWriter<W, F>, mut cached_blocks: Option<&mut Vec<CachedBlock>>) -> Result<Vec<IoStoreTocCompressedBlockEntry>, TocError>
+    where
+        R: Read,
+        W: Write,
+        F: FnMut(u32) -> io::Result<W>
+    {
+        let mut gen_blocks = vec![];
+        let mut data = vec![0u8; self.max_compression_block_size as usize];
+        loop {
+            let len = Self::read_block(reader, &mut data)?;
+            if len == 0 { break }
+
+            // blocks that don't get smaller (level 0, already compressed data) are stored raw, like the engine does
+            let compressed = if settings.compress { Some(self.compress_block(&data[..len], settings.level)?).filter(|c| c.len() < len) } else { None };
+            let (block, compression_method) = match &compressed {
+                Some(compressed) => (&compressed[..], 1),
+                None => (&data[..len], 0)
+            };
+
+            if let Some(cached_blocks) = cached_blocks.as_mut() {
+                cached_blocks.push(CachedBlock { data: block.to_vec(), uncompressed_len: len, compression_method });
+            }
+            gen_blocks.push(self.write_block(block, len, compression_method, settings.alignment, offset, destination)?);
+        }
+        Ok(gen_blocks)
+    }
+
+    // Fill data from reader, stopping early only at the end of the reader. Returns how much was read
+    fn read_block<R: Read + ?Sized>(reader: &mut R, data: &mut [u8]) -> io::Result<usize> {
+        let mut len = 0;
+        while len < data.len() {
+            match reader.read(&mut data[len..])? {
+                0 => break,
+                read => len += read
+            }
+        }
+        Ok(len)
+    }
+
+    #[cfg(feature = "zlib")]
+    fn compress_block(&self, block: &[u8], level: u32) -> io::Result<Vec<u8>> {
+        let mut e = ZlibEncoder::new(Vec::with_capacity(self.max_compression_block_size as usize), Compression::new(level));
+        e.write_all(block)?;
+        e.finish()
+    }
+
+    // Only called for files that should_compress says yes to, which it never does without zlib
+    #[cfg(not(feature = "zlib"))]
+    fn compress_block(&self, block: &[u8], _level: u32) -> io::Result<Vec<u8>> {
+        Ok(block.to_vec())
+    }
+
+    // Whether file is written as one uncompressed block (see store_as_single_block). Files too big for a single block entry
+    // are split into blocks like any other, and a sidecar that sets compression takes the file out of it
+    fn is_stored_whole(&self, file: &IoFileIndexEntry, settings: &FileSettings) -> bool {
+        settings.compress.is_none() && file.file_size <= COMPRESSED_BLOCK_SIZE_MAX as u64 && self.has_extension_in(file, &self.whole_block_extensions)
+    }
+
+    fn has_extension_in(&self, file: &IoFileIndexEntry, extensions: &[String]) -> bool {
+        Path::new(&file.os_path).extension().is_some_and(|e| extensions.contains(&e.to_string_lossy().to_ascii_lowercase()))
+    }
+
+    // Blocks that aren't compressed are stored with method 0, which the game reads as-is even when the container has compression
+    // methods. The extension comes from the source file on disk. A sidecar's setting wins over the extension rules
+    fn should_compress(&self, file: &IoFileIndexEntry, settings: &FileSettings) -> bool {
+        if !self.use_zlib || self.is_stored_whole(file, settings) {
+            return false;
+        }
+        if let Some(compress) = settings.compress {
+            return compress;
+        }
+        match &self.compressed_extensions {
+            Some(extensions) => self.has_extension_in(file, extensions),
+            None => true
+        }
+    }
+
+    // The file's sidecar settings (see FileSettings). Files in a zip don't have sidecars, there's no file next to them to read
+    fn get_file_settings(&self, file: &IoFileIndexEntry) -> Result<FileSettings, TocError> {
+        #[cfg(feature = "zip")]
+        if let AssetSource::Zip(_) = &self.source {
+            return Ok(FileSettings::default());
+        }
+        let settings = FileSettings::for_file(&file.os_path)?;
+        if settings.compress == Some(true) && !self.use_zlib {
+            // the container has no compression method for the block to use
+            return Err(TocError::InvalidFileSettings(FileSettings::get_sidecar_path(&file.os_path), "compression = zlib needs compression to be turned on (-z)".to_string()));
+        }
+        Ok(settings)
+    }
+
+    // Alignment of file's blocks in the ucas, see set_block_alignment_for
+    fn get_block_alignment(&self, file: &IoFileIndexEntry) -> u32 {
+        self.chunk_type_block_alignments.iter()
+            .find(|(&chunk_type, _)| IoChunkType::for_version(chunk_type, self.ue_version) == Some(file.chunk_id.get_type()))
+            .map_or(self.compression_block_alignment, |(_, &alignment)| alignment)
+    }
+
+    fn get_max_block_alignment(&self) -> u32 {
+        self.chunk_type_block_alignments.values().copied().fold(self.compression_block_alignment, u32::max)
+    }
+
+    // Write a single (already compressed) block at the next offset aligned to alignment, returning its compression block entry
+    fn write_block<W, F>(&self, block: &[u8], uncompressed_len: usize, compression_method: u8, alignment: u32, offset: &mut u64, destination: &mut UcasPartitionWriter<W, F>) -> Result<IoStoreTocCompressedBlockEntry, TocError>
+    where
+        W: Write,
+        F: FnMut(u32) -> io::Result<W>
+    {
+        if block.len().max(uncompressed_len) > IO_COMPRESSED_BLOCK_LENGTH_MAX as usize {
+            return Err(TocError::BlockTooLarge(block.len().max(uncompressed_len)));
+        }
+        let stored_block = self.encrypt_block(block);
+        destination.align_to(offset, alignment);
+        destination.reserve(offset, stored_block.len() as u64)?;
+        let end = *offset + stored_block.len() as u64;
+        if end > IO_OFFSET_LENGTH_MAX + 1 {
+            return Err(TocError::UcasTooLarge(end));
+        }
+        let block_entry = IoStoreTocCompressedBlockEntry::new(*offset, block.len() as u32, uncompressed_len as u32, compression_method);
+        destination.write_all(&stored_block)?;
+        #[cfg(feature = "block_crc")]
+        if self.block_crc_path.is_some() {
+            self.block_crcs.borrow_mut().push((*offset, stored_block.len(), crc32fast::hash(&stored_block)));
+        }
+        *offset += stored_block.len() as u64;
+        Ok(block_entry)
+    }
+
+    fn is_encrypting(&self) -> bool {
+        #[cfg(feature = "aes")]
+        if self.encryption_key.is_some() {
+            return true;
+        }
+        false
+    }
+
+    // Encrypted after compression, padded as described at MIN_COMPRESSION_BLOCK_ALIGNMENT
+    fn encrypt_block<'a>(&self, block: &'a [u8]) -> Cow<'a, [u8]> {
+        #[cfg(feature = "aes")]
+        if let Some((key, _)) = self.encryption_key.as_ref() {
+            return Cow::Owned(encryption::encrypt_padded(key, block));
+        }
+        Cow::Borrowed(block)
+    }
+}
+
+// Chainable way to set up a TocFactory. build() returns an error if the chosen options don't work together or need a feature that
+// this build doesn't have, instead of failing (or silently doing nothing) once files are being written
+pub struct TocFactoryBuilder {
+    factory: TocFactory,
+}
+
+impl TocFactoryBuilder {
+    pub fn new(source_folder: String) -> Self {
+        Self { factory: TocFactory::new(source_folder) }
+    }
+
+    pub fn from_manifest(manifest_path: String) -> Self {
+        Self { factory: TocFactory::from_manifest(manifest_path) }
+    }
+
+    #[cfg(feature = "zip")]
+    pub fn from_zip(archive_path: String) -> Self {
+        Self { factory: TocFactory::from_zip(archive_path) }
+    }
+
+    // level is 0 (store) to 9 (best compression)
+    pub fn zlib(mut self, level: u32) -> Self {
+        self.factory.use_zlib_compression();
+        self.factory.zlib_level = level;
+        self
+    }
+
+    pub fn chunk_types(mut self, chunk_types: HashMap<String, IoChunkType4>) -> Self {
+        self.factory.add_chunk_types(chunk_types);
+        self
+    }
+
+    // See TocFactory::set_compression_method_name, only for games that don't call zlib "zlib"
+    pub fn compression_method_name(mut self, name: &str) -> Self {
+        self.factory.set_compression_method_name(name);
+        self
+    }
+
+    // See TocFactory::store_as_single_block
+    pub fn single_block_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.factory.store_as_single_block(extensions);
+        self
+    }
+
+    // See TocFactory::compress_only_extensions, needs zlib to be turned on as well
+    pub fn compressed_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.factory.compress_only_extensions(extensions);
+        self
+    }
+
+    pub fn container_name(mut self, container_name: &str) -> Self {
+        self.factory.set_container_name(container_name);
+        self
+    }
+
+    pub fn mount_point(mut self, mount_point: &str) -> Self {
+        self.factory.mount_point = mount_point.to_string();
+        self
+    }
+
+    // Maximum uncompressed size of each compression block. Files are also aligned to this in the uncompressed address space
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.factory.max_compression_block_size = block_size;
+        self
+    }
+
+    // Alignment of chunk offsets in the uncompressed address space, a multiple of the block size (the default)
+    pub fn offset_alignment(mut self, alignment: u32) -> Self {
+        self.factory.offset_alignment = Some(alignment);
+        self
+    }
+
+    // Compression blocks start at a multiple of this in the ucas. Must be a power of two, from MIN_COMPRESSION_BLOCK_ALIGNMENT up to
+    // the block size
+    pub fn block_alignment(mut self, alignment: u32) -> Self {
+        self.factory.compression_block_alignment = alignment;
+        self
+    }
+
+    // Block alignment for files of one chunk type, see TocFactory::set_block_alignment_for
+    pub fn block_alignment_for(mut self, chunk_type: IoChunkType4, alignment: u32) -> Self {
+        self.factory.set_block_alignment_for(chunk_type, alignment);
+        self
+    }
+
+    pub fn meta_hashing(mut self, enabled: bool) -> Self {
+        self.factory.hash_meta = enabled;
+        self
+    }
+
+    pub fn partition_size(mut self, partition_size: u64) -> Self {
+        self.factory.set_partition_size(partition_size);
+        self
+    }
+
+    pub fn prune_empty(mut self, enabled: bool) -> Self {
+        self.factory.prune_empty = enabled;
+        self
+    }
+
+    pub fn follow_symlinks(mut self, enabled: bool) -> Self {
+        self.factory.folder_options.follow_symlinks = enabled;
+        self
+    }
+
+    pub fn include_hidden(mut self, enabled: bool) -> Self {
+        self.factory.folder_options.include_hidden = enabled;
+        self
+    }
+
+    pub fn sorted(mut self, enabled: bool) -> Self {
+        self.factory.folder_options.sorted = enabled;
+        self
+    }
+
+    pub fn check_bulk_siblings(mut self, enabled: bool) -> Self {
+        self.factory.folder_options.check_bulk_siblings = enabled;
+        self
+    }
+
+    pub fn file_size_range(mut self, size_range: RangeInclusive<u64>) -> Self {
+        self.factory.set_file_size_range(size_range);
+        self
+    }
+
+    pub fn profile_files(mut self, enabled: bool) -> Self {
+        self.factory.profile_files = enabled;
+        self
+    }
+
+    pub fn container_header(mut self, enabled: bool) -> Self {
+        self.factory.container_header = enabled;
+        self
+    }
+
+    pub fn ue_version(mut self, ue_version: UeVersion) -> Self {
+        self.factory.set_ue_version(ue_version);
+        self
+    }
+
+    pub fn sort_order(mut self, sort_order: FileSortOrder) -> Self {
+        self.factory.set_sort_order(sort_order);
+        self
+    }
+
+    pub fn endianness(mut self, endianness: Endianness) -> Self {
+        self.factory.set_endianness(endianness);
+        self
+    }
+
+    pub fn block_table_on_disk(mut self, enabled: bool) -> Self {
+        self.factory.block_table_on_disk = enabled;
+        self
+    }
+
+    pub fn block_cache(mut self, cache_dir: &str) -> Self {
+        self.factory.cache_compressed_blocks_in(cache_dir);
+        self
+    }
+
+    // See TocFactory::set_root_prefix
+    pub fn root_prefix(mut self, root_prefix: &str) -> Self {
+        self.factory.set_root_prefix(root_prefix);
+        self
+    }
+
+    // See TocFactory::set_cancel_token
+    pub fn cancel_token(mut self, token: Arc<AtomicBool>) -> Self {
+        self.factory.set_cancel_token(token);
+        self
+    }
+
+    pub fn chunk_id_hasher(mut self, hasher: Box<dyn Hasher>) -> Self {
+        self.factory.set_chunk_id_hasher(hasher);
+        self
+    }
+
+    pub fn allow_empty(mut self, enabled: bool) -> Self {
+        self.factory.allow_empty = enabled;
+        self
+    }
+
+    // See TocFactory::expect_file_count
+    pub fn expected_files(mut self, count: usize) -> Self {
+        self.factory.expect_file_count(count);
+        self
+    }
+
+    pub fn strict(mut self, enabled: bool) -> Self {
+        self.factory.strict = enabled;
+        self
+    }
+
+    #[cfg(feature = "json")]
+    pub fn json_summary(mut self, summary_path: String) -> Self {
+        self.factory.write_summary_to(summary_path);
+        self
+    }
+
+    #[cfg(feature = "block_crc")]
+    pub fn block_crcs(mut self, block_crc_path: String) -> Self {
+        self.factory.write_block_crcs_to(block_crc_path);
+        self
+    }
+
+    #[cfg(feature = "aes")]
+    pub fn encryption_key(mut self, key: AesKey, key_guid: GUID) -> Self {
+        self.factory.set_encryption_key(key, key_guid);
+        self
+    }
+
+    pub fn build(self) -> Result<TocFactory, TocError> {
+        self.factory.validate()?;
+        Ok(self.factory)
+    }
+}
+
+// Routes ucas writes into the current partition file. Offsets handed to reserve are in UE's virtual partition space
+// (partition index * partition size + offset into the partition), which is what compression block entries record
+struct UcasPartitionWriter<'a, W: Write, F: FnMut(u32) -> io::Result<W>> {
+    first: &'a mut W,
+    current: Option<W>,
+    open_partition: F,
+    partition_index: u32,
+    partition_size: u64,
+    bytes_written: u64, // across every partition, this build only
+}
+
+impl<'a, W: Write, F: FnMut(u32) -> io::Result<W>> UcasPartitionWriter<'a, W, F> {
+    fn new(first: &'a mut W, open_partition: F, partition_size: Option<u64>) -> Self {
+        Self {
+            first,
+            current: None,
+            open_partition,
+            partition_index: 0,
+            partition_size: partition_size.unwrap_or(u64::MAX),
+            bytes_written: 0
+        }
+    }
+
+    // Move on to the next partition if length bytes at offset won't fit in the current one. A block is never split between partitions,
+    // so offset jumps to the start of the new partition. Blocks larger than an entire partition are left where they are
+    fn reserve(&mut self, offset: &mut u64, length: u64) -> io::Result<()> {
+        let partition_start = self.partition_index as u64 * self.partition_size;
+        let partition_offset = *offset - partition_start;
+        if partition_offset > 0 && partition_offset.saturating_add(length) > self.partition_size {
+            self.partition_index += 1;
+            self.current = Some((self.open_partition)(self.partition_index)?);
+            *offset = self.partition_index as u64 * self.partition_size;
+        }
+        Ok(())
+    }
+
+    fn partition_count(&self) -> u32 {
+        self.partition_index + 1
+    }
+
+    fn get_bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+}
+
+impl<'a, W: Write, F: FnMut(u32) -> io::Result<W>> Write for UcasPartitionWriter<'a, W, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = match self.current.as_mut() {
+            Some(partition) => partition.write(buf),
+            None => self.first.write(buf)
+        }?;
+        self.bytes_written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.current.as_mut() {
+            Some(partition) => partition.flush(),
+            None => self.first.flush()
+        }
+    }
+}
+
+// TODO: Set the mount point further up in mods where the file structure doesn't diverge at root
+
+
+pub const PROFILED_FILES_SHOWN: usize = 10;
 
 pub struct TocBuilderProfiler {
     // All file sizes are in bytes
     start_time: Instant,
     time_to_flatten: u128,
-    time_to_serialize: u128
+    time_to_serialize: u128,
+    file_times: Option<Vec<(String, u128)>>, // os path and time taken to compress + write it, only when profiling files
+    chunk_type_sizes: BTreeMap<IoChunkType, ChunkTypeSizes>,
+}
+
+// What the files of one chunk type added up to, before and after compression
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChunkTypeSizes {
+    pub file_count: u64,
+    pub uncompressed_size: u64,
+    pub compressed_size: u64, // sum of the block entries' sizes, so without padding between blocks or for encryption
 }
 
 impl TocBuilderProfiler {
-    pub fn new() -> Self {
+    pub fn new(profile_files: bool) -> Self {
         Self {
             start_time: Instant::now(),
             time_to_flatten: 0,
-            time_to_serialize: 0
+            time_to_serialize: 0,
+            file_times: if profile_files { Some(vec![]) } else { None },
+            chunk_type_sizes: BTreeMap::new(),
         }
     }
 
+    fn is_profiling_files(&self) -> bool {
+        self.file_times.is_some()
+    }
+    fn add_file_time(&mut self, os_path: &str, started: Instant) {
+        if let Some(file_times) = self.file_times.as_mut() {
+            file_times.push((os_path.to_string(), started.elapsed().as_micros()));
+        }
+    }
+
+    fn add_chunk(&mut self, chunk_type: IoChunkType, blocks: &[IoStoreTocCompressedBlockEntry]) {
+        let sizes = self.chunk_type_sizes.entry(chunk_type).or_default();
+        sizes.file_count += 1;
+        sizes.uncompressed_size += blocks.iter().map(|block| block.get_uncompressed_size() as u64).sum::<u64>();
+        sizes.compressed_size += blocks.iter().map(|block| block.get_compressed_size() as u64).sum::<u64>();
+    }
+
+    pub fn get_chunk_type_sizes(&self) -> &BTreeMap<IoChunkType, ChunkTypeSizes> {
+        &self.chunk_type_sizes
+    }
+
+    // One line per chunk type, e.g. to see that bulk data compresses well while uassets barely shrink
+    fn get_chunk_type_table(&self) -> Vec<String> {
+        let mut table = vec![format!("{:<24}{:>8}{:>16}{:>16}{:>8}", "Chunk type", "Files", "Uncompressed", "Compressed", "Ratio")];
+        for (chunk_type, sizes) in &self.chunk_type_sizes {
+            let name = match chunk_type {
+                IoChunkType::UE4(chunk_type) => format!("{:?}", chunk_type),
+                IoChunkType::UE5(chunk_type) => format!("{:?}", chunk_type),
+            };
+            let ratio = sizes.compressed_size as f64 * 100.0 / sizes.uncompressed_size.max(1) as f64;
+            table.push(format!("{:<24}{:>8}{:>13} KB{:>13} KB{:>7.1}%", name, sizes.file_count, sizes.uncompressed_size / 1024, sizes.compressed_size / 1024, ratio));
+        }
+        table
+    }
+
     fn set_flatten_time(&mut self) {
         self.time_to_flatten = self.start_time.elapsed().as_micros();
     }
@@ -335,7 +1878,784 @@ impl TocBuilderProfiler {
     }
     fn display_results(&self) {
         // TODO: Advanced display results
-        println!("Flatten Time: {} ms", self.time_to_flatten as f64 / 1000f64);
-        println!("Serialize Time: {} ms", self.time_to_serialize as f64 / 1000f64);
+        log::info!("Flatten Time: {} ms", self.time_to_flatten as f64 / 1000f64);
+        log::info!("Serialize Time: {} ms", self.time_to_serialize as f64 / 1000f64);
+        if !self.chunk_type_sizes.is_empty() {
+            for line in self.get_chunk_type_table() {
+                log::info!("{}", line);
+            }
+        }
+        if let Some(file_times) = self.file_times.as_ref() {
+            let mut slowest: Vec<&(String, u128)> = file_times.iter().collect();
+            slowest.sort_by(|a, b| b.1.cmp(&a.1));
+            log::info!("Slowest files:");
+            for (os_path, time) in slowest.into_iter().take(PROFILED_FILES_SHOWN) {
+                log::info!("    {} ms: {}", *time as f64 / 1000f64, os_path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asset_collector::TocDirectoryBuilder, test_fixture::TempDir};
+    use byteorder::ReadBytesExt;
+    use crate::string::FStringDeserializer;
+    use crate::asset_collector::TocFileSyncRef;
+
+    #[test]
+    fn block_layout_must_be_powers_of_two() {
+        let build = |block_size: u32, alignment: u32| TocFactoryBuilder::new(String::new()).block_size(block_size).block_alignment(alignment).build();
+
+        assert!(build(0x40000, 0x10).is_ok());
+        assert!(build(0x800, 0x800).is_ok());
+        for (block_size, alignment) in [(0x30000, 0x10), (0, 0x10), (0x40000, 0x30), (0x40000, 0), (0x400, 0x800), (0x40000, 0x8)] {
+            assert!(matches!(build(block_size, alignment), Err(TocError::InvalidSettings(_))), "block size {:#x}, alignment {:#x}", block_size, alignment);
+        }
+        // encrypted or not, UE reads whole AES blocks
+        let small_type_alignment = TocFactoryBuilder::new(String::new()).block_alignment_for(IoChunkType4::BulkData, 0x8).build();
+        assert!(matches!(small_type_alignment, Err(TocError::InvalidSettings(_))));
+    }
+
+    #[test]
+    fn auto_block_size_follows_typical_file_size() {
+        assert_eq!(get_auto_block_size([]), DEFAULT_COMPRESSION_BLOCK_SIZE);
+        // mostly headers, the one big file doesn't count for much
+        assert_eq!(get_auto_block_size([0x800, 0x1200, 0x3000, 0x4000000]), MIN_AUTO_BLOCK_SIZE);
+        assert_eq!(get_auto_block_size([0x1000, 0x30000, 0x50000, 0x60000]), 0x80000);
+        assert_eq!(get_auto_block_size([0x800, 0x4000000, 0x8000000]), MAX_AUTO_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn container_header_can_be_omitted() {
+        let folder = TempDir::with_content("no-container-header", &[("Bar.ubulk", &[1u8; 0x10])]);
+        let output_dir = TempDir::new("no-container-header-out");
+        let output = output_dir.join("Mod");
+        let (utoc_path, ucas_path) = (output.with_extension("utoc"), output.with_extension("ucas"));
+
+        for container_header in [true, false] {
+            let factory = TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).container_header(container_header).build().unwrap();
+            factory.write_files(&mut File::create(&utoc_path).unwrap(), &mut File::create(&ucas_path).unwrap()).unwrap();
+            let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut BufReader::new(File::open(&utoc_path).unwrap())).unwrap();
+
+            let chunk_count = 1 + container_header as usize;
+            assert_eq!(toc.chunk_ids.len(), chunk_count);
+            assert_eq!(toc.offsets_and_lengths.len(), chunk_count);
+            assert_eq!(toc.compression_blocks.len(), chunk_count);
+            assert_eq!(toc.metas.len(), chunk_count);
+            assert_eq!(toc.chunk_ids.iter().any(|id| id.get_type() == IoChunkType::UE4(IoChunkType4::ContainerHeader)), container_header);
+        }
+    }
+
+    #[test]
+    fn container_header_is_written_once() {
+        let folder = TempDir::with_content("container-header-once", &[("Bar.ubulk", &[1u8; 0x13])]);
+        let output_dir = TempDir::new("container-header-once-out");
+        let output = output_dir.join("Mod");
+        let (utoc_path, ucas_path) = (output.with_extension("utoc"), output.with_extension("ucas"));
+
+        let factory = TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).build().unwrap();
+        factory.write_files(&mut File::create(&utoc_path).unwrap(), &mut File::create(&ucas_path).unwrap()).unwrap();
+        let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut BufReader::new(File::open(&utoc_path).unwrap())).unwrap();
+        let ucas = fs::read(&ucas_path).unwrap();
+
+        let expected = ContainerHeader::new(Hasher16::get_cityhash64(DEFAULT_CONTAINER_NAME)).to_buffer::<_, byteorder::NativeEndian>(&mut io::sink()).unwrap();
+        assert_eq!(expected.len() as u64, ContainerHeader::new(0).get_serialized_size());
+        // file data, padding up to the next compression block, then the header and nothing else
+        let header_offset = toc.compression_blocks.last().unwrap().get_offset() as usize;
+        assert_eq!(header_offset, toc.header.get_compression_block_size() as usize);
+        assert_eq!(&ucas[header_offset..], &expected[..]);
+    }
+
+    #[cfg(all(feature = "zip", feature = "hash_meta"))]
+    #[test]
+    fn zip_entries_can_be_hashed() {
+        let folder = TempDir::new("zip-hash-meta");
+        let archive_path = folder.join("Mod.zip");
+        let data: Vec<u8> = (0..0x30u8).collect();
+        let mut writer = zip::ZipWriter::new(File::create(&archive_path).unwrap());
+        writer.start_file("P3R/Content/Bar.ubulk", zip::write::SimpleFileOptions::default()).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.finish().unwrap();
+        let output = folder.join("Out");
+        let (utoc_path, ucas_path) = (output.with_extension("utoc"), output.with_extension("ucas"));
+
+        let factory = TocFactoryBuilder::from_zip(archive_path.to_str().unwrap().to_string()).meta_hashing(true).container_header(false).build().unwrap();
+        factory.write_files(&mut File::create(&utoc_path).unwrap(), &mut File::create(&ucas_path).unwrap()).unwrap();
+        let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut BufReader::new(File::open(&utoc_path).unwrap())).unwrap();
+
+        let to_bytes = |meta: &IoStoreTocEntryMeta| {
+            let mut bytes = vec![];
+            meta.to_buffer::<_, byteorder::NativeEndian>(&mut bytes).unwrap();
+            bytes
+        };
+        assert_eq!(toc.metas.len(), 1);
+        assert_eq!(to_bytes(&toc.metas[0]), to_bytes(&IoStoreTocEntryMeta::new_with_hash(&mut data.as_slice()).unwrap()));
+    }
+
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "disk full"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Like stdout or a pipe, there's no Seek
+    struct WriteOnly(Vec<u8>);
+    impl Write for WriteOnly {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn ucas_can_be_written_without_seeking() {
+        let folder = TempDir::with_content("write-only", &[("A.ubulk", &[1u8; 0x10]), ("B.ubulk", &[2u8; 0x900])]);
+
+        let build = || TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).block_size(0x800).build().unwrap();
+        let (mut utoc, mut ucas) = (vec![], vec![]);
+        let report = build().write_files(&mut utoc, &mut ucas).unwrap();
+        assert_eq!(report.ucas_size, ucas.len() as u64);
+        let (mut streamed_utoc, mut streamed_ucas) = (vec![], WriteOnly(vec![]));
+        build().write_files(&mut streamed_utoc, &mut streamed_ucas).unwrap();
+        assert_eq!(utoc, streamed_utoc);
+        assert_eq!(ucas, streamed_ucas.0);
+    }
+
+    #[test]
+    fn write_failure_keeps_collection_stats() {
+        let folder = TempDir::with_content("write-failure", &[("A.ubulk", &[1u8; 0x10]), ("B.ubulk", &[2u8; 0x10])]);
+
+        let result = TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).build().unwrap().write_files(&mut vec![], &mut FullDisk);
+        let Err(TocError::WriteFailed(failure)) = result else { panic!("expected WriteFailed, got {:?}", result) };
+        assert_eq!(failure.stats.file_count, 2);
+        assert_eq!(failure.file_index, 0);
+        assert!(failure.os_path.ends_with(".ubulk"));
+        assert!(matches!(failure.error, TocError::Io(_)));
+    }
+
+    #[test]
+    fn chunk_type_sizes_are_added_up() {
+        let mut profiler = TocBuilderProfiler::new(false);
+        let bulk = IoChunkType::UE4(IoChunkType4::BulkData);
+        profiler.add_chunk(bulk, &[IoStoreTocCompressedBlockEntry::new(0, 0x400, 0x1000, 1), IoStoreTocCompressedBlockEntry::new(0x400, 0x200, 0x800, 1)]);
+        profiler.add_chunk(bulk, &[IoStoreTocCompressedBlockEntry::new(0x600, 0x400, 0x400, 0)]);
+        profiler.add_chunk(IoChunkType::UE4(IoChunkType4::ExportBundleData), &[IoStoreTocCompressedBlockEntry::new(0xa00, 0x800, 0x800, 0)]);
+
+        assert_eq!(profiler.get_chunk_type_sizes()[&bulk], ChunkTypeSizes { file_count: 2, uncompressed_size: 0x1c00, compressed_size: 0xa00 });
+        let table = profiler.get_chunk_type_table();
+        // header, then one line per chunk type in chunk type order
+        assert_eq!(table.len(), 3);
+        assert!(table[1].starts_with("ExportBundleData") && table[1].ends_with("100.0%"));
+        assert!(table[2].starts_with("BulkData") && table[2].ends_with("35.7%"));
+    }
+
+    #[test]
+    fn cancelled_build_stops_before_the_next_file() {
+        let folder = TempDir::with_content("cancel", &[("A.ubulk", &[1u8; 0x10])]);
+
+        let token = Arc::new(AtomicBool::new(true));
+        let factory = TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).cancel_token(token.clone()).build().unwrap();
+        let (mut utoc, mut ucas) = (vec![], vec![]);
+        let result = factory.write_files(&mut utoc, &mut ucas);
+        token.store(false, Ordering::Relaxed);
+        let not_cancelled = TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).cancel_token(token).build().unwrap().write_files(&mut vec![], &mut vec![]);
+
+        assert!(matches!(result, Err(TocError::Cancelled)));
+        assert!(utoc.is_empty() && ucas.is_empty());
+        assert!(not_cancelled.is_ok());
+    }
+
+    #[test]
+    fn strict_mode_fails_on_skipped_files() {
+        let folder = TempDir::with_content("strict", &[("A.ubulk", &[1u8; 0x10]), ("B.uasset.bak", &[2u8; 0x10]), (".tocignore", b"C.ubulk\n"), ("C.ubulk", &[3u8; 0x10])]);
+        let content = folder.join("P3R").join("Content");
+
+        let build = |strict| TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).strict(strict).build().unwrap().write_files(&mut vec![], &mut vec![]);
+        let lenient = build(false);
+        let strict = build(true);
+        fs::remove_file(content.join("B.uasset.bak")).unwrap();
+        let only_ignored = build(true);
+
+        assert!(lenient.is_ok());
+        let Err(TocError::FilesNotPackaged(files)) = strict else { panic!("expected FilesNotPackaged, got {:?}", strict) };
+        assert_eq!(files.len(), 1);
+        assert!(files[0].0.ends_with("B.uasset.bak"));
+        assert!(only_ignored.is_ok());
+    }
+
+    #[test]
+    fn endianness_flips_header_fields() {
+        let folder = TempDir::with_content("endianness", &[("A.ubulk", &[1u8; 0x10])]);
+
+        let build = |endianness| {
+            let mut utoc = vec![];
+            TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).endianness(endianness).build().unwrap().write_files(&mut utoc, &mut vec![]).unwrap();
+            utoc
+        };
+        let (little, big, native) = (build(Endianness::Little), build(Endianness::Big), build(Endianness::Native));
+        assert_eq!(little.len(), big.len());
+        assert_eq!(little[..0x14], big[..0x14]); // magic, version and padding
+        assert_eq!(little[0x14..0x18], 0x90u32.to_le_bytes()); // toc_header_size
+        assert_eq!(big[0x14..0x18], 0x90u32.to_be_bytes());
+        assert_eq!(native, if cfg!(target_endian = "little") { little } else { big });
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn block_cache_skips_unchanged_files() {
+        let input = TempDir::with_content("block-cache", &[("A.ubulk", &[1u8; 0x2000])]);
+        let cache_dir = TempDir::new("block-cache-dir");
+        let source = input.join("P3R").join("Content").join("A.ubulk");
+
+        let build = || {
+            let (mut utoc, mut ucas) = (vec![], vec![]);
+            TocFactoryBuilder::new(input.to_str().unwrap().to_string())
+                .zlib(6).block_size(0x800).block_cache(cache_dir.to_str().unwrap()).build().unwrap()
+                .write_files(&mut utoc, &mut ucas).unwrap();
+            (utoc, ucas)
+        };
+        let first = build();
+        // same size and modified time, so the cached blocks of the old contents get used
+        let modified = fs::metadata(&source).unwrap().modified().unwrap();
+        fs::write(&source, [2u8; 0x2000]).unwrap();
+        File::options().write(true).open(&source).unwrap().set_modified(modified).unwrap();
+        let cached = build();
+        File::options().write(true).open(&source).unwrap().set_modified(modified + std::time::Duration::from_secs(1)).unwrap();
+        let rebuilt = build();
+
+        assert_eq!(first, cached);
+        assert_ne!(first.1, rebuilt.1);
+    }
+
+    #[test]
+    fn non_ascii_mount_point_is_written_as_utf16() {
+        let folder = TempDir::with_content("mount-point", &[("A.ubulk", &[1u8; 0x10])]);
+        let source = folder.to_str().unwrap().to_string();
+
+        let mount_point = "../../../Caf\u{e9}/";
+        let mut utoc = vec![];
+        TocFactoryBuilder::new(source.clone()).mount_point(mount_point).build().unwrap().write_files(&mut utoc, &mut vec![]).unwrap();
+        let too_long = TocFactoryBuilder::new(source.clone()).mount_point(&"a/".repeat(MAX_MOUNT_POINT_LENGTH / 2 + 1)).build();
+        let with_null = TocFactoryBuilder::new(source).mount_point("../\0../").build();
+
+        // directory_index_size has to cover the UTF-16 mount point, or the rest of the index would be read from the wrong place
+        let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut std::io::Cursor::new(utoc)).unwrap();
+        let mut index = std::io::Cursor::new(&toc.directory_index);
+        assert_eq!(FString32NoHash::from_buffer::<_, byteorder::NativeEndian>(&mut index).unwrap().as_deref(), Some(mount_point));
+        assert_eq!(index.read_u32::<byteorder::NativeEndian>().unwrap(), 3); // root, P3R and Content
+        assert!(matches!(too_long, Err(TocError::InvalidSettings(_))));
+        assert!(matches!(with_null, Err(TocError::InvalidSettings(_))));
+    }
+
+    #[test]
+    fn small_containers_are_warned_about() {
+        let file = |size| IoFileIndexEntry { name: 0, next_file: u32::MAX, user_data: 0, file_size: size, os_path: String::new(), chunk_id: IoChunkId::new_from_hash(0, IoChunkType4::BulkData) };
+        let factory = TocFactoryBuilder::new(String::new()).expected_files(3).build().unwrap();
+        let big = [file(0x10000), file(0x10000), file(0x10000)];
+        assert!(factory.get_small_container_warnings(&big, 3).is_empty());
+        assert_eq!(factory.get_small_container_warnings(&big[..2], 0).len(), 1); // fewer than expected
+        assert_eq!(factory.get_small_container_warnings(&big, 4).len(), 1); // most files skipped
+        assert_eq!(factory.get_small_container_warnings(&[file(0x10), file(0x10), file(0x10)], 0).len(), 1); // tiny
+        assert!(factory.get_small_container_warnings(&[], 10).is_empty()); // TocError::NoAssets covers that
+    }
+
+    #[test]
+    fn estimate_matches_build_when_every_block_is_sampled() {
+        let folder = TempDir::with_content("estimate", &[("A.ubulk", &[1u8; 0x123]), ("B.ubulk", &(0..0x2345).map(|i| (i % 7) as u8).collect::<Vec<u8>>())]);
+
+        // uncompressed files are counted exactly, and so are compressed files that fit in their first block
+        let check = |builder: TocFactoryBuilder| {
+            let factory = builder.build().unwrap();
+            let estimate = factory.estimate().unwrap();
+            let (mut utoc, mut ucas) = (vec![], vec![]);
+            factory.write_files(&mut utoc, &mut ucas).unwrap();
+            assert_eq!(estimate.file_count, 2);
+            assert_eq!(estimate.input_size, 0x123 + 0x2345);
+            assert_eq!(estimate.ucas_size, ucas.len() as u64);
+            estimate
+        };
+        let source = folder.to_str().unwrap().to_string();
+        assert_eq!(check(TocFactoryBuilder::new(source.clone()).block_size(0x1000)).sampled_size, 0);
+        #[cfg(feature = "zlib")]
+        assert_eq!(check(TocFactoryBuilder::new(source.clone()).zlib(9).block_size(0x4000)).sampled_size, 0x123 + 0x2345);
+        #[cfg(feature = "zlib")]
+        assert_eq!(check(TocFactoryBuilder::new(source).zlib(0).block_size(0x4000)).sampled_size, 0x123 + 0x2345); // nothing shrinks
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn compression_method_name_is_recorded() {
+        let folder = TempDir::with_content("compression-name", &[("A.ubulk", &[1u8; 0x100])]);
+
+        let build = |builder: TocFactoryBuilder| {
+            let (mut utoc, mut ucas) = (vec![], vec![]);
+            builder.build().unwrap().write_files(&mut utoc, &mut ucas).unwrap();
+            (TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut std::io::Cursor::new(utoc)).unwrap(), ucas)
+        };
+        let source = folder.to_str().unwrap().to_string();
+        let (zlib_toc, zlib_ucas) = build(TocFactoryBuilder::new(source.clone()).zlib(6));
+        let (named_toc, named_ucas) = build(TocFactoryBuilder::new(source.clone()).zlib(6).compression_method_name("GameZlib"));
+        let uncompressed = TocFactoryBuilder::new(source.clone()).compression_method_name("GameZlib").build();
+        let too_long = TocFactoryBuilder::new(source).zlib(6).compression_method_name(&"a".repeat(COMPRESSION_METHOD_NAME_LENGTH as usize)).build();
+
+        assert_eq!(zlib_toc.compression_methods, ["zlib"]);
+        assert_eq!(named_toc.compression_methods, ["GameZlib"]);
+        assert_eq!(zlib_ucas, named_ucas); // only the name changes
+        assert!(matches!(uncompressed, Err(TocError::InvalidSettings(_))));
+        assert!(matches!(too_long, Err(TocError::InvalidSettings(_))));
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn blocks_that_dont_shrink_are_stored_raw() {
+        let folder = TempDir::with_content("raw-blocks", &[("A.ubulk", &(0..0x2345).map(|i| (i % 7) as u8).collect::<Vec<u8>>())]);
+
+        // level 0 only wraps the data, so every block comes out bigger than it went in
+        let (mut utoc, mut ucas) = (vec![], vec![]);
+        TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).zlib(0).block_size(0x1000).build().unwrap().write_files(&mut utoc, &mut ucas).unwrap();
+        let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut io::Cursor::new(utoc)).unwrap();
+
+        let file_blocks = &toc.compression_blocks[..3];
+        assert!(file_blocks.iter().all(|block| block.get_compression_method() == 0 && block.get_compressed_size() == block.get_uncompressed_size()));
+        assert_eq!(file_blocks.iter().map(|block| block.get_uncompressed_size()).sum::<u32>(), 0x2345);
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn sidecar_settings_override_extension_rules() {
+        let folder = TempDir::with_content("sidecar", &[("A.ubulk", &[0u8; 0x100]), ("A.ubulk.tocmeta", b"compression = store\n"), ("B.ubulk", &[0u8; 0x200]), ("C.uptnl", &[0u8; 0x300]), ("C.uptnl.tocmeta", b"compression = zlib\nlevel = 1\n")]);
+
+        let source = folder.to_str().unwrap().to_string();
+        let (mut utoc, mut ucas) = (vec![], vec![]);
+        TocFactoryBuilder::new(source.clone()).zlib(6).compressed_extensions(vec!["ubulk".to_string()]).build().unwrap().write_files(&mut utoc, &mut ucas).unwrap();
+        // the container has nothing for a zlib block to point to without -z
+        let uncompressed = TocFactoryBuilder::new(source).build().unwrap().write_files(&mut vec![], &mut vec![]);
+        let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut io::Cursor::new(utoc)).unwrap();
+
+        let method_of = |length: u64| {
+            let chunk = toc.offsets_and_lengths.iter().find(|chunk| chunk.get_length() == length).unwrap();
+            toc.compression_blocks[(chunk.get_offset() / DEFAULT_COMPRESSION_BLOCK_SIZE as u64) as usize].get_compression_method()
+        };
+        assert_eq!(toc.header.get_entry_count(), 4); // 3 files and the container header, the sidecars aren't packaged
+        assert_eq!((method_of(0x100), method_of(0x200), method_of(0x300)), (0, 1, 1));
+        assert!(matches!(uncompressed, Err(TocError::InvalidFileSettings(..))));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn default_block_alignment_packs_blocks() {
+        let folder = TempDir::with_content("default-alignment", &[("A.ubulk", &[7u8; 0x31]), ("B.ubulk", &[7u8; 0x5]), ("C.ubulk", &[7u8; 0x1002])]);
+
+        let (mut utoc, mut ucas) = (vec![], vec![]);
+        // the container header is laid out separately from the files' blocks
+        TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).block_size(0x1000).container_header(false).build().unwrap().write_files(&mut utoc, &mut ucas).unwrap();
+        let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut io::Cursor::new(utoc)).unwrap();
+
+        // every block starts on the default alignment, right after the one before it
+        let mut offsets: Vec<_> = toc.compression_blocks.iter().map(|block| (block.get_offset(), block.get_compressed_size() as u64)).collect();
+        offsets.sort();
+        for pair in offsets.windows(2) {
+            assert_eq!(pair[1].0 % DEFAULT_COMPRESSION_BLOCK_ALIGNMENT as u64, 0);
+            assert_eq!(pair[1].0, (pair[0].0 + pair[0].1).align_to(DEFAULT_COMPRESSION_BLOCK_ALIGNMENT as u64));
+        }
+    }
+
+    #[test]
+    fn block_alignment_per_chunk_type() {
+        let folder = TempDir::with_content("type-alignment", &[("A.ubulk", &[3u8; 0x30]), ("B.ubulk", &[3u8; 0x30]), ("C.uptnl", &[3u8; 0x30])]);
+
+        let source = folder.to_str().unwrap().to_string();
+        let factory = TocFactoryBuilder::new(source.clone()).block_size(0x1000).block_alignment(0x800).block_alignment_for(IoChunkType4::BulkData, 0x10).build().unwrap();
+        let estimate = factory.estimate().unwrap();
+        let (mut utoc, mut ucas) = (vec![], vec![]);
+        factory.write_files(&mut utoc, &mut ucas).unwrap();
+        let not_power_of_two = TocFactoryBuilder::new(source).block_alignment_for(IoChunkType4::BulkData, 0x18).build();
+        let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut io::Cursor::new(utoc)).unwrap();
+
+        let mut bulk_offsets = vec![];
+        for (chunk_id, chunk) in toc.chunk_ids.iter().zip(&toc.offsets_and_lengths).filter(|(chunk_id, _)| chunk_id.get_type() != IoChunkType::UE4(IoChunkType4::ContainerHeader)) {
+            let block_offset = toc.compression_blocks[(chunk.get_offset() / 0x1000) as usize].get_offset();
+            match chunk_id.get_type() {
+                IoChunkType::UE4(IoChunkType4::BulkData) => bulk_offsets.push(block_offset),
+                _ => assert_eq!(block_offset % 0x800, 0),
+            }
+        }
+        // the two .ubulk blocks are only 0x10 aligned, so they'd be 0x800 apart otherwise
+        bulk_offsets.sort();
+        assert_eq!(bulk_offsets.len(), 2);
+        assert_eq!(bulk_offsets[1] - bulk_offsets[0], 0x30);
+        assert_eq!(estimate.ucas_size, ucas.len() as u64);
+        assert!(matches!(not_power_of_two, Err(TocError::InvalidSettings(_))));
+    }
+
+    #[test]
+    fn single_block_files_skip_block_splitting() {
+        let data: Vec<u8> = (0..0x2345).map(|i| i as u8).collect();
+        let folder = TempDir::with_content("single-block", &[("A.ubulk", &data), ("B.uptnl", &[2u8; 0x10])]);
+
+        let factory = TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).block_size(0x1000).single_block_extensions(vec![".UBULK".to_string()]).build().unwrap();
+        let estimate = factory.estimate().unwrap();
+        let (mut utoc, mut ucas) = (vec![], vec![]);
+        factory.write_files(&mut utoc, &mut ucas).unwrap();
+        let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut io::Cursor::new(utoc)).unwrap();
+
+        // A.ubulk is one block of the whole file, anything after it starts at the block its offset says, past empty entries
+        let a = toc.offsets_and_lengths.iter().find(|entry| entry.get_length() == 0x2345).unwrap();
+        let first_block = (a.get_offset() / 0x1000) as usize;
+        let block = &toc.compression_blocks[first_block];
+        assert_eq!((block.get_compressed_size(), block.get_uncompressed_size(), block.get_compression_method()), (0x2345, 0x2345, 0));
+        let start = block.get_offset() as usize;
+        assert_eq!(&ucas[start..start + 0x2345], &data[..]);
+        let next = (first_block + 1..toc.compression_blocks.len()).find(|&i| toc.compression_blocks[i].get_compressed_size() != 0).unwrap();
+        assert_eq!(next, first_block + 3);
+        assert!(toc.offsets_and_lengths.iter().any(|entry| (entry.get_offset() / 0x1000) as usize == next));
+        assert_eq!(estimate.ucas_size, ucas.len() as u64);
+    }
+
+    #[test]
+    fn rebuilds_are_identical() {
+        let folder = TempDir::with_content("rebuild", &[("A.ubulk", &[1u8; 0x10]), ("Dir/B.ubulk", &[2u8; 0x20])]);
+
+        let build = || {
+            let (mut utoc, mut ucas) = (vec![], vec![]);
+            TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).container_name("Mod").build().unwrap().write_files(&mut utoc, &mut ucas).unwrap();
+            (utoc, ucas)
+        };
+        let (first, second) = (build(), build());
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn oversized_containers_are_rejected() {
+        let factory = TocFactoryBuilder::new(String::new()).build().unwrap();
+        assert!(factory.check_container_size([0x10, IO_OFFSET_LENGTH_MAX / 2].into_iter()).is_ok());
+        assert!(matches!(factory.check_container_size([IO_OFFSET_LENGTH_MAX / 2, IO_OFFSET_LENGTH_MAX / 2].into_iter()), Err(TocError::ContainerTooLarge(..))));
+        // tiny blocks run out of block indices long before the offsets overflow
+        let factory = TocFactoryBuilder::new(String::new()).block_size(0x10).block_alignment(0x10).build().unwrap();
+        assert!(factory.check_container_size([0x10 << 31].into_iter()).is_ok());
+        assert!(matches!(factory.check_container_size([0x10 << 32].into_iter()), Err(TocError::ContainerTooLarge(..))));
+
+        // the last block that fits ends exactly on the 40 bit limit of the ucas offsets
+        let mut ucas = vec![];
+        let mut destination = UcasPartitionWriter::new(&mut ucas, |_| Ok(vec![]), None);
+        let mut offset = IO_OFFSET_LENGTH_MAX + 1 - 0x10;
+        let block = factory.write_block(&[0; 0x10], 0x10, 0, 0x10, &mut offset, &mut destination).unwrap();
+        assert_eq!(block.get_offset(), IO_OFFSET_LENGTH_MAX + 1 - 0x10);
+        assert!(matches!(factory.write_block(&[0; 0x10], 0x10, 0, 0x10, &mut offset, &mut destination), Err(TocError::UcasTooLarge(end)) if end == IO_OFFSET_LENGTH_MAX + 0x11));
+    }
+
+    #[test]
+    fn non_ascii_names_fit_the_directory_index() {
+        let folder = TempDir::with_content("non-ascii", &[("Caf\u{e9}/Foo.ubulk", &[1u8; 0x10])]);
+        let output_dir = TempDir::new("non-ascii-out");
+        let output = output_dir.join("Mod");
+        let (utoc_path, ucas_path) = (output.with_extension("utoc"), output.with_extension("ucas"));
+
+        TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).build().unwrap()
+            .write_files(&mut File::create(&utoc_path).unwrap(), &mut File::create(&ucas_path).unwrap()).unwrap();
+        let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut BufReader::new(File::open(&utoc_path).unwrap())).unwrap();
+
+        // walk the directory index section by section, the strings have to end exactly where directory_index_size says
+        type EN = byteorder::NativeEndian;
+        let mut index = io::Cursor::new(&toc.directory_index);
+        FString32NoHash::from_buffer::<_, EN>(&mut index).unwrap();
+        let directory_count = index.read_u32::<EN>().unwrap() as i64;
+        index.seek(SeekFrom::Current(directory_count * mem::size_of::<IoDirectoryIndexEntry>() as i64)).unwrap();
+        let file_count = index.read_u32::<EN>().unwrap() as i64;
+        index.seek(SeekFrom::Current(file_count * IO_FILE_INDEX_ENTRY_SERIALIZED_SIZE as i64)).unwrap();
+        let names: Vec<String> = (0..index.read_u32::<EN>().unwrap()).map(|_| FString32NoHash::from_buffer::<_, EN>(&mut index).unwrap().unwrap()).collect();
+        assert!(names.contains(&"Caf\u{e9}".to_string()));
+        assert_eq!(index.position(), toc.directory_index.len() as u64);
+    }
+
+    #[test]
+    fn block_table_on_disk_matches_in_memory() {
+        let folder = TempDir::with_content("block-table", &[("A.ubulk", &[1u8; 0x1800]), ("B.ubulk", &[2u8; 0x10])]);
+        let output_dir = TempDir::new("block-table-out");
+        let output = output_dir.join("Mod");
+        let ucas_path = output.with_extension("ucas");
+
+        let utocs: Vec<Vec<u8>> = [false, true].into_iter().map(|on_disk| {
+            let mut utoc = vec![];
+            TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).block_size(0x800).block_table_on_disk(on_disk).build().unwrap()
+                .write_files(&mut utoc, &mut File::create(&ucas_path).unwrap()).unwrap();
+            utoc
+        }).collect();
+
+        let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut io::Cursor::new(&utocs[1])).unwrap();
+        assert_eq!(toc.compression_blocks.len(), 5);
+        assert_eq!(utocs[0], utocs[1]);
+    }
+
+    #[test]
+    fn offset_alignment_keeps_block_indices() {
+        let folder = TempDir::with_content("offset-alignment", &[("A.ubulk", &[1u8; 0x900]), ("B.ubulk", &[2u8; 0x10])]);
+        let output_dir = TempDir::new("offset-alignment-out");
+        let output = output_dir.join("Mod");
+        let (utoc_path, ucas_path) = (output.with_extension("utoc"), output.with_extension("ucas"));
+
+        let build = |alignment: u32| TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).block_size(0x800).offset_alignment(alignment).build();
+        assert!(matches!(build(0x400), Err(TocError::InvalidSettings(_))));
+        assert!(matches!(build(0x1800), Err(TocError::InvalidSettings(_))));
+        build(0x2000).unwrap().write_files(&mut File::create(&utoc_path).unwrap(), &mut File::create(&ucas_path).unwrap()).unwrap();
+        let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut BufReader::new(File::open(&utoc_path).unwrap())).unwrap();
+
+        // each chunk's first block is still the one at offset / block size, with empty entries for the blocks in between
+        for offset_and_length in &toc.offsets_and_lengths {
+            assert_eq!(offset_and_length.get_offset() % 0x2000, 0);
+            let first_block = &toc.compression_blocks[(offset_and_length.get_offset() / 0x800) as usize];
+            assert_eq!(first_block.get_compressed_size() as u64, std::cmp::min(offset_and_length.get_length(), 0x800));
+        }
+        assert_eq!(toc.compression_blocks.len(), 9);
+        assert_eq!(toc.compression_blocks.iter().filter(|block| block.get_compressed_size() == 0).count(), 5);
+    }
+
+    #[test]
+    fn output_inside_input_is_rejected() {
+        let folder = TempDir::with_content("output-inside-input", &[]);
+        let content = folder.join("P3R").join("Content");
+        let factory = TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).build().unwrap();
+
+        let inside = factory.check_output_path(content.join("Mod").to_str().unwrap());
+        let same = factory.check_output_path(folder.join("Mod").to_str().unwrap());
+        let outside = factory.check_output_path(std::env::temp_dir().join("Mod").to_str().unwrap());
+
+        assert!(matches!(inside, Err(TocError::OutputInsideInput(..))));
+        assert!(matches!(same, Err(TocError::OutputInsideInput(..))));
+        assert!(outside.is_ok());
+    }
+
+    #[test]
+    fn unreal_sort_puts_bulk_data_last() {
+        // root -> Foo (A.uasset, A.ubulk, B.uasset), root -> Bar (C.ubulk, C.uasset)
+        let file = |path: &str, chunk_type, file_size, next_file| IoFileIndexEntry {
+            name: 0, next_file, user_data: 0, file_size, os_path: path.to_string(), chunk_id: IoChunkId::new(path, chunk_type)
+        };
+        let dir = |first_file| IoDirectoryIndexEntry { name: 0, first_child: u32::MAX, next_sibling: u32::MAX, first_file };
+        let directories = vec![dir(u32::MAX), dir(0), dir(3)];
+        let mut files = vec![
+            file("Foo/A.uasset", IoChunkType4::ExportBundleData, 0x300, 1),
+            file("Foo/A.ubulk", IoChunkType4::BulkData, 0x10, 2),
+            file("Foo/B.uasset", IoChunkType4::ExportBundleData, 0x200, u32::MAX),
+            file("Bar/C.ubulk", IoChunkType4::BulkData, 0x400, 4),
+            file("Bar/C.uasset", IoChunkType4::ExportBundleData, 0x100, u32::MAX),
+        ];
+
+        let chunk_order = TocFlattener::sort_chunks(&directories, &mut files, FileSortOrder::Unreal);
+        let sorted: Vec<&str> = chunk_order.iter().map(|&i| files[i].os_path.as_str()).collect();
+        assert_eq!(sorted, ["Foo/B.uasset", "Foo/A.uasset", "Bar/C.uasset", "Foo/A.ubulk", "Bar/C.ubulk"]);
+        for (chunk_index, &file_index) in chunk_order.iter().enumerate() {
+            assert_eq!(files[file_index].user_data, chunk_index as u32);
+        }
+
+        let chunk_order = TocFlattener::sort_chunks(&directories, &mut files, FileSortOrder::Flatten);
+        assert_eq!(chunk_order, [0, 1, 2, 3, 4]);
+        assert!(files.iter().enumerate().all(|(i, file)| file.user_data == i as u32));
+    }
+
+    // A file with the chunk type the collector would give it with the default extensions
+    fn collected_file(name: &str) -> TocFileSyncRef {
+        TocFile::new_rc(name, 0, name, ChunkTypeRegistry::new().get_file_chunk_type(name).unwrap())
+    }
+
+    #[test]
+    fn file_hash_ignores_extension_case() {
+        let expected = IoChunkId::new("/Game/Foo/Bar", IoChunkType4::BulkData);
+        for name in ["Bar.ubulk", "Bar.UBULK", "Bar.uBulk"] {
+            let file = collected_file(name);
+            assert_eq!(TocFlattener::get_file_hash("P3R/Content/Foo/", &file.read().unwrap(), UeVersion::UE4_27, &Hasher16, None).unwrap(), expected, "chunk id of {}", name);
+        }
+    }
+
+    #[test]
+    fn file_hash_uses_chunk_types_of_ue_version() {
+        let file = collected_file("Bar.ubulk");
+        let ue4 = TocFlattener::get_file_hash("P3R/Content/Foo/", &file.read().unwrap(), UeVersion::UE4_27, &Hasher16, None).unwrap();
+        let ue5 = TocFlattener::get_file_hash("P3R/Content/Foo/", &file.read().unwrap(), UeVersion::UE5, &Hasher16, None).unwrap();
+        assert_eq!(ue4.get_type(), IoChunkType::UE4(IoChunkType4::BulkData));
+        assert_eq!(ue5, IoChunkId::new_with_type("/Game/Foo/Bar", IoChunkType::UE5(crate::io_toc::IoChunkType5::BulkData)));
+        assert_eq!(u8::from(ue5.get_type()), 2);
+
+        let unmapped = HashMap::from([("uglobal".to_string(), IoChunkType4::LoaderGlobalMeta)]);
+        assert!(TocFactoryBuilder::new(String::new()).chunk_types(unmapped.clone()).build().is_ok());
+        assert!(matches!(TocFactoryBuilder::new(String::new()).chunk_types(unmapped).ue_version(UeVersion::UE5).build(), Err(TocError::InvalidSettings(_))));
+    }
+
+    #[test]
+    fn hand_built_trees_without_a_chunk_type_are_an_error() {
+        let flatten_file = |name: &str, chunk_type, ue_version| {
+            let mut content = TocDirectoryBuilder::new(Some("Content".to_string()));
+            content.add_file(name, 0x10, name, chunk_type);
+            let mut root = TocDirectoryBuilder::new(Some("P3R".to_string()));
+            root.add_directory(content);
+            flatten(root.build(), ue_version, &Hasher16, None)
+        };
+        assert!(flatten_file("Bar.uasset", IoChunkType4::ExportBundleData, UeVersion::UE5).is_ok());
+        assert!(matches!(flatten_file("NoExt", IoChunkType4::BulkData, UeVersion::UE4_27), Err(TocError::NoChunkType(path, _)) if path == "NoExt"));
+        assert!(matches!(flatten_file("Bar.uglobal", IoChunkType4::LoaderGlobalMeta, UeVersion::UE5), Err(TocError::NoChunkType(path, _)) if path == "Bar.uglobal"));
+    }
+
+    // Hashes to the path's length, so expected chunk ids can be worked out by hand
+    struct PathLength;
+
+    impl Hasher for PathLength {
+        fn hash_path(&self, path: &str) -> u64 {
+            path.len() as u64
+        }
+    }
+
+    #[test]
+    fn file_hash_uses_given_hasher() {
+        let file = collected_file("Bar.uasset");
+        let chunk_id = TocFlattener::get_file_hash("P3R/Content/Foo/", &file.read().unwrap(), UeVersion::UE4_27, &PathLength, None).unwrap();
+        assert_eq!(chunk_id, IoChunkId::new_from_hash("/Game/Foo/Bar".len() as u64, IoChunkType4::ExportBundleData));
+    }
+
+    #[test]
+    fn root_prefix_replaces_game_mapping() {
+        assert_eq!(get_package_path("P3R/Content/Foo/", "Bar", None).unwrap(), "/Game/Foo/Bar");
+        assert_eq!(get_package_path("Foo/", "Bar", Some("MyPlugin")).unwrap(), "/MyPlugin/Foo/Bar");
+        assert_eq!(get_package_path("/", "Bar", Some("MyPlugin")).unwrap(), "/MyPlugin/Bar");
+        assert_eq!(get_package_path("Foo/", "Bar", Some("/MyMod/Content/")).unwrap(), "/MyMod/Content/Foo/Bar");
+
+        // a plugin folder without a Content folder in it is an error without a prefix
+        assert!(matches!(get_package_path("Maps/", "Bar", None), Err(TocError::NotGameLayout(path)) if path == "Maps/Bar"));
+        let file = collected_file("Bar.uasset");
+        let chunk_id = TocFlattener::get_file_hash("Maps/", &file.read().unwrap(), UeVersion::UE4_27, &Hasher16, Some("MyPlugin")).unwrap();
+        assert_eq!(chunk_id, IoChunkId::new("/MyPlugin/Maps/Bar", IoChunkType4::ExportBundleData));
+
+        assert!(TocFactoryBuilder::new(String::new()).root_prefix("\\MyMod\\Content\\").build().is_ok());
+        for root_prefix in ["", "/", "MyMod//Content", "MyMod/../Game"] {
+            assert!(matches!(TocFactoryBuilder::new(String::new()).root_prefix(root_prefix).build(), Err(TocError::InvalidSettings(_))), "{:?}", root_prefix);
+        }
+    }
+
+    #[test]
+    fn flattener_uses_collected_chunk_type() {
+        // the extension isn't looked up again when the chunk id is hashed, so txt files keep the chunk type they were collected with
+        let folder = TempDir::with_content("collected-chunk-type", &[("Notes.txt", &[0u8; 0x10])]);
+        let mut chunk_types = ChunkTypeRegistry::new();
+        chunk_types.extend(HashMap::from([("txt".to_string(), IoChunkType4::BulkData)]));
+        let collector = AssetCollector::from_folder(folder.to_str().unwrap(), &chunk_types, &FolderOptions::default()).unwrap();
+        let (_, files, _) = flatten(collector.get_toc_tree(), UeVersion::UE4_27, &Hasher16, None).unwrap().into_parts();
+        assert_eq!(files.iter().map(|file| file.chunk_id).collect::<Vec<_>>(), vec![IoChunkId::new("/Game/Notes", IoChunkType4::BulkData)]);
+
+        // the stem is everything up to the last dot, the same as the extension the collector checked
+        let file = collected_file("Bar.old.uasset");
+        let chunk_id = TocFlattener::get_file_hash("P3R/Content/Foo/", &file.read().unwrap(), UeVersion::UE4_27, &Hasher16, None).unwrap();
+        assert_eq!(chunk_id, IoChunkId::new("/Game/Foo/Bar.old", IoChunkType4::ExportBundleData));
+    }
+
+    #[test]
+    fn files_outside_of_content_are_an_error() {
+        // e.g. a manifest line that puts a file at the root of the container
+        let mut root = TocDirectoryBuilder::new(None);
+        root.add_file("C.ubulk", 0x10, "C.ubulk", IoChunkType4::BulkData);
+        let flattened = flatten(root.build(), UeVersion::UE4_27, &Hasher16, None);
+        assert!(matches!(flattened, Err(TocError::NotGameLayout(path)) if path == "C"));
+    }
+
+    #[test]
+    fn chunk_id_for_matches_flattener() {
+        let file = collected_file("Bar.ubulk");
+        let flattened = TocFlattener::get_file_hash("P3R/Content/Foo/", &file.read().unwrap(), UeVersion::UE4_27, &Hasher16, None).unwrap();
+        assert_eq!(chunk_id_for("P3R/Content/Foo/Bar", "ubulk"), Some(flattened));
+        assert_eq!(chunk_id_for("/P3R/Content/Foo/Bar", ".UBULK"), Some(flattened));
+        assert_eq!(chunk_id_for("P3R/Content/Foo/Bar", "uasset"), Some(IoChunkId::new("/Game/Foo/Bar", IoChunkType4::ExportBundleData)));
+        assert_eq!(chunk_id_for("P3R/Content/Foo/Bar", "txt"), None);
+        assert_eq!(chunk_id_for("Foo/Bar", "uasset"), None); // no Content folder to map to /Game
+        assert_eq!(chunk_id_for("Bar", "uasset"), None);
+    }
+
+    #[test]
+    fn bulk_data_shares_package_id_with_its_asset() {
+        let chunk_id = |name: &str| TocFlattener::get_file_hash("P3R/Content/Foo/", &collected_file(name).read().unwrap(), UeVersion::UE4_27, &Hasher16, None).unwrap();
+        let package_id = Hasher16::get_cityhash64("/Game/Foo/Bar");
+        assert_eq!(chunk_id("Bar.uasset"), IoChunkId::new_from_hash(package_id, IoChunkType4::ExportBundleData));
+        assert_eq!(chunk_id("Bar.ubulk"), IoChunkId::new_from_hash(package_id, IoChunkType4::BulkData));
+        assert_eq!(chunk_id("Bar.uptnl"), IoChunkId::new_from_hash(package_id, IoChunkType4::OptionalBulkData));
+    }
+
+    #[test]
+    fn optional_bulk_data_is_packaged_as_its_own_chunk() {
+        let files = [
+            ("Foo/Bar.uasset", IoChunkType4::ExportBundleData, vec![1u8; 0x30]),
+            ("Foo/Bar.ubulk", IoChunkType4::BulkData, vec![2u8; 0x1234]),
+            ("Foo/Bar.uptnl", IoChunkType4::OptionalBulkData, (0..0x2345).map(|i| i as u8).collect()),
+        ];
+        let folder = TempDir::with_content("optional-bulk", &files.iter().map(|(path, _, data)| (*path, data.as_slice())).collect::<Vec<_>>());
+
+        let (mut utoc, mut ucas) = (vec![], vec![]);
+        TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).block_size(0x1000).build().unwrap().write_files(&mut utoc, &mut ucas).unwrap();
+        let toc = TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut std::io::Cursor::new(utoc)).unwrap();
+
+        // every chunk is read back through the offsets and blocks the game would use, so the .uptnl has to come out whole
+        // and starting at its own offset, not somewhere in the .ubulk's
+        let package_id = Hasher16::get_cityhash64("/Game/Foo/Bar");
+        let block_size = toc.header.get_compression_block_size() as u64;
+        for (name, chunk_type, data) in &files {
+            let index = toc.chunk_ids.iter().position(|&id| id == IoChunkId::new_from_hash(package_id, *chunk_type)).expect(name);
+            let (offset, length) = (toc.offsets_and_lengths[index].get_offset(), toc.offsets_and_lengths[index].get_length());
+            assert_eq!(offset % block_size, 0, "{}", name);
+            let mut read = vec![];
+            for block in (offset / block_size)..(offset + length).div_ceil(block_size) {
+                let block = &toc.compression_blocks[block as usize];
+                let start = block.get_offset() as usize;
+                read.extend_from_slice(&ucas[start..start + block.get_compressed_size() as usize]);
+            }
+            read.truncate(length as usize);
+            assert_eq!(&read, data, "{}", name);
+        }
+    }
+
+    #[test]
+    fn folder_and_file_with_the_same_name() {
+        let folder = TempDir::new("name-collision");
+        let source = folder.join("Bar.ubulk");
+        fs::write(&source, [0u8; 0x10]).unwrap();
+        let source = source.to_str().unwrap();
+        let manifest = folder.join("manifest.txt");
+        let chunk_types = ChunkTypeRegistry::new();
+        let collect = |lines: &[&str]| {
+            fs::write(&manifest, lines.iter().map(|line| format!("{}\t{}\n", source, line)).collect::<String>()).unwrap();
+            AssetCollector::from_manifest(manifest.to_str().unwrap(), &chunk_types).unwrap().get_toc_tree()
+        };
+
+        // same stem is what cooked content looks like, and everything stays reachable
+        let same_stem = collect(&["P3R/Content/Foo.ubulk", "P3R/Content/Foo/Bar.ubulk"]);
+        assert!(TocFlattener::find_name_collisions(&same_stem).is_empty());
+        let flattened = flatten(same_stem, UeVersion::UE4_27, &Hasher16, None).unwrap();
+        assert_ne!(flattened.get_files()[0].chunk_id, flattened.get_files()[1].chunk_id);
+        assert_eq!(flattened.get_file_paths(), ["P3R/Content/Foo.ubulk", "P3R/Content/Foo/Bar.ubulk"]);
+
+        let same_name = collect(&["P3R/Content/Foo.ubulk", "P3R/Content/Foo.ubulk/Bar.ubulk"]);
+        assert_eq!(TocFlattener::find_name_collisions(&same_name), vec!["P3R/Content/Foo.ubulk".to_string()]);
+    }
+
+    #[test]
+    fn manifest_path_separators_dont_change_chunk_ids() {
+        let folder = TempDir::new("manifest-separators");
+        let source = folder.join("Bar.ubulk");
+        fs::write(&source, [0u8; 0x10]).unwrap();
+        let source = source.to_str().unwrap();
+
+        let expected = IoChunkId::new("/Game/Foo/Bar", IoChunkType4::BulkData);
+        for (i, virtual_path) in ["P3R/Content/Foo/Bar.ubulk", "P3R\\Content\\Foo\\Bar.ubulk", "\\P3R\\Content/Foo\\Bar.ubulk"].iter().enumerate() {
+            let manifest = folder.join(format!("manifest{}.txt", i));
+            fs::write(&manifest, format!("{}\t{}\n", source, virtual_path)).unwrap();
+            let chunk_types = ChunkTypeRegistry::new();
+            let collector = AssetCollector::from_manifest(manifest.to_str().unwrap(), &chunk_types).unwrap();
+            let (_, files, _) = flatten(collector.get_toc_tree(), UeVersion::UE4_27, &Hasher16, None).unwrap().into_parts();
+            assert_eq!(files.len(), 1);
+            assert_eq!(files[0].chunk_id, expected, "chunk id for {}", virtual_path);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::{fs, io, path::{Path, PathBuf}};
+
+pub const TOC_IGNORE_FILE_NAME: &str = ".tocignore";
+
+// Patterns from .tocignore files, which work like a (simplified) .gitignore. Each line of the file is a pattern for files or
+// folders that shouldn't be packaged, relative to the folder the .tocignore is in:
+//      Foo.uasset      - a file or folder called Foo.uasset, in this folder or any folder under it
+//      *.ubulk         - * matches any number of characters (except /), ? matches exactly one
+//      Debug/          - a trailing / only matches folders
+//      /Foo.uasset     - a leading / (or any / in the middle) matches relative to this folder only
+//      Maps/**/Test_*  - ** matches any number of folders
+// Blank lines and lines starting with # are skipped. Negation (!) isn't supported.
+// A .tocignore applies to its folder and everything under it, so nested folders can have their own to add more patterns.
+// This is checked before anything else about the file - ignored files don't go through the extension filter or have their
+// header read, and an ignored folder isn't walked at all
+#[derive(Debug, Clone, Default)]
+pub struct TocIgnore {
+    rules: Vec<TocIgnoreRule>,
+}
+
+#[derive(Debug, Clone)]
+struct TocIgnoreRule {
+    base: PathBuf, // folder that the .tocignore was in
+    segments: Vec<String>,
+    anchored: bool,
+    directory_only: bool,
+}
+
+impl TocIgnore {
+    // Parse the contents of a .tocignore that's inside of base
+    #[allow(dead_code)]
+    pub fn from_contents(base: &Path, contents: &str) -> Self {
+        let mut ignore = Self::default();
+        ignore.add_rules(base, contents);
+        ignore
+    }
+
+    // The rules that apply inside of os_folder_path: these ones, plus the ones in os_folder_path's .tocignore if it has one
+    pub fn with_folder(&self, os_folder_path: &Path) -> io::Result<Self> {
+        let mut ignore = self.clone();
+        match fs::read_to_string(os_folder_path.join(TOC_IGNORE_FILE_NAME)) {
+            Ok(contents) => ignore.add_rules(os_folder_path, &contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        Ok(ignore)
+    }
+
+    fn add_rules(&mut self, base: &Path, contents: &str) {
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let directory_only = line.ends_with('/');
+            let pattern = line.trim_end_matches('/');
+            let anchored = pattern.contains('/');
+            let segments: Vec<String> = pattern.split('/').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect();
+            if !segments.is_empty() {
+                self.rules.push(TocIgnoreRule { base: base.to_path_buf(), segments, anchored, directory_only });
+            }
+        }
+    }
+
+    pub fn is_ignored(&self, os_path: &Path, is_dir: bool) -> bool {
+        self.rules.iter().any(|rule| rule.matches(os_path, is_dir))
+    }
+}
+
+impl TocIgnoreRule {
+    fn matches(&self, os_path: &Path, is_dir: bool) -> bool {
+        if self.directory_only && !is_dir {
+            return false;
+        }
+        let relative = match os_path.strip_prefix(&self.base) {
+            Ok(relative) => relative,
+            Err(_) => return false
+        };
+        let components: Vec<&str> = relative.components().filter_map(|c| c.as_os_str().to_str()).collect();
+        if self.anchored {
+            match_segments(&self.segments, &components)
+        } else {
+            components.last().is_some_and(|name| match_glob(self.segments[0].as_bytes(), name.as_bytes()))
+        }
+    }
+}
+
+// Match a path one folder at a time, where a ** segment can stand in for any number of folders
+fn match_segments(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path_rest)) => match_glob(first.as_bytes(), name.as_bytes()) && match_segments(rest, path_rest),
+            None => false
+        }
+    }
+}
+
+// * and ? wildcards within a single file or folder name
+fn match_glob(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_glob(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_glob(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && match_glob(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_match_like_gitignore() {
+        let base = Path::new("/mod");
+        let ignore = TocIgnore::from_contents(base, "# comment\n\n*.txt\nDebug/\n/P3R/Content/Test.uasset\nMaps/**/Test_?.umap\n");
+
+        assert!(ignore.is_ignored(&base.join("Notes.txt"), false));
+        assert!(ignore.is_ignored(&base.join("P3R/Content/Notes.txt"), false));
+        assert!(ignore.is_ignored(&base.join("P3R/Debug"), true));
+        assert!(!ignore.is_ignored(&base.join("P3R/Debug"), false));
+        assert!(ignore.is_ignored(&base.join("P3R/Content/Test.uasset"), false));
+        assert!(!ignore.is_ignored(&base.join("P3R/Content/Foo/Test.uasset"), false));
+        assert!(ignore.is_ignored(&base.join("Maps/Test_1.umap"), false));
+        assert!(ignore.is_ignored(&base.join("Maps/A/B/Test_2.umap"), false));
+        assert!(!ignore.is_ignored(&base.join("Maps/A/Test_10.umap"), false));
+        assert!(!ignore.is_ignored(&base.join("P3R/Content/Foo.uasset"), false));
+        assert!(!ignore.is_ignored(Path::new("/other/Notes.txt"), false));
+    }
+}
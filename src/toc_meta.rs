@@ -0,0 +1,77 @@
+use std::{fs, io};
+
+use crate::error::TocError;
+
+pub const TOC_META_EXTENSION: &str = "tocmeta";
+
+// Settings for a single file, from a sidecar next to it called <file name>.tocmeta (Foo.ubulk.tocmeta for Foo.ubulk). Each line is
+// key = value:
+//      compression = store    - write the file uncompressed, even in a compressed container
+//      compression = zlib     - compress the file, even if --compress-ext or --store-ext would leave it uncompressed
+//      level = 9              - zlib level for this file, from 0 to 9
+// Blank lines and lines starting with # are skipped. Whatever a sidecar doesn't set comes from the build's settings, so the order
+// is sidecar, then extension rules (--compress-ext, --store-ext), then the global settings (-z, --level).
+// Sidecars are never packaged themselves
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FileSettings {
+    pub compress: Option<bool>,
+    pub level: Option<u32>,
+}
+
+impl FileSettings {
+    pub fn from_contents(contents: &str) -> Result<Self, String> {
+        let mut settings = Self::default();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or(format!("line {} isn't key = value", index + 1))?;
+            match (key.trim(), value.trim()) {
+                ("compression", "store") => settings.compress = Some(false),
+                ("compression", "zlib") => settings.compress = Some(true),
+                ("compression", value) => return Err(format!("unknown compression \"{value}\", expected store or zlib")),
+                ("level", value) => match value.parse() {
+                    Ok(level) if level <= 9 => settings.level = Some(level),
+                    _ => return Err(format!("zlib level \"{value}\" has to be from 0 to 9")),
+                },
+                (key, _) => return Err(format!("unknown setting \"{key}\"")),
+            }
+        }
+        Ok(settings)
+    }
+
+    // The settings in os_path's sidecar, or the defaults if it doesn't have one
+    pub fn for_file(os_path: &str) -> Result<Self, TocError> {
+        let sidecar_path = Self::get_sidecar_path(os_path);
+        match fs::read_to_string(&sidecar_path) {
+            Ok(contents) => Self::from_contents(&contents).map_err(|reason| TocError::InvalidFileSettings(sidecar_path, reason)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(TocError::InvalidFileSettings(sidecar_path, e.to_string())),
+        }
+    }
+
+    pub fn get_sidecar_path(os_path: &str) -> String {
+        format!("{os_path}.{TOC_META_EXTENSION}")
+    }
+
+    pub fn is_sidecar_name(name: &str) -> bool {
+        name.rsplit_once('.').is_some_and(|(_, extension)| extension.eq_ignore_ascii_case(TOC_META_EXTENSION))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_settings() {
+        assert_eq!(FileSettings::from_contents("# stubborn file\ncompression = store\n").unwrap(), FileSettings { compress: Some(false), level: None });
+        assert_eq!(FileSettings::from_contents("compression=zlib\nlevel = 9").unwrap(), FileSettings { compress: Some(true), level: Some(9) });
+        assert_eq!(FileSettings::from_contents("").unwrap(), FileSettings::default());
+        assert!(FileSettings::from_contents("compression = lz4").is_err());
+        assert!(FileSettings::from_contents("level = 10").is_err());
+        assert!(FileSettings::from_contents("store").is_err());
+        assert!(FileSettings::from_contents("method = zlib").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    error::Error,
+    io::{Read, Seek, SeekFrom}
+};
+
+use byteorder::ReadBytesExt;
+
+use crate::io_toc::{
+    io_container_flags, IoChunkId, IoOffsetAndLength, IoStoreTocCompressedBlockEntry, IoStoreTocEntryMeta, IoStoreTocHeaderType3
+};
+
+// Byte offsets of each section inside of the utoc, used when reporting where two TOCs differ
+#[derive(Debug, Default)]
+pub struct TocSectionOffsets {
+    pub chunk_ids: u64,
+    pub offsets_and_lengths: u64,
+    pub compression_blocks: u64,
+    pub compression_methods: u64,
+    pub directory_index: u64,
+    pub metas: u64,
+}
+
+// A UE 4.27 utoc read back into memory. The directory index is kept as raw bytes
+pub struct TocReader {
+    pub header: IoStoreTocHeaderType3,
+    pub chunk_ids: Vec<IoChunkId>,
+    pub offsets_and_lengths: Vec<IoOffsetAndLength>,
+    pub compression_blocks: Vec<IoStoreTocCompressedBlockEntry>,
+    pub compression_methods: Vec<String>,
+    pub directory_index: Vec<u8>,
+    pub metas: Vec<IoStoreTocEntryMeta>,
+    pub sections: TocSectionOffsets,
+}
+
+impl TocReader {
+    pub fn from_buffer<R: Read + Seek, E: byteorder::ByteOrder>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+        let mut sections = TocSectionOffsets::default();
+        let header = IoStoreTocHeaderType3::from_buffer::<R, E>(reader)?;
+        reader.seek(SeekFrom::Start(header.get_header_size() as u64))?;
+        let entry_count = header.get_entry_count() as usize;
+
+        // counts come straight from the file, so nothing is reserved up front in case they're garbage
+        sections.chunk_ids = reader.stream_position()?;
+        let mut chunk_ids = vec![];
+        for _ in 0..entry_count {
+            chunk_ids.push(IoChunkId::from_buffer::<R, E>(reader)?);
+        }
+
+        sections.offsets_and_lengths = reader.stream_position()?;
+        let mut offsets_and_lengths = vec![];
+        for _ in 0..entry_count {
+            offsets_and_lengths.push(IoOffsetAndLength::from_buffer::<R, E>(reader)?);
+        }
+
+        sections.compression_blocks = reader.stream_position()?;
+        let block_count = header.get_compressed_block_entry_count() as usize;
+        let mut compression_blocks = vec![];
+        for _ in 0..block_count {
+            let block_start = reader.stream_position()?;
+            compression_blocks.push(IoStoreTocCompressedBlockEntry::from_buffer::<R, E>(reader)?);
+            reader.seek(SeekFrom::Start(block_start + header.get_compressed_block_entry_size() as u64))?;
+        }
+
+        sections.compression_methods = reader.stream_position()?;
+        let mut compression_methods = vec![];
+        for _ in 0..header.get_compression_method_name_count() {
+            let mut name = vec![0; header.get_compression_method_name_length() as usize];
+            reader.read_exact(&mut name)?;
+            let name_len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+            compression_methods.push(String::from_utf8_lossy(&name[..name_len]).into_owned());
+        }
+
+        if header.get_container_flags() & io_container_flags::SIGNED != 0 {
+            // TOC + block signatures, then a SHA1 hash for each compression block
+            let hash_size = reader.read_u32::<E>()? as i64;
+            reader.seek(SeekFrom::Current(hash_size * 2 + 0x14 * block_count as i64))?;
+        }
+
+        sections.directory_index = reader.stream_position()?;
+        let mut directory_index = vec![];
+        if header.get_container_flags() & io_container_flags::INDEXED != 0 {
+            directory_index = vec![0; header.get_directory_index_size() as usize];
+            reader.read_exact(&mut directory_index)?;
+        }
+
+        sections.metas = reader.stream_position()?;
+        let mut metas = vec![];
+        for _ in 0..entry_count {
+            metas.push(IoStoreTocEntryMeta::from_buffer::<R, E>(reader)?);
+        }
+
+        Ok(Self {
+            header,
+            chunk_ids,
+            offsets_and_lengths,
+            compression_blocks,
+            compression_methods,
+            directory_index,
+            metas,
+            sections,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::{test_fixture::TempDir, toc_factory::TocFactoryBuilder};
+
+    #[test]
+    fn corrupt_tocs_are_an_error() {
+        let folder = TempDir::with_content("corrupt-toc", &[("A.ubulk", &[1u8; 0x123])]);
+        let mut utoc = vec![];
+        TocFactoryBuilder::new(folder.to_str().unwrap().to_string()).build().unwrap().write_files(&mut utoc, &mut vec![]).unwrap();
+
+        type EN = byteorder::NativeEndian;
+        assert!(TocReader::from_buffer::<_, EN>(&mut Cursor::new(&utoc)).is_ok());
+        let truncated = &utoc[..0x90 + 0x10]; // partway through the chunk ids
+        assert!(TocReader::from_buffer::<_, EN>(&mut Cursor::new(truncated)).is_err());
+        let mut no_block_size = utoc.clone();
+        no_block_size[0x2c..0x30].fill(0);
+        assert!(TocReader::from_buffer::<_, EN>(&mut Cursor::new(no_block_size)).is_err());
+        let mut huge_entry_count = utoc.clone();
+        huge_entry_count[0x18..0x1c].fill(0xff);
+        assert!(TocReader::from_buffer::<_, EN>(&mut Cursor::new(huge_entry_count)).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    time::SystemTime
+};
+
+use crate::{
+    io_toc::{IoChunkId, IoFileIndexEntry, IoStoreTocCompressedBlockEntry, IoStoreTocEntryMeta},
+    toc_reader::TocReader
+};
+
+// A chunk that's already stored in the ucas of the container being updated
+pub struct ExistingChunk {
+    pub length: u64,
+    pub compression_blocks: Vec<IoStoreTocCompressedBlockEntry>,
+    pub meta: IoStoreTocEntryMeta,
+}
+
+// Chunks from a previously built container that can be carried over into the updated TOC without being compressed and written
+// again. Data for chunks that changed (or were removed) is left in the ucas as dead space, since there's no compaction yet
+pub struct ExistingContainer {
+    chunks: BTreeMap<IoChunkId, ExistingChunk>,
+    built_at: SystemTime, // when the utoc was last written
+    data_end: u64, // size of the ucas, where new data gets appended
+}
+
+impl ExistingContainer {
+    pub fn from_toc(toc: &TocReader, built_at: SystemTime, data_end: u64) -> Result<Self, String> {
+        let block_size = toc.header.get_compression_block_size() as u64;
+        let mut chunks = BTreeMap::new();
+        for ((chunk_id, offset_and_length), meta) in toc.chunk_ids.iter().zip(toc.offsets_and_lengths.iter()).zip(toc.metas.iter()) {
+            // a chunk's blocks start at the block its uncompressed offset falls in, see write_files
+            let first_block = (offset_and_length.get_offset() / block_size) as usize;
+            let block_count = offset_and_length.get_length().div_ceil(block_size) as usize;
+            let compression_blocks = toc.compression_blocks.get(first_block..first_block + block_count)
+                .ok_or_else(|| format!("Chunk {} refers to compression blocks past the end of the block table", chunk_id))?;
+            chunks.insert(*chunk_id, ExistingChunk {
+                length: offset_and_length.get_length(),
+                compression_blocks: compression_blocks.to_vec(),
+                meta: meta.clone()
+            });
+        }
+        Ok(Self { chunks, built_at, data_end })
+    }
+
+    // Returns the existing chunk for file if it can be reused as-is: same chunk id, same size and not modified since the container
+    // was built
+    pub fn get_unchanged_chunk(&self, file: &IoFileIndexEntry) -> Option<&ExistingChunk> {
+        let chunk = self.chunks.get(&file.chunk_id)?;
+        let modified = fs::metadata(&file.os_path).and_then(|meta| meta.modified()).ok()?;
+        if chunk.length == file.file_size && modified <= self.built_at { Some(chunk) } else { None }
+    }
+
+    pub fn get_data_end(&self) -> u64 {
+        self.data_end
+    }
+}
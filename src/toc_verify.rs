@@ -0,0 +1,182 @@
+use std::{
+    fmt,
+    io::Cursor
+};
+
+use crate::{
+    io_toc::{IoChunkId, IoStoreTocHeaderCommon, IO_CHUNK_ID_SERIALIZED_SIZE},
+    toc_reader::TocReader
+};
+
+// Serialized offsets of each FIoStoreTocHeader field (UE 4.27), used to name the field a byte difference falls in
+const HEADER_FIELDS: [(u64, &str); 15] = [
+    (0x00, "toc_magic"),
+    (0x10, "version"),
+    (0x14, "toc_header_size"),
+    (0x18, "toc_entry_count"),
+    (0x1c, "toc_compressed_block_entry_count"),
+    (0x20, "toc_compressed_block_entry_size"),
+    (0x24, "compression_method_name_count"),
+    (0x28, "compression_method_name_length"),
+    (0x2c, "compression_block_size"),
+    (0x30, "directory_index_size"),
+    (0x34, "partition_count"),
+    (0x38, "container_id"),
+    (0x40, "encryption_key_guid"),
+    (0x50, "container_flags"),
+    (0x58, "partition_size"),
+];
+
+// Fields that identify a container rather than describe its layout, which verify_header doesn't compare
+const IDENTITY_HEADER_FIELDS: [&str; 2] = ["container_id", "encryption_key_guid"];
+
+#[derive(Debug)]
+pub struct TocDivergence {
+    pub generated_offset: u64,
+    pub reference_offset: u64,
+    pub description: String,
+}
+
+impl TocDivergence {
+    fn new(generated_offset: u64, reference_offset: u64, description: String) -> Self {
+        Self { generated_offset, reference_offset, description }
+    }
+}
+
+impl fmt::Display for TocDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (generated offset 0x{:x}, reference offset 0x{:x})", self.description, self.generated_offset, self.reference_offset)
+    }
+}
+
+// Compare a generated utoc against a known-good reference, returning the first place they differ. Header fields are compared first,
+// then chunk ids, compression block counts and the compression method table. With ignore_order, chunk ids only need to contain the
+// same set of ids
+pub fn verify_toc(generated: &TocReader, reference: &TocReader, ignore_order: bool) -> Option<TocDivergence> {
+    verify_header(generated, reference)
+        .or_else(|| if ignore_order { verify_chunk_id_set(generated, reference) } else { verify_chunk_id_order(generated, reference) })
+        .or_else(|| verify_block_count(generated, reference))
+        .or_else(|| verify_compression_methods(generated, reference))
+}
+
+// The container id comes from the container's name and the key guid from whoever built it, so a rebuild under another name or key
+// still matches its reference
+fn verify_header(generated: &TocReader, reference: &TocReader) -> Option<TocDivergence> {
+    type EN = byteorder::NativeEndian;
+    let mut generated_bytes = Cursor::new(vec![]);
+    generated.header.to_buffer::<_, EN>(&mut generated_bytes).unwrap();
+    let mut reference_bytes = Cursor::new(vec![]);
+    reference.header.to_buffer::<_, EN>(&mut reference_bytes).unwrap();
+    let offset = generated_bytes.get_ref().iter().zip(reference_bytes.get_ref().iter()).enumerate()
+        .filter(|(offset, _)| !IDENTITY_HEADER_FIELDS.contains(&get_header_field(*offset as u64)))
+        .find(|(_, (g, r))| g != r)?.0 as u64;
+    Some(TocDivergence::new(offset, offset, format!("Header field {} differs", get_header_field(offset))))
+}
+
+fn get_header_field(offset: u64) -> &'static str {
+    HEADER_FIELDS.iter().rev().find(|(field_offset, _)| *field_offset <= offset).unwrap().1
+}
+
+fn verify_chunk_id_order(generated: &TocReader, reference: &TocReader) -> Option<TocDivergence> {
+    let chunk_id_offset = |toc: &TocReader, i: usize| toc.sections.chunk_ids + i as u64 * IO_CHUNK_ID_SERIALIZED_SIZE;
+    for (i, (g, r)) in generated.chunk_ids.iter().zip(reference.chunk_ids.iter()).enumerate() {
+        if g != r {
+            return Some(TocDivergence::new(
+                chunk_id_offset(generated, i), chunk_id_offset(reference, i),
+                format!("Chunk id {} differs: generated {}, reference {}", i, g, r)
+            ));
+        }
+    }
+    if generated.chunk_ids.len() != reference.chunk_ids.len() {
+        let common = generated.chunk_ids.len().min(reference.chunk_ids.len());
+        return Some(TocDivergence::new(
+            chunk_id_offset(generated, common), chunk_id_offset(reference, common),
+            format!("Chunk id counts differ: generated {}, reference {}", generated.chunk_ids.len(), reference.chunk_ids.len())
+        ));
+    }
+    None
+}
+
+fn verify_chunk_id_set(generated: &TocReader, reference: &TocReader) -> Option<TocDivergence> {
+    let sorted_ids = |toc: &TocReader| {
+        let mut ids: Vec<(IoChunkId, usize)> = toc.chunk_ids.iter().copied().zip(0..).collect();
+        ids.sort();
+        ids
+    };
+    let generated_ids = sorted_ids(generated);
+    let reference_ids = sorted_ids(reference);
+    let (mut g, mut r) = (0, 0);
+    while g < generated_ids.len() || r < reference_ids.len() {
+        let only_in_generated = match (generated_ids.get(g), reference_ids.get(r)) {
+            (Some(gen), Some(rf)) if gen.0 == rf.0 => { g += 1; r += 1; continue; },
+            (Some(gen), Some(rf)) => gen.0 < rf.0,
+            (Some(_), None) => true,
+            _ => false
+        };
+        return Some(if only_in_generated {
+            let (id, index) = generated_ids[g];
+            TocDivergence::new(
+                generated.sections.chunk_ids + index as u64 * IO_CHUNK_ID_SERIALIZED_SIZE, reference.sections.chunk_ids,
+                format!("Chunk id {} is only in the generated TOC", id)
+            )
+        } else {
+            let (id, index) = reference_ids[r];
+            TocDivergence::new(
+                generated.sections.chunk_ids, reference.sections.chunk_ids + index as u64 * IO_CHUNK_ID_SERIALIZED_SIZE,
+                format!("Chunk id {} is only in the reference TOC", id)
+            )
+        });
+    }
+    None
+}
+
+fn verify_block_count(generated: &TocReader, reference: &TocReader) -> Option<TocDivergence> {
+    if generated.compression_blocks.len() != reference.compression_blocks.len() {
+        return Some(TocDivergence::new(
+            generated.sections.compression_blocks, reference.sections.compression_blocks,
+            format!("Compression block counts differ: generated {}, reference {}", generated.compression_blocks.len(), reference.compression_blocks.len())
+        ));
+    }
+    None
+}
+
+fn verify_compression_methods(generated: &TocReader, reference: &TocReader) -> Option<TocDivergence> {
+    let method_offset = |toc: &TocReader, i: usize| toc.sections.compression_methods + i as u64 * toc.header.get_compression_method_name_length() as u64;
+    let method_count = generated.compression_methods.len().max(reference.compression_methods.len());
+    for i in 0..method_count {
+        let (g, r) = (generated.compression_methods.get(i), reference.compression_methods.get(i));
+        if g != r {
+            return Some(TocDivergence::new(
+                method_offset(generated, i), method_offset(reference, i),
+                format!("Compression method {} differs: generated {:?}, reference {:?}", i, g, r)
+            ));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_fixture::TempDir, toc_factory::TocFactoryBuilder};
+
+    #[test]
+    fn header_ignores_container_identity() {
+        let folder = TempDir::with_content("verify-header", &[("A.ubulk", &[1u8; 0x123])]);
+
+        let build = |builder: TocFactoryBuilder| {
+            let (mut utoc, mut ucas) = (vec![], vec![]);
+            builder.build().unwrap().write_files(&mut utoc, &mut ucas).unwrap();
+            TocReader::from_buffer::<_, byteorder::NativeEndian>(&mut Cursor::new(utoc)).unwrap()
+        };
+        let source = folder.to_str().unwrap().to_string();
+        let reference = build(TocFactoryBuilder::new(source.clone()).container_name("Reference"));
+        let renamed = build(TocFactoryBuilder::new(source.clone()).container_name("Renamed"));
+        let other_block_size = build(TocFactoryBuilder::new(source).container_name("Renamed").block_size(0x1000));
+
+        // the container header's chunk id comes from the name too, but that's checked with the chunk ids
+        assert!(verify_header(&renamed, &reference).is_none());
+        let divergence = verify_header(&other_block_size, &reference).unwrap();
+        assert_eq!(divergence.description, "Header field compression_block_size differs");
+    }
+}